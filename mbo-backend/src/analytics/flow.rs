@@ -0,0 +1,316 @@
+use databento::dbn::{Action, MboMsg, Side};
+use serde::Serialize;
+
+use crate::datatypes::{
+    book::BookEffect,
+    market::{MBOMsgEffect, Market, MarketSnapshot},
+    price_level::PriceLevel,
+};
+
+/// Order flow of one side of an instrument's books during a bucket
+#[derive(Debug, Clone, Default, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct SideFlow {
+    pub adds: u64,
+    pub cancels: u64,
+    pub modifies: u64,
+    pub added_size: u64,
+    pub cancelled_size: u64,
+    /// Cancels per add, `None` without adds
+    pub cancel_to_add: Option<f64>,
+    /// Adds per second
+    pub arrival_rate: f64,
+    /// Adds by level of the publisher's book they joined, best first, the
+    ///  last counting every deeper level too
+    pub arrivals_by_level: Vec<u64>,
+    /// `arrivals_by_level` per second
+    pub arrival_rates_by_level: Vec<f64>,
+}
+impl SideFlow {
+    fn new(levels: usize) -> Self {
+        Self {
+            arrivals_by_level: vec![0; levels],
+            ..Default::default()
+        }
+    }
+
+    fn finish(&mut self, seconds: f64) {
+        self.cancel_to_add = (self.adds > 0).then(|| self.cancels as f64 / self.adds as f64);
+        self.arrival_rate = self.adds as f64 / seconds;
+        self.arrival_rates_by_level = self.arrivals_by_level.iter()
+            .map(|arrivals| *arrivals as f64 / seconds)
+            .collect();
+    }
+}
+
+/// Order flow of an instrument during a bucket of time
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct FlowBucket {
+    /// Start of the bucket, in nanoseconds since the UNIX epoch
+    pub start_ts: u64,
+    /// Exclusive end of the bucket
+    pub end_ts: u64,
+    /// Messages of the instrument received during the bucket
+    pub messages: u64,
+    /// Order flow imbalance of the aggregated BBO (Cont, Kukanov and
+    ///  Stoikov, 2014): size joining the bid or leaving the ask, less size
+    ///  leaving the bid or joining the ask
+    pub ofi: i64,
+    /// Trades whose aggressor bought
+    pub buy_volume: u64,
+    /// Trades whose aggressor sold
+    pub sell_volume: u64,
+    /// Buy volume less sell volume - trades without an aggressor side are
+    ///  only counted in `trades`
+    pub signed_volume: i64,
+    pub trades: u64,
+    pub bid: SideFlow,
+    pub ask: SideFlow,
+}
+impl FlowBucket {
+    fn new(start_ts: u64, interval: u64, levels: usize) -> Self {
+        Self {
+            start_ts,
+            end_ts: start_ts.saturating_add(interval),
+            messages: 0,
+            ofi: 0,
+            buy_volume: 0,
+            sell_volume: 0,
+            signed_volume: 0,
+            trades: 0,
+            bid: SideFlow::new(levels),
+            ask: SideFlow::new(levels),
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        let seconds = (self.end_ts - self.start_ts) as f64 / 1e9;
+        self.signed_volume = self.buy_volume as i64 - self.sell_volume as i64;
+        self.bid.finish(seconds);
+        self.ask.finish(seconds);
+        self
+    }
+}
+
+/// Incremental order flow of an instrument, in buckets of `interval`
+///  nanoseconds aligned to the UNIX epoch
+///
+/// Messages are pushed in the order they were received, with the market
+///  after applying each, and a bucket is returned once a message after it
+///  arrives. Buckets without messages of the instrument are skipped.
+#[derive(Debug)]
+pub struct OrderFlow {
+    instrument_id: u32,
+    interval: u64,
+    levels: usize,
+    bbo: (Option<PriceLevel>, Option<PriceLevel>),
+    bucket: Option<FlowBucket>,
+}
+impl OrderFlow {
+    /// Order flow over `levels` levels per side, starting from the BBO of
+    ///  `initial`, the market before the first message pushed
+    pub fn new(instrument_id: u32, interval: u64, levels: usize, initial: Option<&Market>) -> Self {
+        Self {
+            instrument_id,
+            interval,
+            levels,
+            bbo: initial.map_or((None, None), |market| market.aggregated_bbo(instrument_id)),
+            bucket: None,
+        }
+    }
+
+    /// Account for a message and the market after it, returning the
+    ///  bucket it closed, if any
+    pub fn push(&mut self, market: &Market, effect: &MBOMsgEffect) -> Option<FlowBucket> {
+        let msg = &effect.mbo_msg;
+        if msg.hd.instrument_id != self.instrument_id {
+            return None;
+        }
+
+        let start_ts = msg.ts_recv - msg.ts_recv % self.interval;
+        let closed = match &self.bucket {
+            Some(bucket) if bucket.start_ts != start_ts => self.bucket.take(),
+            _ => None,
+        };
+        let bucket = self.bucket.get_or_insert_with(|| FlowBucket::new(start_ts, self.interval, self.levels));
+        bucket.messages += 1;
+
+        let bbo = market.aggregated_bbo(self.instrument_id);
+        bucket.ofi += ofi(&self.bbo, &bbo);
+        self.bbo = bbo;
+
+        if matches!(msg.action(), Ok(Action::Trade)) {
+            bucket.trades += 1;
+            match msg.side() {
+                Ok(Side::Bid) => bucket.buy_volume += msg.size as u64,
+                Ok(Side::Ask) => bucket.sell_volume += msg.size as u64,
+                _ => {}
+            }
+        }
+
+        match &effect.market_effect.book_effect {
+            Ok(Some(BookEffect::Add { side, price, size })) => {
+                let level = arrival_level(market, msg, *side, *price).min(self.levels - 1);
+                if let Some(flow) = side_flow(bucket, *side) {
+                    flow.adds += 1;
+                    flow.added_size += *size as u64;
+                    flow.arrivals_by_level[level] += 1;
+                }
+            }
+            Ok(Some(BookEffect::Cancel { side, size, .. })) => {
+                if let Some(flow) = side_flow(bucket, *side) {
+                    flow.cancels += 1;
+                    flow.cancelled_size += *size as u64;
+                }
+            }
+            Ok(Some(BookEffect::Modify { side, .. })) => {
+                if let Some(flow) = side_flow(bucket, *side) {
+                    flow.modifies += 1;
+                }
+            }
+            Ok(None) | Err(_) => {}
+        }
+
+        closed.map(FlowBucket::finish)
+    }
+
+    /// The last, still open bucket
+    pub fn finish(self) -> Option<FlowBucket> {
+        self.bucket.map(FlowBucket::finish)
+    }
+}
+
+/// Order flow of an instrument's messages received within the inclusive
+///  bounds on `ts_recv`, in buckets of `interval` nanoseconds
+pub fn buckets(
+    snapshots: &[MarketSnapshot],
+    instrument_id: u32,
+    start_ts: Option<u64>,
+    end_ts: Option<u64>,
+    interval: u64,
+    levels: usize,
+) -> Vec<FlowBucket> {
+    // Snapshots are in the order their messages were received
+    let start = start_ts.map_or(0, |ts| snapshots.partition_point(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv < ts));
+    let end = end_ts.map_or(snapshots.len(), |ts| snapshots.partition_point(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv <= ts));
+
+    let initial = start.checked_sub(1).map(|index| &snapshots[index].market);
+    let mut flow = OrderFlow::new(instrument_id, interval, levels, initial);
+    let mut buckets = snapshots[start..end.max(start)].iter()
+        .filter_map(|snapshot| flow.push(&snapshot.market, &snapshot.mbomsg_effect))
+        .collect::<Vec<_>>();
+    buckets.extend(flow.finish());
+    buckets
+}
+
+/// Contribution of a change of the BBO to order flow imbalance
+///
+/// A side without a level is treated as size 0 at a price no other level
+///  beats, so a side emptying or filling counts its whole size.
+fn ofi(from: &(Option<PriceLevel>, Option<PriceLevel>), to: &(Option<PriceLevel>, Option<PriceLevel>)) -> i64 {
+    let bid = |level: &Option<PriceLevel>| level.as_ref().map_or((i64::MIN, 0), |level| (level.price, level.size as i64));
+    let ask = |level: &Option<PriceLevel>| level.as_ref().map_or((i64::MAX, 0), |level| (level.price, level.size as i64));
+    let ((from_bid_price, from_bid_size), (to_bid_price, to_bid_size)) = (bid(&from.0), bid(&to.0));
+    let ((from_ask_price, from_ask_size), (to_ask_price, to_ask_size)) = (ask(&from.1), ask(&to.1));
+
+    let mut e = 0;
+    if to_bid_price >= from_bid_price {
+        e += to_bid_size;
+    }
+    if to_bid_price <= from_bid_price {
+        e -= from_bid_size;
+    }
+    if to_ask_price <= from_ask_price {
+        e -= to_ask_size;
+    }
+    if to_ask_price >= from_ask_price {
+        e += from_ask_size;
+    }
+    e
+}
+
+/// Level an order added at `price` joined in its publisher's book, as the
+///  number of better levels on its side
+fn arrival_level(market: &Market, msg: &MboMsg, side: Side, price: i64) -> usize {
+    let Some((_, book)) = market.books_by_pub(msg.hd.instrument_id)
+        .and_then(|books| books.iter().find(|(publisher, _)| *publisher as u16 == msg.hd.publisher_id)) else {
+        return 0;
+    };
+
+    match side {
+        Side::Bid => book.bid_levels().take_while(|level| level.price > price).count(),
+        Side::Ask => book.ask_levels().take_while(|level| level.price < price).count(),
+        Side::None => 0,
+    }
+}
+
+fn side_flow(bucket: &mut FlowBucket, side: Side) -> Option<&mut SideFlow> {
+    match side {
+        Side::Bid => Some(&mut bucket.bid),
+        Side::Ask => Some(&mut bucket.ask),
+        Side::None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use anyhow::Result;
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    fn level(price: i64, size: u32) -> Option<PriceLevel> {
+        Some(PriceLevel { price, size, count: 1 })
+    }
+
+    #[test]
+    fn test_ofi() {
+        let bbo = (level(100, 10), level(101, 20));
+
+        assert_eq!(ofi(&bbo, &bbo), 0);
+        // Size joining the bid or leaving the ask is buying pressure
+        assert_eq!(ofi(&bbo, &(level(100, 15), level(101, 20))), 5);
+        assert_eq!(ofi(&bbo, &(level(100, 10), level(101, 12))), 8);
+        // A better bid counts its whole size, a worse one loses the old
+        assert_eq!(ofi(&bbo, &(level(101, 3), level(102, 20))), 3 + 20);
+        assert_eq!(ofi(&bbo, &(level(99, 30), level(101, 20))), -10);
+        assert_eq!(ofi(&bbo, &(None, level(101, 20))), -10);
+        assert_eq!(ofi(&(None, None), &bbo), 10 - 20);
+    }
+
+    #[tokio::test]
+    async fn test_order_flow_buckets() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let instrument_id = snapshots[0].mbomsg_effect.mbo_msg.hd.instrument_id;
+        let interval = 1_000_000_000;
+        let levels = 5;
+
+        let buckets = buckets(&snapshots, instrument_id, None, None, interval, levels);
+        let messages = snapshots.iter()
+            .filter(|snapshot| snapshot.mbomsg_effect.mbo_msg.hd.instrument_id == instrument_id)
+            .count() as u64;
+        assert_eq!(buckets.iter().map(|bucket| bucket.messages).sum::<u64>(), messages);
+        assert!(buckets.windows(2).all(|pair| pair[0].end_ts <= pair[1].start_ts));
+        assert!(buckets.iter().all(|bucket| bucket.start_ts % interval == 0 && bucket.messages > 0));
+
+        // Adds are counted once, by level, and rates are per second
+        for bucket in &buckets {
+            for flow in [&bucket.bid, &bucket.ask] {
+                assert_eq!(flow.arrivals_by_level.iter().sum::<u64>(), flow.adds);
+                assert_eq!(flow.arrivals_by_level.len(), levels);
+                assert_eq!(flow.arrival_rate, flow.adds as f64);
+            }
+            assert_eq!(bucket.signed_volume, bucket.buy_volume as i64 - bucket.sell_volume as i64);
+        }
+
+        // Each message contributes to OFI once, whatever the bucketing
+        let total = buckets.iter().map(|bucket| bucket.ofi).sum::<i64>();
+        let coarse = super::buckets(&snapshots, instrument_id, None, None, 60 * interval, levels);
+        assert_eq!(coarse.iter().map(|bucket| bucket.ofi).sum::<i64>(), total);
+        assert!(coarse.len() <= buckets.len());
+
+        assert!(super::buckets(&snapshots, u32::MAX, None, None, interval, levels).is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+pub mod flow;
+pub mod queue;
+
+use databento::dbn::{FIXED_PRICE_SCALE, MboMsg};
+use serde::Serialize;
+
+use crate::datatypes::{market::{Market, MarketSnapshot}, price_level::PriceLevel};
+
+/// Levels per side imbalance and depth-weighted mid are computed over
+///  when no count is given
+pub const DEFAULT_LEVELS: usize = 5;
+
+/// Most levels per side a client may request
+pub const MAX_LEVELS: usize = 100;
+
+/// Microstructure of an instrument's book after a message, aggregated
+///  across publishers
+///
+/// Prices are decimals, and measures needing a side of the book the
+///  instrument doesn't have are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct Microstructure {
+    /// Time the message was received, in nanoseconds since the UNIX epoch
+    pub ts_recv: u64,
+    pub instrument_id: u32,
+    /// Raw symbol of the instrument, if the source knows it
+    pub symbol: Option<String>,
+    pub bid_price: Option<f64>,
+    pub bid_size: Option<u32>,
+    pub ask_price: Option<f64>,
+    pub ask_size: Option<u32>,
+    /// Best ask minus best bid
+    pub spread: Option<f64>,
+    /// Midpoint of the best bid and ask
+    pub mid: Option<f64>,
+    /// Best bid and ask weighted by the size on the opposite side, so the
+    ///  price leans towards the side more likely to trade through
+    pub microprice: Option<f64>,
+    /// Bid size less ask size over their total, across the top levels,
+    ///  from -1 (only asks) to 1 (only bids)
+    pub imbalance: Option<f64>,
+    /// Midpoint of the size-weighted average prices of the top levels of
+    ///  each side
+    pub depth_weighted_mid: Option<f64>,
+}
+impl Microstructure {
+    /// Measure the book of `mbo_msg`'s instrument in `market`, over the
+    ///  top `levels` levels per side, which must be positive
+    pub fn new(market: &Market, mbo_msg: &MboMsg, symbol: Option<&str>, levels: usize) -> Self {
+        let (bids, asks) = market.aggregated_levels(mbo_msg.hd.instrument_id, levels);
+        Self::from_levels(mbo_msg, symbol, &bids, &asks)
+    }
+
+    /// Measure the book of `mbo_msg`'s instrument from its aggregated
+    ///  levels, best first, over every level given
+    pub fn from_levels(mbo_msg: &MboMsg, symbol: Option<&str>, bids: &[PriceLevel], asks: &[PriceLevel]) -> Self {
+        let instrument_id = mbo_msg.hd.instrument_id;
+        let (bid, ask) = (bids.first(), asks.first());
+
+        let both = bid.zip(ask);
+        let microprice = both
+            .filter(|(bid, ask)| bid.size + ask.size > 0)
+            .map(|(bid, ask)| {
+                let (bid_size, ask_size) = (bid.size as f64, ask.size as f64);
+                (price(bid.price) * ask_size + price(ask.price) * bid_size) / (bid_size + ask_size)
+            });
+
+        let bid_depth = bids.iter().map(|level| level.size as f64).sum::<f64>();
+        let ask_depth = asks.iter().map(|level| level.size as f64).sum::<f64>();
+        let imbalance = (bid_depth + ask_depth > 0.0)
+            .then(|| (bid_depth - ask_depth) / (bid_depth + ask_depth));
+
+        let depth_weighted_mid = weighted_price(bids)
+            .zip(weighted_price(asks))
+            .map(|(bid, ask)| (bid + ask) / 2.0);
+
+        Self {
+            ts_recv: mbo_msg.ts_recv,
+            instrument_id,
+            symbol: symbol.map(str::to_string),
+            bid_price: bid.map(|bid| price(bid.price)),
+            bid_size: bid.map(|bid| bid.size),
+            ask_price: ask.map(|ask| price(ask.price)),
+            ask_size: ask.map(|ask| ask.size),
+            spread: both.map(|(bid, ask)| price(ask.price - bid.price)),
+            mid: both.map(|(bid, ask)| (price(bid.price) + price(ask.price)) / 2.0),
+            microprice,
+            imbalance,
+            depth_weighted_mid,
+        }
+    }
+}
+
+/// Microstructure after each of an instrument's messages received within
+///  the inclusive bounds on `ts_recv`
+pub fn series(
+    snapshots: &[MarketSnapshot],
+    instrument_id: u32,
+    start_ts: Option<u64>,
+    end_ts: Option<u64>,
+    levels: usize,
+) -> Vec<Microstructure> {
+    // Snapshots are in the order their messages were received
+    let start = start_ts.map_or(0, |ts| snapshots.partition_point(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv < ts));
+    let end = end_ts.map_or(snapshots.len(), |ts| snapshots.partition_point(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv <= ts));
+
+    snapshots[start..end.max(start)].iter()
+        .filter(|snapshot| snapshot.mbomsg_effect.mbo_msg.hd.instrument_id == instrument_id)
+        .map(|snapshot| {
+            let effect = &snapshot.mbomsg_effect;
+            Microstructure::new(&snapshot.market, &effect.mbo_msg, effect.symbol.as_deref(), levels)
+        })
+        .collect()
+}
+
+fn price(price: i64) -> f64 {
+    price as f64 / FIXED_PRICE_SCALE as f64
+}
+
+/// Size-weighted average price of levels, `None` without any size
+fn weighted_price(levels: &[PriceLevel]) -> Option<f64> {
+    let size = levels.iter().map(|level| level.size as f64).sum::<f64>();
+    (size > 0.0).then(|| levels.iter().map(|level| price(level.price) * level.size as f64).sum::<f64>() / size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use anyhow::Result;
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    #[tokio::test]
+    async fn test_microstructure_series() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let instrument_id = snapshots[0].mbomsg_effect.mbo_msg.hd.instrument_id;
+        let ts_recv = |index: usize| snapshots[index].mbomsg_effect.mbo_msg.ts_recv;
+
+        let (start_ts, end_ts) = (ts_recv(1_000), ts_recv(3_000));
+        let points = series(&snapshots, instrument_id, Some(start_ts), Some(end_ts), DEFAULT_LEVELS);
+        let expected = snapshots.iter()
+            .filter(|snapshot| snapshot.mbomsg_effect.mbo_msg.hd.instrument_id == instrument_id)
+            .filter(|snapshot| (start_ts..=end_ts).contains(&snapshot.mbomsg_effect.mbo_msg.ts_recv))
+            .count();
+        assert_eq!(points.len(), expected);
+        assert!(points.windows(2).all(|pair| pair[0].ts_recv <= pair[1].ts_recv));
+        assert!(points.iter().all(|point| point.symbol.as_deref() == Some("CLX5")), "Points should carry the instrument's symbol");
+
+        for point in &points {
+            let (Some(bid), Some(ask)) = (point.bid_price, point.ask_price) else {
+                continue;
+            };
+            assert_eq!(point.mid, Some((bid + ask) / 2.0));
+            assert!(point.spread.is_some_and(|spread| spread > 0.0));
+            assert!(point.microprice.is_some_and(|microprice| (bid..=ask).contains(&microprice)));
+            assert!(point.imbalance.is_some_and(|imbalance| (-1.0..=1.0).contains(&imbalance)));
+            assert!(point.depth_weighted_mid.is_some());
+        }
+
+        // Over the best levels alone, the aggregated levels are the BBO
+        //  and the depth-weighted mid is the mid
+        let snapshot = &snapshots[2_000];
+        let (bids, asks) = snapshot.market.aggregated_levels(instrument_id, 1);
+        let (bid, ask) = snapshot.market.aggregated_bbo(instrument_id);
+        assert_eq!((bids.first(), asks.first()), (bid.as_ref(), ask.as_ref()));
+        let top = Microstructure::new(&snapshot.market, &snapshot.mbomsg_effect.mbo_msg, None, 1);
+        let (mid, depth_weighted_mid) = top.mid.zip(top.depth_weighted_mid).expect("Both sides are quoted");
+        assert!((mid - depth_weighted_mid).abs() < 1e-9);
+
+        let (bids, asks) = snapshot.market.aggregated_levels(instrument_id, 10);
+        assert!(bids.windows(2).all(|pair| pair[0].price > pair[1].price));
+        assert!(asks.windows(2).all(|pair| pair[0].price < pair[1].price));
+
+        assert!(series(&snapshots, instrument_id, Some(end_ts), Some(start_ts), DEFAULT_LEVELS).is_empty());
+        assert!(series(&snapshots, u32::MAX, None, None, DEFAULT_LEVELS).is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::{Context, Result, ensure};
+use databento::dbn::{Action, MboMsg, Side};
+use serde::{Deserialize, Serialize};
+
+use crate::datatypes::{
+    book::Book,
+    book_state::index_at,
+    market::{Market, MarketSnapshot},
+};
+
+/// Side of the book a virtual order rests on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderSide {
+    Bid,
+    Ask,
+}
+impl From<OrderSide> for Side {
+    fn from(side: OrderSide) -> Self {
+        match side {
+            OrderSide::Bid => Side::Bid,
+            OrderSide::Ask => Side::Ask,
+        }
+    }
+}
+
+/// A hypothetical limit order joining the back of the queue at its price
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct VirtualOrder {
+    pub instrument_id: u32,
+    /// Publisher whose book the order joins (default: the publisher of
+    ///  the instrument's first message)
+    pub publisher_id: Option<u16>,
+    pub side: OrderSide,
+    pub price: i64,
+    pub size: u32,
+    /// Time the order is placed, in nanoseconds since the UNIX epoch
+    pub ts: u64,
+}
+
+/// The order's queue position or fills after an event changed them
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct QueueUpdate {
+    /// Position in the loaded market of the event's last message
+    pub index: usize,
+    pub ts_recv: u64,
+    /// Size resting ahead of the order
+    pub queue_ahead: u64,
+    /// Size of the order filled so far
+    pub filled: u32,
+}
+
+/// Part of the order estimated to trade in an event
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct VirtualFill {
+    /// Position in the loaded market of the event's last message
+    pub index: usize,
+    pub ts_recv: u64,
+    pub size: u32,
+}
+
+/// What would have happened to a virtual order over the replay
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct FillReport {
+    pub order: VirtualOrder,
+    /// Raw symbol of the order's instrument when it was placed, if known
+    pub symbol: Option<String>,
+    pub publisher_id: u16,
+    /// Position in the loaded market of the last message before the order
+    ///  was placed, `None` if it was placed before the first one
+    pub entry_index: Option<usize>,
+    /// Size resting ahead of the order when it was placed
+    pub initial_queue_ahead: u64,
+    /// Size still ahead of the order at the end of the replay
+    pub queue_ahead: u64,
+    /// Size ahead of the order that traded
+    pub traded_ahead: u64,
+    /// Size ahead of the order that was cancelled or lost its priority
+    pub cancelled_ahead: u64,
+    pub filled: u32,
+    pub fills: Vec<VirtualFill>,
+    /// Nanoseconds from placing the order to its last fill, once fully
+    ///  filled
+    pub time_to_fill: Option<u64>,
+    /// Events of the instrument replayed after the order was placed
+    pub events: usize,
+    pub updates: Vec<QueueUpdate>,
+}
+
+/// Replay the loaded market with a virtual order resting in it, up to its
+///  full fill or the last message received at or before `end_ts`
+///
+/// The order is placed after every message received by its `ts` and
+///  joins the back of the queue at its price, behind the orders resting
+///  there. Those leave the queue ahead as they're cancelled, trade, or
+///  lose their priority by being modified to another price or a larger
+///  size. The order isn't seen by the rest of the market, which replays
+///  unchanged.
+///
+/// Fills are estimated at the end of each event, when the book has
+///  settled: trades at the order's price whose aggressor is on the other
+///  side fill it once they exceed the size ahead of it at the start of
+///  the event, trades through its price fill it outright, and orders
+///  resting at or through its price on the other side fill it up to
+///  their size.
+pub fn simulate(snapshots: &[MarketSnapshot], order: VirtualOrder, end_ts: Option<u64>) -> Result<FillReport> {
+    ensure!(order.size > 0, "Order size must be positive");
+    let side = Side::from(order.side);
+    let price = order.price;
+
+    let publisher_id = match order.publisher_id {
+        Some(publisher_id) => publisher_id,
+        None => snapshots.iter()
+            .map(|snapshot| &snapshot.mbomsg_effect.mbo_msg)
+            .find(|msg| msg.hd.instrument_id == order.instrument_id)
+            .map(|msg| msg.hd.publisher_id)
+            .context(format!("Instrument {} has no messages", order.instrument_id))?,
+    };
+
+    let entry_index = index_at(snapshots, order.ts);
+    let empty = Book::new();
+    let entry_market = entry_index.map(|index| &snapshots[index].market);
+    let entry_book = entry_market.and_then(|market| book(market, order.instrument_id, publisher_id)).unwrap_or(&empty);
+    ensure!(
+        through_size(entry_book, side, price) == 0,
+        "Order at {} would trade on entry against the other side of the book",
+        price,
+    );
+
+    // Orders ahead, in queue order, with their size as last seen
+    let mut ahead = entry_book.level_orders(side, price)
+        .filter(|resting| !resting.flags.is_tob())
+        .map(|resting| (resting.order_id, resting.size))
+        .collect::<Vec<_>>();
+    let initial_queue_ahead = ahead.iter().map(|(_, size)| *size as u64).sum::<u64>();
+
+    // As of the instrument's last message before the order, or its first
+    //  one after if there is none
+    let start = entry_index.map_or(0, |index| index + 1);
+    let symbol = snapshots[..start].iter().rev()
+        .chain(&snapshots[start..])
+        .map(|snapshot| &snapshot.mbomsg_effect)
+        .find(|effect| effect.mbo_msg.hd.instrument_id == order.instrument_id)
+        .and_then(|effect| effect.symbol.clone());
+
+    let mut report = FillReport {
+        order,
+        symbol,
+        publisher_id,
+        entry_index,
+        initial_queue_ahead,
+        queue_ahead: initial_queue_ahead,
+        traded_ahead: 0,
+        cancelled_ahead: 0,
+        filled: 0,
+        fills: Vec::new(),
+        time_to_fill: None,
+        events: 0,
+        updates: Vec::new(),
+    };
+
+    let end = end_ts.map_or(snapshots.len(), |ts| snapshots.partition_point(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv <= ts));
+    let mut traded_at_price = 0u64;
+    let mut swept = false;
+
+    for (index, snapshot) in snapshots.iter().enumerate().take(end).skip(start) {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        if msg.hd.instrument_id != report.order.instrument_id || msg.hd.publisher_id != publisher_id {
+            continue;
+        }
+
+        if let Some(traded) = trade_against(msg, side) {
+            if msg.price == price {
+                traded_at_price += traded as u64;
+            } else if is_through(side, msg.price, price) {
+                swept = true;
+            }
+        }
+        if !msg.flags.is_last() {
+            continue;
+        }
+
+        // The event is over and the book has settled
+        report.events += 1;
+        let book = book(&snapshot.market, report.order.instrument_id, publisher_id).unwrap_or(&empty);
+        ahead.retain_mut(|(order_id, size)| match book.order(*order_id) {
+            Some(resting) if resting.price == price && resting.size <= *size => {
+                *size = resting.size;
+                true
+            }
+            _ => false,
+        });
+        let queue_ahead = ahead.iter().map(|(_, size)| *size as u64).sum::<u64>();
+
+        let left_ahead = report.queue_ahead - queue_ahead;
+        let traded_ahead = traded_at_price.min(report.queue_ahead);
+        report.traded_ahead += traded_ahead.min(left_ahead);
+        report.cancelled_ahead += left_ahead.saturating_sub(traded_ahead);
+
+        let remaining = report.order.size - report.filled;
+        let fill = if swept {
+            remaining
+        } else {
+            // Orders resting through the price stay there, as the replay
+            //  doesn't see the order, so they only fill it once
+            let traded_past = traded_at_price.saturating_sub(report.queue_ahead);
+            let resting_through = through_size(book, side, price).saturating_sub(report.filled as u64);
+            traded_past.max(resting_through).min(remaining as u64) as u32
+        };
+        traded_at_price = 0;
+        swept = false;
+
+        if fill == 0 && queue_ahead == report.queue_ahead {
+            continue;
+        }
+        report.queue_ahead = queue_ahead;
+        if fill > 0 {
+            report.filled += fill;
+            report.fills.push(VirtualFill { index, ts_recv: msg.ts_recv, size: fill });
+        }
+        report.updates.push(QueueUpdate {
+            index,
+            ts_recv: msg.ts_recv,
+            queue_ahead,
+            filled: report.filled,
+        });
+
+        if report.filled == report.order.size {
+            report.time_to_fill = Some(msg.ts_recv.saturating_sub(report.order.ts));
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+fn book(market: &Market, instrument_id: u32, publisher_id: u16) -> Option<&Book> {
+    market.books_by_pub(instrument_id)?
+        .iter()
+        .find(|(publisher, _)| *publisher as u16 == publisher_id)
+        .map(|(_, book)| book)
+}
+
+/// Size of a trade whose aggressor took liquidity from `side`
+fn trade_against(msg: &MboMsg, side: Side) -> Option<u32> {
+    let aggressor = match side {
+        Side::Bid => Side::Ask,
+        _ => Side::Bid,
+    };
+    (matches!(msg.action(), Ok(Action::Trade)) && msg.side().is_ok_and(|trade_side| trade_side == aggressor))
+        .then_some(msg.size)
+}
+
+/// Whether `other` is a worse price than `price` for an order on `side`
+fn is_through(side: Side, other: i64, price: i64) -> bool {
+    match side {
+        Side::Bid => other < price,
+        _ => other > price,
+    }
+}
+
+/// Size resting on the other side at or through `price`, which would have
+///  traded with an order on `side` at that price
+fn through_size(book: &Book, side: Side, price: i64) -> u64 {
+    match side {
+        Side::Bid => book.ask_levels().take_while(|level| level.price <= price).map(|level| level.size as u64).sum(),
+        _ => book.bid_levels().take_while(|level| level.price >= price).map(|level| level.size as u64).sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    #[tokio::test]
+    async fn test_virtual_order_queue() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let instrument_id = snapshots[0].mbomsg_effect.mbo_msg.hd.instrument_id;
+
+        // Join the best bid some way into the data
+        let entry = 2_000;
+        let ts = snapshots[entry].mbomsg_effect.mbo_msg.ts_recv;
+        let entry_index = index_at(&snapshots, ts).unwrap();
+        let (bid, ask) = snapshots[entry_index].market.aggregated_bbo(instrument_id);
+        let (bid, ask) = (bid.unwrap(), ask.unwrap());
+        let order = VirtualOrder { instrument_id, publisher_id: None, side: OrderSide::Bid, price: bid.price, size: 5, ts };
+
+        let report = simulate(&snapshots, order.clone(), None)?;
+        assert_eq!(report.entry_index, Some(entry_index));
+        assert_eq!(report.initial_queue_ahead, bid.size as u64);
+        assert_eq!(report.symbol.as_deref(), Some("CLX5"));
+
+        // The queue ahead only shrinks, by what traded or was cancelled,
+        //  and fills add up
+        assert!(report.updates.windows(2).all(|pair| pair[1].queue_ahead <= pair[0].queue_ahead));
+        assert!(report.updates.windows(2).all(|pair| pair[0].index < pair[1].index));
+        assert_eq!(report.initial_queue_ahead - report.queue_ahead, report.traded_ahead + report.cancelled_ahead);
+        assert_eq!(report.fills.iter().map(|fill| fill.size).sum::<u32>(), report.filled);
+        assert!(report.filled <= order.size);
+        assert_eq!(report.time_to_fill.is_some(), report.filled == order.size);
+
+        // A larger order in the same place fills at least as much
+        let larger = simulate(&snapshots, VirtualOrder { size: 50, ..order.clone() }, None)?;
+        assert_eq!(larger.initial_queue_ahead, report.initial_queue_ahead);
+        assert!(larger.filled >= report.filled);
+
+        // Deeper in the book, nothing is ahead, and the replay can stop early
+        let deep = VirtualOrder { price: bid.price - 1_000 * 10_000_000, ..order.clone() };
+        let end_ts = snapshots[entry + 100].mbomsg_effect.mbo_msg.ts_recv;
+        let report = simulate(&snapshots, deep, Some(end_ts))?;
+        assert_eq!(report.initial_queue_ahead, 0);
+        assert!(report.updates.iter().all(|update| update.index <= entry + 100));
+
+        // A bid at the ask would trade on entry
+        assert!(simulate(&snapshots, VirtualOrder { price: ask.price, ..order.clone() }, None).is_err());
+        assert!(simulate(&snapshots, VirtualOrder { instrument_id: u32::MAX, ..order }, None).is_err());
+
+        Ok(())
+    }
+}
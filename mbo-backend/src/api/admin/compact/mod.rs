@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::RwLock;
+use tracing::{instrument, error};
+
+use super::unauthorized;
+use crate::storage::retention::{self, CompactionReport, RetentionConfig};
+
+
+/// Overrides of the configured retention
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct CompactQuery {
+    /// Days of raw messages to keep (default: `RETENTION_RAW_DAYS`)
+    pub raw_days: Option<u64>,
+    /// Reclaim the freed space afterwards (default: `RETENTION_VACUUM`, or true)
+    pub vacuum: Option<bool>,
+}
+
+/// Run a compaction now
+///
+/// Aggregates the trades of raw messages older than the retention into
+/// one-minute bars, then deletes those messages. Messages after the
+/// latest checkpoint are always kept, so the market can be restored.
+///
+/// Requires `ADMIN_TOKEN` as a bearer token, and is disabled when it
+/// isn't set. Returns `400 Bad Request` if no retention is configured or
+/// given.
+#[utoipa::path(
+    post,
+    path = "/api/admin/compact",
+    params(CompactQuery),
+    responses(
+        (status = 200, description = "Outcome of the compaction", body = CompactionReport),
+        (status = 400, description = "No retention configured or given"),
+        (status = 401, description = "Missing or invalid admin token"),
+        (status = 403, description = "No ADMIN_TOKEN configured"),
+        (status = 500, description = "Compaction failed"),
+    ),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<CompactQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let (storage, metrics, configured) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        if let Some(response) = unauthorized(state_read.admin_token.as_deref(), &headers) {
+            return response;
+        }
+        (state_read.storage.clone(), Arc::clone(&state_read.metrics), state_read.retention.clone())
+    };
+
+    let config = match (query.raw_days, configured) {
+        (Some(raw_days), configured) => RetentionConfig {
+            raw_days,
+            interval: configured.as_ref().map_or(Duration::ZERO, |config| config.interval),
+            vacuum: query.vacuum.or(configured.map(|config| config.vacuum)).unwrap_or(true),
+        },
+        (None, Some(configured)) => RetentionConfig {
+            vacuum: query.vacuum.unwrap_or(configured.vacuum),
+            ..configured
+        },
+        (None, None) => {
+            return (StatusCode::BAD_REQUEST, "raw_days is required when RETENTION_RAW_DAYS isn't set").into_response();
+        }
+    };
+
+    let report = match retention::run_once(&storage, &config, &metrics).await {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Compaction failed: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Compaction failed").into_response();
+        }
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(report).into_response()
+}
@@ -0,0 +1,54 @@
+pub mod compact;
+pub mod storage;
+
+use axum::{
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+/// The response to send instead if a request doesn't carry the admin
+///  token as a bearer token
+///
+/// That's `403 Forbidden` if no `ADMIN_TOKEN` is configured, which
+///  disables the endpoint, or `401 Unauthorized` if the token is missing
+///  or wrong.
+pub fn unauthorized(admin_token: Option<&str>, headers: &HeaderMap) -> Option<Response> {
+    let Some(admin_token) = admin_token else {
+        return Some((StatusCode::FORBIDDEN, "Set ADMIN_TOKEN to enable admin endpoints").into_response());
+    };
+
+    let given = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    // Digests are compared so the time taken doesn't reveal the token
+    match given {
+        Some(given) if Sha256::digest(given) == Sha256::digest(admin_token) => None,
+        _ => Some((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Missing or invalid admin token",
+        ).into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_admin_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(unauthorized(None, &headers).map(|response| response.status()), Some(StatusCode::FORBIDDEN));
+        assert_eq!(unauthorized(Some("secret"), &headers).map(|response| response.status()), Some(StatusCode::UNAUTHORIZED));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert_eq!(unauthorized(Some("secret"), &headers).map(|response| response.status()), Some(StatusCode::UNAUTHORIZED));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(unauthorized(Some("secret"), &headers).is_none());
+        assert_eq!(unauthorized(None, &headers).map(|response| response.status()), Some(StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,51 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{instrument, error};
+
+use crate::storage::{retention, StorageStats};
+
+
+/// Size of the store
+///
+/// Reports row counts of raw messages, checkpoints and bars, the oldest
+/// raw message kept, and the size of the database on disk. The same
+/// figures are published to the `mbo_storage_*` metrics.
+#[utoipa::path(
+    get,
+    path = "/api/admin/storage",
+    responses(
+        (status = 200, description = "Storage size", body = StorageStats),
+        (status = 500, description = "Failed to read storage stats"),
+    ),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let (storage, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (state_read.storage.clone(), Arc::clone(&state_read.metrics))
+    };
+
+    let stats = match retention::report_stats(&storage, &metrics).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to read storage stats: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read storage stats").into_response();
+        }
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(stats).into_response()
+}
@@ -0,0 +1,88 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::analytics::{DEFAULT_LEVELS, MAX_LEVELS, flow::{self, FlowBucket}};
+
+/// Bucket length when none is given, in milliseconds
+const DEFAULT_INTERVAL_MS: u64 = 1_000;
+
+/// Longest bucket a client may request, a day
+const MAX_INTERVAL_MS: u64 = 86_400_000;
+
+/// Time range and bucketing of the order flow
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct FlowQuery {
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+    /// Length of each bucket in milliseconds, at most a day (default: 1000)
+    pub interval_ms: Option<u64>,
+    /// Book levels per side to count order arrivals at, between 1 and 100 (default: 5)
+    pub levels: Option<usize>,
+}
+
+/// Order flow statistics of an instrument, in time buckets
+///
+/// Replays the instrument's messages in the loaded market and, for each
+/// bucket, reports order flow imbalance from changes of the aggregated
+/// BBO, trade volume signed by aggressor side, and per side the adds,
+/// cancels and modifies, the cancel-to-add ratio and order arrival rates
+/// by book level. Buckets are aligned to the UNIX epoch and only listed
+/// if the instrument had messages in them.
+#[utoipa::path(
+    get,
+    path = "/api/analytics/{instrument_id}/flow",
+    params(
+        ("instrument_id" = u32, Path, description = "Instrument ID"),
+        FlowQuery,
+    ),
+    responses(
+        (status = 200, description = "Order flow of the instrument by bucket", body = Vec<FlowBucket>),
+        (status = 400, description = "Invalid interval, level count or time range"),
+    ),
+    tag = "analytics"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(instrument_id): Path<u32>,
+    Query(query): Query<FlowQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let interval_ms = query.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS);
+    if !(1..=MAX_INTERVAL_MS).contains(&interval_ms) {
+        return (StatusCode::BAD_REQUEST, format!("interval_ms must be between 1 and {}", MAX_INTERVAL_MS)).into_response();
+    }
+    let levels = query.levels.unwrap_or(DEFAULT_LEVELS);
+    if !(1..=MAX_LEVELS).contains(&levels) {
+        return (StatusCode::BAD_REQUEST, format!("levels must be between 1 and {}", MAX_LEVELS)).into_response();
+    }
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return (StatusCode::BAD_REQUEST, "start_ts must not be after end_ts").into_response();
+        }
+    }
+
+    let (buckets, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (
+            flow::buckets(&state_read.market_snapshots, instrument_id, query.start_ts, query.end_ts, interval_ms * 1_000_000, levels),
+            Arc::clone(&state_read.metrics),
+        )
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(buckets).into_response()
+}
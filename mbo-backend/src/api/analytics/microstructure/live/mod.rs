@@ -0,0 +1,119 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+use tracing::{instrument, info, warn, error};
+
+use crate::{
+    analytics::{DEFAULT_LEVELS, MAX_LEVELS, Microstructure},
+    api::mbo::stream::live::ConnectionGuard,
+};
+
+/// Depth of the live microstructure measures
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct LiveMicrostructureQuery {
+    /// Levels per side for imbalance and depth-weighted mid, between 1 and 100 (default: 5)
+    pub levels: Option<usize>,
+}
+
+/// Stream an instrument's live microstructure as Server-Sent Events
+///
+/// Sends the microstructure of the instrument's live book, aggregated
+/// across publishers, after each of its messages received from the
+/// Databento live gateway, measured from the book exactly as the message
+/// left it. A client falling too far behind skips messages.
+///
+/// Returns `503 Service Unavailable` when live ingestion is not configured.
+#[utoipa::path(
+    get,
+    path = "/api/analytics/{instrument_id}/microstructure/live",
+    params(
+        ("instrument_id" = u32, Path, description = "Instrument ID"),
+        LiveMicrostructureQuery,
+    ),
+    responses(
+        (status = 200, description = "SSE stream of the instrument's microstructure", content_type = "text/event-stream"),
+        (status = 400, description = "Invalid level count"),
+        (status = 503, description = "Live ingestion is not configured"),
+    ),
+    tag = "analytics"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(instrument_id): Path<u32>,
+    Query(query): Query<LiveMicrostructureQuery>,
+) -> Response {
+    use axum::response::sse::{Event, Sse};
+    use futures::stream::{self, StreamExt};
+
+    let start = std::time::Instant::now();
+
+    let levels = query.levels.unwrap_or(DEFAULT_LEVELS);
+    if !(1..=MAX_LEVELS).contains(&levels) {
+        return (StatusCode::BAD_REQUEST, format!("levels must be between 1 and {}", MAX_LEVELS)).into_response();
+    }
+
+    let state_read = state.read().await;
+    state_read.metrics.http_requests_total.inc();
+
+    let Some(live_feed) = &state_read.live_feed else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Live ingestion is not configured").into_response();
+    };
+
+    info!(instrument_id, "Client connected to live microstructure stream");
+    state_read.metrics.active_connections.inc();
+
+    let receiver = live_feed.levels.subscribe();
+    let metrics = Arc::clone(&state_read.metrics);
+    let guard = ConnectionGuard(Arc::clone(&state_read.metrics));
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    // Drop the read lock before streaming
+    drop(state_read);
+
+    let stream = stream::unfold((receiver, guard), move |(mut receiver, guard)| {
+        let metrics = Arc::clone(&metrics);
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(live) if live.mbo_msg.hd.instrument_id == instrument_id => {
+                        let microstructure = Microstructure::from_levels(
+                            &live.mbo_msg,
+                            live.symbol.as_deref(),
+                            &live.bids[..levels.min(live.bids.len())],
+                            &live.asks[..levels.min(live.asks.len())],
+                        );
+
+                        let event = match serde_json::to_string(&microstructure) {
+                            Ok(json) => Event::default().data(json),
+                            Err(e) => {
+                                error!("Failed to serialize Microstructure: {}", e);
+                                metrics.messages_processing_errors.inc();
+                                Event::default().data(format!("{{\"error\": \"{}\"}}", e))
+                            }
+                        };
+                        return Some((Ok::<_, std::convert::Infallible>(event), (receiver, guard)));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Live microstructure client lagged behind, skipping messages");
+                        metrics.messages_processing_errors.inc_by(skipped as f64);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+        .chain(stream::once(async { Ok(Event::default().comment("stream_end")) }));
+
+    Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+            .interval(std::time::Duration::from_secs(15))
+    ).into_response()
+}
@@ -0,0 +1,76 @@
+pub mod live;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::analytics::{self, DEFAULT_LEVELS, MAX_LEVELS, Microstructure};
+
+/// Time range and depth of the microstructure series
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct MicrostructureQuery {
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+    /// Levels per side for imbalance and depth-weighted mid, between 1 and 100 (default: 5)
+    pub levels: Option<usize>,
+}
+
+/// Microstructure time series of an instrument
+///
+/// Measures the instrument's book, aggregated across publishers, after
+/// each of its messages in the loaded market: spread, mid, microprice,
+/// order book imbalance over the top levels and the depth-weighted mid.
+/// Points are in the order their messages were received.
+#[utoipa::path(
+    get,
+    path = "/api/analytics/{instrument_id}/microstructure",
+    params(
+        ("instrument_id" = u32, Path, description = "Instrument ID"),
+        MicrostructureQuery,
+    ),
+    responses(
+        (status = 200, description = "Microstructure after each of the instrument's messages", body = Vec<Microstructure>),
+        (status = 400, description = "Invalid level count or time range"),
+    ),
+    tag = "analytics"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(instrument_id): Path<u32>,
+    Query(query): Query<MicrostructureQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let levels = query.levels.unwrap_or(DEFAULT_LEVELS);
+    if !(1..=MAX_LEVELS).contains(&levels) {
+        return (StatusCode::BAD_REQUEST, format!("levels must be between 1 and {}", MAX_LEVELS)).into_response();
+    }
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return (StatusCode::BAD_REQUEST, "start_ts must not be after end_ts").into_response();
+        }
+    }
+
+    let (points, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (
+            analytics::series(&state_read.market_snapshots, instrument_id, query.start_ts, query.end_ts, levels),
+            Arc::clone(&state_read.metrics),
+        )
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(points).into_response()
+}
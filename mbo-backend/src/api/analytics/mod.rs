@@ -0,0 +1,3 @@
+pub mod flow;
+pub mod microstructure;
+pub mod queue;
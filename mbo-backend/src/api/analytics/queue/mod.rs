@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::analytics::queue::{self, FillReport, OrderSide, VirtualOrder};
+
+/// The virtual order to place and how long to follow it
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct QueueQuery {
+    /// Side of the book the order rests on: `bid` or `ask`
+    #[param(inline)]
+    pub side: OrderSide,
+    /// Limit price of the order, in units of 1e-9
+    pub price: i64,
+    /// Size of the order
+    pub size: u32,
+    /// Time the order is placed, in nanoseconds since the UNIX epoch
+    pub ts: u64,
+    /// Publisher whose book the order joins (default: the publisher of the instrument's first message)
+    pub publisher_id: Option<u16>,
+    /// Stop following the order after this time, in nanoseconds since the UNIX epoch (default: end of the market)
+    pub end_ts: Option<u64>,
+}
+
+/// Simulate the queue position and fills of a hypothetical order
+///
+/// Places a virtual limit order at the back of the queue at its price,
+/// as of `ts`, and replays the instrument's following messages in the
+/// loaded market against it. The queue ahead shrinks as orders ahead are
+/// cancelled, trade or lose priority, and trades reaching past it fill
+/// the order. The report lists every change of queue position, the
+/// estimated fills and the time to a full fill, if it happens.
+///
+/// The replayed market doesn't react to the order, so fills are an
+/// estimate for an order small enough not to change what others do.
+#[utoipa::path(
+    get,
+    path = "/api/analytics/{instrument_id}/queue",
+    params(
+        ("instrument_id" = u32, Path, description = "Instrument ID"),
+        QueueQuery,
+    ),
+    responses(
+        (status = 200, description = "Fill report of the virtual order", body = FillReport),
+        (status = 400, description = "Invalid order, or one that would trade on entry"),
+    ),
+    tag = "analytics"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(instrument_id): Path<u32>,
+    Query(query): Query<QueueQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    if query.end_ts.is_some_and(|end_ts| end_ts < query.ts) {
+        return (StatusCode::BAD_REQUEST, "end_ts must not be before ts").into_response();
+    }
+    let order = VirtualOrder {
+        instrument_id,
+        publisher_id: query.publisher_id,
+        side: query.side,
+        price: query.price,
+        size: query.size,
+        ts: query.ts,
+    };
+
+    let (report, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (
+            queue::simulate(&state_read.market_snapshots, order, query.end_ts),
+            Arc::clone(&state_read.metrics),
+        )
+    };
+
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(report).into_response()
+}
@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::datatypes::book_state::BookAt;
+
+/// Events returned when no count is given
+const DEFAULT_EVENTS: usize = 20;
+
+/// Most events a client may request
+const MAX_EVENTS: usize = 1_000;
+
+/// Point in time to show the book at
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct BookAtQuery {
+    /// Time of the book, in nanoseconds since the UNIX epoch
+    pub ts: u64,
+    /// Levels per side of each book (default: all)
+    pub depth: Option<usize>,
+    /// Last messages of the instrument to include, at most 1000 (default: 20)
+    pub events: Option<usize>,
+}
+
+/// An instrument's book as of an arbitrary timestamp
+///
+/// Binary-searches the loaded market for the last message received at
+/// or before `ts`, so the book is exactly as it was at that nanosecond,
+/// after every message received in it. Each publisher's book is listed
+/// as price levels, best first, alongside the instrument's messages that
+/// led to it, oldest first.
+///
+/// Returns `404 Not Found` if the instrument has no messages by `ts`.
+#[utoipa::path(
+    get,
+    path = "/api/book/{instrument_id}/at",
+    params(
+        ("instrument_id" = u32, Path, description = "Instrument ID"),
+        BookAtQuery,
+    ),
+    responses(
+        (status = 200, description = "Book of the instrument at the timestamp", body = BookAt),
+        (status = 400, description = "Invalid depth or event count"),
+        (status = 404, description = "The instrument has no messages at or before the timestamp"),
+    ),
+    tag = "book"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(instrument_id): Path<u32>,
+    Query(query): Query<BookAtQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    if query.depth == Some(0) {
+        return (StatusCode::BAD_REQUEST, "depth must be positive").into_response();
+    }
+    let event_count = query.events.unwrap_or(DEFAULT_EVENTS);
+    if event_count > MAX_EVENTS {
+        return (StatusCode::BAD_REQUEST, format!("events must be at most {}", MAX_EVENTS)).into_response();
+    }
+
+    let (book_at, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (
+            BookAt::new(&state_read.market_snapshots, instrument_id, query.ts, query.depth, event_count),
+            Arc::clone(&state_read.metrics),
+        )
+    };
+
+    // The instrument's books only exist once it has had a message
+    if book_at.books.is_empty() && book_at.events.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("No messages for instrument {} at or before {}", instrument_id, query.ts),
+        ).into_response();
+    }
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(book_at).into_response()
+}
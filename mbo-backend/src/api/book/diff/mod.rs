@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::datatypes::book_state::{BookDiffRange, index_at};
+
+/// What `from` and `to` refer to
+#[derive(Debug, Clone, Copy, Default, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffBy {
+    /// Nanoseconds since the UNIX epoch, as with `/api/book/{instrument_id}/at`
+    #[default]
+    Ts,
+    /// Positions of messages in the loaded market
+    Index,
+}
+
+/// The two points in time to diff
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct BookDiffQuery {
+    /// Earlier point, the book after every message received by then
+    pub from: u64,
+    /// Later point, not before `from`
+    pub to: u64,
+    /// Whether `from` and `to` are timestamps or message indices (default: `ts`)
+    #[param(inline)]
+    pub by: Option<DiffBy>,
+}
+
+/// What changed in an instrument's books between two points in time
+///
+/// Lists, per publisher, the orders added, removed and changed in price
+/// or size, and the price levels whose size or order count changed, for
+/// publishers whose book changed. The points are either timestamps,
+/// each resolved to the last message received at or before it, or
+/// message indices, each meaning the book after that message.
+#[utoipa::path(
+    get,
+    path = "/api/book/{instrument_id}/diff",
+    params(
+        ("instrument_id" = u32, Path, description = "Instrument ID"),
+        BookDiffQuery,
+    ),
+    responses(
+        (status = 200, description = "Changes to the instrument's books", body = BookDiffRange),
+        (status = 400, description = "`from` is after `to`, or an index is out of range"),
+    ),
+    tag = "book"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(instrument_id): Path<u32>,
+    Query(query): Query<BookDiffQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    if query.from > query.to {
+        return (StatusCode::BAD_REQUEST, "from must not be after to").into_response();
+    }
+
+    let state_read = state.read().await;
+    state_read.metrics.http_requests_total.inc();
+    let snapshots = &state_read.market_snapshots;
+
+    let (from_index, to_index) = match query.by.unwrap_or_default() {
+        DiffBy::Ts => (index_at(snapshots, query.from), index_at(snapshots, query.to)),
+        DiffBy::Index => {
+            if query.to >= snapshots.len() as u64 {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Index {} is out of range, the market has {} messages", query.to, snapshots.len()),
+                ).into_response();
+            }
+            (Some(query.from as usize), Some(query.to as usize))
+        }
+    };
+    let diff = BookDiffRange::new(snapshots, instrument_id, from_index, to_index);
+
+    state_read.metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(diff).into_response()
+}
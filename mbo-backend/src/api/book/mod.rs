@@ -0,0 +1,2 @@
+pub mod at;
+pub mod diff;
\ No newline at end of file
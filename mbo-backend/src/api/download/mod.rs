@@ -0,0 +1,303 @@
+use std::{io::SeekFrom, path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::error;
+
+/// Hex SHA-256 of a value's JSON, for entity tags of downloads which are
+///  fully determined by their parameters
+pub fn key<T: Serialize>(value: &T) -> Result<String> {
+    let json = serde_json::to_vec(value)
+        .context("...while serializing download parameters")?;
+    Ok(format!("{:x}", Sha256::digest(json)))
+}
+
+/// A downloadable representation and its validators, answering
+///  conditional (`If-None-Match`, `If-Modified-Since`) and range
+///  (`Range`, `If-Range`) requests for it
+///
+/// Only single byte ranges are served; requests for several ranges get
+///  the whole representation, which HTTP allows.
+#[derive(Debug, Clone)]
+pub struct Download {
+    /// Strong entity tag, quoted
+    etag: String,
+    last_modified: Option<SystemTime>,
+    content_type: &'static str,
+    filename: String,
+}
+impl Download {
+    pub fn new(key: &str, content_type: &'static str, filename: impl Into<String>) -> Self {
+        Self {
+            etag: format!("\"{}\"", key),
+            last_modified: None,
+            content_type,
+            filename: filename.into(),
+        }
+    }
+
+    pub fn last_modified(mut self, last_modified: SystemTime) -> Self {
+        self.last_modified = Some(last_modified);
+        self
+    }
+
+    /// A `304 Not Modified` response if the client's copy is current
+    ///
+    /// `If-Modified-Since` is only considered without `If-None-Match`.
+    pub fn not_modified(&self, request: &HeaderMap) -> Option<Response> {
+        let current = match request.get(header::IF_NONE_MATCH) {
+            Some(if_none_match) => if_none_match.to_str()
+                .is_ok_and(|tags| tags.split(',').any(|tag| {
+                    let tag = tag.trim();
+                    tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag
+                })),
+            None => match (self.last_modified, request.get(header::IF_MODIFIED_SINCE)) {
+                (Some(last_modified), Some(since)) => since.to_str()
+                    .ok()
+                    .and_then(|since| httpdate::parse_http_date(since).ok())
+                    .is_some_and(|since| truncate_to_secs(last_modified) <= since),
+                _ => false,
+            },
+        };
+
+        current.then(|| (StatusCode::NOT_MODIFIED, self.headers()).into_response())
+    }
+
+    /// Serve a file, or the part of it requested
+    pub async fn file(mut self, request: &HeaderMap, path: &Path) -> Response {
+        let opened = async {
+            let file = tokio::fs::File::open(path).await?;
+            let metadata = file.metadata().await?;
+            std::io::Result::Ok((file, metadata))
+        }.await;
+        let (mut file, metadata) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                error!("Failed to open download {:?}: {:?}", path, e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to open the download").into_response();
+            }
+        };
+        if let Ok(modified) = metadata.modified() {
+            self.last_modified = Some(modified);
+        }
+        if let Some(response) = self.not_modified(request) {
+            return response;
+        }
+
+        let len = metadata.len();
+        let (start, end) = match self.requested_range(request, len) {
+            ByteRange::Partial(start, end) => (start, end),
+            ByteRange::Full => {
+                let mut headers = self.headers();
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+                return (headers, Body::from_stream(ReaderStream::new(file))).into_response();
+            }
+            ByteRange::Unsatisfiable => return self.range_not_satisfiable(len),
+        };
+
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+            error!("Failed to seek download {:?}: {:?}", path, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the download").into_response();
+        }
+        let body = Body::from_stream(ReaderStream::new(file.take(end - start + 1)));
+        (StatusCode::PARTIAL_CONTENT, self.partial_headers(start, end, len), body).into_response()
+    }
+
+    /// Stream a body still being produced, whose length isn't known, so
+    ///  ranges can only be served once it's complete
+    pub fn stream(self, body: Body) -> Response {
+        (self.headers(), body).into_response()
+    }
+
+    /// Stream a body which is never cached, so no range of it is ever
+    ///  served and clients are told not to ask for one
+    pub fn stream_uncached(self, body: Body) -> Response {
+        let mut headers = self.headers();
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+        (headers, body).into_response()
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(self.content_type));
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Some(last_modified) = self.last_modified {
+            if let Ok(last_modified) = HeaderValue::from_str(&httpdate::fmt_http_date(last_modified)) {
+                headers.insert(header::LAST_MODIFIED, last_modified);
+            }
+        }
+        if let Ok(disposition) = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", self.filename)) {
+            headers.insert(header::CONTENT_DISPOSITION, disposition);
+        }
+        headers
+    }
+
+    fn partial_headers(&self, start: u64, end: u64, len: u64) -> HeaderMap {
+        let mut headers = self.headers();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+        if let Ok(content_range) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) {
+            headers.insert(header::CONTENT_RANGE, content_range);
+        }
+        headers
+    }
+
+    fn range_not_satisfiable(&self, len: u64) -> Response {
+        let mut headers = self.headers();
+        if let Ok(content_range) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+            headers.insert(header::CONTENT_RANGE, content_range);
+        }
+        (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+    }
+
+    /// The part of a representation of `len` bytes to serve
+    fn requested_range(&self, request: &HeaderMap, len: u64) -> ByteRange {
+        let Some(range) = request.get(header::RANGE).and_then(|range| range.to_str().ok()) else {
+            return ByteRange::Full;
+        };
+
+        // A range of a representation the client no longer has is useless
+        if let Some(if_range) = request.get(header::IF_RANGE).and_then(|if_range| if_range.to_str().ok()) {
+            let last_modified = self.last_modified.map(httpdate::fmt_http_date);
+            if if_range != self.etag && Some(if_range) != last_modified.as_deref() {
+                return ByteRange::Full;
+            }
+        }
+
+        match parse_range(range, len) {
+            Some(Some((start, end))) => ByteRange::Partial(start, end),
+            Some(None) => ByteRange::Unsatisfiable,
+            None => ByteRange::Full,
+        }
+    }
+}
+
+/// Part of a representation a request asked for
+enum ByteRange {
+    Full,
+    /// Inclusive bounds
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a single `bytes` range against a representation of `len` bytes
+///
+/// `None` when the header should be ignored - it's malformed, for another
+///  unit or for several ranges - and `Some(None)` when it can't be
+///  satisfied.
+fn parse_range(range: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        // The last `suffix` bytes
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+        }
+        (start, end) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = match end {
+                "" => u64::MAX,
+                end => end.parse::<u64>().ok()?,
+            };
+            if end < start {
+                return None;
+            }
+            (start < len).then(|| (start, end.min(len - 1)))
+        }
+    };
+
+    Some(range)
+}
+
+/// HTTP dates have a resolution of seconds
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(since_epoch.as_secs()))
+        .unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1_000), Some(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1_000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=900-5000", 1_000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1_000), Some(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1_000), Some(Some((0, 999))));
+
+        // Unsatisfiable
+        assert_eq!(parse_range("bytes=1000-", 1_000), Some(None));
+        assert_eq!(parse_range("bytes=-0", 1_000), Some(None));
+
+        // Ignored
+        assert_eq!(parse_range("bytes=0-1,5-6", 1_000), None);
+        assert_eq!(parse_range("items=0-1", 1_000), None);
+        assert_eq!(parse_range("bytes=5-1", 1_000), None);
+        assert_eq!(parse_range("bytes=a-", 1_000), None);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_and_range_requests() -> Result<()> {
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let download = Download::new("abc", "application/zip", "export.zip").last_modified(modified);
+
+        let mut request = HeaderMap::new();
+        request.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"xyz\", W/\"abc\""));
+        let response = download.not_modified(&request).expect("Entity tag matches");
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let mut request = HeaderMap::new();
+        request.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap());
+        assert!(download.not_modified(&request).is_some());
+        request.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"xyz\""));
+        assert!(download.not_modified(&request).is_none());
+
+        // Ranges are offered unless the body is never cached
+        assert_eq!(download.clone().stream(Body::empty()).headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(download.clone().stream_uncached(Body::empty()).headers()[header::ACCEPT_RANGES], "none");
+
+        // Served from a file, whose modification time is the download's
+        let path = std::env::temp_dir().join(format!("mbo_test_download_{}", std::process::id()));
+        std::fs::write(&path, (0..=255).collect::<Vec<u8>>())?;
+        let file_modified = httpdate::fmt_http_date(std::fs::metadata(&path)?.modified()?);
+
+        let mut request = HeaderMap::new();
+        request.insert(header::RANGE, HeaderValue::from_static("bytes=10-19"));
+        let response = download.clone().file(&request, &path).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 10-19/256");
+        assert_eq!(response.headers()[header::LAST_MODIFIED], file_modified.as_str());
+
+        // A range of another version of the download gets all of it
+        request.insert(header::IF_RANGE, HeaderValue::from_static("\"xyz\""));
+        assert_eq!(download.clone().file(&request, &path).await.status(), StatusCode::OK);
+
+        let mut request = HeaderMap::new();
+        request.insert(header::RANGE, HeaderValue::from_static("bytes=300-"));
+        let response = download.file(&request, &path).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */256");
+
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
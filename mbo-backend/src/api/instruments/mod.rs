@@ -0,0 +1,73 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{instrument, error};
+
+use crate::storage::{Instrument, MarketStore};
+
+
+/// Filters for the instruments listing
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct InstrumentsQuery {
+    /// Only include mappings of this instrument ID
+    pub instrument_id: Option<u32>,
+    /// Only include mappings to this raw symbol
+    pub symbol: Option<String>,
+    /// Only include mappings valid at this time, in nanoseconds since the UNIX epoch
+    pub ts: Option<u64>,
+}
+
+/// List known instrument symbol mappings
+///
+/// Each mapping ties an instrument ID to a raw symbol over a validity
+/// range, as persisted by ingestion from DBN metadata or live symbol
+/// mapping messages. Mappings are ordered by instrument ID, then start
+/// of validity.
+#[utoipa::path(
+    get,
+    path = "/api/instruments",
+    params(InstrumentsQuery),
+    responses(
+        (status = 200, description = "Instrument symbol mappings", body = Vec<Instrument>),
+        (status = 500, description = "Failed to read instruments from storage"),
+    ),
+    tag = "instruments"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<InstrumentsQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let (storage, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (state_read.storage.clone(), Arc::clone(&state_read.metrics))
+    };
+
+    let instruments = match storage.instruments().await {
+        Ok(instruments) => instruments,
+        Err(e) => {
+            error!("Failed to read instruments: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read instruments").into_response();
+        }
+    };
+    let instruments = instruments.into_iter()
+        .filter(|instrument| {
+            query.instrument_id.is_none_or(|id| instrument.instrument_id == id)
+                && query.symbol.as_ref().is_none_or(|symbol| instrument.raw_symbol == *symbol)
+                && query.ts.is_none_or(|ts| instrument.is_valid_at(ts))
+        })
+        .collect::<Vec<_>>();
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(instruments).into_response()
+}
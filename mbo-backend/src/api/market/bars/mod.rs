@@ -0,0 +1,93 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{instrument, error};
+
+use crate::{
+    ingest::symbology::Symbology,
+    storage::{Bar, MarketStore},
+};
+
+
+/// Filters for the bars listing
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct BarsQuery {
+    /// Only include bars of this instrument
+    pub instrument_id: Option<u32>,
+    /// Inclusive lower bound on the start of the bar, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on the start of the bar, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+}
+
+/// A trade bar of an instrument
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct SymbolBar {
+    /// Raw symbol of the instrument at the start of the bar, if known
+    pub symbol: Option<String>,
+    #[serde(flatten)]
+    pub bar: Bar,
+}
+
+/// List the one-minute trade bars kept for compacted messages
+///
+/// Bars are built by retention from the trades of raw messages before
+/// they are deleted, so they only cover the compacted time range.
+/// Bars are ordered by instrument ID, then start of the minute.
+#[utoipa::path(
+    get,
+    path = "/api/market/bars",
+    params(BarsQuery),
+    responses(
+        (status = 200, description = "Trade bars", body = Vec<SymbolBar>),
+        (status = 400, description = "Invalid time range"),
+        (status = 500, description = "Failed to read bars from storage"),
+    ),
+    tag = "market"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<BarsQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let (storage, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (state_read.storage.clone(), Arc::clone(&state_read.metrics))
+    };
+
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return (StatusCode::BAD_REQUEST, "start_ts must not be after end_ts").into_response();
+        }
+    }
+
+    let bars = storage.bars(query.instrument_id, query.start_ts, query.end_ts).await;
+    let instruments = storage.instruments().await;
+    let (bars, symbology) = match (bars, instruments) {
+        (Ok(bars), Ok(instruments)) => (bars, Symbology::new(instruments)),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to read bars: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read bars").into_response();
+        }
+    };
+    let bars = bars.into_iter()
+        .map(|bar| SymbolBar {
+            symbol: symbology.symbol(bar.instrument_id, bar.ts)
+                .map(str::to_string),
+            bar,
+        })
+        .collect::<Vec<_>>();
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(bars).into_response()
+}
@@ -0,0 +1,177 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::{io::Write, sync::Arc};
+use tokio::sync::{mpsc, OwnedRwLockReadGuard, RwLock};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{instrument, info, error};
+
+use crate::{
+    api::download::{self, Download},
+    archive::{self, ArchiveKind},
+};
+
+/// Rows per record batch, so clients can process the stream incrementally
+const BATCH_ROWS: usize = 8_192;
+
+/// Dataset streamed by the Arrow endpoint
+#[derive(Debug, Clone, Copy, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ArrowKind {
+    /// The raw MBO messages
+    Mbo,
+    /// The aggregated BBO of the instrument after each message
+    Bbo,
+    /// The top levels of the book after each message
+    Depth,
+}
+impl ArrowKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArrowKind::Mbo => "mbo",
+            ArrowKind::Bbo => "bbo",
+            ArrowKind::Depth => "depth",
+        }
+    }
+}
+
+/// Slice of the loaded market to stream
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ArrowQuery {
+    /// Dataset to stream: `mbo`, `bbo` or `depth` (default: `mbo`)
+    #[param(inline)]
+    pub kind: Option<ArrowKind>,
+    /// Only include messages for this instrument
+    pub instrument_id: Option<u32>,
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+    /// Levels per depth snapshot, between 1 and 100 (default: 10)
+    pub levels: Option<u16>,
+}
+
+/// Stream a time/instrument slice of the market as Arrow IPC record batches
+///
+/// The response is an Arrow IPC stream (not a file), readable with e.g.
+/// `pyarrow.ipc.open_stream`. Depth snapshots have one row per level
+/// per message, taken from the book of the message's publisher. Batches
+/// are built as the stream is sent, so a failure ends it early. The
+/// stream is validated against the loaded market, so conditional
+/// requests for an unchanged slice are answered without building it,
+/// but it isn't cached, so byte ranges of it aren't served.
+#[utoipa::path(
+    get,
+    path = "/api/market/export/arrow",
+    params(ArrowQuery),
+    responses(
+        (status = 200, description = "Arrow IPC stream of the requested slice", body = Vec<u8>, content_type = "application/vnd.apache.arrow.stream"),
+        (status = 304, description = "The client's copy of the stream is current"),
+        (status = 400, description = "Invalid time range or level count"),
+        (status = 500, description = "Failed to key the download"),
+    ),
+    tag = "market"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<ArrowQuery>,
+    request_headers: HeaderMap,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    // The read lock is held until the whole slice has been encoded
+    let state_read = Arc::clone(&state).read_owned().await;
+    state_read.metrics.http_requests_total.inc();
+
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return (StatusCode::BAD_REQUEST, "start_ts must not be after end_ts").into_response();
+        }
+    }
+    let levels = query.levels.unwrap_or(10);
+    if !(1..=100).contains(&levels) {
+        return (StatusCode::BAD_REQUEST, "levels must be between 1 and 100").into_response();
+    }
+
+    let kind = query.kind.unwrap_or(ArrowKind::Mbo);
+    let key = match download::key(&(
+        &state_read.artifacts,
+        "arrow",
+        kind.name(),
+        query.instrument_id,
+        query.start_ts,
+        query.end_ts,
+        levels,
+    )) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Failed to key Arrow export: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to key the download").into_response();
+        }
+    };
+    let download = Download::new(&key, "application/vnd.apache.arrow.stream", format!("market_{}.arrows", kind.name()))
+        .last_modified(state_read.loaded_at);
+    if let Some(response) = download.not_modified(&request_headers) {
+        return response;
+    }
+
+    let metrics = Arc::clone(&state_read.metrics);
+    let snapshots = OwnedRwLockReadGuard::map(state_read, |state| state.market_snapshots.as_slice());
+    info!(?kind, "Streaming market slice as Arrow IPC");
+
+    // The slice is selected and encoded on a blocking thread, building
+    //  each batch only once the previous one has been sent, so the body
+    //  is produced at the pace the client reads it
+    let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+    tokio::task::spawn_blocking(move || {
+        let selected = snapshots.iter()
+            .filter(|snapshot| {
+                let msg = &snapshot.mbomsg_effect.mbo_msg;
+                query.instrument_id.is_none_or(|id| msg.hd.instrument_id == id)
+                    && query.start_ts.is_none_or(|ts| msg.ts_recv >= ts)
+                    && query.end_ts.is_none_or(|ts| msg.ts_recv <= ts)
+            })
+            .collect::<Vec<_>>();
+        let (schema, rows) = match kind {
+            ArrowKind::Mbo => (ArchiveKind::Mbo.schema(), BATCH_ROWS),
+            ArrowKind::Bbo => (ArchiveKind::Bbo.schema(), BATCH_ROWS),
+            ArrowKind::Depth => (archive::depth_schema(), (BATCH_ROWS / levels as usize).max(1)),
+        };
+        let batches = selected.chunks(rows).map(|chunk| match kind {
+            ArrowKind::Mbo => archive::record_batch(ArchiveKind::Mbo, chunk),
+            ArrowKind::Bbo => archive::record_batch(ArchiveKind::Bbo, chunk),
+            ArrowKind::Depth => archive::depth_record_batch(chunk, levels),
+        });
+
+        let writer = std::io::BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        if let Err(e) = archive::write_ipc_stream(writer, &schema, batches) {
+            error!("Failed to stream Arrow IPC: {:?}", e);
+            let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+        }
+
+        // The request lasts until the last batch has been sent
+        metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+    });
+
+    download.stream_uncached(Body::from_stream(ReceiverStream::new(rx)))
+}
+
+/// Forwards written bytes to the response body, failing once the client
+///  has gone away so encoding stops early
+struct ChannelWriter(mpsc::Sender<Result<Bytes, std::io::Error>>);
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
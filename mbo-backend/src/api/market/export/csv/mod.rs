@@ -0,0 +1,137 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::{mpsc, OwnedRwLockReadGuard, RwLock};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{instrument, info, error};
+
+use crate::{
+    api::download::{self, Download},
+    export::csv::{self, CsvKind, CsvParams},
+};
+
+/// Slice of the loaded market to export
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct CsvQuery {
+    /// Dataset to export: `mbo`, `bbo` or `depth` (default: `mbo`)
+    #[param(inline)]
+    pub kind: Option<CsvKind>,
+    /// Only include messages for this instrument
+    pub instrument_id: Option<u32>,
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+    /// Levels per depth snapshot, between 1 and 100 (default: 10)
+    pub levels: Option<u16>,
+}
+
+/// Export a time/instrument slice of the market as CSV
+///
+/// Rows are streamed as they are written, with ISO 8601 timestamps and
+/// decimal prices so the file opens directly in a spreadsheet. BBO rows
+/// aggregate every publisher, while depth has one row per level per
+/// message, taken from the book of the message's publisher. `Range`
+/// requests are answered once the whole file has been written to the
+/// export cache, which later requests for the slice are served from.
+#[utoipa::path(
+    get,
+    path = "/api/market/export/csv",
+    params(CsvQuery),
+    responses(
+        (status = 200, description = "CSV of the requested slice", body = String, content_type = "text/csv"),
+        (status = 206, description = "Requested byte range of the CSV", body = String, content_type = "text/csv"),
+        (status = 304, description = "The client's copy of the CSV is current"),
+        (status = 400, description = "Invalid time range or level count"),
+        (status = 416, description = "Requested byte range is past the end of the CSV"),
+        (status = 500, description = "Failed to produce the export"),
+    ),
+    tag = "market"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<CsvQuery>,
+    request_headers: HeaderMap,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return (StatusCode::BAD_REQUEST, "start_ts must not be after end_ts").into_response();
+        }
+    }
+    let levels = query.levels.unwrap_or(10);
+    if !(1..=100).contains(&levels) {
+        return (StatusCode::BAD_REQUEST, "levels must be between 1 and 100").into_response();
+    }
+    let params = CsvParams {
+        kind: query.kind.unwrap_or(CsvKind::Mbo),
+        instrument_id: query.instrument_id,
+        start_ts: query.start_ts,
+        end_ts: query.end_ts,
+        levels,
+    };
+
+    // The read lock is held until every row has been written
+    let state_read = Arc::clone(&state).read_owned().await;
+    state_read.metrics.http_requests_total.inc();
+    let metrics = Arc::clone(&state_read.metrics);
+    let cache = state_read.export_cache.clone();
+
+    let key = match download::key(&(&state_read.artifacts, "csv", &params)) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Failed to key CSV export: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to key the download").into_response();
+        }
+    };
+    let download = Download::new(&key, "text/csv", format!("market_{}.csv", params.kind.name()))
+        .last_modified(state_read.loaded_at);
+    if let Some(response) = download.not_modified(&request_headers) {
+        return response;
+    }
+    let snapshots = OwnedRwLockReadGuard::map(state_read, |state| state.market_snapshots.as_slice());
+
+    let path = cache.csv_path(&key);
+    let response = if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        drop(snapshots);
+        download.file(&request_headers, &path).await
+    } else if request_headers.contains_key(header::RANGE) {
+        match cache.produce_csv(&key, params, snapshots).await {
+            Ok(path) => download.file(&request_headers, &path).await,
+            Err(e) => {
+                error!("Failed to produce CSV export: {:?}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to produce the export").into_response();
+            }
+        }
+    } else {
+        info!(kind = params.kind.name(), "Streaming market slice as CSV");
+
+        // Rows are written on a blocking thread, at the pace the client
+        //  reads them
+        let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+        tokio::task::spawn_blocking(move || {
+            for chunk in csv::chunks(&params, &snapshots) {
+                // The client went away
+                if tx.blocking_send(Ok(Bytes::from(chunk))).is_err() {
+                    break;
+                }
+            }
+
+            // The request lasts until the last row has been sent
+            metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+        });
+
+        return download.stream(Body::from_stream(ReceiverStream::new(rx)));
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    response
+}
@@ -1,47 +1,137 @@
+pub mod arrow;
+pub mod csv;
+pub mod parquet;
+
 use axum::{
-    body::Body,
-    http::{HeaderMap, HeaderValue},
-    response::IntoResponse,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
 };
-use tokio_util::io::ReaderStream;
-use tracing::instrument;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::{OwnedRwLockReadGuard, RwLock};
+use tracing::{instrument, error};
+
+use crate::{api::download::Download, delta::Compression, export::ExportParams};
+
+/// Slice of the loaded market to export
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ExportQuery {
+    /// Inclusive lower bound on the index of the message in the loaded market
+    pub start_index: Option<usize>,
+    /// Inclusive upper bound on the index of the message in the loaded market
+    pub end_index: Option<usize>,
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+    /// Comma-separated instrument IDs to include (default: all)
+    pub instrument_ids: Option<String>,
+    /// Levels per book in `depth.jsonl`, between 1 and 100 (default: 10)
+    pub depth: Option<u16>,
+    /// Compression of `market.mbod`: `none`, `deflate` or `zstd` (default: `zstd`)
+    #[param(inline)]
+    pub compression: Option<Compression>,
+}
 
-/// Export complete market state as a ZIP, loading from `assets/feed.zip`
-/// 
-/// This file is pre-made, this route does not generate it on-the-fly.
+/// Export a slice of the market as a ZIP archive, generated on demand
+///
+/// The archive holds `market.mbod`, the delta-encoded market from just
+/// before the first selected message (see `delta::DeltaWriter`, and
+/// `mbo decode` to read it back), `depth.jsonl`, the top levels of each
+/// book after every message, and `manifest.json`, describing what was
+/// exported.
+///
+/// The archive is streamed while it is being produced, then cached, so
+/// later requests with the same parameters are served from disk. The
+/// `ETag` is known up front, so `If-None-Match` never needs an export to
+/// be produced, while `Range` requests wait for the export to complete
+/// and are then answered with `206 Partial Content`.
 #[utoipa::path(
     get,
     path = "/api/market/export",
+    params(ExportQuery),
     responses(
-        (status = 200, description = "Market state exported successfully (ZIP stream)", body = Vec<u8>),
-        (status = 500, description = "Failed to stream market state")
+        (status = 200, description = "ZIP archive of the requested slice", body = Vec<u8>, content_type = "application/zip"),
+        (status = 206, description = "Requested byte range of the archive", body = Vec<u8>, content_type = "application/zip"),
+        (status = 304, description = "The client's copy of the archive is current"),
+        (status = 400, description = "Invalid range, instrument IDs or depth"),
+        (status = 416, description = "Requested byte range is past the end of the archive"),
+        (status = 500, description = "Failed to produce the export")
     ),
     tag = "market"
 )]
-#[instrument]
-pub async fn handler( ) -> impl IntoResponse {
-    let zip_path_str = std::env::var("ZIP_FILE_PATH")
-        .unwrap_or("assets/feed.zip".to_string());
-    let zip_path = std::path::Path::new(&zip_path_str);
-    let file = tokio::fs::File::open(zip_path).await;
-
-    match file {
-        Ok(file) => {
-            let stream = ReaderStream::new(file);
-            let body = Body::from_stream(stream);
-
-            let mut headers = HeaderMap::new();
-            headers.insert("Content-Type", HeaderValue::from_static("application/zip"));
-            headers.insert("Content-Disposition", HeaderValue::from_static("attachment; filename=\"market_export.zip\""));
-
-            (headers, body)
-        }
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<ExportQuery>,
+    request_headers: HeaderMap,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let instrument_ids = match query.instrument_ids
+        .as_deref()
+        .map(|ids| ids.split(',').map(|id| id.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>())
+        .transpose()
+    {
+        Ok(instrument_ids) => instrument_ids,
+        Err(_) => return (StatusCode::BAD_REQUEST, "instrument_ids must be comma-separated integers").into_response(),
+    };
+    let params = match ExportParams::new(
+        query.start_index,
+        query.end_index,
+        query.start_ts,
+        query.end_ts,
+        instrument_ids,
+        query.depth.unwrap_or(10),
+        query.compression.unwrap_or_default(),
+    ) {
+        Ok(params) => params,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    // The read lock is held by the export until it has been produced
+    let state_read = Arc::clone(&state).read_owned().await;
+    state_read.metrics.http_requests_total.inc();
+    let metrics = Arc::clone(&state_read.metrics);
+    let cache = state_read.export_cache.clone();
+    let snapshots = OwnedRwLockReadGuard::map(state_read, |state| state.market_snapshots.as_slice());
+
+    let key = match params.cache_key(&cache.artifacts) {
+        Ok(key) => key,
         Err(e) => {
-            tracing::error!("Failed to open market export ZIP file: {}", e);
-            (
-                HeaderMap::new(),
-                Body::from("Failed to stream market state"),
-            )
+            error!("Failed to key market export: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to produce the export").into_response();
         }
+    };
+    let download = Download::new(&key, "application/zip", "market_export.zip");
+    if let Some(response) = download.not_modified(&request_headers) {
+        return response;
     }
-}
\ No newline at end of file
+
+    let path = cache.path(&key);
+    let response = if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        drop(snapshots);
+        download.file(&request_headers, &path).await
+    } else if request_headers.contains_key(header::RANGE) {
+        match cache.produce(params, snapshots).await {
+            Ok(path) => download.file(&request_headers, &path).await,
+            Err(e) => {
+                error!("Failed to produce market export: {:?}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to produce the export").into_response();
+            }
+        }
+    } else {
+        match cache.stream(params, snapshots).await {
+            Ok(body) => download.stream(body),
+            Err(e) => {
+                error!("Failed to start market export: {:?}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to produce the export").into_response();
+            }
+        }
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    response
+}
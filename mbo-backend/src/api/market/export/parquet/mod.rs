@@ -0,0 +1,114 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::{OwnedRwLockReadGuard, RwLock};
+use tracing::{instrument, error};
+
+use crate::{
+    api::download::{self, Download},
+    archive::ArchiveKind,
+    export::parquet::ParquetParams,
+};
+
+
+/// Slice of the loaded market to export
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct ParquetQuery {
+    /// Dataset to export: `mbo`, `effects` or `bbo` (default: `mbo`)
+    #[param(inline)]
+    pub kind: Option<ArchiveKind>,
+    /// Only include messages for this instrument
+    pub instrument_id: Option<u32>,
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+}
+
+/// Export a time/instrument slice of the market as a Parquet file
+///
+/// Uses the same schemas as the on-disk archive, so the result can be
+/// read directly with pandas or polars. The file is cached once encoded,
+/// so later requests for the same slice are served from disk. Supports
+/// conditional and byte range requests, validated against the loaded
+/// market.
+#[utoipa::path(
+    get,
+    path = "/api/market/export/parquet",
+    params(ParquetQuery),
+    responses(
+        (status = 200, description = "Parquet file of the requested slice", body = Vec<u8>, content_type = "application/vnd.apache.parquet"),
+        (status = 206, description = "Requested byte range of the Parquet file", body = Vec<u8>, content_type = "application/vnd.apache.parquet"),
+        (status = 304, description = "The client's copy of the file is current"),
+        (status = 400, description = "Invalid time range"),
+        (status = 416, description = "Requested byte range is past the end of the file"),
+        (status = 500, description = "Failed to encode Parquet file"),
+    ),
+    tag = "market"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<ParquetQuery>,
+    request_headers: HeaderMap,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return (StatusCode::BAD_REQUEST, "start_ts must not be after end_ts").into_response();
+        }
+    }
+    let params = ParquetParams {
+        kind: query.kind.unwrap_or(ArchiveKind::Mbo),
+        instrument_id: query.instrument_id,
+        start_ts: query.start_ts,
+        end_ts: query.end_ts,
+    };
+
+    // The read lock is held until the file has been encoded
+    let state_read = Arc::clone(&state).read_owned().await;
+    state_read.metrics.http_requests_total.inc();
+    let metrics = Arc::clone(&state_read.metrics);
+    let cache = state_read.export_cache.clone();
+
+    let key = match download::key(&(
+        &state_read.artifacts,
+        "parquet",
+        params.kind.name(),
+        params.instrument_id,
+        params.start_ts,
+        params.end_ts,
+    )) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Failed to key Parquet export: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to key the download").into_response();
+        }
+    };
+    let download = Download::new(&key, "application/vnd.apache.parquet", format!("market_{}.parquet", params.kind.name()))
+        .last_modified(state_read.loaded_at);
+    if let Some(response) = download.not_modified(&request_headers) {
+        return response;
+    }
+    let snapshots = OwnedRwLockReadGuard::map(state_read, |state| state.market_snapshots.as_slice());
+
+    // Selected and encoded on a blocking thread, then served from the
+    //  cache like any later request for the same slice
+    let path = match cache.produce_parquet(&key, params, snapshots).await {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Failed to produce Parquet export: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode Parquet export").into_response();
+        }
+    };
+    let response = download.file(&request_headers, &path).await;
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    response
+}
@@ -1 +1,2 @@
+pub mod bars;
 pub mod export;
\ No newline at end of file
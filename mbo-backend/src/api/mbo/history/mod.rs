@@ -0,0 +1,187 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use databento::dbn::{Action, MboMsg, Side};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{instrument, error};
+
+use crate::{
+    ingest::symbology::Symbology,
+    storage::{MarketStore, MessageQuery},
+};
+
+/// Messages per page when no limit is given
+const DEFAULT_LIMIT: usize = 100;
+
+/// Largest page a client may request
+const MAX_LIMIT: usize = 10_000;
+
+/// Order of the returned messages
+#[derive(Debug, Clone, Copy, Default, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// Oldest first, in the order messages were persisted
+    #[default]
+    Asc,
+    /// Newest first
+    Desc,
+}
+
+/// Filters and paging for persisted MBO messages
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct HistoryQuery {
+    pub instrument_id: Option<u32>,
+    pub publisher_id: Option<u16>,
+    pub order_id: Option<u64>,
+    /// Action as its DBN character, e.g. `A` (add), `C` (cancel), `M` (modify), `T` (trade), `F` (fill)
+    pub action: Option<char>,
+    /// Side as its DBN character, `B` (bid), `A` (ask) or `N` (none)
+    pub side: Option<char>,
+    /// Inclusive lower bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    /// Inclusive upper bound on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub end_ts: Option<u64>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    /// Messages per page, at most 10000 (default: 100)
+    pub limit: Option<usize>,
+    #[param(inline)]
+    pub order: Option<Order>,
+}
+
+/// A persisted MBO message
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct HistoryMessage {
+    /// Storage row ID, increasing in persistence order
+    pub id: i64,
+    /// Raw symbol of the instrument when the message was received, if known
+    pub symbol: Option<String>,
+    #[schema(value_type = Object)]
+    pub mbo_msg: MboMsg,
+}
+
+/// A page of persisted MBO messages
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct HistoryPage {
+    pub messages: Vec<HistoryMessage>,
+    /// Pass as `cursor` to fetch the next page, `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// Query persisted MBO messages
+///
+/// Reads the message log in storage rather than the in-memory market,
+/// so it covers everything ingested, including previous runs and the
+/// live feed. Pages are keyed on the storage row ID: pass the returned
+/// `next_cursor` as `cursor`, keeping the other parameters unchanged,
+/// until it is `null`.
+#[utoipa::path(
+    get,
+    path = "/api/mbo/history",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "A page of matching messages", body = HistoryPage),
+        (status = 400, description = "Invalid filter, cursor or limit"),
+        (status = 500, description = "Failed to query storage"),
+    ),
+    tag = "mbo"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let (storage, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (state_read.storage.clone(), Arc::clone(&state_read.metrics))
+    };
+
+    let message_query = match message_query(&query) {
+        Ok(message_query) => message_query,
+        Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+    let limit = message_query.limit.unwrap_or(DEFAULT_LIMIT);
+
+    // One extra row tells whether there's a next page
+    let rows = storage.query_messages(&MessageQuery {
+        limit: Some(limit + 1),
+        ..message_query
+    }).await;
+    let instruments = storage.instruments().await;
+    let (mut rows, symbology) = match (rows, instruments) {
+        (Ok(rows), Ok(instruments)) => (rows, Symbology::new(instruments)),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to query message history: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to query message history").into_response();
+        }
+    };
+
+    let next_cursor = (rows.len() > limit).then(|| {
+        rows.truncate(limit);
+        rows.last().map(|row| row.id.to_string())
+    }).flatten();
+    let messages = rows.into_iter()
+        .map(|row| HistoryMessage {
+            id: row.id,
+            symbol: symbology.symbol(row.mbo_msg.hd.instrument_id, row.mbo_msg.ts_recv)
+                .map(str::to_string),
+            mbo_msg: row.mbo_msg,
+        })
+        .collect();
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(HistoryPage { messages, next_cursor }).into_response()
+}
+
+/// Validate request parameters into a storage query
+fn message_query(query: &HistoryQuery) -> Result<MessageQuery, String> {
+    if let (Some(start_ts), Some(end_ts)) = (query.start_ts, query.end_ts) {
+        if start_ts > end_ts {
+            return Err("start_ts must not be after end_ts".to_string());
+        }
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(format!("limit must be between 1 and {}", MAX_LIMIT));
+    }
+
+    let action = query.action
+        .map(|action| u8::try_from(action).ok()
+            .and_then(|action| Action::try_from(action).ok())
+            .ok_or(format!("Unknown action `{}`", action)))
+        .transpose()?;
+    let side = query.side
+        .map(|side| u8::try_from(side).ok()
+            .and_then(|side| Side::try_from(side).ok())
+            .ok_or(format!("Unknown side `{}`", side)))
+        .transpose()?;
+    let after_id = query.cursor.as_ref()
+        .map(|cursor| cursor.parse::<i64>()
+            .map_err(|_| format!("Invalid cursor `{}`", cursor)))
+        .transpose()?;
+
+    Ok(MessageQuery {
+        instrument_id: query.instrument_id,
+        publisher: query.publisher_id,
+        order_id: query.order_id,
+        action,
+        side,
+        start_ts: query.start_ts,
+        end_ts: query.end_ts,
+        start_id: None,
+        end_id: None,
+        after_id,
+        limit: Some(limit),
+        descending: matches!(query.order.unwrap_or_default(), Order::Desc),
+    })
+}
@@ -1 +1,2 @@
+pub mod history;
 pub mod stream;
\ No newline at end of file
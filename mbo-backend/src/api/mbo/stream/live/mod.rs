@@ -0,0 +1,99 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+use tracing::{instrument, info, warn, error};
+
+
+/// Stream live MBO messages as Server-Sent Events
+///
+/// Streams each MBO (Market-By-Order) message received from the
+/// Databento live gateway, together with its effect on the live
+/// market, as soon as it has been applied. Only messages received
+/// after connecting are sent.
+///
+/// Returns `503 Service Unavailable` when live ingestion is not configured.
+#[utoipa::path(
+    get,
+    path = "/api/mbo/stream/live",
+    responses(
+        (status = 200, description = "SSE stream of live MBO messages", content_type = "text/event-stream"),
+        (status = 503, description = "Live ingestion is not configured"),
+    ),
+    tag = "mbo"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+) -> Response {
+    use axum::response::sse::{Event, Sse};
+    use futures::stream::{self, StreamExt};
+
+    let start = std::time::Instant::now();
+
+    let state_read = state.read().await;
+    state_read.metrics.http_requests_total.inc();
+
+    let Some(live_feed) = &state_read.live_feed else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Live ingestion is not configured").into_response();
+    };
+
+    info!("Client connected to live MBO stream");
+    state_read.metrics.active_connections.inc();
+
+    let receiver = live_feed.effects.subscribe();
+    let metrics = Arc::clone(&state_read.metrics);
+    let guard = ConnectionGuard(Arc::clone(&state_read.metrics));
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    // Drop the read lock before streaming
+    drop(state_read);
+
+    // The guard lives as long as the stream, so dropped connections
+    //  and closed feeds both release it
+    let stream = stream::unfold((receiver, guard), move |(mut receiver, guard)| {
+        let metrics = Arc::clone(&metrics);
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(effect) => {
+                        metrics.messages_processed.inc();
+
+                        let event = match serde_json::to_string(&effect) {
+                            Ok(json) => Event::default().data(json),
+                            Err(e) => {
+                                error!("Failed to serialize MBOMsgEffect: {}", e);
+                                metrics.messages_processing_errors.inc();
+                                Event::default().data(format!("{{\"error\": \"{}\"}}", e))
+                            }
+                        };
+                        return Some((Ok::<_, std::convert::Infallible>(event), (receiver, guard)));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Live stream client lagged behind, skipping messages");
+                        metrics.messages_processing_errors.inc_by(skipped as f64);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+        .chain(stream::once(async { Ok(Event::default().comment("stream_end")) }));
+
+    Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+            .interval(std::time::Duration::from_secs(15))
+    ).into_response()
+}
+
+/// Counts an open streaming connection until dropped
+pub struct ConnectionGuard(pub Arc<crate::metrics::Metrics>);
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.dec();
+    }
+}
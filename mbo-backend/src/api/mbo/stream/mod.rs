@@ -1 +1,2 @@
-pub mod json;
\ No newline at end of file
+pub mod json;
+pub mod live;
\ No newline at end of file
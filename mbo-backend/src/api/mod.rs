@@ -1,7 +1,13 @@
+pub mod admin;
+pub mod analytics;
+pub mod book;
+pub mod download;
+pub mod instruments;
 pub mod market;
 pub mod mbo;
+pub mod orders;
 
-use axum::{Router, routing::get, Json, response::Html, http::StatusCode};
+use axum::{Router, routing::{get, post}, Json, response::Html, http::StatusCode};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use crate::State;
@@ -10,12 +16,33 @@ use utoipa::OpenApi;
 #[derive(OpenApi)]
 #[openapi(
     paths(
+        admin::compact::handler,
+        admin::storage::handler,
+        analytics::flow::handler,
+        analytics::microstructure::handler,
+        analytics::microstructure::live::handler,
+        analytics::queue::handler,
+        book::at::handler,
+        book::diff::handler,
+        instruments::handler,
+        market::bars::handler,
         market::export::handler,
+        market::export::parquet::handler,
+        market::export::arrow::handler,
+        market::export::csv::handler,
+        mbo::history::handler,
         mbo::stream::json::handler,
+        mbo::stream::live::handler,
+        orders::handler,
     ),
     tags(
+        (name = "admin", description = "Storage administration endpoints"),
+        (name = "analytics", description = "Market microstructure analytics endpoints"),
+        (name = "book", description = "Order book state endpoints"),
+        (name = "instruments", description = "Instrument symbology endpoints"),
         (name = "market", description = "Market data export endpoints"),
-        (name = "mbo", description = "Market-By-Order message streaming endpoints")
+        (name = "mbo", description = "Market-By-Order message streaming endpoints"),
+        (name = "orders", description = "Order lifecycle endpoints")
     ),
     info(
         title = "MBO Order Book API",
@@ -84,8 +111,24 @@ async fn metrics_handler(
 
 pub fn router(state: Arc<RwLock<State>>) -> Router {
     let api_router = Router::new()
+        .route("/admin/compact", post(admin::compact::handler))
+        .route("/admin/storage", get(admin::storage::handler))
+        .route("/analytics/{instrument_id}/flow", get(analytics::flow::handler))
+        .route("/analytics/{instrument_id}/microstructure", get(analytics::microstructure::handler))
+        .route("/analytics/{instrument_id}/microstructure/live", get(analytics::microstructure::live::handler))
+        .route("/analytics/{instrument_id}/queue", get(analytics::queue::handler))
+        .route("/book/{instrument_id}/at", get(book::at::handler))
+        .route("/book/{instrument_id}/diff", get(book::diff::handler))
+        .route("/instruments", get(instruments::handler))
+        .route("/market/bars", get(market::bars::handler))
         .route("/market/export", get(market::export::handler))
+        .route("/market/export/parquet", get(market::export::parquet::handler))
+        .route("/market/export/arrow", get(market::export::arrow::handler))
+        .route("/market/export/csv", get(market::export::csv::handler))
+        .route("/mbo/history", get(mbo::history::handler))
         .route("/mbo/stream/json/{delay_ms}", get(mbo::stream::json::handler))
+        .route("/mbo/stream/live", get(mbo::stream::live::handler))
+        .route("/orders/{order_id}", get(orders::handler))
         .with_state(Arc::clone(&state));
 
     Router::new()
@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{instrument, error};
+
+use crate::{
+    datatypes::order::OrderLifecycle,
+    ingest::symbology::Symbology,
+    storage::{MarketStore, MessageQuery},
+};
+
+
+/// Disambiguates order IDs reused across instruments or publishers
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+pub struct OrderQuery {
+    pub instrument_id: Option<u32>,
+    pub publisher_id: Option<u16>,
+}
+
+/// Full lifecycle of an order
+///
+/// Lists every persisted message for the order, classified as its add,
+/// modifies (with old and new price and size), partial cancels, fills
+/// and final removal, along with each change of its queue position
+/// (the size resting ahead of it at its price level) while it was in
+/// the loaded market.
+///
+/// Returns `404 Not Found` if no message references the order.
+#[utoipa::path(
+    get,
+    path = "/api/orders/{order_id}",
+    params(
+        ("order_id" = u64, Path, description = "Venue order ID"),
+        OrderQuery,
+    ),
+    responses(
+        (status = 200, description = "Lifecycle of the order", body = OrderLifecycle),
+        (status = 404, description = "No message references the order"),
+        (status = 500, description = "Failed to query storage"),
+    ),
+    tag = "orders"
+)]
+#[instrument(skip(state))]
+pub async fn handler(
+    State(state): State<Arc<RwLock<crate::State>>>,
+    Path(order_id): Path<u64>,
+    Query(query): Query<OrderQuery>,
+) -> Response {
+    let start = std::time::Instant::now();
+
+    let (storage, metrics) = {
+        let state_read = state.read().await;
+        state_read.metrics.http_requests_total.inc();
+        (state_read.storage.clone(), Arc::clone(&state_read.metrics))
+    };
+
+    let messages = storage.query_messages(&MessageQuery {
+        order_id: Some(order_id),
+        instrument_id: query.instrument_id,
+        publisher: query.publisher_id,
+        ..Default::default()
+    }).await;
+    let instruments = storage.instruments().await;
+    let (messages, symbology) = match (messages, instruments) {
+        (Ok(messages), Ok(instruments)) => (messages, Symbology::new(instruments)),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to query order messages: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to query order messages").into_response();
+        }
+    };
+    if messages.is_empty() {
+        return (StatusCode::NOT_FOUND, format!("No messages for order {}", order_id)).into_response();
+    }
+
+    let lifecycle = {
+        let state_read = state.read().await;
+        OrderLifecycle::from_messages(order_id, &messages, &symbology)
+            .with_queue_positions(&state_read.market_snapshots)
+    };
+
+    metrics.http_request_duration.observe(start.elapsed().as_secs_f64());
+
+    Json(lifecycle).into_response()
+}
@@ -0,0 +1,631 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow::{
+    array::{
+        ArrayRef, BooleanBuilder, Int32Builder, Int64Builder, StringBuilder,
+        TimestampNanosecondBuilder, UInt16Builder, UInt32Builder, UInt64Builder, UInt8Builder,
+    },
+    datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+    ipc::writer::StreamWriter,
+    record_batch::RecordBatch,
+};
+use anyhow::{Context, Result, bail};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::info;
+
+use crate::{
+    artifacts::ArtifactManifest,
+    datatypes::{book::BookEffect, market::MarketSnapshot},
+};
+
+/// Datasets which can be archived, each with its own Arrow schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveKind {
+    /// The raw MBO messages
+    Mbo,
+    /// The effect each message had on its book
+    Effects,
+    /// The aggregated BBO of the instrument after each message
+    Bbo,
+}
+impl ArchiveKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArchiveKind::Mbo => "mbo",
+            ArchiveKind::Effects => "effects",
+            ArchiveKind::Bbo => "bbo",
+        }
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        let ts_recv = Field::new("ts_recv", DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())), false);
+        let fields = match self {
+            ArchiveKind::Mbo => vec![
+                ts_recv,
+                Field::new("ts_event", DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())), false),
+                Field::new("instrument_id", DataType::UInt32, false),
+                Field::new("symbol", DataType::Utf8, true),
+                Field::new("publisher_id", DataType::UInt16, false),
+                Field::new("order_id", DataType::UInt64, false),
+                Field::new("action", DataType::Utf8, false),
+                Field::new("side", DataType::Utf8, false),
+                Field::new("price", DataType::Int64, false),
+                Field::new("size", DataType::UInt32, false),
+                Field::new("flags", DataType::UInt8, false),
+                Field::new("sequence", DataType::UInt32, false),
+                Field::new("ts_in_delta", DataType::Int32, false),
+                Field::new("channel_id", DataType::UInt8, false),
+            ],
+            ArchiveKind::Effects => vec![
+                ts_recv,
+                Field::new("instrument_id", DataType::UInt32, false),
+                Field::new("symbol", DataType::Utf8, true),
+                Field::new("publisher_id", DataType::UInt16, false),
+                Field::new("order_id", DataType::UInt64, false),
+                Field::new("effect", DataType::Utf8, true),
+                Field::new("side", DataType::Utf8, true),
+                Field::new("price", DataType::Int64, true),
+                Field::new("size", DataType::UInt32, true),
+                Field::new("old_price", DataType::Int64, true),
+                Field::new("old_size", DataType::UInt32, true),
+                Field::new("publisher_created", DataType::Boolean, false),
+                Field::new("error", DataType::Utf8, true),
+            ],
+            ArchiveKind::Bbo => vec![
+                ts_recv,
+                Field::new("instrument_id", DataType::UInt32, false),
+                Field::new("symbol", DataType::Utf8, true),
+                Field::new("bid_price", DataType::Int64, true),
+                Field::new("bid_size", DataType::UInt32, true),
+                Field::new("bid_count", DataType::UInt32, true),
+                Field::new("ask_price", DataType::Int64, true),
+                Field::new("ask_size", DataType::UInt32, true),
+                Field::new("ask_count", DataType::UInt32, true),
+            ],
+        };
+
+        Arc::new(Schema::new(fields))
+    }
+}
+
+/// Build a record batch of `kind` from market snapshots
+#[tracing::instrument(skip(snapshots))]
+pub fn record_batch(kind: ArchiveKind, snapshots: &[&MarketSnapshot]) -> Result<RecordBatch> {
+    let columns = match kind {
+        ArchiveKind::Mbo => mbo_columns(snapshots),
+        ArchiveKind::Effects => effect_columns(snapshots),
+        ArchiveKind::Bbo => bbo_columns(snapshots),
+    };
+
+    RecordBatch::try_new(kind.schema(), columns)
+        .context(format!("...while building {} record batch", kind.name()))
+}
+
+fn mbo_columns(snapshots: &[&MarketSnapshot]) -> Vec<ArrayRef> {
+    let mut ts_recv = TimestampNanosecondBuilder::new().with_timezone("UTC");
+    let mut ts_event = TimestampNanosecondBuilder::new().with_timezone("UTC");
+    let mut instrument_id = UInt32Builder::new();
+    let mut symbol = StringBuilder::new();
+    let mut publisher_id = UInt16Builder::new();
+    let mut order_id = UInt64Builder::new();
+    let mut action = StringBuilder::new();
+    let mut side = StringBuilder::new();
+    let mut price = Int64Builder::new();
+    let mut size = UInt32Builder::new();
+    let mut flags = UInt8Builder::new();
+    let mut sequence = UInt32Builder::new();
+    let mut ts_in_delta = Int32Builder::new();
+    let mut channel_id = UInt8Builder::new();
+
+    for snapshot in snapshots {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        ts_recv.append_value(msg.ts_recv as i64);
+        ts_event.append_value(msg.hd.ts_event as i64);
+        instrument_id.append_value(msg.hd.instrument_id);
+        symbol.append_option(snapshot.mbomsg_effect.symbol.as_deref());
+        publisher_id.append_value(msg.hd.publisher_id);
+        order_id.append_value(msg.order_id);
+        action.append_value((msg.action as u8 as char).to_string());
+        side.append_value((msg.side as u8 as char).to_string());
+        price.append_value(msg.price);
+        size.append_value(msg.size);
+        flags.append_value(msg.flags.raw());
+        sequence.append_value(msg.sequence);
+        ts_in_delta.append_value(msg.ts_in_delta);
+        channel_id.append_value(msg.channel_id);
+    }
+
+    vec![
+        Arc::new(ts_recv.finish()),
+        Arc::new(ts_event.finish()),
+        Arc::new(instrument_id.finish()),
+        Arc::new(symbol.finish()),
+        Arc::new(publisher_id.finish()),
+        Arc::new(order_id.finish()),
+        Arc::new(action.finish()),
+        Arc::new(side.finish()),
+        Arc::new(price.finish()),
+        Arc::new(size.finish()),
+        Arc::new(flags.finish()),
+        Arc::new(sequence.finish()),
+        Arc::new(ts_in_delta.finish()),
+        Arc::new(channel_id.finish()),
+    ]
+}
+
+fn effect_columns(snapshots: &[&MarketSnapshot]) -> Vec<ArrayRef> {
+    let mut ts_recv = TimestampNanosecondBuilder::new().with_timezone("UTC");
+    let mut instrument_id = UInt32Builder::new();
+    let mut symbol = StringBuilder::new();
+    let mut publisher_id = UInt16Builder::new();
+    let mut order_id = UInt64Builder::new();
+    let mut effect = StringBuilder::new();
+    let mut side = StringBuilder::new();
+    let mut price = Int64Builder::new();
+    let mut size = UInt32Builder::new();
+    let mut old_price = Int64Builder::new();
+    let mut old_size = UInt32Builder::new();
+    let mut publisher_created = BooleanBuilder::new();
+    let mut error = StringBuilder::new();
+
+    for snapshot in snapshots {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        let market_effect = &snapshot.mbomsg_effect.market_effect;
+        ts_recv.append_value(msg.ts_recv as i64);
+        instrument_id.append_value(msg.hd.instrument_id);
+        symbol.append_option(snapshot.mbomsg_effect.symbol.as_deref());
+        publisher_id.append_value(msg.hd.publisher_id);
+        order_id.append_value(msg.order_id);
+        publisher_created.append_value(market_effect.publisher_created.is_some());
+
+        // Adds and cancels have no previous price or size
+        let (name, effect_side, new_price, new_size, previous) = match &market_effect.book_effect {
+            Ok(Some(BookEffect::Add { side, price, size })) =>
+                (Some("add"), Some(*side), Some(*price), Some(*size), None),
+            Ok(Some(BookEffect::Cancel { side, price, size })) =>
+                (Some("cancel"), Some(*side), Some(*price), Some(*size), None),
+            Ok(Some(BookEffect::Modify { side, old_price, new_price, old_size, new_size })) =>
+                (Some("modify"), Some(*side), Some(*new_price), Some(*new_size), Some((*old_price, *old_size))),
+            Ok(None) | Err(_) => (None, None, None, None, None),
+        };
+        effect.append_option(name);
+        side.append_option(effect_side.map(|side| (side as u8 as char).to_string()));
+        price.append_option(new_price);
+        size.append_option(new_size);
+        old_price.append_option(previous.map(|(price, _)| price));
+        old_size.append_option(previous.map(|(_, size)| size));
+        error.append_option(market_effect.book_effect.as_ref().err());
+    }
+
+    vec![
+        Arc::new(ts_recv.finish()),
+        Arc::new(instrument_id.finish()),
+        Arc::new(symbol.finish()),
+        Arc::new(publisher_id.finish()),
+        Arc::new(order_id.finish()),
+        Arc::new(effect.finish()),
+        Arc::new(side.finish()),
+        Arc::new(price.finish()),
+        Arc::new(size.finish()),
+        Arc::new(old_price.finish()),
+        Arc::new(old_size.finish()),
+        Arc::new(publisher_created.finish()),
+        Arc::new(error.finish()),
+    ]
+}
+
+fn bbo_columns(snapshots: &[&MarketSnapshot]) -> Vec<ArrayRef> {
+    let mut ts_recv = TimestampNanosecondBuilder::new().with_timezone("UTC");
+    let mut instrument_id = UInt32Builder::new();
+    let mut symbol = StringBuilder::new();
+    let mut bid_price = Int64Builder::new();
+    let mut bid_size = UInt32Builder::new();
+    let mut bid_count = UInt32Builder::new();
+    let mut ask_price = Int64Builder::new();
+    let mut ask_size = UInt32Builder::new();
+    let mut ask_count = UInt32Builder::new();
+
+    for snapshot in snapshots {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        let (bid, ask) = snapshot.market.aggregated_bbo(msg.hd.instrument_id);
+        ts_recv.append_value(msg.ts_recv as i64);
+        instrument_id.append_value(msg.hd.instrument_id);
+        symbol.append_option(snapshot.mbomsg_effect.symbol.as_deref());
+        bid_price.append_option(bid.as_ref().map(|level| level.price));
+        bid_size.append_option(bid.as_ref().map(|level| level.size));
+        bid_count.append_option(bid.as_ref().map(|level| level.count));
+        ask_price.append_option(ask.as_ref().map(|level| level.price));
+        ask_size.append_option(ask.as_ref().map(|level| level.size));
+        ask_count.append_option(ask.as_ref().map(|level| level.count));
+    }
+
+    vec![
+        Arc::new(ts_recv.finish()),
+        Arc::new(instrument_id.finish()),
+        Arc::new(symbol.finish()),
+        Arc::new(bid_price.finish()),
+        Arc::new(bid_size.finish()),
+        Arc::new(bid_count.finish()),
+        Arc::new(ask_price.finish()),
+        Arc::new(ask_size.finish()),
+        Arc::new(ask_count.finish()),
+    ]
+}
+
+/// Schema of depth snapshots, one row per price level after each message
+pub fn depth_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("ts_recv", DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())), false),
+        Field::new("instrument_id", DataType::UInt32, false),
+        Field::new("symbol", DataType::Utf8, true),
+        Field::new("publisher_id", DataType::UInt16, false),
+        Field::new("level", DataType::UInt16, false),
+        Field::new("bid_price", DataType::Int64, true),
+        Field::new("bid_size", DataType::UInt32, true),
+        Field::new("bid_count", DataType::UInt32, true),
+        Field::new("ask_price", DataType::Int64, true),
+        Field::new("ask_size", DataType::UInt32, true),
+        Field::new("ask_count", DataType::UInt32, true),
+    ]))
+}
+
+/// Build a record batch of the top `levels` levels of the book each
+///  message was applied to, after applying it
+#[tracing::instrument(skip(snapshots))]
+pub fn depth_record_batch(snapshots: &[&MarketSnapshot], levels: u16) -> Result<RecordBatch> {
+    let mut ts_recv = TimestampNanosecondBuilder::new().with_timezone("UTC");
+    let mut instrument_id = UInt32Builder::new();
+    let mut symbol = StringBuilder::new();
+    let mut publisher_id = UInt16Builder::new();
+    let mut level = UInt16Builder::new();
+    let mut bid_price = Int64Builder::new();
+    let mut bid_size = UInt32Builder::new();
+    let mut bid_count = UInt32Builder::new();
+    let mut ask_price = Int64Builder::new();
+    let mut ask_size = UInt32Builder::new();
+    let mut ask_count = UInt32Builder::new();
+
+    for snapshot in snapshots {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        let book = snapshot.market.books_by_pub(msg.hd.instrument_id)
+            .and_then(|books| books.iter().find(|(publisher, _)| *publisher as u16 == msg.hd.publisher_id))
+            .map(|(_, book)| book);
+
+        for i in 0..levels {
+            let bid = book.and_then(|book| book.bid_level(i as usize));
+            let ask = book.and_then(|book| book.ask_level(i as usize));
+            ts_recv.append_value(msg.ts_recv as i64);
+            instrument_id.append_value(msg.hd.instrument_id);
+            symbol.append_option(snapshot.mbomsg_effect.symbol.as_deref());
+            publisher_id.append_value(msg.hd.publisher_id);
+            level.append_value(i);
+            bid_price.append_option(bid.as_ref().map(|level| level.price));
+            bid_size.append_option(bid.as_ref().map(|level| level.size));
+            bid_count.append_option(bid.as_ref().map(|level| level.count));
+            ask_price.append_option(ask.as_ref().map(|level| level.price));
+            ask_size.append_option(ask.as_ref().map(|level| level.size));
+            ask_count.append_option(ask.as_ref().map(|level| level.count));
+        }
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(ts_recv.finish()),
+        Arc::new(instrument_id.finish()),
+        Arc::new(symbol.finish()),
+        Arc::new(publisher_id.finish()),
+        Arc::new(level.finish()),
+        Arc::new(bid_price.finish()),
+        Arc::new(bid_size.finish()),
+        Arc::new(bid_count.finish()),
+        Arc::new(ask_price.finish()),
+        Arc::new(ask_size.finish()),
+        Arc::new(ask_count.finish()),
+    ];
+    RecordBatch::try_new(depth_schema(), columns)
+        .context("...while building depth record batch")
+}
+
+/// Encode a record batch as a ZSTD-compressed Parquet file
+pub fn write_parquet<W: Write + Send>(writer: W, batch: &RecordBatch) -> Result<()> {
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let mut writer = ArrowWriter::try_new(writer, batch.schema(), Some(properties))
+        .context("...while creating Parquet writer")?;
+    writer.write(batch)
+        .context("...while writing Parquet record batch")?;
+    writer.close()
+        .context("...while finalizing Parquet file")?;
+
+    Ok(())
+}
+
+/// Encode record batches sharing `schema` as an Arrow IPC stream,
+///  building each one only once the previous one has been written, and
+///  flushing the writer after each so it can be sent as it's built
+pub fn write_ipc_stream<W: Write>(
+    writer: W,
+    schema: &Schema,
+    batches: impl IntoIterator<Item = Result<RecordBatch>>,
+) -> Result<()> {
+    let mut writer = StreamWriter::try_new(writer, schema)
+        .context("...while creating Arrow IPC stream writer")?;
+    for batch in batches {
+        let batch = batch
+            .context("...while building Arrow IPC record batch")?;
+        writer.write(&batch)
+            .context("...while writing Arrow IPC record batch")?;
+        writer.flush()
+            .context("...while flushing Arrow IPC stream")?;
+    }
+    writer.finish()
+        .context("...while finalizing Arrow IPC stream")?;
+
+    Ok(())
+}
+
+/// Where and what to archive, loaded from the environment
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    pub root: PathBuf,
+    pub kinds: Vec<ArchiveKind>,
+}
+impl ArchiveConfig {
+    /// `None` when `ARCHIVE_DIR` isn't set, messages are always archived
+    ///  while effects and BBO are opt-in through `ARCHIVE_EFFECTS` and
+    ///  `ARCHIVE_BBO`
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(root) = std::env::var("ARCHIVE_DIR") else {
+            return Ok(None);
+        };
+
+        let mut kinds = vec![ArchiveKind::Mbo];
+        for (var, kind) in [("ARCHIVE_EFFECTS", ArchiveKind::Effects), ("ARCHIVE_BBO", ArchiveKind::Bbo)] {
+            let enabled = std::env::var(var)
+                .map(|value| value.parse::<bool>())
+                .unwrap_or(Ok(false))
+                .context(format!("...while parsing {}", var))?;
+            if enabled {
+                kinds.push(kind);
+            }
+        }
+
+        Ok(Some(Self {
+            root: PathBuf::from(root),
+            kinds,
+        }))
+    }
+}
+
+/// Write the snapshots as Parquet files partitioned by UTC date and
+///  instrument, i.e. `<root>/<kind>/date=YYYY-MM-DD/instrument_id=N/part-0.parquet`
+///
+/// Partitions are rewritten as a whole, so archiving the same data again
+///  replaces the previous files rather than duplicating rows. When the
+///  root isn't stamped with `artifacts`, every partition is removed
+///  first, so none built from other data is left behind. Returns the
+///  number of files written.
+#[tracing::instrument(skip(artifacts, snapshots), fields(count = snapshots.len()))]
+pub fn write_archive(config: &ArchiveConfig, artifacts: &ArtifactManifest, snapshots: &[MarketSnapshot]) -> Result<usize> {
+    if !artifacts.matches(&config.root) {
+        for kind in [ArchiveKind::Mbo, ArchiveKind::Effects, ArchiveKind::Bbo] {
+            let dir = config.root.join(kind.name());
+            if dir.exists() {
+                info!("Removing stale Parquet archive {:?}", dir);
+                std::fs::remove_dir_all(&dir)
+                    .context(format!("...while removing stale archive {:?}", dir))?;
+            }
+        }
+    }
+
+    let mut partitions = BTreeMap::<(String, u32), Vec<&MarketSnapshot>>::new();
+    for snapshot in snapshots {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        partitions.entry((partition_date(msg.ts_recv)?, msg.hd.instrument_id))
+            .or_default()
+            .push(snapshot);
+    }
+
+    let mut written = 0;
+    for kind in &config.kinds {
+        for ((date, instrument_id), snapshots) in &partitions {
+            let dir = config.root
+                .join(kind.name())
+                .join(format!("date={}", date))
+                .join(format!("instrument_id={}", instrument_id));
+            write_partition(&dir, *kind, snapshots)
+                .context(format!("...while writing partition {:?}", dir))?;
+            written += 1;
+        }
+    }
+
+    artifacts.stamp(&config.root)?;
+
+    info!(files = written, root = ?config.root, "Wrote Parquet archive");
+    Ok(written)
+}
+
+fn write_partition(dir: &Path, kind: ArchiveKind, snapshots: &[&MarketSnapshot]) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .context("...while creating partition directory")?;
+
+    // Written next to the destination and renamed over it, so readers
+    //  never see a partially written file
+    let path = dir.join("part-0.parquet");
+    let temp_path = dir.join("part-0.parquet.tmp");
+    let batch = record_batch(kind, snapshots)?;
+    let file = std::fs::File::create(&temp_path)
+        .context("...while creating Parquet file")?;
+    write_parquet(file, &batch)?;
+    std::fs::rename(&temp_path, &path)
+        .context("...while moving Parquet file into place")?;
+
+    Ok(())
+}
+
+/// UTC date of a nanosecond timestamp, formatted as `YYYY-MM-DD`
+fn partition_date(ts: u64) -> Result<String> {
+    let Ok(datetime) = OffsetDateTime::from_unix_timestamp_nanos(ts as i128) else {
+        bail!("Timestamp {} is out of range", ts);
+    };
+    let date = datetime.date();
+
+    Ok(format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::{
+        array::{Array, AsArray, types::{Int64Type, UInt64Type}},
+        ipc::reader::StreamReader,
+    };
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::{
+        datatypes::market::load_market_snapshots,
+        ingest::{Dataset, decoder::DbnSource},
+    };
+
+    fn read_partitions(dir: &Path) -> Result<Vec<RecordBatch>> {
+        let mut batches = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                batches.extend(read_partitions(&path)?);
+            } else if path.extension().is_some_and(|ext| ext == "parquet") {
+                let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path)?)?
+                    .build()?;
+                for batch in reader {
+                    batches.push(batch?);
+                }
+            }
+        }
+
+        Ok(batches)
+    }
+
+    #[tokio::test]
+    async fn test_archive_round_trip() -> Result<()> {
+        let root = PathBuf::from("test_archive_round_trip");
+        let _ = std::fs::remove_dir_all(&root);
+
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let config = ArchiveConfig {
+            root: root.clone(),
+            kinds: vec![ArchiveKind::Mbo, ArchiveKind::Effects, ArchiveKind::Bbo],
+        };
+
+        let artifacts = ArtifactManifest::new(&Dataset::from_file(Path::new("assets/CLX5_mbo.dbn"))?);
+
+        // A partition left by another source is removed
+        let stale = root.join("mbo").join("date=1970-01-01");
+        std::fs::create_dir_all(&stale)?;
+
+        let written = write_archive(&config, &artifacts, &snapshots)?;
+        assert!(written >= 3);
+        assert!(!stale.exists());
+        assert!(artifacts.matches(&root));
+
+        // Rewriting replaces partitions instead of adding to them
+        assert_eq!(write_archive(&config, &artifacts, &snapshots)?, written);
+
+        for kind in &config.kinds {
+            let batches = read_partitions(&root.join(kind.name()))?;
+            let rows = batches.iter().map(|batch| batch.num_rows()).sum::<usize>();
+            assert_eq!(rows, snapshots.len(), "Row count mismatch for {}", kind.name());
+            assert!(batches.iter().all(|batch| batch.schema() == kind.schema()));
+        }
+
+        // Partitions are sorted by date, then instrument, so compare as sets
+        let mut archived = read_partitions(&root.join("mbo"))?
+            .iter()
+            .flat_map(|batch| {
+                let order_ids = batch.column_by_name("order_id").unwrap().as_primitive::<UInt64Type>();
+                order_ids.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        let mut expected = snapshots.iter()
+            .map(|snapshot| snapshot.mbomsg_effect.mbo_msg.order_id)
+            .collect::<Vec<_>>();
+        archived.sort_unstable();
+
+        let symbols = read_partitions(&root.join("bbo"))?
+            .iter()
+            .flat_map(|batch| {
+                let symbols = batch.column_by_name("symbol").unwrap().as_string::<i32>();
+                symbols.iter().map(|symbol| symbol.map(str::to_string)).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(symbols.iter().all(|symbol| symbol.as_deref() == Some("CLX5")));
+        expected.sort_unstable();
+        assert_eq!(archived, expected);
+
+        let _ = std::fs::remove_dir_all(&root);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_depth_over_ipc_stream() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let snapshots = snapshots.iter().take(2_000).collect::<Vec<_>>();
+
+        let batches = snapshots.chunks(500)
+            .map(|chunk| depth_record_batch(chunk, 5));
+        let mut bytes = Vec::new();
+        write_ipc_stream(&mut bytes, &depth_schema(), batches)?;
+
+        let reader = StreamReader::try_new(bytes.as_slice(), None)?;
+        let batches = reader.collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(batches.len(), 4);
+        assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), snapshots.len() * 5);
+
+        // Levels are ordered away from the touch on both sides
+        for batch in &batches {
+            let bids = batch.column_by_name("bid_price").unwrap().as_primitive::<Int64Type>();
+            let asks = batch.column_by_name("ask_price").unwrap().as_primitive::<Int64Type>();
+            for row in (0..batch.num_rows()).filter(|row| row % 5 != 4) {
+                if bids.is_valid(row + 1) {
+                    assert!(bids.value(row) > bids.value(row + 1));
+                }
+                if asks.is_valid(row + 1) {
+                    assert!(asks.value(row) < asks.value(row + 1));
+                }
+            }
+        }
+
+        // The first level matches the BBO of single-publisher books
+        let bbo = record_batch(ArchiveKind::Bbo, &snapshots[..500])?;
+        let bbo_bids = bbo.column_by_name("bid_price").unwrap().as_primitive::<Int64Type>();
+        let depth_bids = batches[0].column_by_name("bid_price").unwrap().as_primitive::<Int64Type>();
+        for row in 0..bbo.num_rows() {
+            assert_eq!(bbo_bids.is_valid(row), depth_bids.is_valid(row * 5));
+            if bbo_bids.is_valid(row) {
+                assert_eq!(bbo_bids.value(row), depth_bids.value(row * 5));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_date() -> Result<()> {
+        assert_eq!(partition_date(0)?, "1970-01-01");
+        assert_eq!(partition_date(1_758_672_000_000_000_000)?, "2025-09-24");
+        Ok(())
+    }
+}
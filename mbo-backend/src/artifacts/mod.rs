@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    datatypes::{book::CROSSED_BOOK_POLICY, market::ENGINE_VERSION},
+    ingest::Dataset,
+};
+
+/// File stamping a directory of generated artifacts, named so dataset
+///  readers such as pyarrow skip it
+pub const MANIFEST_FILE: &str = "_manifest.json";
+
+/// Everything generated artifacts depend on besides their own parameters
+///
+/// Artifacts stamped with a different manifest were built from other
+///  data, or by an engine that builds books differently, and have to be
+///  regenerated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct ArtifactManifest {
+    /// Fingerprint of the dataset the market was built from
+    pub source: String,
+    pub engine_version: u32,
+    /// How crossed books are resolved while building the market
+    pub crossed_book_policy: String,
+}
+impl ArtifactManifest {
+    pub fn new(dataset: &Dataset) -> Self {
+        Self {
+            source: dataset.fingerprint.clone(),
+            engine_version: ENGINE_VERSION,
+            crossed_book_policy: CROSSED_BOOK_POLICY.to_string(),
+        }
+    }
+
+    /// Whether `dir` is stamped with this manifest - `false` when it
+    ///  isn't stamped at all, or the stamp can't be read
+    pub fn matches(&self, dir: &Path) -> bool {
+        std::fs::read(dir.join(MANIFEST_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_slice::<Self>(&json).ok())
+            .is_some_and(|manifest| &manifest == self)
+    }
+
+    /// Stamp `dir` as holding artifacts generated under this manifest
+    pub fn stamp(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .context(format!("...while creating artifact directory {:?}", dir))?;
+        let json = serde_json::to_vec_pretty(self)
+            .context("...while serializing artifact manifest")?;
+        std::fs::write(dir.join(MANIFEST_FILE), json)
+            .context(format!("...while stamping artifact directory {:?}", dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_matches_only_same_stamp() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mbo_test_artifacts_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let manifest = ArtifactManifest::new(&Dataset {
+            fingerprint: "sha256:abc".to_string(),
+            source: "file:a.dbn".to_string(),
+        });
+        let other = ArtifactManifest::new(&Dataset {
+            fingerprint: "sha256:def".to_string(),
+            source: "file:b.dbn".to_string(),
+        });
+
+        assert!(!manifest.matches(&dir));
+        manifest.stamp(&dir)?;
+        assert!(manifest.matches(&dir));
+        assert!(!other.matches(&dir));
+
+        let newer_engine = ArtifactManifest { engine_version: ENGINE_VERSION + 1, ..manifest.clone() };
+        assert!(!newer_engine.matches(&dir));
+
+        std::fs::write(dir.join(MANIFEST_FILE), b"not json")?;
+        assert!(!manifest.matches(&dir));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -9,7 +9,12 @@ use databento::{
 };
 use anyhow::{Result, Context, bail, ensure};
 use tracing::warn;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+/// How a book left crossed by an add is resolved, recorded alongside
+///  generated artifacts: both crossed levels are removed, as if they
+///  had traded against each other (see `Book::match_crossed_orders`)
+pub const CROSSED_BOOK_POLICY: &str = "remove_crossed_levels";
 
 #[derive(Debug, Clone, Serialize)]
 pub enum BookEffect {
@@ -23,7 +28,63 @@ impl Default for BookEffect {
     }
 }
 
-#[derive(Debug, Default, Clone, Serialize)]
+/// An order resting in a book
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct RestingOrder {
+    pub order_id: u64,
+    /// `B` (bid) or `A` (ask)
+    pub side: char,
+    pub price: i64,
+    pub size: u32,
+}
+
+/// An order resting in both books of a diff, with a new price or size
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct OrderChange {
+    pub order_id: u64,
+    /// `B` (bid) or `A` (ask)
+    pub side: char,
+    pub old_price: i64,
+    pub new_price: i64,
+    pub old_size: u32,
+    pub new_size: u32,
+}
+
+/// A price level whose size or order count differs between two books,
+///  with zeroes for a level missing from either
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct LevelChange {
+    /// `B` (bid) or `A` (ask)
+    pub side: char,
+    pub price: i64,
+    pub old_size: u32,
+    pub new_size: u32,
+    pub old_count: u32,
+    pub new_count: u32,
+}
+
+/// What changed from one state of a book to another
+#[derive(Debug, Clone, Default, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct BookDiff {
+    /// Orders only in the later book, by order ID
+    pub added_orders: Vec<RestingOrder>,
+    /// Orders only in the earlier book, by order ID
+    pub removed_orders: Vec<RestingOrder>,
+    /// Orders in both books whose price or size changed, by order ID
+    pub changed_orders: Vec<OrderChange>,
+    /// Level changes, bids then asks, each best first
+    pub levels: Vec<LevelChange>,
+}
+impl BookDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_orders.is_empty()
+            && self.removed_orders.is_empty()
+            && self.changed_orders.is_empty()
+            && self.levels.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Book {
     orders_by_id: HashMap<u64, (Side, i64)>,
     offers: BTreeMap<i64, Level>,
@@ -54,6 +115,89 @@ impl Book {
             .map(|(price, orders)| PriceLevel::new(*price, orders.iter()))
     }
 
+    /// Every bid level, best first
+    pub fn bid_levels(&self) -> impl Iterator<Item = PriceLevel> + '_ {
+        self.bids
+            .iter()
+            .rev()
+            .map(|(price, orders)| PriceLevel::new(*price, orders.iter()))
+    }
+
+    /// Every ask level, best first
+    pub fn ask_levels(&self) -> impl Iterator<Item = PriceLevel> + '_ {
+        self.offers
+            .iter()
+            .map(|(price, orders)| PriceLevel::new(*price, orders.iter()))
+    }
+
+    /// Every resting order, by order ID
+    fn resting_orders(&self) -> BTreeMap<u64, RestingOrder> {
+        let bids = self.bids.values().flatten().map(|order| (Side::Bid, order));
+        let asks = self.offers.values().flatten().map(|order| (Side::Ask, order));
+
+        bids.chain(asks)
+            .map(|(side, order)| (order.order_id, RestingOrder {
+                order_id: order.order_id,
+                side: side as u8 as char,
+                price: order.price,
+                size: order.size,
+            }))
+            .collect()
+    }
+
+    /// What changed from this book to `to`, a later state of it
+    pub fn diff(&self, to: &Book) -> BookDiff {
+        let from_orders = self.resting_orders();
+        let mut to_orders = to.resting_orders();
+
+        let mut diff = BookDiff::default();
+        for (order_id, from_order) in from_orders {
+            match to_orders.remove(&order_id) {
+                None => diff.removed_orders.push(from_order),
+                Some(to_order) if to_order != from_order => diff.changed_orders.push(OrderChange {
+                    order_id,
+                    side: to_order.side,
+                    old_price: from_order.price,
+                    new_price: to_order.price,
+                    old_size: from_order.size,
+                    new_size: to_order.size,
+                }),
+                Some(_) => {}
+            }
+        }
+        diff.added_orders = to_orders.into_values().collect();
+
+        for (side, from_levels, to_levels) in [
+            (Side::Bid, &self.bids, &to.bids),
+            (Side::Ask, &self.offers, &to.offers),
+        ] {
+            let mut prices = from_levels.keys().chain(to_levels.keys()).copied().collect::<Vec<_>>();
+            prices.sort_unstable();
+            prices.dedup();
+            if side == Side::Bid {
+                prices.reverse();
+            }
+
+            for price in prices {
+                let level = |levels: &BTreeMap<i64, Level>| levels.get(&price)
+                    .map_or(PriceLevel { price, size: 0, count: 0 }, |orders| PriceLevel::new(price, orders.iter()));
+                let (from_level, to_level) = (level(from_levels), level(to_levels));
+                if from_level != to_level {
+                    diff.levels.push(LevelChange {
+                        side: side as u8 as char,
+                        price,
+                        old_size: from_level.size,
+                        new_size: to_level.size,
+                        old_count: from_level.count,
+                        new_count: to_level.count,
+                    });
+                }
+            }
+        }
+
+        diff
+    }
+
     pub fn bid_level_by_px(&self, px: i64) -> Option<PriceLevel> {
         self.bids
             .get(&px)
@@ -73,6 +217,15 @@ impl Book {
         level.iter().find(|order| order.order_id == order_id)
     }
 
+    /// Orders resting at a price, in queue order
+    pub fn level_orders(&self, side: Side, price: i64) -> impl Iterator<Item = &MboMsg> + '_ {
+        self.side_levels(side)
+            .ok()
+            .and_then(|levels| levels.get(&price))
+            .into_iter()
+            .flatten()
+    }
+
     pub fn queue_pos(&self, order_id: u64) -> Option<u32> {
         let (side, price) = self.orders_by_id.get(&order_id)?;
         let levels = self.side_levels(*side).ok()?;
@@ -561,15 +714,11 @@ mod tests {
             // Check if this is a cancel/modify for an order we don't have
             if let Ok(action) = msg.action() {
                 match action {
-                    Action::Cancel => {
-                        if book.order(order_id).is_none() {
-                            skipped_cancels += 1;
-                        }
+                    Action::Cancel if book.order(order_id).is_none() => {
+                        skipped_cancels += 1;
                     }
-                    Action::Modify => {
-                        if book.order(order_id).is_none() {
-                            skipped_modifies += 1;
-                        }
+                    Action::Modify if book.order(order_id).is_none() => {
+                        skipped_modifies += 1;
                     }
                     _ => {}
                 }
@@ -0,0 +1,265 @@
+use databento::dbn::MboMsg;
+use serde::Serialize;
+
+use super::{
+    book::BookDiff,
+    market::{Market, MarketEffect, MarketSnapshot},
+    price_level::PriceLevel,
+};
+
+/// A publisher's book of an instrument, as price levels best first
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct PublisherBook {
+    pub publisher_id: u16,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+/// A message of the loaded market and the effect it had
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct BookEvent {
+    /// Position of the message in the loaded market
+    pub index: usize,
+    /// Raw symbol of the instrument when the message was received, if known
+    pub symbol: Option<String>,
+    #[schema(value_type = Object)]
+    pub mbo_msg: MboMsg,
+    #[schema(value_type = Object)]
+    pub market_effect: MarketEffect,
+}
+impl BookEvent {
+    pub fn new(index: usize, snapshot: &MarketSnapshot) -> Self {
+        Self {
+            index,
+            symbol: snapshot.mbomsg_effect.symbol.clone(),
+            mbo_msg: snapshot.mbomsg_effect.mbo_msg.clone(),
+            market_effect: snapshot.mbomsg_effect.market_effect.clone(),
+        }
+    }
+}
+
+/// An instrument's books as of a point in time
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct BookAt {
+    pub instrument_id: u32,
+    /// The requested time, in nanoseconds since the UNIX epoch
+    pub ts: u64,
+    /// Position in the loaded market of the last message, of any
+    ///  instrument, received at or before `ts` - `None` if every message
+    ///  was received later
+    pub index: Option<usize>,
+    /// Raw symbol of the instrument as of its last message, if known
+    pub symbol: Option<String>,
+    pub books: Vec<PublisherBook>,
+    /// The instrument's last messages up to `index`, oldest first
+    pub events: Vec<BookEvent>,
+}
+impl BookAt {
+    /// The books after applying every message received at or before `ts`,
+    ///  with up to `depth` levels per side (all when `None`) and the
+    ///  instrument's last `event_count` messages
+    pub fn new(
+        snapshots: &[MarketSnapshot],
+        instrument_id: u32,
+        ts: u64,
+        depth: Option<usize>,
+        event_count: usize,
+    ) -> Self {
+        let index = index_at(snapshots, ts);
+        let (books, mut events) = match index {
+            Some(index) => (
+                books(&snapshots[index].market, instrument_id, depth),
+                snapshots[..=index].iter()
+                    .enumerate()
+                    .rev()
+                    .filter(|(_, snapshot)| snapshot.mbomsg_effect.mbo_msg.hd.instrument_id == instrument_id)
+                    .take(event_count)
+                    .map(|(index, snapshot)| BookEvent::new(index, snapshot))
+                    .collect::<Vec<_>>(),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+        events.reverse();
+
+        Self {
+            instrument_id,
+            ts,
+            index,
+            symbol: events.last().and_then(|event| event.symbol.clone()),
+            books,
+            events,
+        }
+    }
+}
+
+/// What changed in a publisher's book
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct PublisherBookDiff {
+    pub publisher_id: u16,
+    #[serde(flatten)]
+    pub diff: BookDiff,
+}
+
+/// What changed in an instrument's books between two points of the
+///  loaded market
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct BookDiffRange {
+    pub instrument_id: u32,
+    /// Position in the loaded market of the last message applied to the
+    ///  earlier books, `None` for the empty market before the first one
+    pub from_index: Option<usize>,
+    /// Same as `from_index`, for the later books
+    pub to_index: Option<usize>,
+    /// Publishers whose book changed
+    pub books: Vec<PublisherBookDiff>,
+    /// Number of the instrument's messages between the two points
+    pub events: usize,
+}
+impl BookDiffRange {
+    /// Diff the market after the snapshot at `from_index` against the one
+    ///  after `to_index`, which must not be earlier
+    pub fn new(snapshots: &[MarketSnapshot], instrument_id: u32, from_index: Option<usize>, to_index: Option<usize>) -> Self {
+        let empty = Market::new();
+        let market = |index: Option<usize>| index.map_or(&empty, |index| &snapshots[index].market);
+        let books = market(from_index)
+            .diff(market(to_index), instrument_id)
+            .into_iter()
+            .filter(|(_, diff)| !diff.is_empty())
+            .map(|(publisher, diff)| PublisherBookDiff { publisher_id: publisher as u16, diff })
+            .collect();
+
+        let events = match to_index {
+            Some(to_index) => snapshots[from_index.map_or(0, |index| index + 1)..=to_index].iter()
+                .filter(|snapshot| snapshot.mbomsg_effect.mbo_msg.hd.instrument_id == instrument_id)
+                .count(),
+            None => 0,
+        };
+
+        Self { instrument_id, from_index, to_index, books, events }
+    }
+}
+
+/// Position of the last snapshot received at or before `ts`
+///
+/// Snapshots are in the order their messages were received, so this is
+///  a binary search on `ts_recv`.
+pub fn index_at(snapshots: &[MarketSnapshot], ts: u64) -> Option<usize> {
+    snapshots.partition_point(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv <= ts)
+        .checked_sub(1)
+}
+
+/// Each publisher's book of an instrument, with up to `depth` levels per
+///  side (all when `None`)
+pub fn books(market: &Market, instrument_id: u32, depth: Option<usize>) -> Vec<PublisherBook> {
+    let depth = depth.unwrap_or(usize::MAX);
+
+    market.books_by_pub(instrument_id)
+        .unwrap_or_default()
+        .iter()
+        .map(|(publisher, book)| PublisherBook {
+            publisher_id: *publisher as u16,
+            bids: book.bid_levels().take(depth).collect(),
+            asks: book.ask_levels().take(depth).collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use anyhow::Result;
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    #[tokio::test]
+    async fn test_book_at_timestamp() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let ts_recv = |index: usize| snapshots[index].mbomsg_effect.mbo_msg.ts_recv;
+        let instrument_id = snapshots[0].mbomsg_effect.mbo_msg.hd.instrument_id;
+
+        assert_eq!(index_at(&snapshots, ts_recv(0) - 1), None);
+        assert_eq!(index_at(&snapshots, u64::MAX), Some(snapshots.len() - 1));
+
+        // Every message received by the requested nanosecond is applied,
+        //  including others received in the same nanosecond
+        let index = 5_000;
+        let expected = (index..snapshots.len())
+            .take_while(|i| ts_recv(*i) == ts_recv(index))
+            .last()
+            .unwrap();
+        assert_eq!(index_at(&snapshots, ts_recv(index)), Some(expected));
+
+        // A nanosecond before the next message is the same book
+        let before_next = index_at(&snapshots, ts_recv(expected + 1) - 1);
+        assert_eq!(before_next, Some(expected));
+
+        let book_at = BookAt::new(&snapshots, instrument_id, ts_recv(index), Some(5), 10);
+        assert_eq!(book_at.index, Some(expected));
+        assert_eq!(book_at.books, books(&snapshots[expected].market, instrument_id, Some(5)));
+        assert!(book_at.books.iter().all(|book| book.bids.len() <= 5 && book.asks.len() <= 5));
+        let (bid, ask) = snapshots[expected].market.aggregated_bbo(instrument_id);
+        assert_eq!(book_at.books[0].bids.first(), bid.as_ref());
+        assert_eq!(book_at.books[0].asks.first(), ask.as_ref());
+
+        assert_eq!(book_at.events.len(), 10);
+        assert!(book_at.events.windows(2).all(|pair| pair[0].index < pair[1].index));
+        assert!(book_at.events.last().is_some_and(|event| event.index <= expected));
+        assert_eq!(book_at.symbol.as_deref(), Some("CLX5"));
+
+        // Before the first message there is nothing to show
+        let empty = BookAt::new(&snapshots, instrument_id, 0, None, 10);
+        assert_eq!((empty.index, empty.books.len(), empty.events.len()), (None, 0, 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_book_diff_between_snapshots() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let instrument_id = snapshots[0].mbomsg_effect.mbo_msg.hd.instrument_id;
+
+        let unchanged = BookDiffRange::new(&snapshots, instrument_id, Some(2_000), Some(2_000));
+        assert!(unchanged.books.is_empty());
+        assert_eq!(unchanged.events, 0);
+
+        let (from, to) = (1_000, 3_000);
+        let range = BookDiffRange::new(&snapshots, instrument_id, Some(from), Some(to));
+        assert_eq!(range.events, to - from);
+        let diff = &range.books.first().expect("Book changed").diff;
+
+        // Applying the level changes to the earlier book gives the later one
+        let levels = |index: usize| {
+            let book = &books(&snapshots[index].market, instrument_id, None)[0];
+            book.bids.iter().map(|level| (('B', level.price), (level.size, level.count)))
+                .chain(book.asks.iter().map(|level| (('A', level.price), (level.size, level.count))))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+        let mut rebuilt = levels(from);
+        for change in &diff.levels {
+            assert_eq!(rebuilt.get(&(change.side, change.price)).copied().unwrap_or_default(), (change.old_size, change.old_count));
+            if change.new_size == 0 && change.new_count == 0 {
+                rebuilt.remove(&(change.side, change.price));
+            } else {
+                rebuilt.insert((change.side, change.price), (change.new_size, change.new_count));
+            }
+        }
+        assert_eq!(rebuilt, levels(to));
+
+        // Order changes account for every change in resting size
+        let total = |levels: &std::collections::BTreeMap<(char, i64), (u32, u32)>| levels.values().map(|(size, _)| *size as i64).sum::<i64>();
+        let order_delta = diff.added_orders.iter().map(|order| order.size as i64).sum::<i64>()
+            - diff.removed_orders.iter().map(|order| order.size as i64).sum::<i64>()
+            + diff.changed_orders.iter().map(|order| order.new_size as i64 - order.old_size as i64).sum::<i64>();
+        assert_eq!(order_delta, total(&levels(to)) - total(&levels(from)));
+
+        // From the empty market, every resting order was added
+        let from_start = BookDiffRange::new(&snapshots, instrument_id, None, Some(to));
+        let diff = &from_start.books[0].diff;
+        assert!(diff.removed_orders.is_empty() && diff.changed_orders.is_empty());
+        assert_eq!(diff.added_orders.iter().map(|order| order.size as i64).sum::<i64>(), total(&levels(to)));
+
+        Ok(())
+    }
+}
@@ -1,21 +1,32 @@
-use std::collections::HashMap;
-use super::{price_level::PriceLevel, book::Book};
+use std::collections::{BTreeMap, HashMap};
+use super::{price_level::PriceLevel, book::{Book, BookDiff}};
 use databento::{
     dbn::{
         MboMsg, Publisher, Record,
-        decode::{DecodeRecord, dbn::Decoder, DbnMetadata},
-        SymbolIndex
     }
 };
-use anyhow::{Context, Result, ensure};
-use serde::Serialize;
-use tracing::info;
-use std::path::Path;
-use crate::{datatypes::book::BookEffect, storage::Storage};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use crate::{
+    datatypes::book::BookEffect,
+    ingest::MboSource,
+    storage::{Checkpoint, MarketStore, MessageQuery, MessageStream, Storage, LIVE_SOURCE},
+};
+
+/// Version of the book-building logic, to be bumped whenever a change to
+///  it changes the snapshots built from the same messages, so artifacts
+///  generated by an older engine are regenerated
+pub const ENGINE_VERSION: u32 = 1;
+
+/// Messages applied between checkpoints written during ingestion
+pub const CHECKPOINT_INTERVAL: usize = 10_000;
 
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct MBOMsgEffect {
     pub mbo_msg: MboMsg,
+    /// Raw symbol of the message's instrument, if the source knows it
+    pub symbol: Option<String>,
     pub market_effect: MarketEffect,
 }
 #[derive(Debug, Clone, Serialize)]
@@ -44,87 +55,111 @@ impl MarketEffect {
     }
 }
 
-/// Load market state from DBN file and return both the market and all MBO messages
+/// Build market snapshots from every message of a source
 /// 
-/// Optionally persists messages to storage if provided.
-pub fn load_market_snapshots(
-    path: &Path,
-    storage: Option<&Storage>,
+/// Optionally persists messages to storage if provided, along with a
+///  checkpoint of the market every `CHECKPOINT_INTERVAL` messages and
+///  after the last one, tagged with the checkpoint source given with it.
+pub async fn load_market_snapshots<S: MboSource>(
+    source: &mut S,
+    persist: Option<(&Storage, &str)>,
 ) -> Result<Vec<MarketSnapshot>> {
-    // First, check that the file exists - `Decoder::from_file`
-    //  already does, but the error message isn't helpful at all
-    ensure!(path.exists(), "Input file does not exist at path: `{:?}`", path);
-
-    let mut dbn_decoder = Decoder::from_file(path)
-        .context("...while trying to open decoder on file")?;
-    let mut mbo_messages = Vec::new();
-    let symbol_map = dbn_decoder.metadata().symbol_map()?;
-
-    info!("File loaded, beginning to process MBO messages...");
+    if let Some(metadata) = source.metadata() {
+        info!(
+            dataset = %metadata.dataset,
+            start = %metadata.start(),
+            "Source opened, beginning to process MBO messages..."
+        );
+    } else {
+        info!("Source opened, beginning to process MBO messages...");
+    }
     
     // Batch size for database inserts
     const BATCH_SIZE: usize = 1000;
     let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut since_checkpoint = 0;
     
     let mut snapshots = Vec::new();
     let mut market = Market::new();
-    while let Some(mbo_msg) = dbn_decoder.decode_record::<MboMsg>().context("...while trying to decode record")? {
-        // Store the message for TCP streaming
-        mbo_messages.push(mbo_msg.clone());
-        
+    while let Some(mbo_msg) = source.next_mbo().await? {
+        let market_effect = market.apply(mbo_msg.clone())
+            .context("...while trying to apply MBO message to market")?;
+
         // Add to batch for persistence
-        if let Some(storage) = storage {
+        if let Some((storage, checkpoint_source)) = persist {
+            let instruments = source.take_instruments();
+            if !instruments.is_empty() {
+                storage.upsert_instruments(&instruments)
+                    .await
+                    .context("...while persisting instruments")?;
+            }
+
             batch.push(mbo_msg.clone());
+            since_checkpoint += 1;
             
-            // Persist batch when it reaches batch size
-            if batch.len() >= BATCH_SIZE {
+            // Persist batch when it reaches batch size, only at the end
+            //  of an event so duplicate detection sees the whole event
+            if batch.len() >= BATCH_SIZE && mbo_msg.flags.is_last() {
                 storage.insert_mbo_batch(&batch)
+                    .await
                     .context("...while persisting MBO message batch")?;
                 batch.clear();
+
+                if since_checkpoint >= CHECKPOINT_INTERVAL {
+                    write_checkpoint(storage, checkpoint_source, &market, &mbo_msg)
+                        .await
+                        .context("...while writing market checkpoint")?;
+                    since_checkpoint = 0;
+                }
             }
         }
-        let market_effect = market.apply(mbo_msg.clone())
-            .context("...while trying to apply MBO message to market")?;
-
-        // Capture market snapshot after applying the MBO message
-        snapshots.push(MarketSnapshot {
-            market: market.clone(),
-            mbomsg_effect: MBOMsgEffect {
-                mbo_msg: mbo_msg.clone(),
-                market_effect,
-            },
-        });
 
         // If it's the last update in an event, print the state of the aggregated book
         if mbo_msg.flags.is_last() {
-            let symbol = symbol_map.get_for_rec(mbo_msg)
-                .context("...while trying to get symbol for MBO message")?;
+            let symbol = source.symbol(&mbo_msg);
             let (best_bid, best_offer) = market.aggregated_bbo(mbo_msg.hd.instrument_id);
             
             let ts_recv = mbo_msg.ts_recv().context("...while trying to get ts_recv")?;
             info!(
-                symbol = %symbol,
+                symbol = ?symbol,
                 timestamp = %ts_recv,
                 best_bid = ?best_bid,
                 best_offer = ?best_offer,
                 "Aggregated BBO update"
             );
         }
+
+        // Capture market snapshot after applying the MBO message
+        snapshots.push(MarketSnapshot {
+            market: market.clone(),
+            mbomsg_effect: MBOMsgEffect {
+                symbol: source.symbol(&mbo_msg).map(str::to_string),
+                mbo_msg,
+                market_effect,
+            },
+        });
     }
     
     // Persist any remaining messages in the batch
-    if let Some(storage) = storage {
+    if let Some((storage, checkpoint_source)) = persist {
         if !batch.is_empty() {
             storage.insert_mbo_batch(&batch)
+                .await
                 .context("...while persisting final MBO message batch")?;
         }
+        if let (Some(last), true) = (snapshots.last(), since_checkpoint > 0) {
+            write_checkpoint(storage, checkpoint_source, &market, &last.mbomsg_effect.mbo_msg)
+                .await
+                .context("...while writing final market checkpoint")?;
+        }
         
         let total_count = storage.count_messages()
+            .await
             .context("...while counting persisted messages")?;
         info!("Persisted {} total messages to database", total_count);
     }
     
-    info!("Finished processing DBN file. Loaded {} MBO messages.", mbo_messages.len());
+    info!("Finished processing source. Loaded {} MBO messages.", snapshots.len());
 
     Ok(snapshots)
 }
@@ -134,7 +169,75 @@ pub struct MarketSnapshot {
     pub market: Market,
     pub mbomsg_effect: MBOMsgEffect,
 }
-#[derive(Debug, Default, Clone, Serialize)]
+/// Checkpoint the market loaded from `source` as of `last`, the latest
+///  message applied to it, which must already be persisted
+pub async fn write_checkpoint(storage: &Storage, source: &str, market: &Market, last: &MboMsg) -> Result<()> {
+    let Some(message_id) = storage.find_message_id(last)
+        .await
+        .context("...while finding checkpointed message")? else {
+        warn!(order_id = last.order_id, "Checkpointed message isn't persisted, skipping checkpoint");
+        return Ok(());
+    };
+
+    storage.insert_checkpoint(&market.to_checkpoint(source, message_id, last.ts_recv)?)
+        .await
+        .context("...while persisting market checkpoint")
+}
+
+/// Restore the market loaded from `source`, `LIVE_SOURCE` or a dataset's
+///  fingerprint, as of persisted message row `message_id`, or its latest
+///  message if `None`, from the closest checkpoint of that source before
+///  it and the source's messages after that checkpoint
+///
+/// A dataset's messages are the rows it inserted, and the live market's
+///  are those outside every dataset's.
+///
+/// Returns the market along with the row ID of the last message applied,
+///  if any.
+#[tracing::instrument(skip(storage))]
+pub async fn restore_market(storage: &Storage, source: &str, message_id: Option<i64>) -> Result<(Market, Option<i64>)> {
+    let checkpoint = storage.latest_checkpoint(Some(source), message_id)
+        .await
+        .context("...while reading latest checkpoint")?;
+    let datasets = storage.datasets()
+        .await
+        .context("...while reading persisted datasets")?;
+    let is_own = |id: i64| {
+        let dataset = datasets.iter().find(|dataset| dataset.message_ids
+            .is_some_and(|(first, last)| (first..=last).contains(&id)));
+        match dataset {
+            Some(dataset) => dataset.fingerprint == source,
+            None => source == LIVE_SOURCE,
+        }
+    };
+    let (mut market, mut last_id) = match &checkpoint {
+        Some(checkpoint) => (Market::from_checkpoint(checkpoint)?, Some(checkpoint.message_id)),
+        None => (Market::new(), None),
+    };
+
+    let mut messages = MessageStream::new(storage.clone(), MessageQuery {
+        after_id: last_id,
+        ..Default::default()
+    });
+    let mut replayed = 0;
+    while let Some(stored) = messages.next_message().await? {
+        if message_id.is_some_and(|message_id| stored.id > message_id) {
+            break;
+        }
+        if !is_own(stored.id) {
+            continue;
+        }
+        market.apply(stored.mbo_msg)
+            .context("...while replaying message after checkpoint")?;
+        last_id = Some(stored.id);
+        replayed += 1;
+    }
+    info!(checkpoint = ?checkpoint.map(|checkpoint| checkpoint.message_id), replayed, "Restored market");
+
+    Ok((market, last_id))
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Market {
     books: HashMap<u32, Vec<(Publisher, Book)>>,
 }
@@ -143,6 +246,57 @@ impl Market {
         Self::default()
     }
 
+    /// Serialize the market loaded from `source` as a checkpoint taken
+    ///  after message row `message_id`
+    pub fn to_checkpoint(&self, source: &str, message_id: i64, ts_recv: u64) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            message_id,
+            ts_recv,
+            source: source.to_string(),
+            data: serde_json::to_vec(self)
+                .context("...while serializing market checkpoint")?,
+        })
+    }
+
+    pub fn from_checkpoint(checkpoint: &Checkpoint) -> Result<Self> {
+        serde_json::from_slice(&checkpoint.data)
+            .context(format!("...while deserializing checkpoint at message {}", checkpoint.message_id))
+    }
+
+    /// Copy of the market holding only the books of some instruments
+    pub fn with_instruments(&self, instrument_ids: &[u32]) -> Self {
+        Self {
+            books: self.books.iter()
+                .filter(|(instrument_id, _)| instrument_ids.contains(instrument_id))
+                .map(|(instrument_id, books)| (*instrument_id, books.clone()))
+                .collect(),
+        }
+    }
+
+    /// What changed in each publisher's book of an instrument from this
+    ///  market to `to`, a later state of it, by publisher ID
+    ///
+    /// A book missing from either market is diffed as an empty one.
+    pub fn diff(&self, to: &Market, instrument_id: u32) -> Vec<(Publisher, BookDiff)> {
+        let from_books = self.books_by_pub(instrument_id).unwrap_or_default();
+        let to_books = to.books_by_pub(instrument_id).unwrap_or_default();
+        let empty = Book::new();
+
+        let mut publishers = from_books.iter().chain(to_books).map(|(publisher, _)| *publisher).collect::<Vec<_>>();
+        publishers.sort_unstable_by_key(|publisher| *publisher as u16);
+        publishers.dedup();
+
+        publishers.into_iter()
+            .map(|publisher| {
+                let from_book = from_books.iter().find(|(book_publisher, _)| *book_publisher == publisher);
+                let to_book = to_books.iter().find(|(book_publisher, _)| *book_publisher == publisher);
+                let diff = from_book.map_or(&empty, |(_, book)| book)
+                    .diff(to_book.map_or(&empty, |(_, book)| book));
+                (publisher, diff)
+            })
+            .collect()
+    }
+
     pub fn books_by_pub(&self, instrument_id: u32) -> Option<&[(Publisher, Book)]> {
         self.books
             .get(&instrument_id)
@@ -184,6 +338,32 @@ impl Market {
         (agg_bid, agg_ask)
     }
 
+    /// The top `depth` bid and ask levels of an instrument across every
+    ///  publisher's book, best first, merging levels at the same price
+    pub fn aggregated_levels(&self, instrument_id: u32, depth: usize) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        let mut bids = BTreeMap::<i64, PriceLevel>::new();
+        let mut asks = BTreeMap::<i64, PriceLevel>::new();
+        let merge = |levels: &mut BTreeMap<i64, PriceLevel>, level: PriceLevel| {
+            levels.entry(level.price)
+                .and_modify(|merged| {
+                    merged.size += level.size;
+                    merged.count += level.count;
+                })
+                .or_insert(level);
+        };
+
+        // The top levels across books are among each book's top levels
+        for (_, book) in self.books_by_pub(instrument_id).unwrap_or_default() {
+            book.bid_levels().take(depth).for_each(|level| merge(&mut bids, level));
+            book.ask_levels().take(depth).for_each(|level| merge(&mut asks, level));
+        }
+
+        (
+            bids.into_values().rev().take(depth).collect(),
+            asks.into_values().take(depth).collect(),
+        )
+    }
+
     #[tracing::instrument(skip(self), fields(instrument_id = mbo.hd.instrument_id, order_id = mbo.order_id))]
     pub fn apply(&mut self, mbo: MboMsg) -> Result<MarketEffect> {
         let publisher = mbo.publisher()
@@ -196,8 +376,8 @@ impl Market {
         {
             book
         } else {
-            books.push((publisher.clone(), Book::default()));
-            created_publisher = Some(publisher.clone());
+            books.push((publisher, Book::default()));
+            created_publisher = Some(publisher);
             &mut books
                 .last_mut()
                 .context("Books vector is unexpectedly empty after push")?
@@ -219,12 +399,20 @@ impl Market {
 mod tests {
     use super::*;
     
-    #[test]
-    fn test_market_with_real_data() -> Result<()> {
+    use crate::{ingest::{decoder::DbnSource, memory::VecSource}, storage::StoredDataset};
+    use databento::dbn::{
+        rtype, Action, FlagSet, RecordHeader, Side,
+        decode::{DecodeRecord, dbn::Decoder},
+    };
+    use std::path::Path;
+    
+    #[tokio::test]
+    async fn test_market_with_real_data() -> Result<()> {
         let path = Path::new("assets/CLX5_mbo.dbn");
         
         // Load market from the real DBN file (without storage to keep test simple)
-        let market_snapshots = load_market_snapshots(path, None)?;
+        let mut source = DbnSource::from_file(path).await?;
+        let market_snapshots = load_market_snapshots(&mut source, None).await?;
         
         println!("Loaded {} market snapshots from DBN file.", 
             market_snapshots.len(), 
@@ -232,4 +420,202 @@ mod tests {
         
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_market_from_file_and_memory_sources_agree() -> Result<()> {
+        let path = Path::new("assets/CLX5_mbo.dbn");
+
+        let mut decoder = Decoder::from_file(path)?;
+        let mut messages = Vec::new();
+        while let Some(msg) = decoder.decode_record::<MboMsg>()? {
+            messages.push(msg.clone());
+            if messages.len() >= 2000 {
+                break;
+            }
+        }
+
+        let mut file_source = DbnSource::from_file(path).await?;
+        let mut file_snapshots = load_market_snapshots(&mut file_source, None).await?;
+        file_snapshots.truncate(messages.len());
+
+        let mut memory_source = VecSource::new(messages.clone());
+        let memory_snapshots = load_market_snapshots(&mut memory_source, None).await?;
+
+        assert_eq!(file_snapshots.len(), memory_snapshots.len());
+        for (file, memory) in file_snapshots.iter().zip(memory_snapshots.iter()) {
+            let instrument_id = file.mbomsg_effect.mbo_msg.hd.instrument_id;
+            assert_eq!(
+                serde_json::to_string(&file.market.aggregated_bbo(instrument_id))?,
+                serde_json::to_string(&memory.market.aggregated_bbo(instrument_id))?,
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_market_reloaded_into_storage_is_not_duplicated() -> Result<()> {
+        let temp_db = "test_market_reload.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = crate::storage::Storage::open(temp_db).await?;
+        let path = Path::new("assets/CLX5_mbo.dbn");
+
+        let mut source = DbnSource::from_file(path).await?;
+        let snapshots = load_market_snapshots(&mut source, Some((&storage, "file:test"))).await?;
+        assert_eq!(storage.count_messages().await?, snapshots.len());
+
+        let mut source = DbnSource::from_file(path).await?;
+        load_market_snapshots(&mut source, Some((&storage, "file:test"))).await?;
+        assert_eq!(storage.count_messages().await?, snapshots.len(), "Reloading should not duplicate messages");
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_market_restored_from_checkpoints() -> Result<()> {
+        let temp_db = "test_market_checkpoints.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = crate::storage::Storage::open(temp_db).await?;
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, Some((&storage, "file:test"))).await?;
+        assert!(snapshots.len() > CHECKPOINT_INTERVAL, "File should span several checkpoints");
+        storage.record_dataset(&StoredDataset {
+            fingerprint: "file:test".to_string(),
+            source: "file:test".to_string(),
+            message_count: snapshots.len(),
+            message_ids: Some((1, snapshots.len() as i64)),
+        }).await?;
+
+        // The final checkpoint covers every message
+        let latest = storage.latest_checkpoint(Some("file:test"), None).await?.context("No checkpoint written")?;
+        assert_eq!(latest.message_id, snapshots.len() as i64);
+        assert_eq!(storage.latest_checkpoint(Some(LIVE_SOURCE), None).await?, None);
+
+        // Row IDs follow the file order, so each restore matches the
+        //  snapshot after that message, whether or not it needs a replay
+        let middle = snapshots.len() as i64 / 2;
+        for message_id in [None, Some(middle), Some(1)] {
+            let (market, last_id) = restore_market(&storage, "file:test", message_id).await?;
+            let last_id = last_id.context("Nothing restored")?;
+            assert_eq!(last_id, message_id.unwrap_or(latest.message_id));
+            assert_eq!(
+                serde_json::to_value(&market)?,
+                serde_json::to_value(&snapshots[last_id as usize - 1].market)?,
+            );
+        }
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_live_market_restored_without_datasets() -> Result<()> {
+        let temp_db = "test_market_live_restore.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let mbo = |instrument_id: u32, order_id: u64, side: Side, price: i64| MboMsg {
+            hd: RecordHeader::new::<MboMsg>(rtype::MBO, 1, instrument_id, order_id),
+            order_id,
+            price,
+            size: 5,
+            flags: FlagSet::empty(),
+            channel_id: 0,
+            action: Action::Add as u8 as std::ffi::c_char,
+            side: side as u8 as std::ffi::c_char,
+            ts_recv: order_id,
+            ts_in_delta: 0,
+            sequence: order_id as u32,
+        };
+        let live = vec![mbo(42, 1, Side::Bid, 100), mbo(42, 2, Side::Ask, 105)];
+        let uncheckpointed = vec![mbo(42, 3, Side::Bid, 101)];
+        let dataset = vec![mbo(7, 4, Side::Bid, 50), mbo(7, 5, Side::Ask, 55)];
+
+        // Live messages are checkpointed, then more arrive before a restart
+        //  which ingests a dataset ahead of resuming the live feed
+        let storage = crate::storage::Storage::open(temp_db).await?;
+        load_market_snapshots(&mut VecSource::new(live.clone()), Some((&storage, LIVE_SOURCE))).await?;
+        storage.insert_mbo_batch(&uncheckpointed).await?;
+        let dataset_snapshots = load_market_snapshots(&mut VecSource::new(dataset.clone()), Some((&storage, "file:dataset"))).await?;
+        storage.record_dataset(&StoredDataset {
+            fingerprint: "file:dataset".to_string(),
+            source: "file:dataset".to_string(),
+            message_count: dataset.len(),
+            message_ids: Some((4, 5)),
+        }).await?;
+
+        // The live market resumes from its own checkpoint and skips the
+        //  dataset's rows, which were inserted after it
+        let (market, last_id) = restore_market(&storage, LIVE_SOURCE, None).await?;
+        let expected = load_market_snapshots(&mut VecSource::new([live, uncheckpointed].concat()), None).await?;
+        assert_eq!(last_id, Some(3));
+        assert_eq!(
+            serde_json::to_value(&market)?,
+            serde_json::to_value(&expected.last().context("No snapshots")?.market)?,
+        );
+
+        let (market, last_id) = restore_market(&storage, "file:dataset", None).await?;
+        assert_eq!(last_id, Some(5));
+        assert_eq!(
+            serde_json::to_value(&market)?,
+            serde_json::to_value(&dataset_snapshots.last().context("No snapshots")?.market)?,
+        );
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_market_with_synthetic_source() -> Result<()> {
+        let mbo = |order_id: u64, action: Action, side: Side, price: i64, size: u32| MboMsg {
+            hd: RecordHeader::new::<MboMsg>(rtype::MBO, 1, 42, order_id),
+            order_id,
+            price,
+            size,
+            flags: FlagSet::empty(),
+            channel_id: 0,
+            action: action as u8 as std::ffi::c_char,
+            side: side as u8 as std::ffi::c_char,
+            ts_recv: order_id,
+            ts_in_delta: 0,
+            sequence: order_id as u32,
+        };
+
+        let mut source = VecSource::new(vec![
+            mbo(1, Action::Add, Side::Bid, 100, 5),
+            mbo(2, Action::Add, Side::Bid, 100, 3),
+            mbo(3, Action::Add, Side::Ask, 105, 7),
+            mbo(1, Action::Cancel, Side::Bid, 100, 5),
+        ]).with_symbol(42, "TEST");
+        assert_eq!(source.symbol(&mbo(1, Action::Add, Side::Bid, 100, 5)), Some("TEST"));
+
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        assert_eq!(snapshots.len(), 4);
+
+        let market = &snapshots.last().context("No snapshots")?.market;
+        let (bid, ask) = market.aggregated_bbo(42);
+        let bid = bid.context("Expected a bid")?;
+        let ask = ask.context("Expected an ask")?;
+        assert_eq!((bid.price, bid.size, bid.count), (100, 3, 1));
+        assert_eq!((ask.price, ask.size, ask.count), (105, 7, 1));
+
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 pub mod book;
+pub mod book_state;
 pub mod market;
+pub mod order;
 pub mod price_level;
 
 use std::collections::VecDeque;
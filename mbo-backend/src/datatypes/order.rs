@@ -0,0 +1,278 @@
+use databento::dbn::{Action, MboMsg};
+use serde::Serialize;
+
+use super::market::MarketSnapshot;
+use crate::{ingest::symbology::Symbology, storage::StoredMbo};
+
+/// What a message did to an order
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OrderEventKind {
+    Add { price: i64, size: u32 },
+    /// The previous price and size are unknown if the order was added
+    ///  before the message log starts
+    Modify { old_price: Option<i64>, new_price: i64, old_size: Option<u32>, new_size: u32 },
+    PartialCancel { price: i64, canceled_size: u32, remaining_size: u32 },
+    /// Removal of the order from the book
+    Cancel { price: i64, canceled_size: u32 },
+    Fill { price: i64, size: u32 },
+    Trade { price: i64, size: u32 },
+    /// Book clears and other messages which don't change the order itself
+    Other { action: char },
+}
+
+/// A single message of an order's lifecycle
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct OrderEvent {
+    /// Storage row ID of the message
+    pub id: i64,
+    pub ts_recv: u64,
+    pub ts_event: u64,
+    pub instrument_id: u32,
+    /// Raw symbol of the instrument when the message was received, if known
+    pub symbol: Option<String>,
+    pub publisher_id: u16,
+    pub side: char,
+    #[serde(flatten)]
+    pub kind: OrderEventKind,
+}
+
+/// Size resting ahead of the order in its price level's queue
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct QueuePosition {
+    pub ts_recv: u64,
+    pub price: i64,
+    pub size_ahead: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// Still resting in the book after the last message
+    Open,
+    /// Fully canceled
+    Removed,
+    /// The order was added before the message log starts and hasn't
+    ///  been removed, so its size isn't known
+    Unknown,
+}
+
+/// Everything that happened to an order, from its messages
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
+pub struct OrderLifecycle {
+    pub order_id: u64,
+    pub status: OrderStatus,
+    pub events: Vec<OrderEvent>,
+    /// Changes of the order's queue position, empty if the order isn't
+    ///  part of the loaded market
+    pub queue_positions: Vec<QueuePosition>,
+}
+impl OrderLifecycle {
+    /// Classify each of an order's messages, which must be in the order
+    ///  they were received
+    pub fn from_messages(order_id: u64, messages: &[StoredMbo], symbology: &Symbology) -> Self {
+        // Price and remaining size, once known
+        let mut resting: Option<(i64, u32)> = None;
+        let mut status = OrderStatus::Unknown;
+
+        let events = messages.iter()
+            .map(|stored| {
+                let msg = &stored.mbo_msg;
+                let kind = match msg.action() {
+                    Ok(Action::Add) => {
+                        resting = Some((msg.price, msg.size));
+                        status = OrderStatus::Open;
+                        OrderEventKind::Add { price: msg.price, size: msg.size }
+                    },
+                    Ok(Action::Modify) => {
+                        let previous = resting.replace((msg.price, msg.size));
+                        status = OrderStatus::Open;
+                        OrderEventKind::Modify {
+                            old_price: previous.map(|(price, _)| price),
+                            new_price: msg.price,
+                            old_size: previous.map(|(_, size)| size),
+                            new_size: msg.size,
+                        }
+                    },
+                    Ok(Action::Cancel) => match resting {
+                        Some((price, size)) if size > msg.size => {
+                            resting = Some((price, size - msg.size));
+                            OrderEventKind::PartialCancel {
+                                price: msg.price,
+                                canceled_size: msg.size,
+                                remaining_size: size - msg.size,
+                            }
+                        },
+                        // Without a known size, a cancel is taken as the removal
+                        _ => {
+                            resting = None;
+                            status = OrderStatus::Removed;
+                            OrderEventKind::Cancel { price: msg.price, canceled_size: msg.size }
+                        },
+                    },
+                    Ok(Action::Fill) => OrderEventKind::Fill { price: msg.price, size: msg.size },
+                    Ok(Action::Trade) => OrderEventKind::Trade { price: msg.price, size: msg.size },
+                    _ => OrderEventKind::Other { action: msg.action as u8 as char },
+                };
+
+                OrderEvent {
+                    id: stored.id,
+                    ts_recv: msg.ts_recv,
+                    ts_event: msg.hd.ts_event,
+                    instrument_id: msg.hd.instrument_id,
+                    symbol: symbology.symbol(msg.hd.instrument_id, msg.ts_recv)
+                        .map(str::to_string),
+                    publisher_id: msg.hd.publisher_id,
+                    side: msg.side as u8 as char,
+                    kind,
+                }
+            })
+            .collect();
+
+        Self {
+            order_id,
+            status,
+            events,
+            queue_positions: Vec::new(),
+        }
+    }
+
+    /// Track the order's queue position through the market snapshots,
+    ///  recording each change while it rests in the book
+    pub fn with_queue_positions(mut self, snapshots: &[MarketSnapshot]) -> Self {
+        let (Some(first), Some(last)) = (self.events.first(), self.events.last()) else {
+            return self;
+        };
+        let (instrument_id, publisher_id) = (first.instrument_id, first.publisher_id);
+        let start_ts = first.ts_recv;
+        // Open orders are tracked to the end of the loaded market
+        let end_ts = match self.status {
+            OrderStatus::Removed => last.ts_recv,
+            _ => u64::MAX,
+        };
+
+        let in_range = |msg: &MboMsg| msg.hd.instrument_id == instrument_id
+            && (start_ts..=end_ts).contains(&msg.ts_recv);
+        for snapshot in snapshots.iter().filter(|snapshot| in_range(&snapshot.mbomsg_effect.mbo_msg)) {
+            let Some(book) = snapshot.market.books_by_pub(instrument_id)
+                .and_then(|books| books.iter().find(|(publisher, _)| *publisher as u16 == publisher_id))
+                .map(|(_, book)| book) else {
+                continue;
+            };
+            let (Some(order), Some(size_ahead)) = (book.order(self.order_id), book.queue_pos(self.order_id)) else {
+                continue;
+            };
+
+            let position = QueuePosition {
+                ts_recv: snapshot.mbomsg_effect.mbo_msg.ts_recv,
+                price: order.price,
+                size_ahead,
+            };
+            let changed = self.queue_positions.last()
+                .is_none_or(|last| (last.price, last.size_ahead) != (position.price, position.size_ahead));
+            if changed {
+                self.queue_positions.push(position);
+            }
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, path::Path};
+    use anyhow::{Context, Result};
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    #[tokio::test]
+    async fn test_lifecycle_of_real_orders() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+
+        let mut by_order = HashMap::<u64, Vec<StoredMbo>>::new();
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let msg = &snapshot.mbomsg_effect.mbo_msg;
+            by_order.entry(msg.order_id).or_default().push(StoredMbo {
+                id: i as i64 + 1,
+                mbo_msg: msg.clone(),
+            });
+        }
+
+        // An order added within the file, with a few more messages
+        let (order_id, messages) = by_order.iter()
+            .filter(|(order_id, _)| **order_id != 0)
+            .filter(|(_, messages)| {
+                messages[0].mbo_msg.action == Action::Add as u8 as std::ffi::c_char
+                    && messages.len() >= 3
+            })
+            .min_by_key(|(order_id, _)| **order_id)
+            .context("No order with a full lifecycle")?;
+        let lifecycle = OrderLifecycle::from_messages(*order_id, messages, &Symbology::default())
+            .with_queue_positions(&snapshots);
+
+        assert_eq!(lifecycle.events.len(), messages.len());
+        assert!(matches!(lifecycle.events[0].kind, OrderEventKind::Add { .. }));
+        if lifecycle.status == OrderStatus::Removed {
+            assert!(matches!(lifecycle.events.last().unwrap().kind, OrderEventKind::Cancel { .. }));
+        }
+
+        // Each recorded position is a change, and never after removal
+        assert!(!lifecycle.queue_positions.is_empty(), "A resting order should have a queue position");
+        assert!(lifecycle.queue_positions.windows(2).all(|pair| {
+            (pair[0].price, pair[0].size_ahead) != (pair[1].price, pair[1].size_ahead)
+        }));
+        assert_eq!(lifecycle.queue_positions[0].ts_recv, lifecycle.events[0].ts_recv);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancels_reduce_then_remove() {
+        use databento::dbn::{rtype, FlagSet, RecordHeader, Side};
+        use std::ffi::c_char;
+
+        let msg = |id: i64, action: Action, price: i64, size: u32| StoredMbo {
+            id,
+            mbo_msg: MboMsg {
+                hd: RecordHeader::new::<MboMsg>(rtype::MBO, 1, 1, id as u64),
+                order_id: 7,
+                price,
+                size,
+                flags: FlagSet::empty(),
+                channel_id: 0,
+                action: action as u8 as c_char,
+                side: Side::Bid as u8 as c_char,
+                ts_recv: id as u64,
+                ts_in_delta: 0,
+                sequence: id as u32,
+            },
+        };
+
+        let lifecycle = OrderLifecycle::from_messages(7, &[
+            msg(1, Action::Add, 100, 10),
+            msg(2, Action::Modify, 101, 8),
+            msg(3, Action::Fill, 101, 3),
+            msg(4, Action::Cancel, 101, 3),
+            msg(5, Action::Cancel, 101, 5),
+        ], &Symbology::default());
+
+        let kinds = lifecycle.events.iter().map(|event| event.kind.clone()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            OrderEventKind::Add { price: 100, size: 10 },
+            OrderEventKind::Modify { old_price: Some(100), new_price: 101, old_size: Some(10), new_size: 8 },
+            OrderEventKind::Fill { price: 101, size: 3 },
+            OrderEventKind::PartialCancel { price: 101, canceled_size: 3, remaining_size: 5 },
+            OrderEventKind::Cancel { price: 101, canceled_size: 5 },
+        ]);
+        assert_eq!(lifecycle.status, OrderStatus::Removed);
+
+        // Orders from before the log starts are unknown until a modify
+        //  gives their size
+        let lifecycle = OrderLifecycle::from_messages(7, &[msg(1, Action::Modify, 100, 10)], &Symbology::default());
+        assert_eq!(lifecycle.status, OrderStatus::Open);
+        let lifecycle = OrderLifecycle::from_messages(7, &[msg(1, Action::Fill, 100, 10)], &Symbology::default());
+        assert_eq!(lifecycle.status, OrderStatus::Unknown);
+    }
+}
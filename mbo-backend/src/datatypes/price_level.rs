@@ -5,7 +5,7 @@ use databento::{
 };
 use serde::Serialize;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, utoipa::ToSchema)]
 pub struct PriceLevel {
     pub price: i64,
     pub size: u32,
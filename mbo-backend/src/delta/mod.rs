@@ -0,0 +1,270 @@
+pub mod reader;
+
+use std::{collections::HashMap, io::Write};
+
+use databento::dbn::MboMsg;
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::datatypes::{
+    book::BookEffect,
+    market::{Market, MarketEffect, MarketSnapshot},
+};
+
+/// Identifies a delta export, followed by the format version
+const MAGIC: &[u8; 8] = b"MBODELTA";
+const VERSION: u8 = 1;
+
+/// Messages between full market keyframes, bounding how many deltas
+///  have to be replayed to decode any snapshot
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 10_000;
+
+const FRAME_KEYFRAME: u8 = 0;
+const FRAME_SYMBOL: u8 = 1;
+const FRAME_DELTA: u8 = 2;
+
+const EFFECT_NONE: u8 = 0;
+const EFFECT_ADD: u8 = 1;
+const EFFECT_CANCEL: u8 = 2;
+const EFFECT_MODIFY: u8 = 3;
+const EFFECT_ERROR: u8 = 4;
+
+/// Compression of everything after the header of a delta export
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Deflate,
+    #[default]
+    Zstd,
+}
+impl Compression {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Deflate => 1,
+            Self::Zstd => 2,
+        }
+    }
+}
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value {
+            "none" => Self::None,
+            "deflate" => Self::Deflate,
+            "zstd" => Self::Zstd,
+            other => bail!("Unknown compression `{}`, expected `none`, `deflate` or `zstd`", other),
+        })
+    }
+}
+
+/// Writes market snapshots as an initial full market followed by one
+///  delta per message
+///
+/// The format is a header - magic, version, compression and keyframe
+///  interval - then a stream of frames, each a tag, a little-endian
+///  `u32` length and a payload:
+///
+/// - Keyframe: the number of messages applied so far, as a `u64`, and the
+///   full market as JSON. One is written before the first delta and
+///   after every `keyframe_interval` deltas.
+/// - Symbol: an instrument ID and its raw symbol, written whenever the
+///   symbol of a delta's instrument changes.
+/// - Delta: the MBO message and the `MarketEffect` it had, i.e. whether
+///   it created a publisher's book and the `BookEffect` on that book.
+///
+/// Replaying the messages onto the last keyframe rebuilds the whole
+///  market, order queues included, while consumers that only need price
+///  levels can apply the book effects alone.
+pub struct DeltaWriter<W: Write> {
+    encoder: Encoder<W>,
+    keyframe_interval: u32,
+    /// Only these instruments' books are kept in keyframes, if set
+    instrument_ids: Option<Vec<u32>>,
+    symbols: HashMap<u32, Option<String>>,
+    count: u64,
+}
+impl<W: Write> DeltaWriter<W> {
+    /// Start an export from `initial`, the market before the first message
+    ///
+    /// With `instrument_ids`, keyframes only hold those instruments' books
+    ///  and only their messages may be pushed.
+    pub fn new(
+        mut writer: W,
+        compression: Compression,
+        keyframe_interval: u32,
+        initial: &Market,
+        instrument_ids: Option<Vec<u32>>,
+    ) -> Result<Self> {
+        ensure!(keyframe_interval > 0, "Keyframe interval must be positive");
+
+        writer.write_all(MAGIC)
+            .and_then(|_| writer.write_all(&[VERSION, compression.to_u8()]))
+            .and_then(|_| writer.write_all(&keyframe_interval.to_le_bytes()))
+            .context("...while writing delta export header")?;
+
+        let mut delta_writer = Self {
+            encoder: Encoder::new(writer, compression)?,
+            keyframe_interval,
+            instrument_ids,
+            symbols: HashMap::new(),
+            count: 0,
+        };
+        delta_writer.write_keyframe(initial)?;
+
+        Ok(delta_writer)
+    }
+
+    /// Append the delta for the next snapshot
+    pub fn push(&mut self, snapshot: &MarketSnapshot) -> Result<()> {
+        let effect = &snapshot.mbomsg_effect;
+        let instrument_id = effect.mbo_msg.hd.instrument_id;
+        if self.symbols.get(&instrument_id) != Some(&effect.symbol) {
+            let mut payload = instrument_id.to_le_bytes().to_vec();
+            payload.extend_from_slice(effect.symbol.as_deref().unwrap_or_default().as_bytes());
+            self.write_frame(FRAME_SYMBOL, &payload)?;
+            self.symbols.insert(instrument_id, effect.symbol.clone());
+        }
+
+        let mut payload = Vec::with_capacity(96);
+        encode_mbo(&mut payload, &effect.mbo_msg);
+        encode_market_effect(&mut payload, &effect.market_effect);
+        self.write_frame(FRAME_DELTA, &payload)?;
+
+        self.count += 1;
+        if self.count.is_multiple_of(self.keyframe_interval as u64) {
+            self.write_keyframe(&snapshot.market)?;
+        }
+
+        Ok(())
+    }
+
+    /// The underlying writer, from which output written so far can be
+    ///  drained while the export is still being produced
+    pub fn get_mut(&mut self) -> &mut W {
+        self.encoder.get_mut()
+    }
+
+    /// Flush the compressed stream, returning the underlying writer
+    pub fn finish(self) -> Result<W> {
+        self.encoder.finish()
+            .context("...while finishing delta export")
+    }
+
+    fn write_keyframe(&mut self, market: &Market) -> Result<()> {
+        let mut payload = self.count.to_le_bytes().to_vec();
+        match &self.instrument_ids {
+            Some(instrument_ids) => serde_json::to_writer(&mut payload, &market.with_instruments(instrument_ids)),
+            None => serde_json::to_writer(&mut payload, market),
+        }.context("...while serializing keyframe market")?;
+
+        self.write_frame(FRAME_KEYFRAME, &payload)
+    }
+
+    fn write_frame(&mut self, tag: u8, payload: &[u8]) -> Result<()> {
+        let len = u32::try_from(payload.len())
+            .context("Delta export frame is over 4 GiB")?;
+
+        self.encoder.write_all(&[tag])
+            .and_then(|_| self.encoder.write_all(&len.to_le_bytes()))
+            .and_then(|_| self.encoder.write_all(payload))
+            .context("...while writing delta export frame")
+    }
+}
+
+enum Encoder<W: Write> {
+    None(W),
+    Deflate(flate2::write::DeflateEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+impl<W: Write> Encoder<W> {
+    fn new(writer: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(writer),
+            Compression::Deflate => Self::Deflate(flate2::write::DeflateEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Self::Zstd(
+                zstd::stream::write::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .context("...while starting zstd stream")?
+            ),
+        })
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::None(writer) => writer,
+            Self::Deflate(encoder) => encoder.get_mut(),
+            Self::Zstd(encoder) => encoder.get_mut(),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            Self::None(mut writer) => writer.flush().map(|_| writer),
+            Self::Deflate(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Deflate(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Deflate(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn encode_mbo(payload: &mut Vec<u8>, msg: &MboMsg) {
+    payload.extend_from_slice(&msg.hd.ts_event.to_le_bytes());
+    payload.extend_from_slice(&msg.hd.publisher_id.to_le_bytes());
+    payload.extend_from_slice(&msg.hd.instrument_id.to_le_bytes());
+    payload.extend_from_slice(&msg.order_id.to_le_bytes());
+    payload.extend_from_slice(&msg.price.to_le_bytes());
+    payload.extend_from_slice(&msg.size.to_le_bytes());
+    payload.extend_from_slice(&[msg.flags.raw(), msg.channel_id, msg.action as u8, msg.side as u8]);
+    payload.extend_from_slice(&msg.ts_recv.to_le_bytes());
+    payload.extend_from_slice(&msg.ts_in_delta.to_le_bytes());
+    payload.extend_from_slice(&msg.sequence.to_le_bytes());
+}
+
+fn encode_market_effect(payload: &mut Vec<u8>, effect: &MarketEffect) {
+    // Publisher IDs start at 1, so 0 stands for no publisher created
+    let publisher_created = effect.publisher_created.map_or(0, |publisher| publisher as u16);
+    payload.extend_from_slice(&publisher_created.to_le_bytes());
+
+    match &effect.book_effect {
+        Ok(None) => payload.push(EFFECT_NONE),
+        Ok(Some(BookEffect::Add { side, price, size })) => {
+            payload.extend_from_slice(&[EFFECT_ADD, *side as u8]);
+            payload.extend_from_slice(&price.to_le_bytes());
+            payload.extend_from_slice(&size.to_le_bytes());
+        },
+        Ok(Some(BookEffect::Cancel { side, price, size })) => {
+            payload.extend_from_slice(&[EFFECT_CANCEL, *side as u8]);
+            payload.extend_from_slice(&price.to_le_bytes());
+            payload.extend_from_slice(&size.to_le_bytes());
+        },
+        Ok(Some(BookEffect::Modify { side, old_price, new_price, old_size, new_size })) => {
+            payload.extend_from_slice(&[EFFECT_MODIFY, *side as u8]);
+            payload.extend_from_slice(&old_price.to_le_bytes());
+            payload.extend_from_slice(&new_price.to_le_bytes());
+            payload.extend_from_slice(&old_size.to_le_bytes());
+            payload.extend_from_slice(&new_size.to_le_bytes());
+        },
+        Err(error) => {
+            payload.push(EFFECT_ERROR);
+            payload.extend_from_slice(error.as_bytes());
+        },
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    ffi::c_char,
+    io::{BufReader, Read},
+};
+
+use databento::dbn::{FlagSet, MboMsg, Publisher, RecordHeader, Side, rtype};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+
+use super::{
+    Compression, EFFECT_ADD, EFFECT_CANCEL, EFFECT_ERROR, EFFECT_MODIFY, EFFECT_NONE,
+    FRAME_DELTA, FRAME_KEYFRAME, FRAME_SYMBOL, MAGIC, VERSION,
+};
+use crate::datatypes::{
+    book::BookEffect,
+    market::{MBOMsgEffect, Market, MarketEffect, MarketSnapshot},
+};
+
+/// Rebuilds market snapshots from a delta export
+///
+/// Reading only moves forward: `snapshot` skips ahead to any later
+///  snapshot, replaying only the deltas since the keyframe before it.
+pub struct DeltaReader<R: Read> {
+    decoder: Decoder<R>,
+    pub compression: Compression,
+    pub keyframe_interval: u32,
+    market: Market,
+    /// Messages applied to `market`
+    applied: u64,
+    /// Messages read since, not yet applied
+    pending: Vec<MboMsg>,
+    symbols: HashMap<u32, String>,
+}
+impl<R: Read> DeltaReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; 14];
+        reader.read_exact(&mut header)
+            .context("...while reading delta export header")?;
+        ensure!(&header[..8] == MAGIC, "Not a delta export");
+        ensure!(header[8] == VERSION, "Unsupported delta export version {}", header[8]);
+        let compression = compression_from_u8(header[9])?;
+        let keyframe_interval = u32::from_le_bytes(header[10..14].try_into()?);
+
+        Ok(Self {
+            decoder: Decoder::new(reader, compression)?,
+            compression,
+            keyframe_interval,
+            market: Market::new(),
+            applied: 0,
+            pending: Vec::new(),
+            symbols: HashMap::new(),
+        })
+    }
+
+    /// Index of the snapshot `next_snapshot` would return
+    pub fn position(&self) -> u64 {
+        self.applied + self.pending.len() as u64
+    }
+
+    pub fn next_snapshot(&mut self) -> Result<Option<MarketSnapshot>> {
+        self.snapshot(self.position())
+    }
+
+    /// Snapshot after message `index`, or `None` if the export has fewer
+    ///  messages
+    pub fn snapshot(&mut self, index: u64) -> Result<Option<MarketSnapshot>> {
+        ensure!(
+            index >= self.position(),
+            "Delta export reader is past snapshot {}, at {}",
+            index,
+            self.position()
+        );
+
+        while let Some((tag, payload)) = self.read_frame()? {
+            match tag {
+                FRAME_KEYFRAME => {
+                    let applied = u64::from_le_bytes(take::<8>(&mut payload.as_slice())?);
+                    ensure!(
+                        applied == self.position(),
+                        "Keyframe after {} messages found after {}",
+                        applied,
+                        self.position()
+                    );
+                    self.market = serde_json::from_slice(&payload[8..])
+                        .context(format!("...while deserializing keyframe after {} messages", applied))?;
+                    self.applied = applied;
+                    self.pending.clear();
+                },
+                FRAME_SYMBOL => {
+                    let mut payload = payload.as_slice();
+                    let instrument_id = u32::from_le_bytes(take::<4>(&mut payload)?);
+                    let symbol = std::str::from_utf8(payload)
+                        .context("...while decoding symbol")?;
+                    self.symbols.insert(instrument_id, symbol.to_string());
+                },
+                FRAME_DELTA => {
+                    let mut payload = payload.as_slice();
+                    let mbo_msg = decode_mbo(&mut payload)?;
+                    if self.position() < index {
+                        self.pending.push(mbo_msg);
+                        continue;
+                    }
+
+                    for pending in self.pending.drain(..) {
+                        self.market.apply(pending)
+                            .context("...while replaying delta")?;
+                    }
+                    self.market.apply(mbo_msg.clone())
+                        .context("...while applying delta")?;
+                    self.applied = index + 1;
+
+                    return Ok(Some(MarketSnapshot {
+                        market: self.market.clone(),
+                        mbomsg_effect: MBOMsgEffect {
+                            symbol: self.symbols.get(&mbo_msg.hd.instrument_id)
+                                .filter(|symbol| !symbol.is_empty())
+                                .cloned(),
+                            market_effect: decode_market_effect(&mut payload)?,
+                            mbo_msg,
+                        },
+                    }));
+                },
+                other => bail!("Unknown delta export frame {}", other),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn read_frame(&mut self) -> Result<Option<(u8, Vec<u8>)>> {
+        let mut tag = [0u8; 1];
+        if self.decoder.read(&mut tag).context("...while reading delta export frame")? == 0 {
+            return Ok(None);
+        }
+
+        let mut len = [0u8; 4];
+        self.decoder.read_exact(&mut len)
+            .context("...while reading delta export frame length")?;
+        let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.decoder.read_exact(&mut payload)
+            .context("...while reading delta export frame payload")?;
+
+        Ok(Some((tag[0], payload)))
+    }
+}
+
+enum Decoder<R: Read> {
+    None(R),
+    Deflate(flate2::read::DeflateDecoder<R>),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<R>>),
+}
+impl<R: Read> Decoder<R> {
+    fn new(reader: R, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(reader),
+            Compression::Deflate => Self::Deflate(flate2::read::DeflateDecoder::new(reader)),
+            Compression::Zstd => Self::Zstd(
+                zstd::stream::read::Decoder::new(reader)
+                    .context("...while starting zstd stream")?
+            ),
+        })
+    }
+}
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(reader) => reader.read(buf),
+            Self::Deflate(decoder) => decoder.read(buf),
+            Self::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+fn compression_from_u8(value: u8) -> Result<Compression> {
+    Ok(match value {
+        0 => Compression::None,
+        1 => Compression::Deflate,
+        2 => Compression::Zstd,
+        other => bail!("Unknown delta export compression {}", other),
+    })
+}
+
+fn decode_mbo(payload: &mut &[u8]) -> Result<MboMsg> {
+    let ts_event = u64::from_le_bytes(take(payload)?);
+    let publisher_id = u16::from_le_bytes(take(payload)?);
+    let instrument_id = u32::from_le_bytes(take(payload)?);
+    let order_id = u64::from_le_bytes(take(payload)?);
+    let price = i64::from_le_bytes(take(payload)?);
+    let size = u32::from_le_bytes(take(payload)?);
+    let [flags, channel_id, action, side] = take::<4>(payload)?;
+
+    Ok(MboMsg {
+        hd: RecordHeader::new::<MboMsg>(rtype::MBO, publisher_id, instrument_id, ts_event),
+        order_id,
+        price,
+        size,
+        flags: FlagSet::new(flags),
+        channel_id,
+        action: action as c_char,
+        side: side as c_char,
+        ts_recv: u64::from_le_bytes(take(payload)?),
+        ts_in_delta: i32::from_le_bytes(take(payload)?),
+        sequence: u32::from_le_bytes(take(payload)?),
+    })
+}
+
+fn decode_market_effect(payload: &mut &[u8]) -> Result<MarketEffect> {
+    let publisher_created = match u16::from_le_bytes(take(payload)?) {
+        0 => None,
+        publisher_id => Some(Publisher::try_from(publisher_id)
+            .map_err(|_| anyhow!("Unknown publisher {}", publisher_id))?),
+    };
+
+    let [kind] = take::<1>(payload)?;
+    let side = |payload: &mut &[u8]| -> Result<Side> {
+        let [side] = take::<1>(payload)?;
+        Side::try_from(side).map_err(|_| anyhow!("Unknown side {}", side))
+    };
+    let book_effect = match kind {
+        EFFECT_NONE => Ok(None),
+        EFFECT_ADD => Ok(Some(BookEffect::Add {
+            side: side(payload)?,
+            price: i64::from_le_bytes(take(payload)?),
+            size: u32::from_le_bytes(take(payload)?),
+        })),
+        EFFECT_CANCEL => Ok(Some(BookEffect::Cancel {
+            side: side(payload)?,
+            price: i64::from_le_bytes(take(payload)?),
+            size: u32::from_le_bytes(take(payload)?),
+        })),
+        EFFECT_MODIFY => Ok(Some(BookEffect::Modify {
+            side: side(payload)?,
+            old_price: i64::from_le_bytes(take(payload)?),
+            new_price: i64::from_le_bytes(take(payload)?),
+            old_size: u32::from_le_bytes(take(payload)?),
+            new_size: u32::from_le_bytes(take(payload)?),
+        })),
+        EFFECT_ERROR => Err(std::str::from_utf8(payload)
+            .context("...while decoding book effect error")?
+            .to_string()),
+        other => bail!("Unknown book effect {}", other),
+    };
+
+    Ok(MarketEffect {
+        publisher_created,
+        book_effect,
+    })
+}
+
+/// Split the next `N` bytes off a payload
+fn take<const N: usize>(payload: &mut &[u8]) -> Result<[u8; N]> {
+    ensure!(payload.len() >= N, "Delta export frame is truncated");
+    let (bytes, rest) = payload.split_at(N);
+    *payload = rest;
+
+    Ok(bytes.try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::{DeltaWriter, MAGIC};
+    use std::path::Path;
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    /// Every snapshot of a delta export, in order
+    fn read_snapshots<R: Read>(reader: R) -> Result<Vec<MarketSnapshot>> {
+        let mut reader = DeltaReader::new(reader)?;
+        let mut snapshots = Vec::new();
+        while let Some(snapshot) = reader.next_snapshot()? {
+            snapshots.push(snapshot);
+        }
+
+        Ok(snapshots)
+    }
+
+    #[tokio::test]
+    async fn test_delta_export_round_trip() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let expected = |index: usize| serde_json::to_value(&snapshots[index]);
+
+        for compression in [Compression::None, Compression::Deflate, Compression::Zstd] {
+            let mut writer = DeltaWriter::new(Vec::new(), compression, 5_000, &Market::new(), None)?;
+            for snapshot in &snapshots {
+                writer.push(snapshot)?;
+            }
+            let bytes = writer.finish()?;
+
+            // Every snapshot is rebuilt in order
+            let decoded = read_snapshots(bytes.as_slice())?;
+            assert_eq!(decoded.len(), snapshots.len());
+            for index in [0, 1, 4_999, 5_000, 5_001, snapshots.len() - 1] {
+                assert_eq!(serde_json::to_value(&decoded[index])?, expected(index)?, "Snapshot {} differs", index);
+            }
+
+            // Or skipped to, around keyframes
+            let mut reader = DeltaReader::new(bytes.as_slice())?;
+            assert_eq!((reader.compression, reader.keyframe_interval), (compression, 5_000));
+            for index in [3, 4_999, 12_345, snapshots.len() - 1] {
+                let snapshot = reader.snapshot(index as u64)?.context("Snapshot missing")?;
+                assert_eq!(serde_json::to_value(&snapshot)?, expected(index)?, "Snapshot {} differs", index);
+            }
+            assert!(reader.snapshot(0).is_err(), "Readers only move forward");
+            assert!(reader.next_snapshot()?.is_none());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_export_rejects_other_files() {
+        assert!(DeltaReader::new(&b"PAR1 not a delta export"[..]).is_err());
+        assert!(DeltaReader::new(&MAGIC[..]).is_err());
+    }
+}
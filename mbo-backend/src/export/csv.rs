@@ -0,0 +1,214 @@
+use std::fmt::Write;
+
+use databento::dbn::{UNDEF_PRICE, pretty::{Px, Ts}};
+use serde::{Deserialize, Serialize};
+
+use crate::datatypes::{market::MarketSnapshot, price_level::PriceLevel};
+
+/// Bytes of rows gathered into each chunk of the stream
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Dataset exported as CSV
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvKind {
+    /// The raw MBO messages
+    Mbo,
+    /// The aggregated BBO of the instrument after each message
+    Bbo,
+    /// The top levels of the book after each message
+    Depth,
+}
+impl CsvKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CsvKind::Mbo => "mbo",
+            CsvKind::Bbo => "bbo",
+            CsvKind::Depth => "depth",
+        }
+    }
+
+    fn header(&self) -> &'static str {
+        match self {
+            CsvKind::Mbo => "ts_recv,ts_event,instrument_id,symbol,publisher_id,order_id,action,side,price,size,flags,sequence,ts_in_delta,channel_id\n",
+            CsvKind::Bbo => "ts_recv,instrument_id,symbol,bid_price,bid_size,bid_count,ask_price,ask_size,ask_count\n",
+            CsvKind::Depth => "ts_recv,instrument_id,symbol,publisher_id,level,bid_price,bid_size,bid_count,ask_price,ask_size,ask_count\n",
+        }
+    }
+}
+
+/// Slice of the loaded market to export as CSV
+#[derive(Debug, Clone, Serialize)]
+pub struct CsvParams {
+    pub kind: CsvKind,
+    pub instrument_id: Option<u32>,
+    /// Inclusive bounds on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    pub end_ts: Option<u64>,
+    /// Levels per depth snapshot
+    pub levels: u16,
+}
+impl CsvParams {
+    fn includes(&self, snapshot: &MarketSnapshot) -> bool {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        self.instrument_id.is_none_or(|id| msg.hd.instrument_id == id)
+            && self.start_ts.is_none_or(|ts| msg.ts_recv >= ts)
+            && self.end_ts.is_none_or(|ts| msg.ts_recv <= ts)
+    }
+}
+
+/// The CSV of the selected snapshots, header first, in chunks of roughly
+///  `CHUNK_SIZE` bytes built as the iterator is advanced
+///
+/// Timestamps are ISO 8601 in UTC and prices are decimals, with undefined
+///  prices and missing levels left empty.
+pub fn chunks<'a>(params: &'a CsvParams, snapshots: &'a [MarketSnapshot]) -> impl Iterator<Item = String> + Send + 'a {
+    let mut rows = snapshots.iter().filter(|snapshot| params.includes(snapshot)).peekable();
+    let mut header = Some(params.kind.header());
+
+    std::iter::from_fn(move || {
+        if header.is_none() && rows.peek().is_none() {
+            return None;
+        }
+
+        let mut chunk = String::with_capacity(CHUNK_SIZE + 1024);
+        chunk.push_str(header.take().unwrap_or_default());
+        while chunk.len() < CHUNK_SIZE {
+            let Some(snapshot) = rows.next() else {
+                break;
+            };
+            write_rows(&mut chunk, params, snapshot);
+        }
+
+        Some(chunk)
+    })
+}
+
+fn write_rows(out: &mut String, params: &CsvParams, snapshot: &MarketSnapshot) {
+    let msg = &snapshot.mbomsg_effect.mbo_msg;
+    let symbol = escape(snapshot.mbomsg_effect.symbol.as_deref().unwrap_or_default());
+
+    // Writing to a string is infallible
+    match params.kind {
+        CsvKind::Mbo => {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                Ts(msg.ts_recv),
+                Ts(msg.hd.ts_event),
+                msg.hd.instrument_id,
+                symbol,
+                msg.hd.publisher_id,
+                msg.order_id,
+                msg.action as u8 as char,
+                msg.side as u8 as char,
+                price(msg.price),
+                msg.size,
+                msg.flags.raw(),
+                msg.sequence,
+                msg.ts_in_delta,
+                msg.channel_id,
+            );
+        }
+        CsvKind::Bbo => {
+            let (bid, ask) = snapshot.market.aggregated_bbo(msg.hd.instrument_id);
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{}",
+                Ts(msg.ts_recv),
+                msg.hd.instrument_id,
+                symbol,
+                level(bid.as_ref()),
+                level(ask.as_ref()),
+            );
+        }
+        CsvKind::Depth => {
+            let book = snapshot.market.books_by_pub(msg.hd.instrument_id)
+                .and_then(|books| books.iter().find(|(publisher, _)| *publisher as u16 == msg.hd.publisher_id))
+                .map(|(_, book)| book);
+            for i in 0..params.levels as usize {
+                let _ = writeln!(
+                    out,
+                    "{},{},{},{},{},{},{}",
+                    Ts(msg.ts_recv),
+                    msg.hd.instrument_id,
+                    symbol,
+                    msg.hd.publisher_id,
+                    i,
+                    level(book.and_then(|book| book.bid_level(i)).as_ref()),
+                    level(book.and_then(|book| book.ask_level(i)).as_ref()),
+                );
+            }
+        }
+    }
+}
+
+fn price(price: i64) -> String {
+    if price == UNDEF_PRICE {
+        String::new()
+    } else {
+        Px(price).to_string()
+    }
+}
+
+/// Price, size and count of a level, or three empty fields
+fn level(level: Option<&PriceLevel>) -> String {
+    level.map_or(",,".to_string(), |level| format!("{},{},{}", price(level.price), level.size, level.count))
+}
+
+/// Quote a field if it holds a separator, quote or line break
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use anyhow::Result;
+    use crate::{datatypes::market::load_market_snapshots, ingest::decoder::DbnSource};
+
+    #[tokio::test]
+    async fn test_csv_rows() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+        let start_ts = snapshots[1_000].mbomsg_effect.mbo_msg.ts_recv;
+        let end_ts = snapshots[2_000].mbomsg_effect.mbo_msg.ts_recv;
+        let selected = snapshots.iter()
+            .filter(|snapshot| (start_ts..=end_ts).contains(&snapshot.mbomsg_effect.mbo_msg.ts_recv))
+            .collect::<Vec<_>>();
+
+        for (kind, rows_per_message) in [(CsvKind::Mbo, 1), (CsvKind::Bbo, 1), (CsvKind::Depth, 3)] {
+            let params = CsvParams { kind, instrument_id: None, start_ts: Some(start_ts), end_ts: Some(end_ts), levels: 3 };
+            let csv = chunks(&params, &snapshots).collect::<String>();
+            let mut lines = csv.lines();
+
+            let columns = lines.next().map(|header| header.split(',').count());
+            assert!(lines.clone().all(|line| Some(line.split(',').count()) == columns), "Ragged {} rows", kind.name());
+            assert_eq!(lines.count(), selected.len() * rows_per_message);
+        }
+
+        // Prices and timestamps are human-readable
+        let params = CsvParams { kind: CsvKind::Mbo, instrument_id: None, start_ts: Some(start_ts), end_ts: Some(end_ts), levels: 1 };
+        let csv = chunks(&params, &snapshots).collect::<String>();
+        let first = csv.lines().nth(1).unwrap().split(',').map(str::to_string).collect::<Vec<_>>();
+        let msg = &selected[0].mbomsg_effect.mbo_msg;
+        assert_eq!(first[0], Ts(msg.ts_recv).to_string());
+        assert!(first[0].ends_with('Z'));
+        assert_eq!(first[8], price(msg.price));
+        assert_eq!(first[5], msg.order_id.to_string());
+
+        // An empty selection is just the header
+        let params = CsvParams { kind: CsvKind::Bbo, instrument_id: Some(u32::MAX), start_ts: None, end_ts: None, levels: 1 };
+        assert_eq!(chunks(&params, &snapshots).collect::<String>(), CsvKind::Bbo.header());
+
+        assert_eq!(escape("CL,X5"), "\"CL,X5\"");
+        assert_eq!(level(None), ",,");
+
+        Ok(())
+    }
+}
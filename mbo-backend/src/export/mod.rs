@@ -0,0 +1,715 @@
+pub mod csv;
+pub mod parquet;
+
+use std::{
+    io::{BufWriter, Write},
+    ops::{Deref, RangeInclusive},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_zip::{Compression as ZipCompression, ZipEntryBuilder, tokio::write::ZipFileWriter};
+use axum::body::{Body, Bytes};
+use databento::dbn::BidAskPair;
+use anyhow::{Context, Result, ensure};
+use futures::{AsyncWriteExt, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite}, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, error};
+
+use crate::{
+    artifacts::ArtifactManifest,
+    datatypes::market::{Market, MarketSnapshot},
+    delta::{self, Compression, DeltaWriter},
+    export::{csv::CsvParams, parquet::ParquetParams},
+};
+
+/// Bytes buffered before they are written to the archive, and the size
+///  of the pipe between the archive and the response body
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Makes temporary cache files of concurrent exports unique
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Selection of the loaded market to export, normalized so requests for
+///  the same data share a cache entry
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportParams {
+    /// Inclusive bounds on the index of the message in the loaded market
+    pub start_index: Option<usize>,
+    pub end_index: Option<usize>,
+    /// Inclusive bounds on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    pub end_ts: Option<u64>,
+    /// Sorted and deduplicated, all instruments when `None`
+    pub instrument_ids: Option<Vec<u32>>,
+    /// Levels per book in the depth snapshots
+    pub depth: u16,
+    /// Compression of the delta-encoded market
+    pub compression: Compression,
+}
+impl ExportParams {
+    pub fn new(
+        start_index: Option<usize>,
+        end_index: Option<usize>,
+        start_ts: Option<u64>,
+        end_ts: Option<u64>,
+        instrument_ids: Option<Vec<u32>>,
+        depth: u16,
+        compression: Compression,
+    ) -> Result<Self> {
+        if let (Some(start_index), Some(end_index)) = (start_index, end_index) {
+            ensure!(start_index <= end_index, "start_index must not be after end_index");
+        }
+        if let (Some(start_ts), Some(end_ts)) = (start_ts, end_ts) {
+            ensure!(start_ts <= end_ts, "start_ts must not be after end_ts");
+        }
+        ensure!((1..=100).contains(&depth), "depth must be between 1 and 100");
+
+        let instrument_ids = instrument_ids.map(|mut instrument_ids| {
+            instrument_ids.sort_unstable();
+            instrument_ids.dedup();
+            instrument_ids
+        });
+        ensure!(
+            instrument_ids.as_ref().is_none_or(|instrument_ids| !instrument_ids.is_empty()),
+            "instrument_ids must not be empty"
+        );
+
+        Ok(Self { start_index, end_index, start_ts, end_ts, instrument_ids, depth, compression })
+    }
+
+    /// Hex SHA-256 of the parameters and what the market was built from,
+    ///  naming the export in the cache
+    pub fn cache_key(&self, artifacts: &ArtifactManifest) -> Result<String> {
+        let key = serde_json::to_vec(&(artifacts, self))
+            .context("...while serializing export parameters")?;
+        Ok(format!("{:x}", Sha256::digest(key)))
+    }
+
+    /// Indices of the snapshots within the index bounds, if any are
+    fn index_range(&self, len: usize) -> Option<RangeInclusive<usize>> {
+        let start = self.start_index.unwrap_or(0);
+        let end = self.end_index.unwrap_or(usize::MAX).min(len.checked_sub(1)?);
+        (start <= end).then_some(start..=end)
+    }
+
+    /// Whether a snapshot within the index bounds is selected
+    fn includes(&self, snapshot: &MarketSnapshot) -> bool {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        self.start_ts.is_none_or(|ts| msg.ts_recv >= ts)
+            && self.end_ts.is_none_or(|ts| msg.ts_recv <= ts)
+            && self.instrument_ids.as_ref().is_none_or(|ids| ids.binary_search(&msg.hd.instrument_id).is_ok())
+    }
+
+    /// Indices and snapshots of the selected messages, in order
+    fn select<'a>(&'a self, snapshots: &'a [MarketSnapshot]) -> impl Iterator<Item = (usize, &'a MarketSnapshot)> + 'a {
+        self.index_range(snapshots.len())
+            .into_iter()
+            .flatten()
+            .map(|index| (index, &snapshots[index]))
+            .filter(|(_, snapshot)| self.includes(snapshot))
+    }
+}
+
+/// Describes an export, written as `manifest.json` in the archive
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportManifest {
+    pub artifacts: ArtifactManifest,
+    pub params: ExportParams,
+    pub messages: usize,
+    pub first_index: Option<usize>,
+    pub last_index: Option<usize>,
+    pub first_ts_recv: Option<u64>,
+    pub last_ts_recv: Option<u64>,
+}
+
+/// One line of `depth.jsonl`, the books of the message's instrument
+///  after it was applied
+#[derive(Serialize)]
+struct DepthLine<'a> {
+    index: usize,
+    ts_recv: u64,
+    instrument_id: u32,
+    symbol: Option<&'a str>,
+    books: Vec<DepthBook>,
+}
+
+#[derive(Serialize)]
+struct DepthBook {
+    publisher_id: u16,
+    levels: Vec<BidAskPair>,
+}
+
+/// Write the selected messages as a ZIP archive of:
+///
+/// - `market.mbod`: the delta-encoded market, starting from the market
+///   before the first selected message (see `delta::DeltaWriter`)
+/// - `depth.jsonl`: the top `depth` levels of each publisher's book after
+///   every selected message
+/// - `manifest.json`: what the market was built from, the parameters and
+///   the range actually exported
+///
+/// Entries are written in chunks as they are produced, so the archive
+///  can be streamed while later messages are still being encoded.
+#[tracing::instrument(skip(snapshots, artifacts, writer))]
+pub async fn write_export<W: AsyncWrite + Unpin>(
+    snapshots: &[MarketSnapshot],
+    params: &ExportParams,
+    artifacts: &ArtifactManifest,
+    writer: W,
+) -> Result<ExportManifest> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    let first = params.select(snapshots).next();
+    let empty = Market::new();
+    let initial = first
+        .and_then(|(index, _)| index.checked_sub(1))
+        .map_or(&empty, |index| &snapshots[index].market);
+
+    // The delta export is already compressed, so it is stored as is
+    let mut entry = zip.write_entry_stream(ZipEntryBuilder::new("market.mbod".into(), ZipCompression::Stored))
+        .await
+        .context("...while starting market entry")?;
+    let mut delta_writer = DeltaWriter::new(
+        Vec::with_capacity(CHUNK_SIZE),
+        params.compression,
+        delta::DEFAULT_KEYFRAME_INTERVAL,
+        initial,
+        params.instrument_ids.clone(),
+    )?;
+    let mut manifest = ExportManifest {
+        artifacts: artifacts.clone(),
+        params: params.clone(),
+        messages: 0,
+        first_index: None,
+        last_index: None,
+        first_ts_recv: None,
+        last_ts_recv: None,
+    };
+    for (index, snapshot) in params.select(snapshots) {
+        delta_writer.push(snapshot)?;
+
+        let ts_recv = snapshot.mbomsg_effect.mbo_msg.ts_recv;
+        manifest.messages += 1;
+        manifest.first_index.get_or_insert(index);
+        manifest.first_ts_recv.get_or_insert(ts_recv);
+        manifest.last_index = Some(index);
+        manifest.last_ts_recv = Some(ts_recv);
+
+        let buffer = delta_writer.get_mut();
+        if buffer.len() >= CHUNK_SIZE {
+            entry.write_all(buffer)
+                .await
+                .context("...while writing market entry")?;
+            buffer.clear();
+        }
+    }
+    entry.write_all(&delta_writer.finish()?)
+        .await
+        .context("...while writing market entry")?;
+    entry.close()
+        .await
+        .context("...while finishing market entry")?;
+
+    let mut entry = zip.write_entry_stream(ZipEntryBuilder::new("depth.jsonl".into(), ZipCompression::Deflate))
+        .await
+        .context("...while starting depth entry")?;
+    let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+    for (index, snapshot) in params.select(snapshots) {
+        let effect = &snapshot.mbomsg_effect;
+        let instrument_id = effect.mbo_msg.hd.instrument_id;
+        let line = DepthLine {
+            index,
+            ts_recv: effect.mbo_msg.ts_recv,
+            instrument_id,
+            symbol: effect.symbol.as_deref(),
+            books: snapshot.market.books_by_pub(instrument_id)
+                .unwrap_or_default()
+                .iter()
+                .map(|(publisher, book)| DepthBook {
+                    publisher_id: *publisher as u16,
+                    levels: book.snapshot(params.depth as usize),
+                })
+                .collect(),
+        };
+        serde_json::to_writer(&mut buffer, &line)
+            .context("...while serializing depth line")?;
+        buffer.push(b'\n');
+
+        if buffer.len() >= CHUNK_SIZE {
+            entry.write_all(&buffer)
+                .await
+                .context("...while writing depth entry")?;
+            buffer.clear();
+        }
+    }
+    entry.write_all(&buffer)
+        .await
+        .context("...while writing depth entry")?;
+    entry.close()
+        .await
+        .context("...while finishing depth entry")?;
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)
+        .context("...while serializing export manifest")?;
+    zip.write_entry_whole(ZipEntryBuilder::new("manifest.json".into(), ZipCompression::Deflate), &manifest_json)
+        .await
+        .context("...while writing manifest entry")?;
+
+    let mut writer = zip.close()
+        .await
+        .context("...while finishing export archive")?
+        .into_inner();
+    tokio::io::AsyncWriteExt::shutdown(&mut writer)
+        .await
+        .context("...while flushing export archive")?;
+
+    Ok(manifest)
+}
+
+/// Directory of finished exports, ZIP archives, CSV and Parquet files,
+///  named by their parameters' cache key
+#[derive(Debug, Clone)]
+pub struct ExportCache {
+    pub dir: PathBuf,
+    /// Exports kept before the least recently written are evicted
+    pub max_entries: usize,
+    /// What the exported market was built from
+    pub artifacts: ArtifactManifest,
+}
+impl ExportCache {
+    /// Cache in `EXPORT_CACHE_DIR` (default: `assets/export_cache`), of at
+    ///  most `EXPORT_CACHE_MAX_ENTRIES` (default: 16) exports
+    pub fn from_env(artifacts: ArtifactManifest) -> Result<Self> {
+        let dir = std::env::var("EXPORT_CACHE_DIR")
+            .unwrap_or("assets/export_cache".to_string());
+        let max_entries = std::env::var("EXPORT_CACHE_MAX_ENTRIES")
+            .map(|max_entries| max_entries.parse::<usize>())
+            .unwrap_or(Ok(16))
+            .context("...while parsing EXPORT_CACHE_MAX_ENTRIES")?;
+
+        Ok(Self { dir: PathBuf::from(dir), max_entries, artifacts })
+    }
+
+    /// Remove every cached export unless the cache is stamped with the
+    ///  current artifact manifest, then stamp it
+    ///
+    /// Exports from another source could never be hit, as the manifest is
+    ///  part of the cache key, but would take up space until evicted.
+    pub fn invalidate_stale(&self) -> Result<()> {
+        if self.artifacts.matches(&self.dir) {
+            return Ok(());
+        }
+
+        if self.dir.exists() {
+            for entry in std::fs::read_dir(&self.dir).context("...while listing export cache")? {
+                let path = entry.context("...while listing export cache")?.path();
+                if path.extension().is_some_and(|extension| extension == "zip" || extension == "csv" || extension == "parquet" || extension == "tmp") {
+                    std::fs::remove_file(&path)
+                        .context(format!("...while removing stale export {:?}", path))?;
+                }
+            }
+            info!("Removed exports of another source from {:?}", self.dir);
+        }
+
+        self.artifacts.stamp(&self.dir)
+    }
+
+    /// Where the export with this cache key is kept once produced
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.zip", key))
+    }
+
+    /// Where the CSV export with this cache key is kept once produced
+    pub fn csv_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.csv", key))
+    }
+
+    /// Where the Parquet export with this cache key is kept once produced
+    pub fn parquet_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.parquet", key))
+    }
+
+    /// Unique file an export is written to before it's moved into place
+    fn temp_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}.{}.tmp",
+            key,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        ))
+    }
+
+    /// Stream the export for `params`, from the cache if it was already
+    ///  produced, otherwise encoded while it is sent and cached once done
+    ///
+    /// `snapshots` is held until the export is complete, even if the
+    ///  client goes away first, so a read guard on the state can be
+    ///  passed in.
+    pub async fn stream<S>(&self, params: ExportParams, snapshots: S) -> Result<Body>
+    where
+        S: Deref<Target = [MarketSnapshot]> + Send + Sync + 'static,
+    {
+        let key = params.cache_key(&self.artifacts)?;
+        let path = self.path(&key);
+        if let Ok(file) = tokio::fs::File::open(&path).await {
+            debug!(key, "Serving cached export");
+            return Ok(Body::from_stream(ReaderStream::new(file)));
+        }
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context(format!("...while creating export cache directory {:?}", self.dir))?;
+        let temp_path = self.temp_path(&key);
+        let temp_file = tokio::fs::File::create(&temp_path)
+            .await
+            .context("...while creating temporary export file")?;
+
+        let (zip_writer, zip_reader) = tokio::io::duplex(CHUNK_SIZE);
+        let artifacts = self.artifacts.clone();
+        let producer = tokio::spawn(async move {
+            write_export(&snapshots, &params, &artifacts, zip_writer).await
+        });
+
+        let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let copied = tee(zip_reader, temp_file, &tx).await;
+            let produced = producer.await
+                .context("Export task panicked")
+                .and_then(|result| result);
+
+            let result = match (copied, produced) {
+                (Ok(()), Ok(manifest)) => tokio::fs::rename(&temp_path, &path)
+                    .await
+                    .context("...while moving export into the cache")
+                    .map(|_| manifest),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            };
+            match result {
+                Ok(manifest) => {
+                    info!(key, messages = manifest.messages, "Cached export");
+                    if let Err(e) = cache.evict().await {
+                        error!("Failed to evict cached exports: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to produce export: {:?}", e);
+                    let _ = tokio::fs::remove_file(&temp_path).await;
+                    let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+                }
+            }
+        });
+
+        Ok(Body::from_stream(ReceiverStream::new(rx)))
+    }
+
+    /// Produce the export for `params` into the cache, unless it's there
+    ///  already, returning its path once complete
+    pub async fn produce<S>(&self, params: ExportParams, snapshots: S) -> Result<PathBuf>
+    where
+        S: Deref<Target = [MarketSnapshot]> + Send + Sync + 'static,
+    {
+        let path = self.path(&params.cache_key(&self.artifacts)?);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(path);
+        }
+
+        let mut body = self.stream(params, snapshots).await?.into_data_stream();
+        while let Some(chunk) = body.next().await {
+            chunk.context("...while producing export")?;
+        }
+
+        Ok(path)
+    }
+
+    /// Write the CSV for `params`, keyed by `key`, into the cache on a
+    ///  blocking thread, unless it's there already, returning its path
+    ///  once complete
+    pub async fn produce_csv<S>(&self, key: &str, params: CsvParams, snapshots: S) -> Result<PathBuf>
+    where
+        S: Deref<Target = [MarketSnapshot]> + Send + 'static,
+    {
+        self.produce_file(key, self.csv_path(key), "CSV", move |writer| {
+            for chunk in csv::chunks(&params, &snapshots) {
+                writer.write_all(chunk.as_bytes())
+                    .context("...while writing CSV export to the cache")?;
+            }
+            Ok(())
+        }).await
+    }
+
+    /// Encode the Parquet file for `params`, keyed by `key`, into the cache
+    ///  on a blocking thread, unless it's there already, returning its path
+    ///  once complete
+    pub async fn produce_parquet<S>(&self, key: &str, params: ParquetParams, snapshots: S) -> Result<PathBuf>
+    where
+        S: Deref<Target = [MarketSnapshot]> + Send + 'static,
+    {
+        self.produce_file(key, self.parquet_path(key), "Parquet", move |writer| {
+            parquet::write(&params, &snapshots, writer)
+        }).await
+    }
+
+    /// Write an export to `path` in the cache with `write` on a blocking
+    ///  thread, unless it's there already, returning `path` once complete
+    async fn produce_file<F>(&self, key: &str, path: PathBuf, format: &'static str, write: F) -> Result<PathBuf>
+    where
+        F: FnOnce(&mut BufWriter<std::fs::File>) -> Result<()> + Send + 'static,
+    {
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(path);
+        }
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .context(format!("...while creating export cache directory {:?}", self.dir))?;
+        let temp_path = self.temp_path(key);
+        let written = tokio::task::spawn_blocking({
+            let temp_path = temp_path.clone();
+            move || {
+                let file = std::fs::File::create(&temp_path)
+                    .context("...while creating temporary export file")?;
+                let mut writer = BufWriter::with_capacity(CHUNK_SIZE, file);
+                write(&mut writer)?;
+                writer.flush()
+                    .context(format!("...while flushing {} export to the cache", format))
+            }
+        }).await
+            .context(format!("{} export task panicked", format))
+            .and_then(|result| result);
+
+        let moved = match written {
+            Ok(()) => tokio::fs::rename(&temp_path, &path)
+                .await
+                .context(format!("...while moving {} export into the cache", format)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = moved {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        info!(key, "Cached {} export", format);
+        if let Err(e) = self.evict().await {
+            error!("Failed to evict cached exports: {:?}", e);
+        }
+
+        Ok(path)
+    }
+
+    /// Remove the oldest exports beyond `max_entries`
+    async fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir)
+            .await
+            .context("...while listing export cache")?;
+        while let Some(entry) = dir.next_entry().await.context("...while listing export cache")? {
+            let path = entry.path();
+            if path.extension().is_some_and(|extension| extension == "zip" || extension == "csv" || extension == "parquet") {
+                let modified = entry.metadata()
+                    .await
+                    .and_then(|metadata| metadata.modified())
+                    .context("...while reading cached export metadata")?;
+                entries.push((modified, path));
+            }
+        }
+
+        entries.sort();
+        let excess = entries.len().saturating_sub(self.max_entries);
+        for (_, path) in entries.into_iter().take(excess) {
+            debug!("Evicting cached export {:?}", path);
+            tokio::fs::remove_file(&path)
+                .await
+                .context(format!("...while removing cached export {:?}", path))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Copy the archive to the cache file and the response body
+///
+/// A client going away doesn't stop the copy, so the export still ends
+///  up in the cache.
+async fn tee(
+    mut reader: impl AsyncRead + Unpin,
+    mut file: tokio::fs::File,
+    tx: &mpsc::Sender<Result<Bytes, std::io::Error>>,
+) -> Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)
+            .await
+            .context("...while reading export archive")?;
+        if read == 0 {
+            break;
+        }
+
+        tokio::io::AsyncWriteExt::write_all(&mut file, &buffer[..read])
+            .await
+            .context("...while writing export to the cache")?;
+        let _ = tx.send(Ok(Bytes::copy_from_slice(&buffer[..read]))).await;
+    }
+
+    tokio::io::AsyncWriteExt::flush(&mut file)
+        .await
+        .context("...while flushing export to the cache")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use futures::AsyncReadExt as _;
+    use crate::{
+        archive::ArchiveKind,
+        datatypes::market::load_market_snapshots,
+        delta::reader::DeltaReader,
+        ingest::{Dataset, decoder::DbnSource},
+    };
+
+    fn artifacts(fingerprint: &str) -> ArtifactManifest {
+        ArtifactManifest::new(&Dataset {
+            fingerprint: fingerprint.to_string(),
+            source: "test".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_params_normalized() -> Result<()> {
+        let artifacts = artifacts("sha256:abc");
+        let params = ExportParams::new(None, None, None, None, Some(vec![3, 1, 3]), 10, Compression::Zstd)?;
+        let same = ExportParams::new(None, None, None, None, Some(vec![1, 3]), 10, Compression::Zstd)?;
+        assert_eq!(params.instrument_ids, Some(vec![1, 3]));
+        assert_eq!(params.cache_key(&artifacts)?, same.cache_key(&artifacts)?);
+
+        let deeper = ExportParams::new(None, None, None, None, Some(vec![1, 3]), 20, Compression::Zstd)?;
+        assert_ne!(params.cache_key(&artifacts)?, deeper.cache_key(&artifacts)?);
+
+        // The same parameters over other data are another export
+        assert_ne!(params.cache_key(&artifacts)?, params.cache_key(&self::artifacts("sha256:def"))?);
+
+        assert!(ExportParams::new(Some(5), Some(4), None, None, None, 10, Compression::Zstd).is_err());
+        assert!(ExportParams::new(None, None, Some(5), Some(4), None, 10, Compression::Zstd).is_err());
+        assert!(ExportParams::new(None, None, None, None, Some(vec![]), 10, Compression::Zstd).is_err());
+        assert!(ExportParams::new(None, None, None, None, None, 0, Compression::Zstd).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_range_rebuilds_market() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, None).await?;
+
+        let params = ExportParams::new(Some(1_000), Some(4_999), None, None, None, 5, Compression::Deflate)?;
+        let mut archive = Vec::new();
+        let manifest = write_export(&snapshots, &params, &artifacts("sha256:abc"), &mut archive).await?;
+        assert_eq!(manifest.messages, 4_000);
+        assert_eq!((manifest.first_index, manifest.last_index), (Some(1_000), Some(4_999)));
+
+        let zip = async_zip::base::read::mem::ZipFileReader::new(archive).await?;
+        let mut entries = std::collections::HashMap::new();
+        for index in 0..zip.file().entries().len() {
+            let name = zip.file().entries()[index].filename().as_str()?.to_string();
+            let mut data = Vec::new();
+            zip.reader_with_entry(index).await?.read_to_end(&mut data).await?;
+            entries.insert(name, data);
+        }
+
+        // The delta export starts from the market before the range and
+        //  ends on the market after it
+        let mut reader = DeltaReader::new(entries["market.mbod"].as_slice())?;
+        let mut last = None;
+        while let Some(snapshot) = reader.next_snapshot()? {
+            last = Some(snapshot);
+        }
+        assert_eq!(
+            serde_json::to_value(&last.context("No snapshots in export")?.market)?,
+            serde_json::to_value(&snapshots[4_999].market)?,
+        );
+
+        let depth = String::from_utf8(entries["depth.jsonl"].clone())?;
+        assert_eq!(depth.lines().count(), 4_000);
+        let first: serde_json::Value = serde_json::from_str(depth.lines().next().context("No depth lines")?)?;
+        assert_eq!(first["index"], 1_000);
+        assert!(first["books"].as_array().context("No books")?.iter().all(|book| book["levels"].as_array().is_some_and(|levels| levels.len() == 5)));
+
+        let manifest: serde_json::Value = serde_json::from_slice(&entries["manifest.json"])?;
+        assert_eq!(manifest["messages"], 4_000);
+        assert_eq!(manifest["artifacts"]["source"], "sha256:abc");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_csv_written_to_cache_once() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let mut snapshots = load_market_snapshots(&mut source, None).await?;
+        snapshots.truncate(2_000);
+        let snapshots = std::sync::Arc::<[MarketSnapshot]>::from(snapshots);
+
+        let dir = std::env::temp_dir().join(format!("mbo_test_csv_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = ExportCache { dir: dir.clone(), max_entries: 1, artifacts: artifacts("sha256:abc") };
+        let params = |kind| CsvParams { kind, instrument_id: None, start_ts: None, end_ts: None, levels: 3 };
+
+        let path = cache.produce_csv("bbo", params(csv::CsvKind::Bbo), snapshots.clone()).await?;
+        let expected = csv::chunks(&params(csv::CsvKind::Bbo), &snapshots).collect::<String>();
+        assert_eq!(path, cache.csv_path("bbo"));
+        assert_eq!(std::fs::read_to_string(&path)?, expected);
+
+        // A cached CSV isn't written again, and the oldest export is
+        //  evicted beyond the maximum, leaving no temporary files
+        let modified = std::fs::metadata(&path)?.modified()?;
+        cache.produce_csv("bbo", params(csv::CsvKind::Bbo), snapshots.clone()).await?;
+        assert_eq!(std::fs::metadata(&path)?.modified()?, modified);
+        cache.produce_csv("mbo", params(csv::CsvKind::Mbo), snapshots).await?;
+        let files = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(files, vec!["mbo.csv".to_string()]);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parquet_written_to_cache_once() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let mut snapshots = load_market_snapshots(&mut source, None).await?;
+        snapshots.truncate(2_000);
+        let snapshots = std::sync::Arc::<[MarketSnapshot]>::from(snapshots);
+
+        let dir = std::env::temp_dir().join(format!("mbo_test_parquet_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = ExportCache { dir: dir.clone(), max_entries: 1, artifacts: artifacts("sha256:abc") };
+        let end_ts = snapshots[999].mbomsg_effect.mbo_msg.ts_recv;
+        let params = ParquetParams { kind: ArchiveKind::Mbo, instrument_id: None, start_ts: None, end_ts: Some(end_ts) };
+
+        let path = cache.produce_parquet("mbo", params.clone(), snapshots.clone()).await?;
+        assert_eq!(path, cache.parquet_path("mbo"));
+        let rows = ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path)?)?
+            .build()?
+            .map(|batch| Ok(batch?.num_rows()))
+            .sum::<Result<usize>>()?;
+        let expected = snapshots.iter()
+            .filter(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv <= end_ts)
+            .count();
+        assert_eq!(rows, expected);
+
+        // A cached file isn't encoded again
+        let modified = std::fs::metadata(&path)?.modified()?;
+        cache.produce_parquet("mbo", params, snapshots).await?;
+        assert_eq!(std::fs::metadata(&path)?.modified()?, modified);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
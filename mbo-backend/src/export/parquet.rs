@@ -0,0 +1,39 @@
+use std::io::Write;
+
+use anyhow::Result;
+use tracing::info;
+
+use crate::{
+    archive::{self, ArchiveKind},
+    datatypes::market::MarketSnapshot,
+};
+
+/// Slice of the loaded market to export as Parquet
+#[derive(Debug, Clone)]
+pub struct ParquetParams {
+    pub kind: ArchiveKind,
+    pub instrument_id: Option<u32>,
+    /// Inclusive bounds on `ts_recv`, in nanoseconds since the UNIX epoch
+    pub start_ts: Option<u64>,
+    pub end_ts: Option<u64>,
+}
+impl ParquetParams {
+    fn includes(&self, snapshot: &MarketSnapshot) -> bool {
+        let msg = &snapshot.mbomsg_effect.mbo_msg;
+        self.instrument_id.is_none_or(|id| msg.hd.instrument_id == id)
+            && self.start_ts.is_none_or(|ts| msg.ts_recv >= ts)
+            && self.end_ts.is_none_or(|ts| msg.ts_recv <= ts)
+    }
+}
+
+/// Encode the selected snapshots as a Parquet file, with the schema of
+///  the on-disk archive of the same kind
+pub fn write<W: Write + Send>(params: &ParquetParams, snapshots: &[MarketSnapshot], writer: W) -> Result<()> {
+    let selected = snapshots.iter()
+        .filter(|snapshot| params.includes(snapshot))
+        .collect::<Vec<_>>();
+    let batch = archive::record_batch(params.kind, &selected)?;
+    info!(rows = batch.num_rows(), kind = params.kind.name(), "Exporting market slice as Parquet");
+
+    archive::write_parquet(writer, &batch)
+}
@@ -0,0 +1,132 @@
+use std::path::Path;
+
+use databento::{
+    dbn::{
+        decode::{AsyncDbnDecoder, DbnMetadata},
+        MboMsg, Metadata, Schema, SymbolIndex, TsSymbolMap,
+    },
+    historical::timeseries::GetRangeParams,
+    HistoricalClient,
+};
+use anyhow::{Context, Result, ensure};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::io::AsyncReadExt;
+
+use super::{MboSource, symbology::instruments_from_metadata};
+use crate::storage::Instrument;
+
+/// MBO messages decoded from a DBN stream, either a local file or the
+///  response body of a historical request
+pub struct DbnSource<R: AsyncReadExt + Unpin> {
+    decoder: AsyncDbnDecoder<R>,
+    symbol_map: TsSymbolMap,
+    instruments: Vec<Instrument>,
+}
+impl<R: AsyncReadExt + Unpin> DbnSource<R> {
+    fn new(decoder: AsyncDbnDecoder<R>) -> Result<Self> {
+        let symbol_map = decoder.metadata().symbol_map()
+            .context("...while building symbol map from DBN metadata")?;
+
+        let instruments = instruments_from_metadata(decoder.metadata())
+            .context("...while reading instruments from DBN metadata")?;
+
+        Ok(Self {
+            decoder,
+            symbol_map,
+            instruments,
+        })
+    }
+}
+impl DbnSource<tokio::fs::File> {
+    #[tracing::instrument]
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        // First, check that the file exists - `from_file` already
+        //  does, but the error message isn't helpful at all
+        ensure!(path.exists(), "Input file does not exist at path: `{:?}`", path);
+
+        let decoder = AsyncDbnDecoder::from_file(path)
+            .await
+            .context("...while trying to open decoder on file")?;
+
+        Self::new(decoder)
+    }
+}
+
+/// Parameters of a historical MBO request, read from the environment
+#[derive(Debug, Clone)]
+pub struct HistoricalConfig {
+    pub dataset: String,
+    pub symbols: Vec<String>,
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+}
+impl HistoricalConfig {
+    pub fn from_env() -> Result<Self> {
+        let dataset = std::env::var("DBN_HISTORICAL_DATASET")
+            .unwrap_or("GLBX.MDP3".to_string());
+        let symbols = std::env::var("DBN_HISTORICAL_SYMBOLS")
+            .context("DBN_HISTORICAL_SYMBOLS environment variable not set")?
+            .split(',')
+            .map(|symbol| symbol.trim().to_string())
+            .filter(|symbol| !symbol.is_empty())
+            .collect::<Vec<_>>();
+        ensure!(!symbols.is_empty(), "DBN_HISTORICAL_SYMBOLS contains no symbols");
+
+        let parse_time = |var: &str| -> Result<OffsetDateTime> {
+            let value = std::env::var(var)
+                .context(format!("{} environment variable not set", var))?;
+            OffsetDateTime::parse(&value, &Rfc3339)
+                .context(format!("...while parsing {} as an RFC 3339 timestamp", var))
+        };
+
+        Ok(Self {
+            dataset,
+            symbols,
+            start: parse_time("DBN_HISTORICAL_START")?,
+            end: parse_time("DBN_HISTORICAL_END")?,
+        })
+    }
+}
+
+/// Open a streaming historical MBO request
+#[tracing::instrument(skip(client))]
+pub async fn from_historical(
+    client: &mut HistoricalClient,
+    config: &HistoricalConfig,
+) -> Result<DbnSource<impl AsyncReadExt + Unpin>> {
+    let decoder = client.timeseries()
+        .get_range(
+            &GetRangeParams::builder()
+                .dataset(config.dataset.clone())
+                .symbols(config.symbols.clone())
+                .schema(Schema::Mbo)
+                .date_time_range((config.start, config.end))
+                .build()
+        )
+        .await
+        .context("...while requesting historical MBO data")?;
+
+    DbnSource::new(decoder)
+}
+
+impl<R: AsyncReadExt + Unpin> MboSource for DbnSource<R> {
+    fn metadata(&self) -> Option<&Metadata> {
+        Some(self.decoder.metadata())
+    }
+
+    fn symbol(&self, mbo_msg: &MboMsg) -> Option<&str> {
+        self.symbol_map.get_for_rec(mbo_msg)
+            .map(String::as_str)
+    }
+
+    fn take_instruments(&mut self) -> Vec<Instrument> {
+        std::mem::take(&mut self.instruments)
+    }
+
+    async fn next_mbo(&mut self) -> Result<Option<MboMsg>> {
+        Ok(self.decoder.decode_record::<MboMsg>()
+            .await
+            .context("...while trying to decode record")?
+            .cloned())
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use databento::dbn::{
+    decode::{AsyncDbnDecoder, DbnMetadata},
+    encode::{AsyncDbnMetadataEncoder, AsyncDbnRecordEncoder},
+    MboMsg, MetadataBuilder, SType, Schema, SymbolMappingMsg, UNDEF_TIMESTAMP,
+};
+use anyhow::{Context, Result, bail};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn, error};
+
+/// Symbol accepted by the gateway to subscribe to every instrument
+const ALL_SYMBOLS: &str = "ALL_SYMBOLS";
+
+/// A stand-in for a Databento live subscription gateway
+///
+/// Speaks the gateway's line-based control protocol (greeting, CRAM
+/// challenge, authentication, subscriptions and session start), then
+/// replays the MBO records of a DBN file to the client as a live DBN
+/// stream. The CRAM response is only checked for its shape, any API key
+/// is accepted.
+pub struct MockGateway {
+    listener: TcpListener,
+    dbn_path: PathBuf,
+    record_delay: Duration,
+}
+impl MockGateway {
+    #[tracing::instrument]
+    pub async fn bind(
+        addr: &str,
+        dbn_path: &Path,
+        record_delay: Duration,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("...while binding mock gateway to {}", addr))?;
+
+        Ok(Self {
+            listener,
+            dbn_path: dbn_path.to_path_buf(),
+            record_delay,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+            .context("...while reading mock gateway address")
+    }
+
+    /// Accept clients forever, serving each session on its own task
+    pub async fn serve(self) -> Result<()> {
+        info!(addr = ?self.local_addr()?, "Mock live gateway accepting connections");
+
+        let session_counter = AtomicU64::new(1);
+        loop {
+            let (stream, peer) = self.listener.accept()
+                .await
+                .context("...while accepting mock gateway client")?;
+            let session_id = session_counter.fetch_add(1, Ordering::Relaxed);
+            let dbn_path = self.dbn_path.clone();
+            let record_delay = self.record_delay;
+
+            tokio::spawn(async move {
+                info!(%peer, session_id, "Mock gateway session opened");
+                match serve_session(stream, session_id, &dbn_path, record_delay).await {
+                    Ok(sent) => info!(%peer, session_id, sent, "Mock gateway session finished"),
+                    Err(e) => error!(%peer, session_id, "Mock gateway session failed: {:?}", e),
+                }
+            });
+        }
+    }
+}
+
+/// Run the control protocol with one client and replay the file to it,
+///  returning the number of MBO records sent
+async fn serve_session(
+    stream: TcpStream,
+    session_id: u64,
+    dbn_path: &Path,
+    record_delay: Duration,
+) -> Result<usize> {
+    stream.set_nodelay(true)
+        .context("...while configuring client socket")?;
+    let mut stream = BufReader::new(stream);
+
+    // Greeting and CRAM challenge
+    stream.write_all(b"lsg_version=0.0.0-mock\n").await?;
+    stream.write_all(format!("cram={:016x}{:016x}\n", session_id, rand_seed()).as_bytes()).await?;
+
+    // Authentication request
+    let auth_line = read_line(&mut stream).await?
+        .context("Client disconnected before authenticating")?;
+    let auth = parse_fields(&auth_line);
+    if let Err(e) = check_auth(&auth) {
+        warn!(session_id, "Rejecting mock gateway authentication: {}", e);
+        stream.write_all(format!("success=0|error={}\n", e).as_bytes()).await?;
+        stream.shutdown().await?;
+        return Ok(0);
+    }
+    stream.write_all(format!("success=1|session_id={}\n", session_id).as_bytes()).await?;
+
+    // Subscriptions, terminated by the session start request
+    let mut symbols = HashSet::new();
+    loop {
+        let line = read_line(&mut stream).await?
+            .context("Client disconnected before starting the session")?;
+        if line == "start_session" {
+            break;
+        }
+
+        let sub = parse_fields(&line);
+        match sub.get("schema") {
+            Some(&"mbo") => {}
+            other => bail!("Mock gateway only serves the `mbo` schema, got {:?}", other),
+        }
+        match sub.get("stype_in") {
+            Some(&"raw_symbol") => {}
+            other => bail!("Mock gateway only supports `raw_symbol` symbology, got {:?}", other),
+        }
+        symbols.extend(
+            sub.get("symbols")
+                .context("Subscription request has no symbols")?
+                .split(',')
+                .map(str::to_string)
+        );
+    }
+
+    // Resolve the subscribed symbols against the file's symbology
+    let mut decoder = AsyncDbnDecoder::from_file(dbn_path)
+        .await
+        .context("...while opening DBN file for replay")?;
+    let file_metadata = decoder.metadata().clone();
+    let mut instruments: HashMap<u32, String> = HashMap::new();
+    for mapping in file_metadata.mappings.iter() {
+        if !symbols.contains(ALL_SYMBOLS) && !symbols.contains(&mapping.raw_symbol) {
+            continue;
+        }
+        for interval in mapping.intervals.iter() {
+            let instrument_id = interval.symbol.parse::<u32>()
+                .context(format!("...while parsing instrument ID for {}", mapping.raw_symbol))?;
+            instruments.insert(instrument_id, mapping.raw_symbol.clone());
+        }
+    }
+    info!(session_id, ?symbols, ?instruments, "Mock gateway session starting");
+
+    // Session metadata - live sessions carry no symbology, that is
+    //  sent in-band as symbol mapping records instead
+    let metadata = MetadataBuilder::new()
+        .dataset(file_metadata.dataset.clone())
+        .schema(Some(Schema::Mbo))
+        .start(file_metadata.start)
+        .stype_in(Some(SType::RawSymbol))
+        .stype_out(SType::InstrumentId)
+        .build();
+    AsyncDbnMetadataEncoder::new(&mut stream)
+        .encode(&metadata)
+        .await
+        .context("...while sending session metadata")?;
+
+    let mut encoder = AsyncDbnRecordEncoder::new(&mut stream);
+    for (instrument_id, raw_symbol) in instruments.iter() {
+        let mapping = SymbolMappingMsg::new(
+            *instrument_id,
+            file_metadata.start,
+            SType::RawSymbol,
+            raw_symbol,
+            SType::InstrumentId,
+            &instrument_id.to_string(),
+            file_metadata.start,
+            UNDEF_TIMESTAMP,
+        ).context("...while building symbol mapping record")?;
+        encoder.encode(&mapping)
+            .await
+            .context("...while sending symbol mapping record")?;
+    }
+
+    // Replay the file's MBO records for the subscribed instruments
+    let mut sent = 0;
+    while let Some(mbo_msg) = decoder.decode_record::<MboMsg>()
+        .await
+        .context("...while decoding record for replay")?
+    {
+        if !instruments.contains_key(&mbo_msg.hd.instrument_id) {
+            continue;
+        }
+
+        encoder.encode(mbo_msg)
+            .await
+            .context("...while sending MBO record")?;
+        sent += 1;
+
+        if !record_delay.is_zero() {
+            encoder.get_mut().flush().await?;
+            tokio::time::sleep(record_delay).await;
+        }
+    }
+
+    // Closing the connection ends the session for the client
+    stream.shutdown().await
+        .context("...while closing session")?;
+
+    Ok(sent)
+}
+
+/// Validate the shape of a CRAM authentication request
+fn check_auth(fields: &HashMap<&str, &str>) -> Result<()> {
+    let auth = fields.get("auth")
+        .context("missing auth")?;
+    let (response, bucket_id) = auth.split_once('-')
+        .context("malformed auth")?;
+    if response.len() != 64 || !response.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("malformed CRAM response");
+    }
+    if bucket_id.len() != 5 {
+        bail!("malformed bucket ID");
+    }
+    if fields.get("dataset").is_none_or(|dataset| dataset.is_empty()) {
+        bail!("missing dataset");
+    }
+    if fields.get("encoding") != Some(&"dbn") {
+        bail!("only dbn encoding is supported");
+    }
+
+    Ok(())
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<Option<String>> {
+    let mut line = String::new();
+    let read = stream.read_line(&mut line)
+        .await
+        .context("...while reading control message")?;
+    if read == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim_end().to_string()))
+}
+
+fn parse_fields(line: &str) -> HashMap<&str, &str> {
+    line.split('|')
+        .filter_map(|field| field.split_once('='))
+        .collect()
+}
+
+/// Cheap per-session entropy for the CRAM challenge
+fn rand_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,472 @@
+pub mod mock_gateway;
+
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use databento::{
+    dbn::{ErrorMsg, MboMsg, Metadata, PitSymbolMap, SType, Schema, SymbolMappingMsg, SystemMsg},
+    live::Subscription,
+    LiveClient,
+};
+use anyhow::{Context, Result, ensure};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{info, warn, error};
+
+use super::{MboSource, symbology::instrument_from_symbol_mapping};
+use crate::{
+    analytics::MAX_LEVELS,
+    datatypes::{
+        market::{write_checkpoint, MBOMsgEffect, Market, CHECKPOINT_INTERVAL},
+        price_level::PriceLevel,
+    },
+    metrics::Metrics,
+    storage::{Instrument, MarketStore, Storage, LIVE_SOURCE},
+};
+
+/// Number of effects buffered per live subscriber before it starts lagging
+const LIVE_CHANNEL_CAPACITY: usize = 16_384;
+
+/// Longest a quiet feed keeps received messages unpersisted
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configuration for the Databento live gateway ingest
+#[derive(Debug, Clone)]
+pub struct LiveConfig {
+    pub key: String,
+    pub dataset: String,
+    pub symbols: Vec<String>,
+    /// Overrides the gateway address, e.g. to point at a mock gateway
+    pub gateway: Option<String>,
+}
+impl LiveConfig {
+    /// Read the live configuration from the environment
+    ///
+    /// Returns `None` when `DBN_LIVE_SYMBOLS` is not set, which
+    ///  disables live ingestion.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(symbols) = std::env::var("DBN_LIVE_SYMBOLS") else {
+            return Ok(None);
+        };
+        let symbols = symbols.split(',')
+            .map(|symbol| symbol.trim().to_string())
+            .filter(|symbol| !symbol.is_empty())
+            .collect::<Vec<_>>();
+        ensure!(!symbols.is_empty(), "DBN_LIVE_SYMBOLS is set but contains no symbols");
+
+        let key = std::env::var("DBN_KEY")
+            .context("DBN_KEY environment variable not set")?;
+        let dataset = std::env::var("DBN_LIVE_DATASET")
+            .unwrap_or("GLBX.MDP3".to_string());
+        let gateway = std::env::var("DBN_LIVE_GATEWAY").ok();
+
+        Ok(Some(Self {
+            key,
+            dataset,
+            symbols,
+            gateway,
+        }))
+    }
+}
+
+/// Market state built from the live feed, shared with the API
+#[derive(Clone)]
+pub struct LiveFeed {
+    pub market: Arc<RwLock<Market>>,
+    pub effects: broadcast::Sender<MBOMsgEffect>,
+    /// Aggregated levels of each message's instrument right after it,
+    ///  only built while anyone is subscribed
+    pub levels: broadcast::Sender<Arc<LiveLevels>>,
+}
+impl LiveFeed {
+    pub fn new() -> Self {
+        let (effects, _) = broadcast::channel(LIVE_CHANNEL_CAPACITY);
+        let (levels, _) = broadcast::channel(LIVE_CHANNEL_CAPACITY);
+
+        Self {
+            market: Arc::new(RwLock::new(Market::new())),
+            effects,
+            levels,
+        }
+    }
+}
+
+/// The top `MAX_LEVELS` levels per side of a message's instrument across
+///  publishers, best first, as the message left the live book
+#[derive(Debug, Clone)]
+pub struct LiveLevels {
+    pub mbo_msg: MboMsg,
+    /// Raw symbol of the message's instrument, if the source knows it
+    pub symbol: Option<String>,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+/// MBO messages received from a Databento live gateway session
+pub struct LiveSource {
+    client: LiveClient,
+    metadata: Metadata,
+    symbol_map: PitSymbolMap,
+    instruments: Vec<Instrument>,
+}
+impl LiveSource {
+    /// Connect to the live gateway, subscribe to MBO for the configured
+    ///  symbols and start the session
+    #[tracing::instrument]
+    pub async fn connect(config: &LiveConfig) -> Result<Self> {
+        let mut client = {
+            let builder = LiveClient::builder()
+                .key(config.key.clone())
+                .context("...while building live client")?
+                .dataset(config.dataset.clone());
+            let builder = match &config.gateway {
+                Some(gateway) => builder.addr(gateway.as_str())
+                    .await
+                    .context(format!("...while resolving live gateway {}", gateway))?,
+                None => builder,
+            };
+
+            builder.build()
+                .await
+                .context("...while connecting to live gateway")?
+        };
+
+        client.subscribe(
+            Subscription::builder()
+                .symbols(config.symbols.clone())
+                .schema(Schema::Mbo)
+                .stype_in(SType::RawSymbol)
+                .build()
+        ).await
+            .context("...while subscribing to live MBO")?;
+        let metadata = client.start()
+            .await
+            .context("...while starting live session")?;
+        info!(session_id = client.session_id(), "Live session started");
+
+        Ok(Self {
+            client,
+            metadata,
+            symbol_map: PitSymbolMap::new(),
+            instruments: Vec::new(),
+        })
+    }
+}
+impl MboSource for LiveSource {
+    fn metadata(&self) -> Option<&Metadata> {
+        Some(&self.metadata)
+    }
+
+    fn symbol(&self, mbo_msg: &MboMsg) -> Option<&str> {
+        self.symbol_map.get(mbo_msg.hd.instrument_id)
+            .map(String::as_str)
+    }
+
+    fn take_instruments(&mut self) -> Vec<Instrument> {
+        std::mem::take(&mut self.instruments)
+    }
+
+    async fn next_mbo(&mut self) -> Result<Option<MboMsg>> {
+        while let Some(record) = self.client.next_record()
+            .await
+            .context("...while receiving live record")?
+        {
+            if let Some(mbo_msg) = record.get::<MboMsg>() {
+                return Ok(Some(mbo_msg.clone()));
+            } else if let Some(error_msg) = record.get::<ErrorMsg>() {
+                error!(error = ?error_msg.err(), "Live gateway reported an error");
+            } else if let Some(system_msg) = record.get::<SystemMsg>() {
+                if !system_msg.is_heartbeat() {
+                    info!(msg = ?system_msg.msg(), "Live gateway system message");
+                }
+            } else {
+                if let Some(mapping) = record.get::<SymbolMappingMsg>() {
+                    self.instruments.push(instrument_from_symbol_mapping(mapping)?);
+                }
+                self.symbol_map.on_record(record)
+                    .context("...while updating live symbology")?;
+            }
+        }
+
+        warn!(symbols = ?self.symbol_map.inner(), "Live gateway closed the session");
+        Ok(None)
+    }
+}
+
+/// Apply every message of a source to the live market as it arrives,
+///  until the source is exhausted
+///
+/// Each effect is broadcast to the live stream subscribers as soon as
+///  it is applied, and messages are persisted to storage if provided,
+///  along with periodic checkpoints of the live market.
+///
+/// Waiting for the next message is abandoned to flush a quiet feed, so
+///  the source's `next_mbo` must be cancel safe, as `LiveSource`'s is.
+#[tracing::instrument(skip_all)]
+pub async fn run<S: MboSource>(
+    mut source: S,
+    feed: LiveFeed,
+    storage: Option<Storage>,
+    metrics: Arc<Metrics>,
+) -> Result<usize> {
+    // Same batching as file ingestion, flushed at the end of an event once
+    //  full or when the feed is too slow to fill it
+    const BATCH_SIZE: usize = 1000;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut last_flush = Instant::now();
+    let mut since_checkpoint = 0;
+
+    let mut applied = 0;
+    loop {
+        // Only a batch ending on a complete event is flushed, so duplicate
+        //  detection sees the whole event
+        let flushable = batch.last().is_some_and(|last: &MboMsg| last.flags.is_last());
+        let next = match &storage {
+            Some(storage) if flushable => tokio::select! {
+                next = source.next_mbo() => next?,
+                _ = tokio::time::sleep_until((last_flush + FLUSH_INTERVAL).into()) => {
+                    flush(storage, &feed, &mut batch, &mut since_checkpoint).await?;
+                    last_flush = Instant::now();
+                    continue;
+                }
+            },
+            _ => source.next_mbo().await?,
+        };
+        let Some(mbo_msg) = next else {
+            break;
+        };
+        let start = Instant::now();
+
+        let (market_effect, levels) = {
+            let mut market = feed.market.write().await;
+            let market_effect = match market.apply(mbo_msg.clone()) {
+                Ok(market_effect) => market_effect,
+                Err(e) => {
+                    error!("Failed to apply live MBO message: {:?}", e);
+                    metrics.messages_processing_errors.inc();
+                    continue;
+                }
+            };
+
+            // Taken under the same lock, before any later message moves
+            //  the book on
+            let levels = (feed.levels.receiver_count() > 0).then(|| {
+                let (bids, asks) = market.aggregated_levels(mbo_msg.hd.instrument_id, MAX_LEVELS);
+                LiveLevels {
+                    mbo_msg: mbo_msg.clone(),
+                    symbol: source.symbol(&mbo_msg).map(str::to_string),
+                    bids,
+                    asks,
+                }
+            });
+            (market_effect, levels)
+        };
+        metrics.order_book_apply_duration.observe(start.elapsed().as_secs_f64());
+        metrics.order_book_updates.inc();
+        applied += 1;
+
+        if let Some(storage) = &storage {
+            let instruments = source.take_instruments();
+            if !instruments.is_empty() {
+                storage.upsert_instruments(&instruments)
+                    .await
+                    .context("...while persisting live instruments")?;
+            }
+
+            batch.push(mbo_msg.clone());
+            since_checkpoint += 1;
+            let flush_due = batch.len() >= BATCH_SIZE || last_flush.elapsed() >= FLUSH_INTERVAL;
+            if flush_due && mbo_msg.flags.is_last() {
+                flush(storage, &feed, &mut batch, &mut since_checkpoint).await?;
+                last_flush = Instant::now();
+            }
+        }
+
+        // No subscribers isn't an error, the live market is still updated
+        if let Some(levels) = levels {
+            let _ = feed.levels.send(Arc::new(levels));
+        }
+        let _ = feed.effects.send(MBOMsgEffect {
+            symbol: source.symbol(&mbo_msg).map(str::to_string),
+            mbo_msg,
+            market_effect,
+        });
+    }
+
+    if let Some(storage) = &storage {
+        if !batch.is_empty() {
+            storage.insert_mbo_batch(&batch)
+                .await
+                .context("...while persisting final live MBO message batch")?;
+        }
+    }
+
+    Ok(applied)
+}
+
+/// Persist a batch of live messages ending on a complete event, then
+///  checkpoint the live market, which is as of the batch's last message,
+///  once `CHECKPOINT_INTERVAL` messages were batched since the last one
+async fn flush(storage: &Storage, feed: &LiveFeed, batch: &mut Vec<MboMsg>, since_checkpoint: &mut usize) -> Result<()> {
+    let Some(last) = batch.last().cloned() else {
+        return Ok(());
+    };
+    storage.insert_mbo_batch(batch)
+        .await
+        .context("...while persisting live MBO message batch")?;
+    batch.clear();
+
+    if *since_checkpoint >= CHECKPOINT_INTERVAL {
+        let market = feed.market.read().await.clone();
+        write_checkpoint(storage, LIVE_SOURCE, &market, &last)
+            .await
+            .context("...while writing live market checkpoint")?;
+        *since_checkpoint = 0;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::Path, time::Duration};
+    use crate::{datatypes::market::load_market_snapshots, ingest::{decoder::DbnSource, memory::VecSource}};
+    use self::mock_gateway::MockGateway;
+
+    #[tokio::test]
+    async fn test_live_ingest_from_mock_gateway() -> Result<()> {
+        let path = Path::new("assets/CLX5_mbo.dbn");
+
+        let gateway = MockGateway::bind("127.0.0.1:0", path, Duration::ZERO).await?;
+        let addr = gateway.local_addr()?;
+        tokio::spawn(gateway.serve());
+
+        let config = LiveConfig {
+            key: "a".repeat(32),
+            dataset: "GLBX.MDP3".to_string(),
+            symbols: vec!["ALL_SYMBOLS".to_string()],
+            gateway: Some(addr.to_string()),
+        };
+        let feed = LiveFeed::new();
+        let mut subscriber = feed.effects.subscribe();
+        let metrics = Metrics::new()?;
+
+        let subscriber_task = tokio::spawn(async move {
+            let mut received = 0;
+            loop {
+                match subscriber.recv().await {
+                    Ok(_) => received += 1,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break received,
+                }
+            }
+        });
+
+        let source = LiveSource::connect(&config).await?;
+        let applied = run(source, feed.clone(), None, metrics).await?;
+
+        // The live market must end up where the file replay does
+        let mut file_source = DbnSource::from_file(path).await?;
+        let snapshots = load_market_snapshots(&mut file_source, None).await?;
+        assert_eq!(applied, snapshots.len(), "Every MBO record should be applied");
+
+        let expected = snapshots.last().context("No snapshots")?;
+        let instrument_id = expected.mbomsg_effect.mbo_msg.hd.instrument_id;
+        let (live_bid, live_ask) = feed.market.read().await.aggregated_bbo(instrument_id);
+        let (file_bid, file_ask) = expected.market.aggregated_bbo(instrument_id);
+        assert_eq!(live_bid.map(|l| (l.price, l.size)), file_bid.map(|l| (l.price, l.size)));
+        assert_eq!(live_ask.map(|l| (l.price, l.size)), file_ask.map(|l| (l.price, l.size)));
+
+        // Closing the sender ends the subscriber
+        drop(feed);
+        let received = subscriber_task.await?;
+        assert!(received > 0, "Subscriber should have received live effects");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_live_levels_follow_each_message() -> Result<()> {
+        let mut file_source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut file_source, None).await?;
+        let snapshots = &snapshots[..2_000];
+        let messages = snapshots.iter()
+            .map(|snapshot| snapshot.mbomsg_effect.mbo_msg.clone())
+            .collect::<Vec<_>>();
+
+        let feed = LiveFeed::new();
+        let mut subscriber = feed.levels.subscribe();
+        let source = VecSource::new(messages).with_symbol(snapshots[0].mbomsg_effect.mbo_msg.hd.instrument_id, "CLX5");
+        run(source, feed.clone(), None, Metrics::new()?).await?;
+        drop(feed);
+
+        // Each message's levels are those of the book it left, not of the
+        //  book once the feed moved on
+        let mut received = 0;
+        while let Ok(levels) = subscriber.recv().await {
+            let snapshot = &snapshots[received];
+            assert_eq!(levels.mbo_msg.order_id, snapshot.mbomsg_effect.mbo_msg.order_id);
+            assert_eq!(levels.symbol.as_deref(), Some("CLX5"));
+            assert_eq!((levels.bids.clone(), levels.asks.clone()), snapshot.market.aggregated_levels(levels.mbo_msg.hd.instrument_id, MAX_LEVELS));
+            received += 1;
+        }
+        assert_eq!(received, snapshots.len());
+
+        Ok(())
+    }
+
+    /// Replays messages, then goes quiet without ever ending
+    struct StallingSource {
+        messages: std::vec::IntoIter<MboMsg>,
+    }
+    impl MboSource for StallingSource {
+        fn metadata(&self) -> Option<&Metadata> {
+            None
+        }
+
+        fn symbol(&self, _mbo_msg: &MboMsg) -> Option<&str> {
+            None
+        }
+
+        fn take_instruments(&mut self) -> Vec<Instrument> {
+            Vec::new()
+        }
+
+        async fn next_mbo(&mut self) -> Result<Option<MboMsg>> {
+            match self.messages.next() {
+                Some(mbo_msg) => Ok(Some(mbo_msg)),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quiet_live_feed_is_flushed() -> Result<()> {
+        let mut file_source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut file_source, None).await?;
+        let event_end = snapshots.iter()
+            .enumerate()
+            .filter(|(_, snapshot)| snapshot.mbomsg_effect.mbo_msg.flags.is_last())
+            .map(|(index, _)| index + 1)
+            .nth(1)
+            .context("File should have two events")?;
+        assert!(event_end < 1000, "Both events should fit in a single batch");
+
+        // The feed goes quiet for good after the second event, which is
+        //  flushed without filling the batch or another message arriving
+        let storage = Storage::open(":memory:").await?;
+        let source = StallingSource {
+            messages: snapshots[..event_end].iter()
+                .map(|snapshot| snapshot.mbomsg_effect.mbo_msg.clone())
+                .collect::<Vec<_>>()
+                .into_iter(),
+        };
+        let ingest = tokio::spawn(run(source, LiveFeed::new(), Some(storage.clone()), Metrics::new()?));
+
+        tokio::time::sleep(FLUSH_INTERVAL * 3).await;
+        assert!(!ingest.is_finished(), "The source never ends");
+        assert_eq!(storage.count_messages().await?, event_end);
+        ingest.abort();
+
+        Ok(())
+    }
+}
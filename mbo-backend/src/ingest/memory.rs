@@ -0,0 +1,54 @@
+use std::collections::{HashMap, VecDeque};
+
+use databento::dbn::{MboMsg, Metadata};
+use anyhow::Result;
+
+use super::MboSource;
+use crate::storage::Instrument;
+
+/// MBO messages held in memory, e.g. synthetic streams built by tests
+#[derive(Debug, Default, Clone)]
+pub struct VecSource {
+    messages: VecDeque<MboMsg>,
+    symbols: HashMap<u32, String>,
+    instruments: Vec<Instrument>,
+}
+impl VecSource {
+    pub fn new(messages: Vec<MboMsg>) -> Self {
+        Self {
+            messages: messages.into(),
+            symbols: HashMap::new(),
+            instruments: Vec::new(),
+        }
+    }
+
+    /// Attach a raw symbol to an instrument ID, valid for all time
+    pub fn with_symbol(mut self, instrument_id: u32, symbol: impl ToString) -> Self {
+        self.symbols.insert(instrument_id, symbol.to_string());
+        self.instruments.push(Instrument {
+            instrument_id,
+            raw_symbol: symbol.to_string(),
+            valid_from: 0,
+            valid_until: None,
+        });
+        self
+    }
+}
+impl MboSource for VecSource {
+    fn metadata(&self) -> Option<&Metadata> {
+        None
+    }
+
+    fn symbol(&self, mbo_msg: &MboMsg) -> Option<&str> {
+        self.symbols.get(&mbo_msg.hd.instrument_id)
+            .map(String::as_str)
+    }
+
+    fn take_instruments(&mut self) -> Vec<Instrument> {
+        std::mem::take(&mut self.instruments)
+    }
+
+    async fn next_mbo(&mut self) -> Result<Option<MboMsg>> {
+        Ok(self.messages.pop_front())
+    }
+}
@@ -0,0 +1,273 @@
+pub mod decoder;
+pub mod live;
+#[cfg(test)]
+pub mod memory;
+pub mod storage;
+pub mod symbology;
+
+use std::{future::Future, path::Path};
+
+use databento::dbn::{MboMsg, Metadata};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    datatypes::market::{MarketSnapshot, load_market_snapshots},
+    storage::{Instrument, MarketStore, MessageQuery, Storage, StoredDataset},
+};
+use self::{decoder::HistoricalConfig, storage::StorageSource};
+
+/// A source of MBO messages the market can be built from
+///
+/// Implemented for DBN files and historical requests (`decoder`), the
+///  live gateway (`live`), in-memory vectors (`memory`) and replays of
+///  previously persisted messages (`storage`), so the book-building
+///  pipeline doesn't need to know where its messages come from.
+pub trait MboSource {
+    /// DBN metadata describing the source, if it has any
+    fn metadata(&self) -> Option<&Metadata>;
+
+    /// Raw symbol of the instrument an MBO message belongs to, if the
+    ///  source carries symbology
+    fn symbol(&self, mbo_msg: &MboMsg) -> Option<&str>;
+
+    /// Symbol mappings learned since the last call, so ingestion can
+    ///  persist them as they arrive
+    fn take_instruments(&mut self) -> Vec<Instrument>;
+
+    /// Fetch the next MBO message, or `None` once the source is exhausted
+    ///
+    /// Records of other schemas are consumed internally (e.g. to keep
+    ///  symbology current) and never returned.
+    async fn next_mbo(&mut self) -> Result<Option<MboMsg>>;
+}
+
+/// Identifies a dataset which may already be persisted to storage
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    /// Changes whenever the underlying data does
+    pub fingerprint: String,
+    /// Human-readable description of where the data comes from
+    pub source: String,
+}
+impl Dataset {
+    /// Identify a DBN file by a SHA-256 of its contents, so moving or
+    ///  touching the file doesn't cause it to be ingested again
+    #[tracing::instrument]
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let path = path.canonicalize()
+            .context(format!("...while resolving DBN file path {:?}", path))?;
+        let mut file = std::fs::File::open(&path)
+            .context("...while opening DBN file to fingerprint it")?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)
+            .context("...while hashing DBN file")?;
+
+        Ok(Self {
+            fingerprint: format!("sha256:{:x}", hasher.finalize()),
+            source: format!("file:{}", path.display()),
+        })
+    }
+
+    /// Identify a historical request by its parameters
+    pub fn from_historical(config: &HistoricalConfig) -> Self {
+        let source = format!(
+            "historical:{}:{}:{}:{}",
+            config.dataset,
+            config.symbols.join(","),
+            config.start.unix_timestamp_nanos(),
+            config.end.unix_timestamp_nanos()
+        );
+
+        Self {
+            fingerprint: source.clone(),
+            source,
+        }
+    }
+}
+
+/// Build market snapshots from a dataset, persisting its messages only
+///  the first time it is seen
+///
+/// If the dataset was already persisted its messages aren't inserted
+///  again, and with `rebuild_from_storage` the source isn't even opened -
+///  the market is rebuilt from the rows it inserted instead, unless they
+///  no longer cover it.
+///
+/// The rows a dataset inserted are told apart by their IDs, so nothing
+///  else may write messages to the store while it is being persisted.
+#[tracing::instrument(skip(open, storage))]
+pub async fn load_dataset<S, F>(
+    dataset: &Dataset,
+    open: F,
+    storage: &Storage,
+    rebuild_from_storage: bool,
+) -> Result<Vec<MarketSnapshot>>
+where
+    S: MboSource,
+    F: Future<Output = Result<S>>,
+{
+    let stored = storage.dataset(&dataset.fingerprint)
+        .await
+        .context("...while checking whether the dataset is persisted")?;
+
+    if let Some(stored) = stored.as_ref().filter(|_| rebuild_from_storage) {
+        info!(source = %dataset.source, "Dataset already persisted, rebuilding market from storage");
+
+        match replay_dataset(storage, stored).await? {
+            Some(snapshots) => return Ok(snapshots),
+            None => warn!(source = %dataset.source, "Stored messages don't cover the dataset, reading its source"),
+        }
+    }
+
+    let mut source = open.await
+        .context("...while opening dataset source")?;
+    if stored.is_some() {
+        info!(source = %dataset.source, "Dataset already persisted, skipping re-insertion");
+
+        return load_market_snapshots(&mut source, None)
+            .await
+            .context("...while loading market from dataset");
+    }
+
+    let latest = MessageQuery { descending: true, ..Default::default() };
+    let before = first_message_id(storage, latest.clone()).await?;
+    let snapshots = load_market_snapshots(&mut source, Some((storage, &dataset.fingerprint)))
+        .await
+        .context("...while loading and persisting market from dataset")?;
+
+    // Messages already stored by another dataset aren't inserted again,
+    //  so the rows inserted may not cover this one
+    let first = first_message_id(storage, MessageQuery { after_id: before, ..Default::default() }).await?;
+    let last = first_message_id(storage, latest).await?;
+
+    // Only recorded once every message made it to storage, so an
+    //  interrupted ingest is retried on the next start
+    storage.record_dataset(&StoredDataset {
+        fingerprint: dataset.fingerprint.clone(),
+        source: dataset.source.clone(),
+        message_count: snapshots.len(),
+        message_ids: first.zip(last),
+    }).await
+        .context("...while recording persisted dataset")?;
+
+    Ok(snapshots)
+}
+
+/// Rebuild market snapshots from the rows a dataset inserted
+///
+/// Returns `None` if they don't cover the dataset, e.g. as some of its
+///  messages were already stored by another dataset or were since
+///  deleted by retention.
+#[tracing::instrument(skip(storage))]
+pub async fn replay_dataset(storage: &Storage, dataset: &StoredDataset) -> Result<Option<Vec<MarketSnapshot>>> {
+    let Some(query) = dataset.message_query() else {
+        return Ok(None);
+    };
+
+    let mut source = StorageSource::new(storage.clone(), query);
+    let snapshots = load_market_snapshots(&mut source, None)
+        .await
+        .context("...while rebuilding market from storage")?;
+
+    Ok((snapshots.len() == dataset.message_count).then_some(snapshots))
+}
+
+/// ID of the first row a query returns, if any
+async fn first_message_id(storage: &Storage, query: MessageQuery) -> Result<Option<i64>> {
+    let rows = storage.query_messages(&MessageQuery {
+        limit: Some(1),
+        ..query
+    }).await
+        .context("...while reading stored message IDs")?;
+
+    Ok(rows.first().map(|stored| stored.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::decoder::DbnSource;
+
+    #[tokio::test]
+    async fn test_dataset_persisted_once_and_rebuilt_from_storage() -> Result<()> {
+        let temp_db = "test_ingest_dataset.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = Storage::open(temp_db).await?;
+        let path = Path::new("assets/CLX5_mbo.dbn");
+        let dataset = Dataset::from_file(path)?;
+
+        // First start persists every message
+        let first = load_dataset(&dataset, DbnSource::from_file(path), &storage, false).await?;
+        let persisted = storage.count_messages().await?;
+        assert_eq!(persisted, first.len());
+        let stored = storage.dataset(&dataset.fingerprint).await?.context("Dataset should be recorded")?;
+        assert_eq!(stored.message_count, first.len());
+        assert_eq!(storage.datasets().await?, vec![stored.clone()]);
+        let instruments = storage.instruments().await?;
+        assert!(instruments.iter().any(|instrument| instrument.raw_symbol == "CLX5"));
+
+        // Restarting doesn't duplicate rows
+        let second = load_dataset(&dataset, DbnSource::from_file(path), &storage, false).await?.len();
+        assert_eq!(second, first.len());
+        assert_eq!(storage.count_messages().await?, persisted, "Restart should not re-insert messages");
+
+        // Rebuilding from storage must not open the source at all
+        let rebuilt = load_dataset(
+            &dataset,
+            async { anyhow::bail!("Source should not be opened") as Result<DbnSource<tokio::fs::File>> },
+            &storage,
+            true,
+        ).await?;
+        assert_eq!(rebuilt.len(), first.len());
+        assert_eq!(storage.count_messages().await?, persisted);
+        assert_eq!(storage.instruments().await?, instruments, "Restarts should not duplicate instruments");
+
+        // Replays resolve symbols from the persisted instruments
+        for (expected, actual) in first.iter().zip(&rebuilt) {
+            assert_eq!(expected.mbomsg_effect.symbol, actual.mbomsg_effect.symbol);
+        }
+        assert!(rebuilt.iter().all(|snapshot| snapshot.mbomsg_effect.symbol.is_some()));
+
+        let expected = first.last().context("No snapshots")?;
+        let actual = rebuilt.last().context("No rebuilt snapshots")?;
+        let instrument_id = expected.mbomsg_effect.mbo_msg.hd.instrument_id;
+        assert_eq!(
+            serde_json::to_string(&expected.market.aggregated_bbo(instrument_id))?,
+            serde_json::to_string(&actual.market.aggregated_bbo(instrument_id))?,
+        );
+
+        // Every load holds a market per message, so only the first is kept
+        drop(rebuilt);
+
+        // Rows written afterwards, e.g. by live ingestion, aren't part of
+        //  the rebuilt dataset
+        let mut later = expected.mbomsg_effect.mbo_msg.clone();
+        later.ts_recv += 1;
+        assert_eq!(storage.insert_mbo_batch(&[later]).await?, 1);
+        let rebuilt = load_dataset(
+            &dataset,
+            async { anyhow::bail!("Source should not be opened") as Result<DbnSource<tokio::fs::File>> },
+            &storage,
+            true,
+        ).await?.len();
+        assert_eq!(rebuilt, first.len());
+
+        // Once retention deleted some of its rows, the source is read again
+        let cutoff = first[first.len() / 2].mbomsg_effect.mbo_msg.ts_recv;
+        assert!(crate::storage::retention::compact(&storage, cutoff).await?.deleted_messages > 0);
+        assert!(replay_dataset(&storage, &stored).await?.is_none());
+        let reread = load_dataset(&dataset, DbnSource::from_file(path), &storage, true).await?.len();
+        assert_eq!(reread, first.len());
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+}
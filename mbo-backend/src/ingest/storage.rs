@@ -0,0 +1,54 @@
+use databento::dbn::{MboMsg, Metadata};
+use anyhow::{Context, Result};
+
+use super::{MboSource, symbology::Symbology};
+use crate::storage::{Instrument, MarketStore, MessageQuery, MessageStream};
+
+/// Replay of the MBO messages persisted in a store matching a query, in
+///  insertion order
+///
+/// Symbols are resolved from the store's instruments, which are loaded
+///  along with the first message.
+pub struct StorageSource<S> {
+    store: S,
+    messages: MessageStream<S>,
+    symbology: Option<Symbology>,
+}
+impl<S: MarketStore + Clone> StorageSource<S> {
+    pub fn new(store: S, query: MessageQuery) -> Self {
+        Self {
+            messages: MessageStream::new(store.clone(), query),
+            store,
+            symbology: None,
+        }
+    }
+}
+impl<S: MarketStore + Clone> MboSource for StorageSource<S> {
+    fn metadata(&self) -> Option<&Metadata> {
+        None
+    }
+
+    fn symbol(&self, mbo_msg: &MboMsg) -> Option<&str> {
+        self.symbology.as_ref()?
+            .symbol(mbo_msg.hd.instrument_id, mbo_msg.ts_recv)
+    }
+
+    /// The instruments replayed messages map to are already persisted
+    fn take_instruments(&mut self) -> Vec<Instrument> {
+        Vec::new()
+    }
+
+    async fn next_mbo(&mut self) -> Result<Option<MboMsg>> {
+        if self.symbology.is_none() {
+            let instruments = self.store.instruments()
+                .await
+                .context("...while loading stored instruments")?;
+            self.symbology = Some(Symbology::new(instruments));
+        }
+
+        Ok(self.messages.next_message()
+            .await
+            .context("...while reading stored MBO messages")?
+            .map(|stored| stored.mbo_msg))
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use databento::dbn::{Metadata, SType, SymbolMappingMsg, UNDEF_TIMESTAMP};
+use anyhow::{Context, Result, anyhow};
+use time::Date;
+
+use crate::storage::Instrument;
+
+/// Resolves instrument IDs to raw symbols at a point in time
+#[derive(Debug, Clone, Default)]
+pub struct Symbology {
+    mappings: HashMap<u32, Vec<Instrument>>,
+}
+impl Symbology {
+    pub fn new(instruments: impl IntoIterator<Item = Instrument>) -> Self {
+        let mut mappings = HashMap::<u32, Vec<Instrument>>::new();
+        for instrument in instruments {
+            mappings.entry(instrument.instrument_id)
+                .or_default()
+                .push(instrument);
+        }
+
+        Self { mappings }
+    }
+
+    /// Raw symbol of an instrument at `ts`, in nanoseconds since the UNIX epoch
+    pub fn symbol(&self, instrument_id: u32, ts: u64) -> Option<&str> {
+        self.mappings.get(&instrument_id)?
+            .iter()
+            .find(|instrument| instrument.is_valid_at(ts))
+            .map(|instrument| instrument.raw_symbol.as_str())
+    }
+}
+
+/// Symbol mappings carried by DBN metadata, each valid over whole UTC days
+///
+/// Only metadata mapping raw symbols to instrument IDs can be used, for
+///  any other output symbology there's nothing to return.
+pub fn instruments_from_metadata(metadata: &Metadata) -> Result<Vec<Instrument>> {
+    if metadata.stype_out != SType::InstrumentId {
+        return Ok(Vec::new());
+    }
+
+    let mut instruments = Vec::new();
+    for mapping in &metadata.mappings {
+        for interval in &mapping.intervals {
+            // Symbols which didn't resolve on a date have an empty mapping
+            if interval.symbol.is_empty() {
+                continue;
+            }
+
+            instruments.push(Instrument {
+                instrument_id: interval.symbol.parse()
+                    .context(format!("...while parsing instrument ID `{}`", interval.symbol))?,
+                raw_symbol: mapping.raw_symbol.clone(),
+                valid_from: date_to_nanos(interval.start_date)?,
+                valid_until: Some(date_to_nanos(interval.end_date)?),
+            });
+        }
+    }
+
+    Ok(instruments)
+}
+
+/// Symbol mapping sent by the live gateway
+pub fn instrument_from_symbol_mapping(msg: &SymbolMappingMsg) -> Result<Instrument> {
+    Ok(Instrument {
+        instrument_id: msg.hd.instrument_id,
+        raw_symbol: msg.stype_out_symbol()
+            .context("...while decoding symbol mapping")?
+            .to_string(),
+        valid_from: msg.start_ts,
+        valid_until: (msg.end_ts != UNDEF_TIMESTAMP).then_some(msg.end_ts),
+    })
+}
+
+fn date_to_nanos(date: Date) -> Result<u64> {
+    u64::try_from(date.midnight().assume_utc().unix_timestamp_nanos())
+        .map_err(|_| anyhow!("Date {} is before the UNIX epoch", date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::ingest::{decoder::DbnSource, MboSource};
+
+    #[tokio::test]
+    async fn test_metadata_symbology_matches_source() -> Result<()> {
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let instruments = instruments_from_metadata(source.metadata().context("No metadata")?)?;
+        assert!(!instruments.is_empty(), "File should carry symbol mappings");
+
+        // Every message resolves to the symbol the DBN symbol map gives it
+        let symbology = Symbology::new(instruments);
+        let mut checked = 0;
+        while let Some(mbo_msg) = source.next_mbo().await? {
+            assert_eq!(
+                symbology.symbol(mbo_msg.hd.instrument_id, mbo_msg.ts_recv),
+                source.symbol(&mbo_msg),
+            );
+            checked += 1;
+        }
+        assert!(checked > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbology_respects_validity() {
+        let symbology = Symbology::new([
+            Instrument {
+                instrument_id: 1,
+                raw_symbol: "CLX5".to_string(),
+                valid_from: 10,
+                valid_until: Some(20),
+            },
+            Instrument {
+                instrument_id: 1,
+                raw_symbol: "CLZ5".to_string(),
+                valid_from: 20,
+                valid_until: None,
+            },
+        ]);
+
+        assert_eq!(symbology.symbol(1, 5), None);
+        assert_eq!(symbology.symbol(1, 10), Some("CLX5"));
+        assert_eq!(symbology.symbol(1, 20), Some("CLZ5"));
+        assert_eq!(symbology.symbol(1, u64::MAX), Some("CLZ5"));
+        assert_eq!(symbology.symbol(2, 10), None);
+    }
+}
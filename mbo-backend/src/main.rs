@@ -2,18 +2,32 @@ mod datatypes;
 mod api;
 mod storage;
 mod metrics;
+mod ingest;
+mod archive;
+mod artifacts;
+mod delta;
+mod export;
+mod analytics;
 
-use std::{path::Path, sync::Arc, time::Duration};
+use std::{io::{BufWriter, Write}, path::Path, sync::Arc, time::{Duration, SystemTime}};
 
 use databento::HistoricalClient;
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
 use tokio::sync::RwLock;
-use tracing::{info, warn};
-use std::io::Write;
+use tracing::{info, error};
 
-use crate::datatypes::market::{MarketSnapshot, load_market_snapshots};
+use crate::archive::ArchiveConfig;
+use crate::artifacts::ArtifactManifest;
+use crate::datatypes::market::{MarketSnapshot, restore_market};
+use crate::delta::reader::DeltaReader;
+use crate::export::ExportCache;
+use crate::ingest::{
+    Dataset, load_dataset, replay_dataset,
+    decoder::{self, DbnSource, HistoricalConfig},
+    live::{LiveConfig, LiveFeed, LiveSource, mock_gateway::MockGateway},
+};
 
-use self::storage::Storage;
+use self::storage::{retention::{self, RetentionConfig}, MarketStore, Storage, LIVE_SOURCE};
 use self::metrics::Metrics;
 
 
@@ -22,13 +36,23 @@ pub struct State {
     pub market_snapshots: Vec<MarketSnapshot>,
     pub storage: Storage,
     pub metrics: Arc<Metrics>,
+    pub live_feed: Option<LiveFeed>,
+    pub retention: Option<RetentionConfig>,
+    /// Bearer token required by admin endpoints which change the store,
+    ///  which are disabled without one
+    pub admin_token: Option<String>,
+    pub export_cache: ExportCache,
+    /// What the loaded market was built from
+    pub artifacts: ArtifactManifest,
+    /// When the market was loaded, i.e. last changed
+    pub loaded_at: SystemTime,
 }
 impl State {
     #[tracing::instrument]
-    fn from_env() -> Result<Self> {
+    async fn from_env() -> Result<Self> {
         // Load DBN API key from environment variable and
         //  initialize the DBN client
-        let dbn_client = {
+        let mut dbn_client = {
             let dbn_api_key = std::env::var("DBN_KEY")
                 .context("DBN_KEY environment variable not set")?;
 
@@ -39,103 +63,116 @@ impl State {
                 .context("...while building DBN client")?
         };
 
-        // Initialize SQLite storage
-        let storage = {
-            let db_path = std::env::var("DB_PATH")
-                .unwrap_or("mbo_data.db".to_string());
-            
-            Storage::new(db_path)
-                .context("...while initializing SQLite storage")?
-        };
+        // Initialize storage, SQLite unless configured otherwise
+        let storage = Storage::from_env()
+            .await
+            .context("...while initializing storage")?;
 
-        // Build the market from a DBN file path specified
-        let market_snapshots = {
-            let dbn_file_path_st = std::env::var("DBN_FILE_PATH")
-                .unwrap_or("assets/CLX5_mbo.dbn".to_string());
-            let path = Path::new(&dbn_file_path_st);
+        // Whether to rebuild the market from storage instead of re-reading
+        //  a dataset that has already been persisted
+        let rebuild_from_storage = std::env::var("REBUILD_FROM_STORAGE")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
 
-            load_market_snapshots(path, Some(&storage))
-                .context("...while loading market from DBN file")?
-        };
+        // Build the market from the configured source - a DBN file by
+        //  default, or a historical request or a replay of storage
+        let (dataset, market_snapshots) = match std::env::var("INGEST_SOURCE")
+            .unwrap_or("file".to_string())
+            .as_str()
+        {
+            "file" => {
+                let dbn_file_path_st = std::env::var("DBN_FILE_PATH")
+                    .unwrap_or("assets/CLX5_mbo.dbn".to_string());
+                let path = Path::new(&dbn_file_path_st);
+                let dataset = Dataset::from_file(path)
+                    .context("...while identifying DBN file")?;
 
-        // Write each snapshot to `assets/snapshots/<index>.json`
-        let snapshots_dir_path = std::env::var("SNAPSHOTS_FILE_PATH")
-            .unwrap_or("assets/snapshots".to_string());
-        std::fs::create_dir_all(&snapshots_dir_path)
-            .context("...while creating snapshots directory")?;
-        for (i, snapshot) in market_snapshots.iter().enumerate() {
-            let snapshot_path = Path::new(&snapshots_dir_path)
-                .join(format!("snapshot_{}.json", i));
-
-            // Check that the file doesn't already exist
-            if snapshot_path.exists() {
-                info!("Snapshot file {:?} already exists, skipping write", snapshot_path);
-                continue;
+                let snapshots = load_dataset(&dataset, DbnSource::from_file(path), &storage, rebuild_from_storage)
+                    .await
+                    .context("...while loading market from DBN file")?;
+                (dataset, snapshots)
             }
-            
-            // Serialize to JSON
-            let snapshot_json = serde_json::to_string(&snapshot)
-                .context("...while serializing snapshot to JSON")?;
+            "historical" => {
+                let config = HistoricalConfig::from_env()
+                    .context("...while loading historical configuration")?;
+                let dataset = Dataset::from_historical(&config);
 
-            // Write to file
-            std::fs::write(&snapshot_path, snapshot_json)
-                .context(format!("...while writing snapshot to {:?}", snapshot_path))?;
+                let snapshots = load_dataset(
+                    &dataset,
+                    decoder::from_historical(&mut dbn_client, &config),
+                    &storage,
+                    rebuild_from_storage,
+                ).await
+                    .context("...while loading market from historical data")?;
+                (dataset, snapshots)
+            }
+            "storage" => {
+                // Messages are already persisted, so they aren't written
+                //  again - only those of one dataset are replayed, the one
+                //  persisted last unless `INGEST_DATASET` names another
+                let stored = match std::env::var("INGEST_DATASET") {
+                    Ok(fingerprint) => storage.dataset(&fingerprint)
+                        .await
+                        .context("...while reading persisted dataset")?
+                        .context(format!("Dataset {} has not been persisted", fingerprint))?,
+                    Err(_) => storage.datasets()
+                        .await
+                        .context("...while reading persisted datasets")?
+                        .pop()
+                        .context("No dataset has been persisted")?,
+                };
+                let snapshots = replay_dataset(&storage, &stored)
+                    .await
+                    .context("...while loading market from storage")?
+                    .context(format!("Stored messages don't cover dataset {}", stored.source))?;
+                (Dataset { fingerprint: stored.fingerprint, source: stored.source }, snapshots)
+            }
+            other => bail!("Unknown INGEST_SOURCE `{}`, expected `file`, `historical` or `storage`", other),
+        };
 
-            warn!("First generation of snapshot {} to {:?}", i, snapshot_path);
-        }
+        // Generated artifacts are stamped with what they were built from,
+        //  and regenerated once that changes
+        let artifacts = ArtifactManifest::new(&dataset);
+        info!(source = %dataset.source, fingerprint = %artifacts.source, "Loaded market");
 
-        // Zip the entire thing into `assets/feed.zip`
-        {
-            let zip_path_str = std::env::var("ZIP_FILE_PATH")
-                .unwrap_or("assets/feed.zip".to_string());
-            let zip_path = Path::new(&zip_path_str);
-            // Check that the zip file doesn't already exist
-            if !zip_path.exists() {
-                info!("ZIP file {:?} already exists, skipping creation", zip_path);
-
-                let file = std::fs::File::create(&zip_path)
-                    .context("...while creating zip file")?;
-                let mut zip = zip::ZipWriter::new(file);
-
-                let options: zip::write::FileOptions<'_, ()> = zip::write::FileOptions::default()
-                    .compression_method(zip::CompressionMethod::Stored)
-                    .unix_permissions(0o644);
-
-                for entry in std::fs::read_dir(snapshots_dir_path)
-                    .context("...while reading snapshots directory")? 
-                {
-                    let entry = entry.context("...while reading snapshot entry")?;
-                    let path = entry.path();
-                    if path.is_file() {
-                        let name = path.file_name()
-                            .and_then(|n| n.to_str())
-                            .ok_or_else(|| anyhow::anyhow!("Invalid filename"))?;
-                        
-                        zip.start_file(name, options)
-                            .context("...while adding file to zip")?;
-                        
-                        let data = std::fs::read(&path)
-                            .context("...while reading snapshot file for zipping")?;
-                        
-                        zip.write_all(&data)
-                            .context("...while writing file data to zip")?;
-                    }
-                }
-
-                zip.finish()
-                    .context("...while finalizing zip file")?;
-            }
+        // Archive the loaded market as Parquet, if configured
+        if let Some(config) = ArchiveConfig::from_env()
+            .context("...while loading archive configuration")? {
+            archive::write_archive(&config, &artifacts, &market_snapshots)
+                .context("...while writing Parquet archive")?;
         }
 
         // Initialize metrics
         let metrics = Metrics::new()
             .context("...while initializing metrics")?;
 
+        // Live ingestion is only enabled when symbols are configured
+        let live_feed = std::env::var("DBN_LIVE_SYMBOLS")
+            .is_ok()
+            .then(LiveFeed::new);
+
+        let retention = RetentionConfig::from_env()
+            .context("...while loading retention configuration")?;
+        let admin_token = std::env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+
+        let export_cache = ExportCache::from_env(artifacts.clone())
+            .context("...while loading export cache configuration")?;
+        export_cache.invalidate_stale()
+            .context("...while invalidating stale exports")?;
+
         Ok(Self {
             dbn_client,
             market_snapshots,
             storage,
             metrics,
+            live_feed,
+            retention,
+            admin_token,
+            export_cache,
+            artifacts,
+            loaded_at: SystemTime::now(),
         })
     }
 }
@@ -154,12 +191,27 @@ async fn main() -> Result<()> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
+    // `mbo decode` reads an export back instead of serving
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("decode") {
+        return decode(args);
+    }
+
     // Initialize state from environment variables
     println!("Loading application state...");
     let state = Arc::new(RwLock::new(State::from_env()
+        .await
         .context("...while loading configuration from environment")?));
     println!("State loaded successfully!");
 
+    // Start the live gateway ingest in the background, if configured
+    spawn_live_ingest(&state)
+        .await
+        .context("...while starting live ingestion")?;
+
+    // Compact stored messages in the background, if configured
+    spawn_retention(&state).await;
+
     // Build the API router
     let app = api::router(Arc::clone(&state));
 
@@ -181,6 +233,136 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Print the snapshots of a delta export, such as an export archive's
+///  `market.mbod`, as JSON lines
+///
+/// Usage: `mbo decode <market.mbod> [index]`, where `index` selects a
+///  single snapshot, which is skipped to from the keyframe before it.
+fn decode(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let path = args.next()
+        .context("Usage: mbo decode <market.mbod> [index]")?;
+    let index = args.next()
+        .map(|index| index.parse::<u64>())
+        .transpose()
+        .context("...while parsing snapshot index")?;
+
+    let file = std::fs::File::open(&path)
+        .context(format!("Failed to open {}", path))?;
+    let mut reader = DeltaReader::new(file)
+        .context("...while reading delta export")?;
+    eprintln!(
+        "Decoding {} ({:?} compression, keyframe every {} messages)",
+        path, reader.compression, reader.keyframe_interval,
+    );
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    let mut print = |snapshot: &MarketSnapshot| -> Result<()> {
+        serde_json::to_writer(&mut stdout, snapshot)
+            .context("...while writing snapshot")?;
+        writeln!(stdout)
+            .context("...while writing snapshot")
+    };
+    match index {
+        Some(index) => {
+            let snapshot = reader.snapshot(index)?
+                .context(format!("Export has no snapshot {}", index))?;
+            print(&snapshot)?;
+        }
+        None => {
+            while let Some(snapshot) = reader.next_snapshot()? {
+                print(&snapshot)?;
+            }
+        }
+    }
+
+    stdout.flush()
+        .context("...while writing snapshots")
+}
+
+/// Spawn the live ingest task if `DBN_LIVE_SYMBOLS` is set
+///
+/// When `DBN_LIVE_MOCK_FILE` is set, a local mock gateway replaying that
+///  DBN file is started first and the ingest is pointed at it, which
+///  allows running the live pipeline without a Databento live license.
+///
+/// When `DBN_LIVE_RESUME` is `true`, the live market starts from the
+///  latest checkpoint in storage, plus the messages persisted after it,
+///  instead of an empty market.
+async fn spawn_live_ingest(state: &Arc<RwLock<State>>) -> Result<()> {
+    let Some(mut config) = LiveConfig::from_env()
+        .context("...while loading live configuration")? else {
+        info!("DBN_LIVE_SYMBOLS not set, live ingestion disabled");
+        return Ok(());
+    };
+
+    if let Ok(mock_file) = std::env::var("DBN_LIVE_MOCK_FILE") {
+        let record_delay = std::env::var("DBN_LIVE_MOCK_DELAY_US")
+            .ok()
+            .map(|delay| delay.parse::<u64>())
+            .transpose()
+            .context("...while parsing DBN_LIVE_MOCK_DELAY_US")?
+            .unwrap_or(0);
+        let gateway = MockGateway::bind(
+            "127.0.0.1:0",
+            Path::new(&mock_file),
+            Duration::from_micros(record_delay),
+        ).await
+            .context("...while starting mock live gateway")?;
+        config.gateway = Some(gateway.local_addr()?.to_string());
+
+        tokio::spawn(async move {
+            if let Err(e) = gateway.serve().await {
+                error!("Mock live gateway stopped: {:?}", e);
+            }
+        });
+    }
+
+    let (feed, storage, metrics) = {
+        let state = state.read().await;
+        let feed = state.live_feed.clone()
+            .context("Live feed was not initialized")?;
+        (feed, state.storage.clone(), Arc::clone(&state.metrics))
+    };
+
+    let resume = std::env::var("DBN_LIVE_RESUME")
+        .is_ok_and(|resume| resume.eq_ignore_ascii_case("true"));
+    if resume {
+        let (market, message_id) = restore_market(&storage, LIVE_SOURCE, None)
+            .await
+            .context("...while restoring live market from checkpoint")?;
+        info!(?message_id, "Resuming live market");
+        *feed.market.write().await = market;
+    }
+
+    tokio::spawn(async move {
+        let source = match LiveSource::connect(&config).await {
+            Ok(source) => source,
+            Err(e) => {
+                error!("Failed to connect to live gateway: {:?}", e);
+                return;
+            }
+        };
+
+        match ingest::live::run(source, feed, Some(storage), metrics).await {
+            Ok(applied) => info!(applied, "Live ingestion finished"),
+            Err(e) => error!("Live ingestion failed: {:?}", e),
+        }
+    });
+
+    Ok(())
+}
+
+/// Spawn the compaction task if `RETENTION_RAW_DAYS` is set
+async fn spawn_retention(state: &Arc<RwLock<State>>) {
+    let state = state.read().await;
+    let Some(config) = state.retention.clone() else {
+        info!("RETENTION_RAW_DAYS not set, stored messages are kept forever");
+        return;
+    };
+
+    info!(raw_days = config.raw_days, interval = ?config.interval, "Starting compaction task");
+    tokio::spawn(retention::run(state.storage.clone(), config, Arc::clone(&state.metrics)));
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
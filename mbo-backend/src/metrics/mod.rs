@@ -25,6 +25,14 @@ pub struct Metrics {
     // Database metrics
     pub db_operations_total: Counter,
     pub db_operation_duration: Histogram,
+
+    // Retention metrics
+    pub storage_messages: IntGauge,
+    pub storage_checkpoints: IntGauge,
+    pub storage_bars: IntGauge,
+    pub storage_size_bytes: IntGauge,
+    pub compacted_messages: Counter,
+    pub last_compaction_timestamp: IntGauge,
 }
 
 impl Metrics {
@@ -89,6 +97,37 @@ impl Metrics {
         )?;
         registry.register(Box::new(db_operation_duration.clone()))?;
         
+        // Retention
+        let storage_messages = IntGauge::with_opts(
+            Opts::new("mbo_storage_messages", "Number of raw MBO messages stored")
+        )?;
+        registry.register(Box::new(storage_messages.clone()))?;
+        
+        let storage_checkpoints = IntGauge::with_opts(
+            Opts::new("mbo_storage_checkpoints", "Number of market checkpoints stored")
+        )?;
+        registry.register(Box::new(storage_checkpoints.clone()))?;
+        
+        let storage_bars = IntGauge::with_opts(
+            Opts::new("mbo_storage_bars", "Number of bars derived from compacted messages")
+        )?;
+        registry.register(Box::new(storage_bars.clone()))?;
+        
+        let storage_size_bytes = IntGauge::with_opts(
+            Opts::new("mbo_storage_size_bytes", "Size of the database on disk")
+        )?;
+        registry.register(Box::new(storage_size_bytes.clone()))?;
+        
+        let compacted_messages = Counter::with_opts(
+            Opts::new("mbo_compacted_messages_total", "Total number of raw messages deleted by compaction")
+        )?;
+        registry.register(Box::new(compacted_messages.clone()))?;
+        
+        let last_compaction_timestamp = IntGauge::with_opts(
+            Opts::new("mbo_last_compaction_timestamp_seconds", "UNIX time of the last completed compaction")
+        )?;
+        registry.register(Box::new(last_compaction_timestamp.clone()))?;
+        
         Ok(Arc::new(Self {
             registry,
             messages_processed,
@@ -101,6 +140,12 @@ impl Metrics {
             http_request_duration,
             db_operations_total,
             db_operation_duration,
+            storage_messages,
+            storage_checkpoints,
+            storage_bars,
+            storage_size_bytes,
+            compacted_messages,
+            last_compaction_timestamp,
         }))
     }
     
@@ -1,313 +1,700 @@
-use rusqlite::{Connection, params};
-use databento::dbn::MboMsg;
-use anyhow::{Context, Result};
-use tracing::{info, debug};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-
-pub struct Storage {
-    conn: Arc<Mutex<Connection>>,
+pub mod postgres;
+pub mod retention;
+pub mod sqlite;
+
+use std::{collections::{HashMap, VecDeque}, path::Path};
+
+use databento::dbn::{Action, MboMsg, Side};
+use anyhow::{Context, Result, bail};
+
+use self::{postgres::PostgresStore, sqlite::SqliteStore};
+
+/// Number of rows fetched per query while streaming messages
+const PAGE_SIZE: usize = 10_000;
+
+/// Persistence of MBO messages and the state derived from them
+///
+/// Implemented for SQLite (`sqlite`) and Postgres/TimescaleDB
+///  (`postgres`). Application code uses the `Storage` handle, which
+///  dispatches to whichever backend was configured.
+pub trait MarketStore {
+    /// Insert a batch of messages, ignoring any that are already stored
+    ///
+    /// Messages sharing a natural key are told apart by their position
+    ///  within the batch, so a batch must not split an event - flush on
+    ///  `F_LAST` - for re-ingestion to line up with what was stored.
+    ///
+    /// Returns the number of messages that were new.
+    async fn insert_mbo_batch(&self, messages: &[MboMsg]) -> Result<usize>;
+
+    /// Row ID of a persisted message, matched on its natural key
+    ///
+    /// When several stored messages share the key, the latest row is
+    ///  returned, which is the last of them in the batch.
+    async fn find_message_id(&self, mbo_msg: &MboMsg) -> Result<Option<i64>>;
+
+    /// Read every message matching a query
+    ///
+    /// Prefer a `MessageStream` when the result set may be large.
+    async fn query_messages(&self, query: &MessageQuery) -> Result<Vec<StoredMbo>>;
+
+    async fn count_messages(&self) -> Result<usize>;
+
+    /// The dataset with this fingerprint, if it was already fully persisted
+    async fn dataset(&self, fingerprint: &str) -> Result<Option<StoredDataset>>;
+
+    /// Every persisted dataset, the one recorded most recently last
+    async fn datasets(&self) -> Result<Vec<StoredDataset>>;
+
+    /// Record that every message of a dataset has been persisted
+    async fn record_dataset(&self, dataset: &StoredDataset) -> Result<()>;
+
+    /// Insert symbol mappings, updating any already known for the same
+    ///  instrument and start of validity
+    async fn upsert_instruments(&self, instruments: &[Instrument]) -> Result<()>;
+
+    /// Every known symbol mapping, by instrument ID then start of validity
+    async fn instruments(&self) -> Result<Vec<Instrument>>;
+
+    /// Insert a checkpoint, replacing any taken at the same message
+    async fn insert_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()>;
+
+    /// Most recent checkpoint of `source`, or of any source if `None`,
+    ///  taken at or before message row `message_id`, or the most recent
+    ///  overall if `None`
+    async fn latest_checkpoint(&self, source: Option<&str>, message_id: Option<i64>) -> Result<Option<Checkpoint>>;
+
+    /// Number of compactions applied so far
+    async fn compaction_generation(&self) -> Result<u64>;
+
+    /// Store a compaction's bars, merging any with a bar already stored
+    ///  for the same instrument and interval as trades which came after
+    ///  it, and delete the messages they were built from, all in one
+    ///  transaction
+    ///
+    /// Returns the number of messages deleted, or `None` without changing
+    ///  anything if another compaction was applied since the generation
+    ///  it was built at, as both could count the same trades.
+    async fn apply_compaction(&self, compaction: &Compaction) -> Result<Option<usize>>;
+
+    /// Bars by instrument ID then start of interval, optionally filtered
+    ///  on instrument and an inclusive range of interval starts
+    async fn bars(&self, instrument_id: Option<u32>, start_ts: Option<u64>, end_ts: Option<u64>) -> Result<Vec<Bar>>;
+
+    /// Reclaim the space freed by deleted rows
+    async fn vacuum(&self) -> Result<()>;
+
+    /// Row counts and on-disk size of the store
+    async fn stats(&self) -> Result<StorageStats>;
+}
+
+/// Filters for reading persisted MBO messages
+///
+/// Results are ordered by row ID, i.e. insertion order, or the reverse
+///  with `descending`. Timestamps filter on `ts_recv` and are inclusive.
+#[derive(Debug, Clone, Default)]
+pub struct MessageQuery {
+    pub instrument_id: Option<u32>,
+    pub publisher: Option<u16>,
+    pub order_id: Option<u64>,
+    pub action: Option<Action>,
+    pub side: Option<Side>,
+    pub start_ts: Option<u64>,
+    pub end_ts: Option<u64>,
+    /// Inclusive bounds on row ID
+    pub start_id: Option<i64>,
+    pub end_id: Option<i64>,
+    /// Only return rows past this row ID in query order, for keyset pagination
+    pub after_id: Option<i64>,
+    pub limit: Option<usize>,
+    pub descending: bool,
+}
+
+/// A persisted MBO message along with its row ID
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMbo {
+    pub id: i64,
+    pub mbo_msg: MboMsg,
+}
+
+/// A dataset whose messages were all persisted
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredDataset {
+    pub fingerprint: String,
+    pub source: String,
+    pub message_count: usize,
+    /// Inclusive range of the row IDs its messages were inserted as, or
+    ///  `None` if it inserted none (e.g. they were all already stored, or
+    ///  it was recorded before ranges were)
+    pub message_ids: Option<(i64, i64)>,
+}
+impl StoredDataset {
+    /// Query for the rows the dataset inserted, if any
+    pub fn message_query(&self) -> Option<MessageQuery> {
+        let (start_id, end_id) = self.message_ids?;
+
+        Some(MessageQuery {
+            start_id: Some(start_id),
+            end_id: Some(end_id),
+            ..Default::default()
+        })
+    }
+}
+
+/// Mapping of an instrument ID to its raw symbol over a period of time
+///
+/// Venues reuse instrument IDs, so the same ID can map to different
+///  symbols over non-overlapping periods.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct Instrument {
+    pub instrument_id: u32,
+    pub raw_symbol: String,
+    /// Start of validity, in nanoseconds since the UNIX epoch (inclusive)
+    pub valid_from: u64,
+    /// End of validity, in nanoseconds since the UNIX epoch (exclusive),
+    ///  or `None` if the mapping has no known end
+    pub valid_until: Option<u64>,
+}
+impl Instrument {
+    /// Whether the mapping is valid at a timestamp
+    pub fn is_valid_at(&self, ts: u64) -> bool {
+        ts >= self.valid_from && self.valid_until.is_none_or(|until| ts < until)
+    }
+}
+
+/// Source tag of checkpoints of the live market
+///
+/// Stored messages outside the rows of every dataset are the live
+///  market's.
+pub const LIVE_SOURCE: &str = "live";
+
+/// Serialized market state as of a persisted message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Row ID of the last message applied to the market
+    pub message_id: i64,
+    pub ts_recv: u64,
+    /// What the market was loaded from, `LIVE_SOURCE` or the fingerprint
+    ///  of a dataset
+    pub source: String,
+    pub data: Vec<u8>,
+}
+
+/// Trades of an instrument aggregated over a fixed interval
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct Bar {
+    pub instrument_id: u32,
+    /// Start of the interval, in nanoseconds since the UNIX epoch
+    pub ts: u64,
+    pub open: i64,
+    pub high: i64,
+    pub low: i64,
+    pub close: i64,
+    pub volume: u64,
+    pub trades: u64,
+}
+impl Bar {
+    /// Merge trades which came after the bar's
+    pub fn merge(&mut self, later: &Bar) {
+        self.high = self.high.max(later.high);
+        self.low = self.low.min(later.low);
+        self.close = later.close;
+        self.volume += later.volume;
+        self.trades += later.trades;
+    }
+}
+
+/// Bars built from the trades received before `cutoff_ts`, to be stored
+///  in place of the messages they came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compaction {
+    /// Compactions applied before the bars were built
+    pub generation: u64,
+    pub cutoff_ts: u64,
+    /// Last row the bars were built from - rows inserted afterwards are
+    ///  kept, even if received before the cutoff, until the next one
+    pub last_message_id: i64,
+    pub bars: Vec<Bar>,
+}
+
+/// Size of the store, as reported to metrics and the admin API
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct StorageStats {
+    pub messages: u64,
+    pub checkpoints: u64,
+    pub bars: u64,
+    /// `ts_recv` of the oldest raw message kept, if any
+    pub oldest_ts_recv: Option<u64>,
+    /// Size of the database on disk, in bytes
+    pub size_bytes: u64,
+}
+
+/// A single, ordered step of a backend's schema history
+///
+/// Steps are never edited once released - a change to the schema is
+///  always a new step appended to the backend's `MIGRATIONS`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Handle on the configured storage backend
+#[derive(Clone)]
+pub enum Storage {
+    Sqlite(SqliteStore),
+    Postgres(PostgresStore),
 }
 impl Storage {
+    /// Open a SQLite database
+    pub async fn open<P: AsRef<Path> + std::fmt::Debug>(db_path: P) -> Result<Self> {
+        Ok(Self::Sqlite(SqliteStore::open(db_path).await?))
+    }
+
+    /// Open the backend selected by `STORAGE_BACKEND`, SQLite at `DB_PATH`
+    ///  by default or Postgres at `DATABASE_URL`
     #[tracing::instrument]
-    pub fn new<P: AsRef<Path> + std::fmt::Debug>(db_path: P) -> Result<Self> {
-        info!("Opening SQLite database at {:?}", db_path.as_ref());
-        
-        let conn = Connection::open(db_path)
-            .context("Failed to open SQLite database")?;
-        
-        // Enable WAL mode for better concurrent reads
-        conn.pragma_update(None, "journal_mode", "WAL")
-            .context("Failed to enable WAL mode")?;
-        
-        // Enable foreign keys
-        conn.pragma_update(None, "foreign_keys", "ON")
-            .context("Failed to enable foreign keys")?;
-        
-        let storage = Self { 
-            conn: Arc::new(Mutex::new(conn))
-        };
-        
-        storage.initialize_schema()
-            .context("Failed to initialize database schema")?;
-        
-        Ok(storage)
+    pub async fn from_env() -> Result<Self> {
+        let backend = std::env::var("STORAGE_BACKEND")
+            .unwrap_or("sqlite".to_string());
+
+        match backend.as_str() {
+            "sqlite" => {
+                let db_path = std::env::var("DB_PATH")
+                    .unwrap_or("mbo_data.db".to_string());
+
+                Self::open(db_path)
+                    .await
+                    .context("...while initializing SQLite storage")
+            },
+            "postgres" => {
+                let url = std::env::var("DATABASE_URL")
+                    .context("DATABASE_URL environment variable not set")?;
+
+                Ok(Self::Postgres(
+                    PostgresStore::connect(&url)
+                        .await
+                        .context("...while initializing Postgres storage")?
+                ))
+            },
+            other => bail!("Unknown STORAGE_BACKEND `{}`, expected `sqlite` or `postgres`", other),
+        }
+    }
+}
+impl MarketStore for Storage {
+    async fn insert_mbo_batch(&self, messages: &[MboMsg]) -> Result<usize> {
+        match self {
+            Self::Sqlite(store) => store.insert_mbo_batch(messages).await,
+            Self::Postgres(store) => store.insert_mbo_batch(messages).await,
+        }
     }
 
-    /// Create all tables if they don't exist
-    #[tracing::instrument(skip(self))]
-    fn initialize_schema(&self) -> Result<()> {
-        info!("Initializing database schema...");
-        
-        let conn = self.conn.lock().unwrap();
-        
-        // Create instruments table
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS instruments (
-                instrument_id INTEGER PRIMARY KEY,
-                symbol TEXT,
-                publisher INTEGER NOT NULL
-            )",
-            [],
-        ).context("Failed to create instruments table")?;
-
-        // Create MBO messages table with all relevant fields
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS mbo_messages (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                ts_recv INTEGER NOT NULL,
-                ts_event INTEGER NOT NULL,
-                instrument_id INTEGER NOT NULL,
-                publisher INTEGER NOT NULL,
-                order_id INTEGER NOT NULL,
-                action TEXT NOT NULL,
-                side TEXT NOT NULL,
-                price INTEGER NOT NULL,
-                size INTEGER NOT NULL,
-                flags INTEGER NOT NULL,
-                sequence INTEGER NOT NULL,
-                ts_in_delta INTEGER NOT NULL,
-                channel_id INTEGER NOT NULL
-            )",
-            [],
-        ).context("Failed to create mbo_messages table")?;
-
-        // Create indices for common queries
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_mbo_instrument_time 
-             ON mbo_messages(instrument_id, ts_recv DESC)",
-            [],
-        ).context("Failed to create instrument_time index")?;
-
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_mbo_order 
-             ON mbo_messages(order_id, ts_recv DESC)",
-            [],
-        ).context("Failed to create order index")?;
-
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_mbo_publisher 
-             ON mbo_messages(publisher, instrument_id, ts_recv DESC)",
-            [],
-        ).context("Failed to create publisher index")?;
-
-        info!("Database schema initialized successfully");
-        Ok(())
+    async fn find_message_id(&self, mbo_msg: &MboMsg) -> Result<Option<i64>> {
+        match self {
+            Self::Sqlite(store) => store.find_message_id(mbo_msg).await,
+            Self::Postgres(store) => store.find_message_id(mbo_msg).await,
+        }
     }
 
-    #[tracing::instrument(skip(self), fields(order_id = msg.order_id, instrument_id = msg.hd.instrument_id))]
-    pub fn insert_mbo(&self, msg: &MboMsg) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        
-        // Convert action enum to string
-        let action = format!("{:?}", msg.action);
-        
-        // Convert side enum to string
-        let side = format!("{:?}", msg.side);
-        
-        conn.execute(
-            "INSERT INTO mbo_messages 
-             (ts_recv, ts_event, instrument_id, publisher, order_id, action, side, 
-              price, size, flags, sequence, ts_in_delta, channel_id)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
-            params![
-                msg.hd.ts_event as i64,
-                msg.ts_recv as i64,
-                msg.hd.instrument_id,
-                msg.hd.publisher_id,
-                msg.order_id as i64,
-                action,
-                side,
-                msg.price,
-                msg.size,
-                msg.flags.raw(),
-                msg.sequence,
-                msg.ts_in_delta,
-                msg.channel_id,
-            ],
-        ).context("Failed to insert MBO message")?;
+    async fn query_messages(&self, query: &MessageQuery) -> Result<Vec<StoredMbo>> {
+        match self {
+            Self::Sqlite(store) => store.query_messages(query).await,
+            Self::Postgres(store) => store.query_messages(query).await,
+        }
+    }
 
-        Ok(())
+    async fn count_messages(&self) -> Result<usize> {
+        match self {
+            Self::Sqlite(store) => store.count_messages().await,
+            Self::Postgres(store) => store.count_messages().await,
+        }
     }
-    
-    #[tracing::instrument(skip(self, messages), fields(count = messages.len()))]
-    pub fn insert_mbo_batch(&self, messages: &[MboMsg]) -> Result<()> {
-        let mut conn = self.conn.lock().unwrap();
-        let tx = conn.transaction()
-            .context("Failed to begin transaction")?;
-
-        {
-            let mut stmt = tx.prepare(
-                "INSERT INTO mbo_messages 
-                 (ts_recv, ts_event, instrument_id, publisher, order_id, action, side, 
-                  price, size, flags, sequence, ts_in_delta, channel_id)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)"
-            ).context("Failed to prepare statement")?;
-
-            for msg in messages {
-                let action = format!("{:?}", msg.action);
-                let side = format!("{:?}", msg.side);
-                
-                stmt.execute(params![
-                    msg.hd.ts_event as i64,
-                    msg.ts_recv as i64,
-                    msg.hd.instrument_id,
-                    msg.hd.publisher_id,
-                    msg.order_id as i64,
-                    action,
-                    side,
-                    msg.price,
-                    msg.size,
-                    msg.flags.raw(),
-                    msg.sequence,
-                    msg.ts_in_delta,
-                    msg.channel_id,
-                ]).context("Failed to execute insert statement")?;
-            }
+
+    async fn dataset(&self, fingerprint: &str) -> Result<Option<StoredDataset>> {
+        match self {
+            Self::Sqlite(store) => store.dataset(fingerprint).await,
+            Self::Postgres(store) => store.dataset(fingerprint).await,
         }
+    }
 
-        tx.commit().context("Failed to commit transaction")?;
-        debug!("Inserted batch of {} MBO messages", messages.len());
-        
-        Ok(())
+    async fn datasets(&self) -> Result<Vec<StoredDataset>> {
+        match self {
+            Self::Sqlite(store) => store.datasets().await,
+            Self::Postgres(store) => store.datasets().await,
+        }
     }
 
-    pub fn count_messages(&self) -> Result<usize> {
-        let conn = self.conn.lock().unwrap();
-        let count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM mbo_messages",
-            [],
-            |row| row.get(0)
-        ).context("Failed to count messages")?;
-        
-        Ok(count as usize)
+    async fn record_dataset(&self, dataset: &StoredDataset) -> Result<()> {
+        match self {
+            Self::Sqlite(store) => store.record_dataset(dataset).await,
+            Self::Postgres(store) => store.record_dataset(dataset).await,
+        }
     }
 
-    #[allow(dead_code)]
-    #[allow(clippy::type_complexity)]
-    pub fn get_messages_for_instrument(
-        &self,
-        instrument_id: u32,
-        start_ts: Option<i64>,
-        end_ts: Option<i64>,
-        limit: Option<usize>,
-    ) -> Result<Vec<(i64, String, String, i64, u32)>> {
-        let conn = self.conn.lock().unwrap();
-        
-        let mut query = String::from(
-            "SELECT ts_recv, action, side, price, size 
-             FROM mbo_messages 
-             WHERE instrument_id = ?1"
-        );
-        
-        let mut params_vec: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(instrument_id)];
-        
-        if let Some(start) = start_ts {
-            query.push_str(" AND ts_recv >= ?");
-            params_vec.push(Box::new(start));
+    async fn upsert_instruments(&self, instruments: &[Instrument]) -> Result<()> {
+        match self {
+            Self::Sqlite(store) => store.upsert_instruments(instruments).await,
+            Self::Postgres(store) => store.upsert_instruments(instruments).await,
         }
-        
-        if let Some(end) = end_ts {
-            query.push_str(" AND ts_recv <= ?");
-            params_vec.push(Box::new(end));
+    }
+
+    async fn instruments(&self) -> Result<Vec<Instrument>> {
+        match self {
+            Self::Sqlite(store) => store.instruments().await,
+            Self::Postgres(store) => store.instruments().await,
         }
-        
-        query.push_str(" ORDER BY ts_recv DESC");
-        
-        if let Some(lim) = limit {
-            query.push_str(" LIMIT ?");
-            params_vec.push(Box::new(lim));
+    }
+
+    async fn insert_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        match self {
+            Self::Sqlite(store) => store.insert_checkpoint(checkpoint).await,
+            Self::Postgres(store) => store.insert_checkpoint(checkpoint).await,
         }
-        
-        let mut stmt = conn.prepare(&query)
-            .context("Failed to prepare query")?;
-        
-        let params_refs: Vec<&dyn rusqlite::ToSql> = params_vec.iter()
-            .map(|p| p.as_ref())
-            .collect();
-        
-        let rows = stmt.query_map(params_refs.as_slice(), |row| {
-            Ok((
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-                row.get(4)?,
-            ))
-        }).context("Failed to query messages")?;
-        
-        rows.collect::<Result<Vec<_>, _>>()
-            .context("Failed to collect query results")
+    }
+
+    async fn latest_checkpoint(&self, source: Option<&str>, message_id: Option<i64>) -> Result<Option<Checkpoint>> {
+        match self {
+            Self::Sqlite(store) => store.latest_checkpoint(source, message_id).await,
+            Self::Postgres(store) => store.latest_checkpoint(source, message_id).await,
+        }
+    }
+
+    async fn compaction_generation(&self) -> Result<u64> {
+        match self {
+            Self::Sqlite(store) => store.compaction_generation().await,
+            Self::Postgres(store) => store.compaction_generation().await,
+        }
+    }
+
+    async fn apply_compaction(&self, compaction: &Compaction) -> Result<Option<usize>> {
+        match self {
+            Self::Sqlite(store) => store.apply_compaction(compaction).await,
+            Self::Postgres(store) => store.apply_compaction(compaction).await,
+        }
+    }
+
+    async fn bars(&self, instrument_id: Option<u32>, start_ts: Option<u64>, end_ts: Option<u64>) -> Result<Vec<Bar>> {
+        match self {
+            Self::Sqlite(store) => store.bars(instrument_id, start_ts, end_ts).await,
+            Self::Postgres(store) => store.bars(instrument_id, start_ts, end_ts).await,
+        }
+    }
+
+    async fn vacuum(&self) -> Result<()> {
+        match self {
+            Self::Sqlite(store) => store.vacuum().await,
+            Self::Postgres(store) => store.vacuum().await,
+        }
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        match self {
+            Self::Sqlite(store) => store.stats().await,
+            Self::Postgres(store) => store.stats().await,
+        }
+    }
+}
+
+/// Number each message by how many earlier messages in the batch share
+///  its natural key
+///
+/// The venue fields alone aren't unique - an aggressor trading through
+///  several levels emits one trade per level under the same sequence, and
+///  fills are often followed by a cancel of the same order - so backends
+///  store this `occurrence` as part of the key.
+pub(crate) fn natural_key_occurrences(messages: &[MboMsg]) -> Vec<u32> {
+    let mut occurrences: HashMap<(u16, u32, u8, u32, u64, u64), u32> = HashMap::new();
+
+    messages.iter()
+        .map(|msg| {
+            let occurrence = occurrences.entry((
+                msg.hd.publisher_id,
+                msg.hd.instrument_id,
+                msg.channel_id,
+                msg.sequence,
+                msg.ts_recv,
+                msg.order_id,
+            )).or_default();
+            *occurrence += 1;
+            *occurrence - 1
+        })
+        .collect()
+}
+
+/// Lazily reads every message matching a query, a page at a time
+///
+/// Pages are keyed on row ID, so nothing is held open between pages and
+///  writers aren't blocked for the whole scan.
+pub struct MessageStream<S> {
+    store: S,
+    query: MessageQuery,
+    remaining: Option<usize>,
+    page: VecDeque<StoredMbo>,
+    exhausted: bool,
+}
+impl<S: MarketStore> MessageStream<S> {
+    pub fn new(store: S, query: MessageQuery) -> Self {
+        Self {
+            store,
+            remaining: query.limit,
+            query,
+            page: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Fetch the next message, or `None` once every match was returned
+    pub async fn next_message(&mut self) -> Result<Option<StoredMbo>> {
+        if self.page.is_empty() && !self.exhausted {
+            self.fetch_page()
+                .await
+                .context("...while fetching page of stored messages")?;
+        }
+
+        Ok(self.page.pop_front())
+    }
+
+    async fn fetch_page(&mut self) -> Result<()> {
+        let limit = self.remaining
+            .map_or(PAGE_SIZE, |remaining| remaining.min(PAGE_SIZE));
+        if limit == 0 {
+            self.exhausted = true;
+            return Ok(());
+        }
+
+        let rows = self.store.query_messages(&MessageQuery {
+            limit: Some(limit),
+            ..self.query.clone()
+        }).await?;
+
+        self.exhausted = rows.len() < limit;
+        if let Some(last) = rows.last() {
+            self.query.after_id = Some(last.id);
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= rows.len();
+        }
+        self.page.extend(rows);
+
+        Ok(())
     }
 }
 
+/// Behaviour every backend must share, run against each of them
 #[cfg(test)]
-mod tests {
+pub(crate) mod contract {
     use super::*;
-    use databento::dbn::decode::{dbn::Decoder, DecodeRecord};
-    use std::path::Path as StdPath;
-    
-    #[test]
-    fn test_storage_initialization() -> Result<()> {
-        // Create a temporary database
-        let temp_db = "test_storage_init.db";
-        
-        // Clean up any existing test database
-        let _ = std::fs::remove_file(temp_db);
-        
-        let storage = Storage::new(temp_db)?;
-        let count = storage.count_messages()?;
-        
-        assert_eq!(count, 0, "New database should have 0 messages");
-        
-        // Clean up
-        drop(storage);
-        let _ = std::fs::remove_file(temp_db);
-        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
-        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
-        
-        Ok(())
+    use databento::dbn::{rtype, Action, FlagSet, RecordHeader, Side};
+    use std::ffi::c_char;
+
+    fn mbo(i: u64, instrument_id: u32) -> MboMsg {
+        MboMsg {
+            hd: RecordHeader::new::<MboMsg>(rtype::MBO, 1, instrument_id, i),
+            order_id: i,
+            price: i as i64,
+            size: 1,
+            flags: FlagSet::empty(),
+            channel_id: 0,
+            action: Action::Add as u8 as c_char,
+            side: Side::Bid as u8 as c_char,
+            ts_recv: i,
+            ts_in_delta: 0,
+            sequence: i as u32,
+        }
     }
-    
-    #[test]
-    fn test_storage_batch_insert() -> Result<()> {
-        use databento::dbn::MboMsg;
-        
-        let temp_db = "test_storage_batch.db";
-        let _ = std::fs::remove_file(temp_db);
-        
-        let storage = Storage::new(temp_db)?;
-        
-        // Load some real MBO messages
-        let path = StdPath::new("assets/CLX5_mbo.dbn");
-        let mut decoder = Decoder::from_file(path)?;
-        
-        let mut messages = Vec::new();
-        for _ in 0..100 {
-            if let Some(msg) = decoder.decode_record::<MboMsg>()? {
-                messages.push(msg.clone());
-            } else {
-                break;
-            }
+
+    pub async fn exercise_store<S: MarketStore + Clone>(store: &S) -> Result<()> {
+        assert_eq!(store.count_messages().await?, 0, "Store should start empty");
+
+        // Two interleaved instruments, enough rows to span several pages
+        let messages = (0..(PAGE_SIZE as u64 * 2 + 123))
+            .map(|i| mbo(i, (i % 2) as u32))
+            .collect::<Vec<_>>();
+        assert_eq!(store.insert_mbo_batch(&messages).await?, messages.len());
+        assert_eq!(store.insert_mbo_batch(&messages).await?, 0, "Repeated batch should insert nothing");
+        assert_eq!(store.count_messages().await?, messages.len());
+
+        let query = MessageQuery {
+            instrument_id: Some(1),
+            start_ts: Some(100),
+            ..Default::default()
+        };
+        let expected = messages.iter()
+            .filter(|msg| msg.hd.instrument_id == 1 && msg.ts_recv >= 100)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut stream = MessageStream::new(store.clone(), query.clone());
+        let mut streamed = Vec::new();
+        while let Some(stored) = stream.next_message().await? {
+            streamed.push(stored.mbo_msg);
         }
-        
-        assert!(!messages.is_empty(), "Should have loaded some messages");
-        
-        // Insert batch
-        storage.insert_mbo_batch(&messages)?;
-        
-        // Verify count
-        let count = storage.count_messages()?;
-        assert_eq!(count, messages.len(), "Should have inserted all messages");
-        
-        println!("Successfully inserted {} messages", count);
-        
-        // Clean up
-        drop(storage);
-        let _ = std::fs::remove_file(temp_db);
-        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
-        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
-        
+        assert_eq!(streamed, expected);
+
+        // Limits span pages too, and descending walks back from the end
+        let mut stream = MessageStream::new(store.clone(), MessageQuery {
+            limit: Some(PAGE_SIZE + 1),
+            descending: true,
+            ..query
+        });
+        let mut limited = Vec::new();
+        while let Some(stored) = stream.next_message().await? {
+            limited.push(stored);
+        }
+        assert_eq!(limited.len(), PAGE_SIZE + 1);
+        assert_eq!(limited[0].mbo_msg, *expected.last().context("No messages")?);
+        assert!(limited.windows(2).all(|pair| pair[0].id > pair[1].id));
+
+        // Messages are found by their natural key
+        let last = messages.last().context("No messages")?;
+        let last_id = store.query_messages(&MessageQuery { descending: true, limit: Some(1), ..Default::default() })
+            .await?
+            .first()
+            .context("No stored messages")?
+            .id;
+        assert_eq!(store.find_message_id(last).await?, Some(last_id));
+        assert_eq!(store.find_message_id(&mbo(u32::MAX as u64, 0)).await?, None);
+
+        // Venue fields filter too
+        for (query, expected) in [
+            (MessageQuery { publisher: Some(1), side: Some(Side::Bid), ..Default::default() }, 5),
+            (MessageQuery { publisher: Some(2), ..Default::default() }, 0),
+            (MessageQuery { action: Some(Action::Cancel), ..Default::default() }, 0),
+            (MessageQuery { side: Some(Side::Ask), ..Default::default() }, 0),
+        ] {
+            let query = MessageQuery { limit: Some(5), ..query };
+            assert_eq!(store.query_messages(&query).await?.len(), expected, "Unexpected rows for {:?}", query);
+        }
+
+        // Row ID bounds are inclusive
+        let first_id = last_id - messages.len() as i64 + 1;
+        let bounded = store.query_messages(&MessageQuery {
+            start_id: Some(first_id + 1),
+            end_id: Some(first_id + 3),
+            ..Default::default()
+        }).await?;
+        assert_eq!(bounded.iter().map(|stored| stored.id).collect::<Vec<_>>(), vec![first_id + 1, first_id + 2, first_id + 3]);
+
+        // Datasets, in the order they were last recorded
+        assert_eq!(store.dataset("sha256:abc").await?, None);
+        assert_eq!(store.datasets().await?, vec![]);
+        let dataset = StoredDataset {
+            fingerprint: "sha256:abc".to_string(),
+            source: "file:test.dbn".to_string(),
+            message_count: messages.len(),
+            message_ids: Some((first_id, last_id)),
+        };
+        let empty = StoredDataset {
+            fingerprint: "sha256:def".to_string(),
+            source: "file:empty.dbn".to_string(),
+            message_count: 0,
+            message_ids: None,
+        };
+        store.record_dataset(&dataset).await?;
+        store.record_dataset(&empty).await?;
+        assert_eq!(store.dataset("sha256:abc").await?, Some(dataset.clone()));
+        assert_eq!(store.datasets().await?, vec![dataset.clone(), empty.clone()]);
+        store.record_dataset(&dataset).await?;
+        assert_eq!(store.datasets().await?, vec![empty, dataset]);
+
+        // Instruments, with an ID reused for a second symbol later on
+        let mut first = Instrument {
+            instrument_id: 42,
+            raw_symbol: "CLX5".to_string(),
+            valid_from: 100,
+            valid_until: None,
+        };
+        let second = Instrument {
+            instrument_id: 42,
+            raw_symbol: "CLZ5".to_string(),
+            valid_from: 200,
+            valid_until: Some(300),
+        };
+        let other = Instrument {
+            instrument_id: 7,
+            raw_symbol: "NGX5".to_string(),
+            valid_from: 0,
+            valid_until: Some(u64::MAX >> 1),
+        };
+        store.upsert_instruments(&[second.clone(), first.clone(), other.clone()]).await?;
+        first.valid_until = Some(200);
+        store.upsert_instruments(&[first.clone()]).await?;
+        store.upsert_instruments(&[]).await?;
+        assert_eq!(store.instruments().await?, vec![other, first, second]);
+
+        // Checkpoints, of any source or only of one
+        assert_eq!(store.latest_checkpoint(None, None).await?, None);
+        let early = Checkpoint {
+            message_id: 10,
+            ts_recv: 10,
+            source: LIVE_SOURCE.to_string(),
+            data: vec![1, 2, 3],
+        };
+        let late = Checkpoint {
+            message_id: 20,
+            ts_recv: 20,
+            source: "sha256:abc".to_string(),
+            data: vec![4, 5, 6],
+        };
+        store.insert_checkpoint(&early).await?;
+        store.insert_checkpoint(&late).await?;
+        assert_eq!(store.latest_checkpoint(None, None).await?, Some(late.clone()));
+        assert_eq!(store.latest_checkpoint(None, Some(15)).await?, Some(early.clone()));
+        assert_eq!(store.latest_checkpoint(None, Some(5)).await?, None);
+        assert_eq!(store.latest_checkpoint(Some(LIVE_SOURCE), None).await?, Some(early));
+        assert_eq!(store.latest_checkpoint(Some("sha256:abc"), None).await?, Some(late));
+        assert_eq!(store.latest_checkpoint(Some("sha256:def"), None).await?, None);
+
+        // Compactions, which merge bars with later trades of the same
+        //  interval and leave checkpoints alone
+        let stats = store.stats().await?;
+        assert_eq!((stats.messages, stats.checkpoints, stats.bars), (messages.len() as u64, 2, 0));
+        assert_eq!(stats.oldest_ts_recv, Some(0));
+        assert!(stats.size_bytes > 0);
+
+        let bar = |instrument_id: u32, ts: u64, price: i64| Bar {
+            instrument_id,
+            ts,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 2,
+            trades: 1,
+        };
+        assert_eq!(store.compaction_generation().await?, 0);
+        let compaction = Compaction {
+            generation: 0,
+            cutoff_ts: 100,
+            last_message_id: last_id,
+            bars: vec![bar(1, 60, 100), bar(0, 0, 100), bar(1, 0, 50)],
+        };
+        assert_eq!(store.apply_compaction(&compaction).await?, Some(100));
+        assert_eq!(store.compaction_generation().await?, 1);
+
+        // Applying it again, or another built alongside it, changes nothing
+        assert_eq!(store.apply_compaction(&compaction).await?, None);
+        assert_eq!(store.bars(None, None, None).await?.len(), 3);
+
+        // Rows past the last one aggregated are kept
+        let later = Compaction {
+            generation: 1,
+            cutoff_ts: 110,
+            last_message_id: first_id + 104,
+            bars: vec![bar(1, 60, 110)],
+        };
+        assert_eq!(store.apply_compaction(&later).await?, Some(5));
+        let mut merged = bar(1, 60, 100);
+        merged.merge(&bar(1, 60, 110));
+        assert_eq!(merged, Bar { open: 100, high: 110, low: 100, close: 110, volume: 4, trades: 2, ..merged.clone() });
+        assert_eq!(store.bars(None, None, None).await?, vec![bar(0, 0, 100), bar(1, 0, 50), merged.clone()]);
+        assert_eq!(store.bars(Some(1), Some(30), None).await?, vec![merged]);
+        assert_eq!(store.bars(None, None, Some(30)).await?.len(), 2);
+
+        store.vacuum().await?;
+        let stats = store.stats().await?;
+        assert_eq!((stats.messages, stats.checkpoints, stats.bars), (messages.len() as u64 - 105, 2, 3));
+        assert_eq!(stats.oldest_ts_recv, Some(105));
+
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,225 @@
+use sqlx::{PgPool, Row};
+use anyhow::{Context, Result, bail};
+use tracing::info;
+
+use crate::storage::Migration;
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create instruments, mbo_messages, datasets and checkpoints tables",
+        sql: "
+            CREATE TABLE instruments (
+                instrument_id BIGINT PRIMARY KEY,
+                symbol TEXT,
+                publisher INTEGER NOT NULL
+            );
+
+            -- The primary key includes `ts_recv` as hypertables require
+            --  every unique index to include the partitioning column
+            CREATE TABLE mbo_messages (
+                id BIGINT GENERATED ALWAYS AS IDENTITY,
+                ts_recv BIGINT NOT NULL,
+                ts_event BIGINT NOT NULL,
+                instrument_id BIGINT NOT NULL,
+                publisher INTEGER NOT NULL,
+                order_id BIGINT NOT NULL,
+                action SMALLINT NOT NULL,
+                side SMALLINT NOT NULL,
+                price BIGINT NOT NULL,
+                size BIGINT NOT NULL,
+                flags SMALLINT NOT NULL,
+                sequence BIGINT NOT NULL,
+                ts_in_delta INTEGER NOT NULL,
+                channel_id SMALLINT NOT NULL,
+                occurrence INTEGER NOT NULL,
+                PRIMARY KEY (id, ts_recv)
+            );
+
+            CREATE INDEX idx_mbo_instrument_time ON mbo_messages(instrument_id, ts_recv DESC);
+            CREATE INDEX idx_mbo_order ON mbo_messages(order_id, ts_recv DESC);
+            CREATE INDEX idx_mbo_publisher ON mbo_messages(publisher, instrument_id, ts_recv DESC);
+            CREATE UNIQUE INDEX idx_mbo_natural_key
+                ON mbo_messages(publisher, instrument_id, channel_id, sequence, ts_recv, order_id, occurrence);
+
+            CREATE TABLE datasets (
+                fingerprint TEXT PRIMARY KEY,
+                source TEXT NOT NULL,
+                message_count BIGINT NOT NULL,
+                ingested_at BIGINT NOT NULL
+            );
+
+            CREATE TABLE checkpoints (
+                message_id BIGINT PRIMARY KEY,
+                ts_recv BIGINT NOT NULL,
+                data BYTEA NOT NULL
+            );
+        ",
+    },
+    // Plain Postgres works too, the hypertable only changes performance
+    Migration {
+        version: 2,
+        description: "Partition mbo_messages by ts_recv when TimescaleDB is available",
+        sql: "
+            DO $$
+            BEGIN
+                IF EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb') THEN
+                    CREATE EXTENSION IF NOT EXISTS timescaledb;
+                    PERFORM create_hypertable(
+                        'mbo_messages',
+                        'ts_recv',
+                        chunk_time_interval => 86400000000000::BIGINT,
+                        migrate_data => TRUE
+                    );
+                ELSE
+                    RAISE NOTICE 'TimescaleDB is not available, mbo_messages stays a plain table';
+                END IF;
+            END
+            $$;
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "Store instrument symbol mappings with validity ranges",
+        sql: "
+            CREATE TABLE instrument_mappings (
+                instrument_id BIGINT NOT NULL,
+                raw_symbol TEXT NOT NULL,
+                valid_from BIGINT NOT NULL,
+                valid_until BIGINT,
+                PRIMARY KEY (instrument_id, valid_from)
+            );
+
+            INSERT INTO instrument_mappings (instrument_id, raw_symbol, valid_from, valid_until)
+                SELECT instrument_id, symbol, 0, NULL FROM instruments WHERE symbol IS NOT NULL;
+
+            DROP TABLE instruments;
+            ALTER TABLE instrument_mappings RENAME TO instruments;
+            CREATE INDEX idx_instruments_symbol ON instruments(raw_symbol);
+        ",
+    },
+    // Hypertables already index `ts_recv`, the index only helps plain tables
+    Migration {
+        version: 4,
+        description: "Create bars table and index messages by time for retention",
+        sql: "
+            CREATE TABLE bars (
+                instrument_id BIGINT NOT NULL,
+                ts BIGINT NOT NULL,
+                open BIGINT NOT NULL,
+                high BIGINT NOT NULL,
+                low BIGINT NOT NULL,
+                close BIGINT NOT NULL,
+                volume BIGINT NOT NULL,
+                trades BIGINT NOT NULL,
+                PRIMARY KEY (instrument_id, ts)
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_mbo_time ON mbo_messages(ts_recv);
+        ",
+    },
+    // Datasets persisted before have no known range and are re-read from
+    //  their source rather than rebuilt from storage. `recorded` orders
+    //  datasets recorded within the same second of `ingested_at`.
+    Migration {
+        version: 5,
+        description: "Record the message row IDs each dataset inserted",
+        sql: "
+            CREATE SEQUENCE datasets_recorded;
+            ALTER TABLE datasets
+                ADD COLUMN first_message_id BIGINT,
+                ADD COLUMN last_message_id BIGINT,
+                ADD COLUMN recorded BIGINT NOT NULL DEFAULT nextval('datasets_recorded');
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "Record applied compactions",
+        sql: "
+            CREATE TABLE compactions (
+                generation BIGINT PRIMARY KEY,
+                cutoff_ts BIGINT NOT NULL,
+                last_message_id BIGINT NOT NULL,
+                compacted_at BIGINT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "Tag checkpoints with the source of the market they were taken of",
+        sql: "
+            ALTER TABLE checkpoints ADD COLUMN source TEXT NOT NULL DEFAULT '';
+            CREATE INDEX idx_checkpoints_source ON checkpoints(source, message_id);
+        ",
+    },
+];
+
+/// Latest schema version known to this build
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Bring the schema up to the latest version, applying each pending
+///  migration in its own transaction
+#[tracing::instrument(skip(pool))]
+pub async fn migrate(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at BIGINT NOT NULL
+        )"
+    ).execute(pool)
+        .await
+        .context("Failed to create schema_version table")?;
+
+    let current = current_version(pool).await?;
+    if current > latest_version() {
+        bail!(
+            "Database schema version {} is newer than the latest known version {}",
+            current,
+            latest_version()
+        );
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        info!(version = migration.version, "Applying migration: {}", migration.description);
+
+        let applied_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .context("System clock is before the UNIX epoch")?
+            .as_secs() as i64;
+
+        let mut tx = pool.begin()
+            .await
+            .context("Failed to begin migration transaction")?;
+        sqlx::raw_sql(migration.sql)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to apply migration {}", migration.version))?;
+        sqlx::query("INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)")
+            .bind(migration.version as i32)
+            .bind(migration.description)
+            .bind(applied_at)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to record schema version")?;
+        tx.commit()
+            .await
+            .context(format!("Failed to commit migration {}", migration.version))?;
+    }
+
+    Ok(())
+}
+
+/// Highest migration recorded in `schema_version`, or 0 if none
+pub async fn current_version(pool: &PgPool) -> Result<u32> {
+    let version: Option<i32> = sqlx::query("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await
+        .context("Failed to read schema version")?
+        .try_get(0)
+        .context("Failed to decode schema version")?;
+
+    Ok(version.unwrap_or(0) as u32)
+}
@@ -0,0 +1,570 @@
+mod migrations;
+
+use std::ffi::c_char;
+
+use databento::dbn::{rtype, Action, FlagSet, MboMsg, RecordHeader, Side};
+use anyhow::{Context, Result, anyhow};
+use sqlx::{postgres::{PgPoolOptions, PgRow}, PgPool, Postgres, QueryBuilder, Row};
+use tracing::{info, debug};
+
+use super::{Bar, Checkpoint, Compaction, Instrument, MarketStore, MessageQuery, StorageStats, StoredDataset, StoredMbo, natural_key_occurrences};
+
+/// Rows per `INSERT`, keeping the bind count under Postgres' 65535 limit
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Postgres storage, using a TimescaleDB hypertable for messages when the
+///  extension is installed
+#[derive(Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+}
+impl PostgresStore {
+    #[tracing::instrument]
+    pub async fn connect(url: &str) -> Result<Self> {
+        info!("Connecting to Postgres database");
+
+        let pool = PgPoolOptions::new()
+            .max_connections(8)
+            .connect(url)
+            .await
+            .context("Failed to connect to Postgres")?;
+
+        migrations::migrate(&pool)
+            .await
+            .context("Failed to migrate database schema")?;
+
+        Ok(Self { pool })
+    }
+}
+impl MarketStore for PostgresStore {
+    #[tracing::instrument(skip(self, messages), fields(count = messages.len()))]
+    async fn insert_mbo_batch(&self, messages: &[MboMsg]) -> Result<usize> {
+        let occurrences = natural_key_occurrences(messages);
+        let mut tx = self.pool.begin()
+            .await
+            .context("Failed to begin transaction")?;
+
+        let mut inserted = 0;
+        for (messages, occurrences) in messages.chunks(INSERT_CHUNK_SIZE).zip(occurrences.chunks(INSERT_CHUNK_SIZE)) {
+            let mut builder = QueryBuilder::<Postgres>::new(
+                "INSERT INTO mbo_messages
+                 (ts_recv, ts_event, instrument_id, publisher, order_id, action, side,
+                  price, size, flags, sequence, ts_in_delta, channel_id, occurrence) "
+            );
+            builder.push_values(messages.iter().zip(occurrences), |mut row, (msg, occurrence)| {
+                row.push_bind(msg.ts_recv as i64)
+                    .push_bind(msg.hd.ts_event as i64)
+                    .push_bind(msg.hd.instrument_id as i64)
+                    .push_bind(msg.hd.publisher_id as i32)
+                    .push_bind(msg.order_id as i64)
+                    .push_bind(msg.action as u8 as i16)
+                    .push_bind(msg.side as u8 as i16)
+                    .push_bind(msg.price)
+                    .push_bind(msg.size as i64)
+                    .push_bind(msg.flags.raw() as i16)
+                    .push_bind(msg.sequence as i64)
+                    .push_bind(msg.ts_in_delta)
+                    .push_bind(msg.channel_id as i16)
+                    .push_bind(*occurrence as i32);
+            });
+            builder.push(" ON CONFLICT DO NOTHING");
+
+            inserted += builder.build()
+                .execute(&mut *tx)
+                .await
+                .context("Failed to execute insert statement")?
+                .rows_affected() as usize;
+        }
+
+        tx.commit()
+            .await
+            .context("Failed to commit transaction")?;
+        debug!("Inserted {} new of a batch of {} MBO messages", inserted, messages.len());
+
+        Ok(inserted)
+    }
+
+    #[tracing::instrument(skip(self, mbo_msg), fields(order_id = mbo_msg.order_id))]
+    async fn find_message_id(&self, mbo_msg: &MboMsg) -> Result<Option<i64>> {
+        sqlx::query(
+            "SELECT MAX(id) FROM mbo_messages
+             WHERE publisher = $1 AND instrument_id = $2 AND channel_id = $3
+               AND sequence = $4 AND ts_recv = $5 AND order_id = $6"
+        )
+            .bind(mbo_msg.hd.publisher_id as i32)
+            .bind(mbo_msg.hd.instrument_id as i64)
+            .bind(mbo_msg.channel_id as i16)
+            .bind(mbo_msg.sequence as i64)
+            .bind(mbo_msg.ts_recv as i64)
+            .bind(mbo_msg.order_id as i64)
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to find message")?
+            .try_get(0)
+            .context("Failed to decode message ID")
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn query_messages(&self, query: &MessageQuery) -> Result<Vec<StoredMbo>> {
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "SELECT id, ts_recv, ts_event, instrument_id, publisher, order_id, action, side,
+                    price, size, flags, sequence, ts_in_delta, channel_id
+             FROM mbo_messages WHERE TRUE"
+        );
+
+        if let Some(instrument_id) = query.instrument_id {
+            builder.push(" AND instrument_id = ").push_bind(instrument_id as i64);
+        }
+        if let Some(publisher) = query.publisher {
+            builder.push(" AND publisher = ").push_bind(publisher as i32);
+        }
+        if let Some(order_id) = query.order_id {
+            builder.push(" AND order_id = ").push_bind(order_id as i64);
+        }
+        if let Some(action) = query.action {
+            builder.push(" AND action = ").push_bind(action as u8 as i16);
+        }
+        if let Some(side) = query.side {
+            builder.push(" AND side = ").push_bind(side as u8 as i16);
+        }
+        if let Some(start) = query.start_ts {
+            builder.push(" AND ts_recv >= ").push_bind(start as i64);
+        }
+        if let Some(end) = query.end_ts {
+            builder.push(" AND ts_recv <= ").push_bind(end as i64);
+        }
+        if let Some(start_id) = query.start_id {
+            builder.push(" AND id >= ").push_bind(start_id);
+        }
+        if let Some(end_id) = query.end_id {
+            builder.push(" AND id <= ").push_bind(end_id);
+        }
+        if let Some(after_id) = query.after_id {
+            builder.push(if query.descending { " AND id < " } else { " AND id > " })
+                .push_bind(after_id);
+        }
+
+        builder.push(if query.descending { " ORDER BY id DESC" } else { " ORDER BY id ASC" });
+
+        if let Some(limit) = query.limit {
+            builder.push(" LIMIT ").push_bind(limit as i64);
+        }
+
+        builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query messages")?
+            .iter()
+            .map(|row| Ok(StoredMbo {
+                id: row.try_get("id")?,
+                mbo_msg: mbo_from_row(row)?,
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to decode query results")
+    }
+
+    async fn count_messages(&self) -> Result<usize> {
+        let count: i64 = sqlx::query("SELECT COUNT(*) FROM mbo_messages")
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to count messages")?
+            .try_get(0)?;
+
+        Ok(count as usize)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn dataset(&self, fingerprint: &str) -> Result<Option<StoredDataset>> {
+        sqlx::query(
+            "SELECT fingerprint, source, message_count, first_message_id, last_message_id
+             FROM datasets WHERE fingerprint = $1"
+        )
+            .bind(fingerprint)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query datasets")?
+            .map(|row| dataset_from_row(&row))
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn datasets(&self) -> Result<Vec<StoredDataset>> {
+        sqlx::query(
+            "SELECT fingerprint, source, message_count, first_message_id, last_message_id
+             FROM datasets ORDER BY recorded"
+        )
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query datasets")?
+            .iter()
+            .map(dataset_from_row)
+            .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn record_dataset(&self, dataset: &StoredDataset) -> Result<()> {
+        let ingested_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .context("System clock is before the UNIX epoch")?
+            .as_secs() as i64;
+
+        sqlx::query(
+            "INSERT INTO datasets
+             (fingerprint, source, message_count, ingested_at, first_message_id, last_message_id)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (fingerprint) DO UPDATE
+             SET source = excluded.source, message_count = excluded.message_count, ingested_at = excluded.ingested_at,
+                 first_message_id = excluded.first_message_id, last_message_id = excluded.last_message_id,
+                 recorded = nextval('datasets_recorded')"
+        )
+            .bind(&dataset.fingerprint)
+            .bind(&dataset.source)
+            .bind(dataset.message_count as i64)
+            .bind(ingested_at)
+            .bind(dataset.message_ids.map(|(first, _)| first))
+            .bind(dataset.message_ids.map(|(_, last)| last))
+            .execute(&self.pool)
+            .await
+            .context("Failed to record dataset")?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, instruments), fields(count = instruments.len()))]
+    async fn upsert_instruments(&self, instruments: &[Instrument]) -> Result<()> {
+        if instruments.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin()
+            .await
+            .context("Failed to begin transaction")?;
+
+        for instruments in instruments.chunks(INSERT_CHUNK_SIZE) {
+            let mut builder = QueryBuilder::<Postgres>::new(
+                "INSERT INTO instruments (instrument_id, raw_symbol, valid_from, valid_until) "
+            );
+            builder.push_values(instruments, |mut row, instrument| {
+                row.push_bind(instrument.instrument_id as i64)
+                    .push_bind(&instrument.raw_symbol)
+                    .push_bind(instrument.valid_from as i64)
+                    .push_bind(instrument.valid_until.map(|ts| ts as i64));
+            });
+            builder.push(
+                " ON CONFLICT (instrument_id, valid_from) DO UPDATE
+                  SET raw_symbol = excluded.raw_symbol, valid_until = excluded.valid_until"
+            );
+
+            builder.build()
+                .execute(&mut *tx)
+                .await
+                .context("Failed to upsert instruments")?;
+        }
+
+        tx.commit()
+            .await
+            .context("Failed to commit transaction")
+    }
+
+    async fn instruments(&self) -> Result<Vec<Instrument>> {
+        sqlx::query(
+            "SELECT instrument_id, raw_symbol, valid_from, valid_until FROM instruments
+             ORDER BY instrument_id, valid_from"
+        )
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query instruments")?
+            .iter()
+            .map(|row| Ok(Instrument {
+                instrument_id: row.try_get::<i64, _>("instrument_id")? as u32,
+                raw_symbol: row.try_get("raw_symbol")?,
+                valid_from: row.try_get::<i64, _>("valid_from")? as u64,
+                valid_until: row.try_get::<Option<i64>, _>("valid_until")?.map(|ts| ts as u64),
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to decode instruments")
+    }
+
+    #[tracing::instrument(skip(self, checkpoint), fields(message_id = checkpoint.message_id))]
+    async fn insert_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO checkpoints (message_id, ts_recv, source, data) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (message_id) DO UPDATE
+             SET ts_recv = excluded.ts_recv, source = excluded.source, data = excluded.data"
+        )
+            .bind(checkpoint.message_id)
+            .bind(checkpoint.ts_recv as i64)
+            .bind(&checkpoint.source)
+            .bind(&checkpoint.data)
+            .execute(&self.pool)
+            .await
+            .context("Failed to insert checkpoint")?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn latest_checkpoint(&self, source: Option<&str>, message_id: Option<i64>) -> Result<Option<Checkpoint>> {
+        sqlx::query(
+            "SELECT message_id, ts_recv, source, data FROM checkpoints
+             WHERE message_id <= $1 AND ($2::TEXT IS NULL OR source = $2)
+             ORDER BY message_id DESC
+             LIMIT 1"
+        )
+            .bind(message_id.unwrap_or(i64::MAX))
+            .bind(source)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query checkpoints")?
+            .map(|row| Ok(Checkpoint {
+                message_id: row.try_get("message_id")?,
+                ts_recv: row.try_get::<i64, _>("ts_recv")? as u64,
+                source: row.try_get("source")?,
+                data: row.try_get("data")?,
+            }))
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn compaction_generation(&self) -> Result<u64> {
+        let generation: i64 = sqlx::query("SELECT COALESCE(MAX(generation), 0) FROM compactions")
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to query compactions")?
+            .try_get(0)?;
+
+        Ok(generation as u64)
+    }
+
+    #[tracing::instrument(skip(self, compaction), fields(cutoff_ts = compaction.cutoff_ts, bars = compaction.bars.len()))]
+    async fn apply_compaction(&self, compaction: &Compaction) -> Result<Option<usize>> {
+        let compacted_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .context("System clock is before the UNIX epoch")?
+            .as_secs() as i64;
+        let mut tx = self.pool.begin()
+            .await
+            .context("Failed to begin transaction")?;
+
+        // Compactions built at the same generation claim the same number,
+        //  and a concurrent claim waits for the first to commit, so only
+        //  the first of them is applied
+        let claimed = sqlx::query(
+            "INSERT INTO compactions (generation, cutoff_ts, last_message_id, compacted_at)
+             SELECT $1, $2, $3, $4 WHERE (SELECT COALESCE(MAX(generation), 0) FROM compactions) = $5
+             ON CONFLICT (generation) DO NOTHING"
+        )
+            .bind(compaction.generation as i64 + 1)
+            .bind(compaction.cutoff_ts as i64)
+            .bind(compaction.last_message_id)
+            .bind(compacted_at)
+            .bind(compaction.generation as i64)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to record compaction")?
+            .rows_affected();
+        if claimed == 0 {
+            return Ok(None);
+        }
+
+        for bars in compaction.bars.chunks(INSERT_CHUNK_SIZE) {
+            let mut builder = QueryBuilder::<Postgres>::new(
+                "INSERT INTO bars (instrument_id, ts, open, high, low, close, volume, trades) "
+            );
+            builder.push_values(bars, |mut row, bar| {
+                row.push_bind(bar.instrument_id as i64)
+                    .push_bind(bar.ts as i64)
+                    .push_bind(bar.open)
+                    .push_bind(bar.high)
+                    .push_bind(bar.low)
+                    .push_bind(bar.close)
+                    .push_bind(bar.volume as i64)
+                    .push_bind(bar.trades as i64);
+            });
+            builder.push(
+                " ON CONFLICT (instrument_id, ts) DO UPDATE
+                  SET high = GREATEST(bars.high, excluded.high), low = LEAST(bars.low, excluded.low),
+                      close = excluded.close, volume = bars.volume + excluded.volume,
+                      trades = bars.trades + excluded.trades"
+            );
+
+            builder.build()
+                .execute(&mut *tx)
+                .await
+                .context("Failed to upsert bars")?;
+        }
+
+        let deleted = sqlx::query("DELETE FROM mbo_messages WHERE ts_recv < $1 AND id <= $2")
+            .bind(compaction.cutoff_ts as i64)
+            .bind(compaction.last_message_id)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to delete messages")?
+            .rows_affected();
+
+        tx.commit()
+            .await
+            .context("Failed to commit transaction")?;
+
+        Ok(Some(deleted as usize))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn bars(&self, instrument_id: Option<u32>, start_ts: Option<u64>, end_ts: Option<u64>) -> Result<Vec<Bar>> {
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "SELECT instrument_id, ts, open, high, low, close, volume, trades FROM bars WHERE TRUE"
+        );
+        if let Some(instrument_id) = instrument_id {
+            builder.push(" AND instrument_id = ").push_bind(instrument_id as i64);
+        }
+        if let Some(start_ts) = start_ts {
+            builder.push(" AND ts >= ").push_bind(start_ts as i64);
+        }
+        if let Some(end_ts) = end_ts {
+            builder.push(" AND ts <= ").push_bind(end_ts as i64);
+        }
+        builder.push(" ORDER BY instrument_id, ts");
+
+        builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query bars")?
+            .iter()
+            .map(|row| Ok(Bar {
+                instrument_id: row.try_get::<i64, _>("instrument_id")? as u32,
+                ts: row.try_get::<i64, _>("ts")? as u64,
+                open: row.try_get("open")?,
+                high: row.try_get("high")?,
+                low: row.try_get("low")?,
+                close: row.try_get("close")?,
+                volume: row.try_get::<i64, _>("volume")? as u64,
+                trades: row.try_get::<i64, _>("trades")? as u64,
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to decode bars")
+    }
+
+    /// Marks the space of deleted rows as reusable, without returning it
+    ///  to the operating system as `VACUUM FULL` would
+    #[tracing::instrument(skip(self))]
+    async fn vacuum(&self) -> Result<()> {
+        sqlx::raw_sql("VACUUM ANALYZE mbo_messages")
+            .execute(&self.pool)
+            .await
+            .context("Failed to vacuum mbo_messages")?;
+
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        let row = sqlx::query(
+            "SELECT
+                (SELECT COUNT(*) FROM mbo_messages) AS messages,
+                (SELECT COUNT(*) FROM checkpoints) AS checkpoints,
+                (SELECT COUNT(*) FROM bars) AS bars,
+                (SELECT MIN(ts_recv) FROM mbo_messages) AS oldest_ts_recv,
+                pg_database_size(current_database()) AS size_bytes"
+        )
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to query storage stats")?;
+
+        Ok(StorageStats {
+            messages: row.try_get::<i64, _>("messages")? as u64,
+            checkpoints: row.try_get::<i64, _>("checkpoints")? as u64,
+            bars: row.try_get::<i64, _>("bars")? as u64,
+            oldest_ts_recv: row.try_get::<Option<i64>, _>("oldest_ts_recv")?.map(|ts| ts as u64),
+            size_bytes: row.try_get::<i64, _>("size_bytes")? as u64,
+        })
+    }
+}
+
+fn dataset_from_row(row: &PgRow) -> Result<StoredDataset> {
+    let first: Option<i64> = row.try_get("first_message_id")?;
+    let last: Option<i64> = row.try_get("last_message_id")?;
+
+    Ok(StoredDataset {
+        fingerprint: row.try_get("fingerprint")?,
+        source: row.try_get("source")?,
+        message_count: row.try_get::<i64, _>("message_count")? as usize,
+        message_ids: first.zip(last),
+    })
+}
+
+/// Rebuild an `MboMsg` from a row of `mbo_messages`
+fn mbo_from_row(row: &PgRow) -> Result<MboMsg> {
+    let action = row.try_get::<i16, _>("action")? as u8;
+    Action::try_from(action)
+        .map_err(|_| anyhow!("Invalid action `{}`", action))?;
+    let side = row.try_get::<i16, _>("side")? as u8;
+    Side::try_from(side)
+        .map_err(|_| anyhow!("Invalid side `{}`", side))?;
+
+    Ok(MboMsg {
+        hd: RecordHeader::new::<MboMsg>(
+            rtype::MBO,
+            row.try_get::<i32, _>("publisher")? as u16,
+            row.try_get::<i64, _>("instrument_id")? as u32,
+            row.try_get::<i64, _>("ts_event")? as u64,
+        ),
+        order_id: row.try_get::<i64, _>("order_id")? as u64,
+        price: row.try_get("price")?,
+        size: row.try_get::<i64, _>("size")? as u32,
+        flags: FlagSet::new(row.try_get::<i16, _>("flags")? as u8),
+        channel_id: row.try_get::<i16, _>("channel_id")? as u8,
+        action: action as c_char,
+        side: side as c_char,
+        ts_recv: row.try_get::<i64, _>("ts_recv")? as u64,
+        ts_in_delta: row.try_get("ts_in_delta")?,
+        sequence: row.try_get::<i64, _>("sequence")? as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against a throwaway database created through the server at
+    ///  `TEST_POSTGRES_URL`, so it's ignored unless asked for, e.g.
+    ///
+    /// `TEST_POSTGRES_URL=postgres://postgres@localhost:5432/postgres cargo test -- --ignored test_postgres_store_contract`
+    #[tokio::test]
+    #[ignore = "needs a Postgres server at TEST_POSTGRES_URL"]
+    async fn test_postgres_store_contract() -> Result<()> {
+        let url = std::env::var("TEST_POSTGRES_URL")
+            .context("TEST_POSTGRES_URL must be set to run the Postgres test")?;
+
+        let admin = PgPool::connect(&url).await?;
+        let database = format!(
+            "mbo_test_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos()
+        );
+        sqlx::query(&format!("CREATE DATABASE {}", database))
+            .execute(&admin)
+            .await?;
+
+        let options = url.parse::<sqlx::postgres::PgConnectOptions>()?
+            .database(&database);
+        let result = async {
+            let pool = PgPoolOptions::new().connect_with(options).await?;
+            migrations::migrate(&pool).await?;
+            assert_eq!(migrations::current_version(&pool).await?, migrations::latest_version());
+
+            // Migrating again is a no-op
+            migrations::migrate(&pool).await?;
+
+            let store = PostgresStore { pool };
+            crate::storage::contract::exercise_store(&store).await?;
+            store.pool.close().await;
+
+            anyhow::Ok(())
+        }.await;
+
+        sqlx::query(&format!("DROP DATABASE IF EXISTS {}", database))
+            .execute(&admin)
+            .await?;
+
+        result
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::BTreeMap, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use databento::dbn::{Action, UNDEF_PRICE};
+use anyhow::{Context, Result, bail};
+use tracing::{info, warn, error};
+
+use super::{Bar, Compaction, MarketStore, MessageQuery, MessageStream, Storage, StorageStats, LIVE_SOURCE};
+use crate::metrics::Metrics;
+
+/// Interval of the bars kept in place of compacted messages
+pub const BAR_INTERVAL_NS: u64 = 60_000_000_000;
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+
+/// Times a compaction is rebuilt after losing to a concurrent one
+const COMPACTION_ATTEMPTS: usize = 3;
+
+/// How long raw messages are kept, loaded from the environment
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    pub raw_days: u64,
+    /// Time between compactions
+    pub interval: Duration,
+    /// Whether to reclaim the freed space after a compaction deletes rows
+    pub vacuum: bool,
+}
+impl RetentionConfig {
+    /// `None` when `RETENTION_RAW_DAYS` isn't set, otherwise compaction
+    ///  runs every `RETENTION_INTERVAL_SECS` (default: an hour) followed
+    ///  by a vacuum unless `RETENTION_VACUUM` is `false`
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(raw_days) = std::env::var("RETENTION_RAW_DAYS") else {
+            return Ok(None);
+        };
+
+        let interval = std::env::var("RETENTION_INTERVAL_SECS")
+            .map(|secs| secs.parse::<u64>())
+            .unwrap_or(Ok(3600))
+            .context("...while parsing RETENTION_INTERVAL_SECS")?;
+        let vacuum = std::env::var("RETENTION_VACUUM")
+            .map(|value| value.parse::<bool>())
+            .unwrap_or(Ok(true))
+            .context("...while parsing RETENTION_VACUUM")?;
+
+        Ok(Some(Self {
+            raw_days: raw_days.parse()
+                .context("...while parsing RETENTION_RAW_DAYS")?,
+            interval: Duration::from_secs(interval),
+            vacuum,
+        }))
+    }
+
+    /// Messages received before this time, in nanoseconds since the UNIX
+    ///  epoch, are due for compaction
+    pub fn cutoff_ts(&self) -> Result<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System clock is before the UNIX epoch")?
+            .as_nanos() as u64;
+
+        Ok(now.saturating_sub(self.raw_days.saturating_mul(NANOS_PER_DAY)))
+    }
+}
+
+/// Outcome of a single compaction
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct CompactionReport {
+    /// Messages received before this time were compacted
+    pub cutoff_ts: u64,
+    pub bars: usize,
+    pub deleted_messages: usize,
+}
+
+/// Aggregate the trades received before `before_ts` into bars, then
+///  delete the raw messages they came from
+///
+/// The cutoff is moved back to the start of a bar interval, so every
+///  bar is built from complete data, and to no later than the latest
+///  checkpoint or the live market's latest one, whichever is earlier,
+///  so a restore never needs deleted messages.
+///
+/// The bars are stored and the messages deleted in one transaction,
+///  which is rejected if another compaction was applied in the meantime,
+///  so no trade is ever counted twice.
+#[tracing::instrument(skip(storage))]
+pub async fn compact(storage: &Storage, before_ts: u64) -> Result<CompactionReport> {
+    let mut checkpoint_ts = u64::MAX;
+    for source in [None, Some(LIVE_SOURCE)] {
+        let checkpoint = storage.latest_checkpoint(source, None)
+            .await
+            .context("...while reading latest checkpoint")?;
+        if let Some(checkpoint) = checkpoint {
+            checkpoint_ts = checkpoint_ts.min(checkpoint.ts_recv);
+        }
+    }
+    let cutoff_ts = before_ts.min(checkpoint_ts) / BAR_INTERVAL_NS * BAR_INTERVAL_NS;
+    let skipped = CompactionReport { cutoff_ts, bars: 0, deleted_messages: 0 };
+    if cutoff_ts == 0 {
+        return Ok(skipped);
+    }
+
+    for _ in 0..COMPACTION_ATTEMPTS {
+        let Some(compaction) = build_compaction(storage, cutoff_ts).await? else {
+            return Ok(skipped);
+        };
+
+        match storage.apply_compaction(&compaction).await.context("...while applying compaction")? {
+            Some(deleted_messages) => {
+                info!(cutoff_ts, bars = compaction.bars.len(), deleted_messages, "Compacted stored messages");
+                return Ok(CompactionReport {
+                    cutoff_ts,
+                    bars: compaction.bars.len(),
+                    deleted_messages,
+                });
+            }
+            None => warn!(generation = compaction.generation, "Another compaction was applied meanwhile, rebuilding"),
+        }
+    }
+
+    bail!("Compaction was overtaken by concurrent ones {} times", COMPACTION_ATTEMPTS)
+}
+
+/// Aggregate the trades stored before `cutoff_ts` into bars, or `None` if
+///  no messages are stored
+async fn build_compaction(storage: &Storage, cutoff_ts: u64) -> Result<Option<Compaction>> {
+    // Read before the trades, so a compaction applied while they're read
+    //  is noticed
+    let generation = storage.compaction_generation()
+        .await
+        .context("...while reading compaction generation")?;
+    let Some(last_message_id) = storage.query_messages(&MessageQuery {
+        limit: Some(1),
+        descending: true,
+        ..Default::default()
+    }).await
+        .context("...while reading latest stored message")?
+        .first()
+        .map(|stored| stored.id) else {
+        return Ok(None);
+    };
+
+    let mut bars = BTreeMap::<(u32, u64), Bar>::new();
+    let mut trades = MessageStream::new(storage.clone(), MessageQuery {
+        action: Some(Action::Trade),
+        end_ts: Some(cutoff_ts - 1),
+        end_id: Some(last_message_id),
+        ..Default::default()
+    });
+    while let Some(stored) = trades.next_message().await? {
+        let msg = stored.mbo_msg;
+        if msg.price == UNDEF_PRICE {
+            continue;
+        }
+
+        let trade = Bar {
+            instrument_id: msg.hd.instrument_id,
+            ts: msg.ts_recv / BAR_INTERVAL_NS * BAR_INTERVAL_NS,
+            open: msg.price,
+            high: msg.price,
+            low: msg.price,
+            close: msg.price,
+            volume: msg.size as u64,
+            trades: 1,
+        };
+        bars.entry((trade.instrument_id, trade.ts))
+            .and_modify(|bar| bar.merge(&trade))
+            .or_insert(trade);
+    }
+
+    Ok(Some(Compaction {
+        generation,
+        cutoff_ts,
+        last_message_id,
+        bars: bars.into_values().collect(),
+    }))
+}
+
+/// Read the store's size and publish it to the metrics
+pub async fn report_stats(storage: &Storage, metrics: &Metrics) -> Result<StorageStats> {
+    let stats = storage.stats()
+        .await
+        .context("...while reading storage stats")?;
+
+    metrics.storage_messages.set(stats.messages as i64);
+    metrics.storage_checkpoints.set(stats.checkpoints as i64);
+    metrics.storage_bars.set(stats.bars as i64);
+    metrics.storage_size_bytes.set(stats.size_bytes as i64);
+
+    Ok(stats)
+}
+
+/// Compact with the configured retention, vacuum if anything was
+///  deleted, and publish the outcome to the metrics
+pub async fn run_once(storage: &Storage, config: &RetentionConfig, metrics: &Metrics) -> Result<CompactionReport> {
+    let report = compact(storage, config.cutoff_ts()?).await?;
+    if config.vacuum && report.deleted_messages > 0 {
+        storage.vacuum()
+            .await
+            .context("...while vacuuming storage")?;
+    }
+
+    metrics.compacted_messages.inc_by(report.deleted_messages as f64);
+    metrics.last_compaction_timestamp.set(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System clock is before the UNIX epoch")?
+            .as_secs() as i64
+    );
+    report_stats(storage, metrics).await?;
+
+    Ok(report)
+}
+
+/// Compact on the configured interval until the process exits
+#[tracing::instrument(skip_all)]
+pub async fn run(storage: Storage, config: RetentionConfig, metrics: Arc<Metrics>) {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        // A failed compaction is retried on the next tick
+        if let Err(e) = run_once(&storage, &config, &metrics).await {
+            error!("Compaction failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::{datatypes::market::{load_market_snapshots, restore_market}, ingest::decoder::DbnSource};
+
+    #[tokio::test]
+    async fn test_compaction_keeps_bars_and_restorable_market() -> Result<()> {
+        let storage = Storage::open(":memory:").await?;
+        let mut source = DbnSource::from_file(Path::new("assets/CLX5_mbo.dbn")).await?;
+        let snapshots = load_market_snapshots(&mut source, Some((&storage, LIVE_SOURCE))).await?;
+        let first_ts = snapshots.first().context("No snapshots")?.mbomsg_effect.mbo_msg.ts_recv;
+        let last_ts = snapshots.last().context("No snapshots")?.mbomsg_effect.mbo_msg.ts_recv;
+
+        // Compact the first half of the file
+        let before_ts = first_ts + (last_ts - first_ts) / 2;
+        let report = compact(&storage, before_ts).await?;
+        assert!(report.cutoff_ts <= before_ts && report.cutoff_ts > before_ts - BAR_INTERVAL_NS);
+        let kept = snapshots.iter()
+            .filter(|snapshot| snapshot.mbomsg_effect.mbo_msg.ts_recv >= report.cutoff_ts)
+            .count();
+        assert_eq!(report.deleted_messages, snapshots.len() - kept);
+        assert_eq!(storage.count_messages().await?, kept);
+
+        // Bars match the trades of the compacted range
+        let trades = snapshots.iter()
+            .map(|snapshot| &snapshot.mbomsg_effect.mbo_msg)
+            .filter(|msg| msg.ts_recv < report.cutoff_ts && msg.action == Action::Trade as u8 as std::ffi::c_char)
+            .collect::<Vec<_>>();
+        let bars = storage.bars(None, None, None).await?;
+        assert_eq!(bars.len(), report.bars);
+        assert_eq!(bars.iter().map(|bar| bar.trades).sum::<u64>(), trades.len() as u64);
+        assert_eq!(bars.iter().map(|bar| bar.volume).sum::<u64>(), trades.iter().map(|msg| msg.size as u64).sum::<u64>());
+        assert!(bars.iter().all(|bar| bar.low <= bar.open.min(bar.close) && bar.high >= bar.open.max(bar.close)));
+
+        // Compacting the same range again finds nothing left to do
+        let again = compact(&storage, before_ts).await?;
+        assert_eq!((again.bars, again.deleted_messages), (0, 0));
+        assert_eq!(storage.bars(None, None, None).await?, bars);
+
+        // Of two compactions built alongside each other, e.g. by a retry
+        //  or another instance, only the first is applied
+        let later_ts = before_ts + (last_ts - before_ts) / 2;
+        let cutoff_ts = later_ts / BAR_INTERVAL_NS * BAR_INTERVAL_NS;
+        let first = build_compaction(&storage, cutoff_ts).await?.context("Messages should be stored")?;
+        let second = build_compaction(&storage, cutoff_ts).await?.context("Messages should be stored")?;
+        assert_eq!(first, second);
+        assert!(storage.apply_compaction(&first).await?.is_some_and(|deleted| deleted > 0));
+        assert_eq!(storage.apply_compaction(&second).await?, None);
+        let compacted_trades = snapshots.iter()
+            .map(|snapshot| &snapshot.mbomsg_effect.mbo_msg)
+            .filter(|msg| msg.ts_recv < cutoff_ts && msg.action == Action::Trade as u8 as std::ffi::c_char)
+            .count();
+        let bars = storage.bars(None, None, None).await?;
+        assert_eq!(bars.iter().map(|bar| bar.trades).sum::<u64>(), compacted_trades as u64);
+
+        // Nothing past the latest checkpoint is ever compacted, so the
+        //  market can still be restored
+        let report = compact(&storage, u64::MAX).await?;
+        assert!(report.cutoff_ts <= last_ts);
+        assert!(storage.count_messages().await? > 0);
+        let (market, _) = restore_market(&storage, LIVE_SOURCE, None).await?;
+        assert_eq!(
+            serde_json::to_value(&market)?,
+            serde_json::to_value(&snapshots.last().context("No snapshots")?.market)?,
+        );
+
+        storage.vacuum().await?;
+        let stats = storage.stats().await?;
+        assert_eq!(stats.messages as usize, storage.count_messages().await?);
+        assert_eq!(stats.bars as usize, storage.bars(None, None, None).await?.len());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+use sqlx::{Row, Sqlite, SqlitePool};
+use anyhow::{Context, Result, bail};
+use tracing::info;
+
+use crate::storage::Migration;
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create instruments and mbo_messages tables",
+        sql: "
+            CREATE TABLE instruments (
+                instrument_id INTEGER PRIMARY KEY,
+                symbol TEXT,
+                publisher INTEGER NOT NULL
+            );
+
+            CREATE TABLE mbo_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                ts_recv INTEGER NOT NULL,
+                ts_event INTEGER NOT NULL,
+                instrument_id INTEGER NOT NULL,
+                publisher INTEGER NOT NULL,
+                order_id INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                side TEXT NOT NULL,
+                price INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                flags INTEGER NOT NULL,
+                sequence INTEGER NOT NULL,
+                ts_in_delta INTEGER NOT NULL,
+                channel_id INTEGER NOT NULL
+            );
+
+            CREATE INDEX idx_mbo_instrument_time ON mbo_messages(instrument_id, ts_recv DESC);
+            CREATE INDEX idx_mbo_order ON mbo_messages(order_id, ts_recv DESC);
+            CREATE INDEX idx_mbo_publisher ON mbo_messages(publisher, instrument_id, ts_recv DESC);
+        ",
+    },
+    // Messages written by version 1 had `ts_recv`/`ts_event` swapped and
+    //  were duplicated on every restart. The timestamps are swapped back,
+    //  copies identical on every column collapse to one row, and rows
+    //  sharing a natural key with a different payload are kept as further
+    //  occurrences of it.
+    Migration {
+        version: 2,
+        description: "Deduplicate mbo_messages on a natural key and track ingested datasets",
+        sql: "
+            DROP INDEX idx_mbo_instrument_time;
+            DROP INDEX idx_mbo_order;
+            DROP INDEX idx_mbo_publisher;
+            ALTER TABLE mbo_messages RENAME TO mbo_messages_v1;
+
+            CREATE TABLE mbo_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                ts_recv INTEGER NOT NULL,
+                ts_event INTEGER NOT NULL,
+                instrument_id INTEGER NOT NULL,
+                publisher INTEGER NOT NULL,
+                order_id INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                side TEXT NOT NULL,
+                price INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                flags INTEGER NOT NULL,
+                sequence INTEGER NOT NULL,
+                ts_in_delta INTEGER NOT NULL,
+                channel_id INTEGER NOT NULL,
+                occurrence INTEGER NOT NULL
+            );
+
+            INSERT INTO mbo_messages (id, ts_recv, ts_event, instrument_id, publisher, order_id, action,
+                side, price, size, flags, sequence, ts_in_delta, channel_id, occurrence)
+                SELECT id, ts_event, ts_recv, instrument_id, publisher, order_id, action,
+                    side, price, size, flags, sequence, ts_in_delta, channel_id,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY publisher, instrument_id, channel_id, sequence, ts_event, order_id
+                        ORDER BY id
+                    ) - 1
+                FROM (
+                    SELECT MIN(id) AS id, ts_recv, ts_event, instrument_id, publisher, order_id, action,
+                        side, price, size, flags, sequence, ts_in_delta, channel_id
+                    FROM mbo_messages_v1
+                    GROUP BY ts_recv, ts_event, instrument_id, publisher, order_id, action,
+                        side, price, size, flags, sequence, ts_in_delta, channel_id
+                );
+            DROP TABLE mbo_messages_v1;
+
+            CREATE INDEX idx_mbo_instrument_time ON mbo_messages(instrument_id, ts_recv DESC);
+            CREATE INDEX idx_mbo_order ON mbo_messages(order_id, ts_recv DESC);
+            CREATE INDEX idx_mbo_publisher ON mbo_messages(publisher, instrument_id, ts_recv DESC);
+            CREATE UNIQUE INDEX idx_mbo_natural_key
+                ON mbo_messages(publisher, instrument_id, channel_id, sequence, ts_recv, order_id, occurrence);
+
+            CREATE TABLE datasets (
+                fingerprint TEXT PRIMARY KEY,
+                source TEXT NOT NULL,
+                message_count INTEGER NOT NULL,
+                ingested_at INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "Create checkpoints table",
+        sql: "
+            CREATE TABLE checkpoints (
+                message_id INTEGER PRIMARY KEY,
+                ts_recv INTEGER NOT NULL,
+                data BLOB NOT NULL
+            );
+        ",
+    },
+    // Mappings without a symbol carried no information and are dropped,
+    //  the rest are kept as valid for all time
+    Migration {
+        version: 4,
+        description: "Store instrument symbol mappings with validity ranges",
+        sql: "
+            CREATE TABLE instrument_mappings (
+                instrument_id INTEGER NOT NULL,
+                raw_symbol TEXT NOT NULL,
+                valid_from INTEGER NOT NULL,
+                valid_until INTEGER,
+                PRIMARY KEY (instrument_id, valid_from)
+            );
+
+            INSERT INTO instrument_mappings (instrument_id, raw_symbol, valid_from, valid_until)
+                SELECT instrument_id, symbol, 0, NULL FROM instruments WHERE symbol IS NOT NULL;
+
+            DROP TABLE instruments;
+            ALTER TABLE instrument_mappings RENAME TO instruments;
+            CREATE INDEX idx_instruments_symbol ON instruments(raw_symbol);
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "Create bars table and index messages by time for retention",
+        sql: "
+            CREATE TABLE bars (
+                instrument_id INTEGER NOT NULL,
+                ts INTEGER NOT NULL,
+                open INTEGER NOT NULL,
+                high INTEGER NOT NULL,
+                low INTEGER NOT NULL,
+                close INTEGER NOT NULL,
+                volume INTEGER NOT NULL,
+                trades INTEGER NOT NULL,
+                PRIMARY KEY (instrument_id, ts)
+            );
+
+            CREATE INDEX idx_mbo_time ON mbo_messages(ts_recv);
+        ",
+    },
+    // Datasets persisted before have no known range and are re-read from
+    //  their source rather than rebuilt from storage
+    Migration {
+        version: 6,
+        description: "Record the message row IDs each dataset inserted",
+        sql: "
+            ALTER TABLE datasets ADD COLUMN first_message_id INTEGER;
+            ALTER TABLE datasets ADD COLUMN last_message_id INTEGER;
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "Record applied compactions",
+        sql: "
+            CREATE TABLE compactions (
+                generation INTEGER PRIMARY KEY,
+                cutoff_ts INTEGER NOT NULL,
+                last_message_id INTEGER NOT NULL,
+                compacted_at INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "Tag checkpoints with the source of the market they were taken of",
+        sql: "
+            ALTER TABLE checkpoints ADD COLUMN source TEXT NOT NULL DEFAULT '';
+            CREATE INDEX idx_checkpoints_source ON checkpoints(source, message_id);
+        ",
+    },
+];
+
+/// Latest schema version known to this build
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Bring the schema up to the latest version, applying each pending
+///  migration in its own transaction
+#[tracing::instrument(skip(pool))]
+pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )"
+    ).execute(pool)
+        .await
+        .context("Failed to create schema_version table")?;
+
+    let mut current = current_version(pool).await?;
+    if current == 0 {
+        current = detect_unversioned(pool).await?;
+        if current > 0 {
+            info!("Adopting unversioned database at schema version {}", current);
+            record_version(pool, &MIGRATIONS[current as usize - 1]).await?;
+        }
+    }
+
+    if current > latest_version() {
+        bail!(
+            "Database schema version {} is newer than the latest known version {}",
+            current,
+            latest_version()
+        );
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        info!(version = migration.version, "Applying migration: {}", migration.description);
+
+        let mut tx = pool.begin()
+            .await
+            .context("Failed to begin migration transaction")?;
+        sqlx::raw_sql(migration.sql)
+            .execute(&mut *tx)
+            .await
+            .context(format!("Failed to apply migration {}", migration.version))?;
+        record_version(&mut *tx, migration).await?;
+        tx.commit()
+            .await
+            .context(format!("Failed to commit migration {}", migration.version))?;
+    }
+
+    Ok(())
+}
+
+/// Highest migration recorded in `schema_version`, or 0 if none
+pub async fn current_version(pool: &SqlitePool) -> Result<u32> {
+    let version: Option<u32> = sqlx::query("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await
+        .context("Failed to read schema version")?
+        .try_get(0)
+        .context("Failed to decode schema version")?;
+
+    Ok(version.unwrap_or(0))
+}
+
+async fn record_version<'c, E>(executor: E, migration: &Migration) -> Result<()>
+where
+    E: sqlx::Executor<'c, Database = Sqlite>,
+{
+    let applied_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("System clock is before the UNIX epoch")?
+        .as_secs() as i64;
+
+    sqlx::query("INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)")
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(applied_at)
+        .execute(executor)
+        .await
+        .context("Failed to record schema version")?;
+
+    Ok(())
+}
+
+/// Work out which version a database created before `schema_version`
+///  existed is at, from the shape of its tables
+async fn detect_unversioned(pool: &SqlitePool) -> Result<u32> {
+    let has_messages = sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'mbo_messages'")
+        .fetch_optional(pool)
+        .await
+        .context("Failed to inspect database tables")?
+        .is_some();
+    if !has_messages {
+        return Ok(0);
+    }
+
+    let has_occurrence: bool = sqlx::query("SELECT COUNT(*) > 0 FROM pragma_table_info('mbo_messages') WHERE name = 'occurrence'")
+        .fetch_one(pool)
+        .await
+        .context("Failed to inspect mbo_messages columns")?
+        .try_get(0)?;
+
+    Ok(if has_occurrence { 2 } else { 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Connection, SqliteConnection};
+    use crate::storage::{sqlite::SqliteStore, MarketStore, MessageQuery};
+
+    fn remove_db(path: &str) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(format!("{}-shm", path));
+        let _ = std::fs::remove_file(format!("{}-wal", path));
+    }
+
+    /// Create a database at `path` by running raw SQL, bypassing migrations
+    async fn create_raw(path: &str, sql: &str) -> Result<()> {
+        let mut conn = SqliteConnection::connect(&format!("sqlite://{}?mode=rwc", path)).await?;
+        sqlx::raw_sql(sql).execute(&mut conn).await?;
+        conn.close().await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1, "Migrations should be numbered from 1 without gaps");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_from_unversioned_v1_database() -> Result<()> {
+        let temp_db = "test_migrations_v1.db";
+        remove_db(temp_db);
+
+        // A database as created before migrations existed
+        create_raw(temp_db, &format!(
+            "{}
+             INSERT INTO mbo_messages (ts_recv, ts_event, instrument_id, publisher, order_id, action,
+                side, price, size, flags, sequence, ts_in_delta, channel_id)
+             VALUES (2, 1, 42, 1, 5, '65', '66', 100, 1, 0, 7, 0, 0),
+                    (2, 1, 42, 1, 5, '65', '66', 100, 1, 0, 7, 0, 0),
+                    (2, 1, 42, 1, 5, '65', '66', 100, 3, 0, 7, 0, 0),
+                    (4, 3, 42, 1, 6, '65', '66', 101, 2, 0, 8, 0, 0);
+             INSERT INTO instruments (instrument_id, symbol, publisher) VALUES (42, 'CLX5', 1);",
+            MIGRATIONS[0].sql
+        )).await?;
+
+        // Version 1 wrote `ts_event` into `ts_recv` and the other way round
+        let storage = SqliteStore::open(temp_db).await?;
+        let messages = storage.query_messages(&MessageQuery::default()).await?;
+        assert_eq!(messages.len(), 3, "v1 messages should survive the upgrade");
+        let timestamps = messages.iter()
+            .map(|stored| (stored.mbo_msg.order_id, stored.mbo_msg.ts_recv, stored.mbo_msg.hd.ts_event))
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![(5, 1, 2), (5, 1, 2), (6, 3, 4)], "v1 timestamps should be swapped back");
+
+        // The restart duplicate collapses, while a different payload under
+        //  the same natural key is kept as its next occurrence
+        let rows: Vec<(i64, i64)> = sqlx::query_as("SELECT size, occurrence FROM mbo_messages ORDER BY id")
+            .fetch_all(&storage.pool)
+            .await?;
+        assert_eq!(rows, vec![(1, 0), (3, 1), (2, 0)]);
+        assert_eq!(storage.insert_mbo_batch(&[messages[2].mbo_msg.clone()]).await?, 0, "Upgraded rows should deduplicate new inserts");
+
+        assert_eq!(current_version(&storage.pool).await?, latest_version());
+        let instruments = storage.instruments().await?;
+        assert_eq!(instruments.len(), 1, "Instruments should survive the upgrade");
+        assert_eq!(instruments[0].raw_symbol, "CLX5");
+        assert!(instruments[0].is_valid_at(0));
+        assert_eq!(storage.dataset("anything").await?, None);
+
+        drop(storage);
+        remove_db(temp_db);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_adopt_unversioned_v2_database_and_reopen() -> Result<()> {
+        let temp_db = "test_migrations_v2.db";
+        remove_db(temp_db);
+
+        // A database as created by the deduplicating schema, before
+        //  versions were recorded
+        create_raw(temp_db, &format!(
+            "{}{}
+             INSERT INTO mbo_messages (ts_recv, ts_event, instrument_id, publisher, order_id, action,
+                side, price, size, flags, sequence, ts_in_delta, channel_id, occurrence)
+             VALUES (2, 1, 42, 1, 5, '65', '66', 100, 1, 0, 7, 0, 0, 0);",
+            MIGRATIONS[0].sql,
+            MIGRATIONS[1].sql
+        )).await?;
+
+        let storage = SqliteStore::open(temp_db).await?;
+        assert_eq!(storage.count_messages().await?, 1, "Current messages should be kept");
+        drop(storage);
+
+        // Reopening applies nothing
+        let storage = SqliteStore::open(temp_db).await?;
+        assert_eq!(storage.count_messages().await?, 1);
+        let applied: i64 = sqlx::query("SELECT COUNT(*) FROM schema_version")
+            .fetch_one(&storage.pool)
+            .await?
+            .try_get(0)?;
+        assert_eq!(applied, latest_version() as i64 - 1, "Only migrations after the adopted version should be recorded");
+
+        drop(storage);
+        remove_db(temp_db);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_newer_database_is_rejected() -> Result<()> {
+        let temp_db = "test_migrations_newer.db";
+        remove_db(temp_db);
+
+        drop(SqliteStore::open(temp_db).await?);
+        create_raw(temp_db, &format!(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ({}, 'From the future', 0);",
+            latest_version() + 1
+        )).await?;
+
+        assert!(SqliteStore::open(temp_db).await.is_err(), "Unknown schema versions should not be opened");
+
+        remove_db(temp_db);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,867 @@
+mod migrations;
+mod query;
+
+use std::{path::Path, str::FromStr, time::Duration};
+
+use databento::dbn::MboMsg;
+use anyhow::{Context, Result, anyhow};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow},
+    QueryBuilder, Row, Sqlite, SqlitePool,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, debug};
+
+use super::{Bar, Checkpoint, Compaction, Instrument, MarketStore, MessageQuery, StorageStats, StoredDataset, StoredMbo, natural_key_occurrences};
+
+/// Connections shared by readers and the writer task
+const POOL_SIZE: u32 = 8;
+
+/// Rows per `INSERT`, keeping the bind count under SQLite's 32766 limit
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Writes queued for the writer task before callers have to wait
+const WRITE_QUEUE_SIZE: usize = 64;
+
+/// SQLite storage on an async connection pool
+///
+/// Reads run concurrently on the pool, while every write is handed to a
+///  single writer task so they never contend for SQLite's write lock.
+#[derive(Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+    writer: mpsc::Sender<WriteCommand>,
+}
+impl SqliteStore {
+    /// Open or create the database at `db_path`, or a private in-memory
+    ///  database for `:memory:`
+    #[tracing::instrument]
+    pub async fn open<P: AsRef<Path> + std::fmt::Debug>(db_path: P) -> Result<Self> {
+        info!("Opening SQLite database at {:?}", db_path.as_ref());
+
+        // Every connection to `:memory:` is a separate database, so the pool
+        //  is limited to a single connection which is never closed
+        let in_memory = db_path.as_ref() == Path::new(":memory:");
+        let (options, pool_options) = if in_memory {
+            (
+                SqliteConnectOptions::from_str("sqlite::memory:")
+                    .context("Failed to parse in-memory database options")?,
+                SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .min_connections(1)
+                    .idle_timeout(None)
+                    .max_lifetime(None),
+            )
+        } else {
+            (
+                // WAL mode lets readers run alongside the writer
+                SqliteConnectOptions::new()
+                    .filename(db_path.as_ref())
+                    .create_if_missing(true)
+                    .journal_mode(SqliteJournalMode::Wal),
+                SqlitePoolOptions::new()
+                    .max_connections(POOL_SIZE),
+            )
+        };
+        let options = options
+            .foreign_keys(true)
+            .busy_timeout(Duration::from_secs(5));
+
+        let pool = pool_options.connect_with(options)
+            .await
+            .context("Failed to open SQLite database")?;
+
+        info!("Initializing database schema...");
+        migrations::migrate(&pool)
+            .await
+            .context("Failed to migrate database schema")?;
+        info!("Database schema initialized successfully");
+
+        let (writer, commands) = mpsc::channel(WRITE_QUEUE_SIZE);
+        tokio::spawn(run_writer(pool.clone(), commands));
+
+        Ok(Self { pool, writer })
+    }
+
+    /// Queue a write for the writer task and wait for its result
+    async fn write<T>(&self, command: impl FnOnce(oneshot::Sender<Result<T>>) -> WriteCommand) -> Result<T> {
+        let (reply, result) = oneshot::channel();
+        self.writer.send(command(reply))
+            .await
+            .map_err(|_| anyhow!("SQLite writer task has stopped"))?;
+
+        result.await
+            .map_err(|_| anyhow!("SQLite writer task dropped the write"))?
+    }
+}
+impl MarketStore for SqliteStore {
+    #[tracing::instrument(skip(self, messages), fields(count = messages.len()))]
+    async fn insert_mbo_batch(&self, messages: &[MboMsg]) -> Result<usize> {
+        self.write(|reply| WriteCommand::InsertMboBatch {
+            messages: messages.to_vec(),
+            reply,
+        }).await
+    }
+
+    #[tracing::instrument(skip(self, mbo_msg), fields(order_id = mbo_msg.order_id))]
+    async fn find_message_id(&self, mbo_msg: &MboMsg) -> Result<Option<i64>> {
+        sqlx::query(
+            "SELECT MAX(id) FROM mbo_messages
+             WHERE publisher = ?1 AND instrument_id = ?2 AND channel_id = ?3
+               AND sequence = ?4 AND ts_recv = ?5 AND order_id = ?6"
+        )
+            .bind(mbo_msg.hd.publisher_id)
+            .bind(mbo_msg.hd.instrument_id)
+            .bind(mbo_msg.channel_id)
+            .bind(mbo_msg.sequence)
+            .bind(mbo_msg.ts_recv as i64)
+            .bind(mbo_msg.order_id as i64)
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to find message")?
+            .try_get(0)
+            .context("Failed to decode message ID")
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn query_messages(&self, query: &MessageQuery) -> Result<Vec<StoredMbo>> {
+        query::query_messages(&self.pool, query).await
+    }
+
+    async fn count_messages(&self) -> Result<usize> {
+        let count: i64 = sqlx::query("SELECT COUNT(*) FROM mbo_messages")
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to count messages")?
+            .try_get(0)?;
+
+        Ok(count as usize)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn dataset(&self, fingerprint: &str) -> Result<Option<StoredDataset>> {
+        sqlx::query(
+            "SELECT fingerprint, source, message_count, first_message_id, last_message_id
+             FROM datasets WHERE fingerprint = ?1"
+        )
+            .bind(fingerprint)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query datasets")?
+            .map(|row| dataset_from_row(&row))
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn datasets(&self) -> Result<Vec<StoredDataset>> {
+        // Recording a dataset again replaces its row, so the latest has
+        //  the highest rowid
+        sqlx::query(
+            "SELECT fingerprint, source, message_count, first_message_id, last_message_id
+             FROM datasets ORDER BY rowid"
+        )
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query datasets")?
+            .iter()
+            .map(dataset_from_row)
+            .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn record_dataset(&self, dataset: &StoredDataset) -> Result<()> {
+        self.write(|reply| WriteCommand::RecordDataset {
+            dataset: dataset.clone(),
+            reply,
+        }).await
+    }
+
+    #[tracing::instrument(skip(self, instruments), fields(count = instruments.len()))]
+    async fn upsert_instruments(&self, instruments: &[Instrument]) -> Result<()> {
+        if instruments.is_empty() {
+            return Ok(());
+        }
+
+        self.write(|reply| WriteCommand::UpsertInstruments {
+            instruments: instruments.to_vec(),
+            reply,
+        }).await
+    }
+
+    async fn instruments(&self) -> Result<Vec<Instrument>> {
+        sqlx::query(
+            "SELECT instrument_id, raw_symbol, valid_from, valid_until FROM instruments
+             ORDER BY instrument_id, valid_from"
+        )
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query instruments")?
+            .iter()
+            .map(|row| Ok(Instrument {
+                instrument_id: row.try_get("instrument_id")?,
+                raw_symbol: row.try_get("raw_symbol")?,
+                valid_from: row.try_get::<i64, _>("valid_from")? as u64,
+                valid_until: row.try_get::<Option<i64>, _>("valid_until")?.map(|ts| ts as u64),
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to decode instruments")
+    }
+
+    #[tracing::instrument(skip(self, checkpoint), fields(message_id = checkpoint.message_id))]
+    async fn insert_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        self.write(|reply| WriteCommand::InsertCheckpoint {
+            checkpoint: checkpoint.clone(),
+            reply,
+        }).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn latest_checkpoint(&self, source: Option<&str>, message_id: Option<i64>) -> Result<Option<Checkpoint>> {
+        sqlx::query(
+            "SELECT message_id, ts_recv, source, data FROM checkpoints
+             WHERE message_id <= ?1 AND (?2 IS NULL OR source = ?2)
+             ORDER BY message_id DESC
+             LIMIT 1"
+        )
+            .bind(message_id.unwrap_or(i64::MAX))
+            .bind(source)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Failed to query checkpoints")?
+            .map(|row| Ok(Checkpoint {
+                message_id: row.try_get("message_id")?,
+                ts_recv: row.try_get::<i64, _>("ts_recv")? as u64,
+                source: row.try_get("source")?,
+                data: row.try_get("data")?,
+            }))
+            .transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn compaction_generation(&self) -> Result<u64> {
+        let generation: i64 = sqlx::query("SELECT COALESCE(MAX(generation), 0) FROM compactions")
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to query compactions")?
+            .try_get(0)?;
+
+        Ok(generation as u64)
+    }
+
+    /// Other writes wait for the whole compaction, as it's a single
+    ///  transaction
+    #[tracing::instrument(skip(self, compaction), fields(cutoff_ts = compaction.cutoff_ts, bars = compaction.bars.len()))]
+    async fn apply_compaction(&self, compaction: &Compaction) -> Result<Option<usize>> {
+        self.write(|reply| WriteCommand::ApplyCompaction {
+            compaction: compaction.clone(),
+            reply,
+        }).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn bars(&self, instrument_id: Option<u32>, start_ts: Option<u64>, end_ts: Option<u64>) -> Result<Vec<Bar>> {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "SELECT instrument_id, ts, open, high, low, close, volume, trades FROM bars WHERE 1 = 1"
+        );
+        if let Some(instrument_id) = instrument_id {
+            builder.push(" AND instrument_id = ").push_bind(instrument_id);
+        }
+        if let Some(start_ts) = start_ts {
+            builder.push(" AND ts >= ").push_bind(start_ts as i64);
+        }
+        if let Some(end_ts) = end_ts {
+            builder.push(" AND ts <= ").push_bind(end_ts as i64);
+        }
+        builder.push(" ORDER BY instrument_id, ts");
+
+        builder.build()
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to query bars")?
+            .iter()
+            .map(|row| Ok(Bar {
+                instrument_id: row.try_get("instrument_id")?,
+                ts: row.try_get::<i64, _>("ts")? as u64,
+                open: row.try_get("open")?,
+                high: row.try_get("high")?,
+                low: row.try_get("low")?,
+                close: row.try_get("close")?,
+                volume: row.try_get::<i64, _>("volume")? as u64,
+                trades: row.try_get::<i64, _>("trades")? as u64,
+            }))
+            .collect::<Result<Vec<_>>>()
+            .context("Failed to decode bars")
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn vacuum(&self) -> Result<()> {
+        self.write(|reply| WriteCommand::Vacuum { reply }).await
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        let row = sqlx::query(
+            "SELECT
+                (SELECT COUNT(*) FROM mbo_messages) AS messages,
+                (SELECT COUNT(*) FROM checkpoints) AS checkpoints,
+                (SELECT COUNT(*) FROM bars) AS bars,
+                (SELECT MIN(ts_recv) FROM mbo_messages) AS oldest_ts_recv,
+                (SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()) AS size_bytes"
+        )
+            .fetch_one(&self.pool)
+            .await
+            .context("Failed to query storage stats")?;
+
+        Ok(StorageStats {
+            messages: row.try_get::<i64, _>("messages")? as u64,
+            checkpoints: row.try_get::<i64, _>("checkpoints")? as u64,
+            bars: row.try_get::<i64, _>("bars")? as u64,
+            oldest_ts_recv: row.try_get::<Option<i64>, _>("oldest_ts_recv")?.map(|ts| ts as u64),
+            size_bytes: row.try_get::<i64, _>("size_bytes")? as u64,
+        })
+    }
+}
+
+/// A write handed to the writer task, with where to send its result
+enum WriteCommand {
+    InsertMboBatch {
+        messages: Vec<MboMsg>,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    RecordDataset {
+        dataset: StoredDataset,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    UpsertInstruments {
+        instruments: Vec<Instrument>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    InsertCheckpoint {
+        checkpoint: Checkpoint,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    ApplyCompaction {
+        compaction: Compaction,
+        reply: oneshot::Sender<Result<Option<usize>>>,
+    },
+    Vacuum {
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Apply writes one at a time until every `SqliteStore` handle is dropped
+#[tracing::instrument(skip_all)]
+async fn run_writer(pool: SqlitePool, mut commands: mpsc::Receiver<WriteCommand>) {
+    while let Some(command) = commands.recv().await {
+        // A caller that stopped waiting for its result isn't an error
+        match command {
+            WriteCommand::InsertMboBatch { messages, reply } => {
+                let _ = reply.send(insert_mbo_batch(&pool, &messages).await);
+            },
+            WriteCommand::RecordDataset { dataset, reply } => {
+                let _ = reply.send(record_dataset(&pool, &dataset).await);
+            },
+            WriteCommand::UpsertInstruments { instruments, reply } => {
+                let _ = reply.send(upsert_instruments(&pool, &instruments).await);
+            },
+            WriteCommand::InsertCheckpoint { checkpoint, reply } => {
+                let _ = reply.send(insert_checkpoint(&pool, &checkpoint).await);
+            },
+            WriteCommand::ApplyCompaction { compaction, reply } => {
+                let _ = reply.send(apply_compaction(&pool, &compaction).await);
+            },
+            WriteCommand::Vacuum { reply } => {
+                let _ = reply.send(vacuum(&pool).await);
+            },
+        }
+    }
+
+    debug!("SQLite writer task stopped");
+}
+
+async fn insert_mbo_batch(pool: &SqlitePool, messages: &[MboMsg]) -> Result<usize> {
+    let occurrences = natural_key_occurrences(messages);
+    let mut tx = pool.begin()
+        .await
+        .context("Failed to begin transaction")?;
+
+    let mut inserted = 0;
+    for (messages, occurrences) in messages.chunks(INSERT_CHUNK_SIZE).zip(occurrences.chunks(INSERT_CHUNK_SIZE)) {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "INSERT OR IGNORE INTO mbo_messages
+             (ts_recv, ts_event, instrument_id, publisher, order_id, action, side,
+              price, size, flags, sequence, ts_in_delta, channel_id, occurrence) "
+        );
+        builder.push_values(messages.iter().zip(occurrences), |mut row, (msg, occurrence)| {
+            row.push_bind(msg.ts_recv as i64)
+                .push_bind(msg.hd.ts_event as i64)
+                .push_bind(msg.hd.instrument_id)
+                .push_bind(msg.hd.publisher_id)
+                .push_bind(msg.order_id as i64)
+                .push_bind(format!("{:?}", msg.action))
+                .push_bind(format!("{:?}", msg.side))
+                .push_bind(msg.price)
+                .push_bind(msg.size)
+                .push_bind(msg.flags.raw())
+                .push_bind(msg.sequence)
+                .push_bind(msg.ts_in_delta)
+                .push_bind(msg.channel_id)
+                .push_bind(*occurrence);
+        });
+
+        inserted += builder.build()
+            .execute(&mut *tx)
+            .await
+            .context("Failed to execute insert statement")?
+            .rows_affected() as usize;
+    }
+
+    tx.commit()
+        .await
+        .context("Failed to commit transaction")?;
+    debug!("Inserted {} new of a batch of {} MBO messages", inserted, messages.len());
+
+    Ok(inserted)
+}
+
+async fn record_dataset(pool: &SqlitePool, dataset: &StoredDataset) -> Result<()> {
+    let ingested_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("System clock is before the UNIX epoch")?
+        .as_secs() as i64;
+
+    sqlx::query(
+        "INSERT OR REPLACE INTO datasets
+         (fingerprint, source, message_count, ingested_at, first_message_id, last_message_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+    )
+        .bind(&dataset.fingerprint)
+        .bind(&dataset.source)
+        .bind(dataset.message_count as i64)
+        .bind(ingested_at)
+        .bind(dataset.message_ids.map(|(first, _)| first))
+        .bind(dataset.message_ids.map(|(_, last)| last))
+        .execute(pool)
+        .await
+        .context("Failed to record dataset")?;
+
+    Ok(())
+}
+
+fn dataset_from_row(row: &SqliteRow) -> Result<StoredDataset> {
+    let first: Option<i64> = row.try_get("first_message_id")?;
+    let last: Option<i64> = row.try_get("last_message_id")?;
+
+    Ok(StoredDataset {
+        fingerprint: row.try_get("fingerprint")?,
+        source: row.try_get("source")?,
+        message_count: row.try_get::<i64, _>("message_count")? as usize,
+        message_ids: first.zip(last),
+    })
+}
+
+async fn upsert_instruments(pool: &SqlitePool, instruments: &[Instrument]) -> Result<()> {
+    let mut tx = pool.begin()
+        .await
+        .context("Failed to begin transaction")?;
+
+    for instruments in instruments.chunks(INSERT_CHUNK_SIZE) {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO instruments (instrument_id, raw_symbol, valid_from, valid_until) "
+        );
+        builder.push_values(instruments, |mut row, instrument| {
+            row.push_bind(instrument.instrument_id)
+                .push_bind(&instrument.raw_symbol)
+                .push_bind(instrument.valid_from as i64)
+                .push_bind(instrument.valid_until.map(|ts| ts as i64));
+        });
+        builder.push(
+            " ON CONFLICT (instrument_id, valid_from) DO UPDATE
+              SET raw_symbol = excluded.raw_symbol, valid_until = excluded.valid_until"
+        );
+
+        builder.build()
+            .execute(&mut *tx)
+            .await
+            .context("Failed to upsert instruments")?;
+    }
+
+    tx.commit()
+        .await
+        .context("Failed to commit transaction")
+}
+
+async fn insert_checkpoint(pool: &SqlitePool, checkpoint: &Checkpoint) -> Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO checkpoints (message_id, ts_recv, source, data) VALUES (?1, ?2, ?3, ?4)")
+        .bind(checkpoint.message_id)
+        .bind(checkpoint.ts_recv as i64)
+        .bind(&checkpoint.source)
+        .bind(&checkpoint.data)
+        .execute(pool)
+        .await
+        .context("Failed to insert checkpoint")?;
+
+    Ok(())
+}
+
+async fn apply_compaction(pool: &SqlitePool, compaction: &Compaction) -> Result<Option<usize>> {
+    let compacted_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("System clock is before the UNIX epoch")?
+        .as_secs() as i64;
+    let mut tx = pool.begin()
+        .await
+        .context("Failed to begin transaction")?;
+
+    // Compactions built at the same generation claim the same number, so
+    //  only the first of them is applied
+    let claimed = sqlx::query(
+        "INSERT INTO compactions (generation, cutoff_ts, last_message_id, compacted_at)
+         SELECT ?1, ?2, ?3, ?4 WHERE (SELECT COALESCE(MAX(generation), 0) FROM compactions) = ?5"
+    )
+        .bind(compaction.generation as i64 + 1)
+        .bind(compaction.cutoff_ts as i64)
+        .bind(compaction.last_message_id)
+        .bind(compacted_at)
+        .bind(compaction.generation as i64)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to record compaction")?
+        .rows_affected();
+    if claimed == 0 {
+        return Ok(None);
+    }
+
+    for bars in compaction.bars.chunks(INSERT_CHUNK_SIZE) {
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO bars (instrument_id, ts, open, high, low, close, volume, trades) "
+        );
+        builder.push_values(bars, |mut row, bar| {
+            row.push_bind(bar.instrument_id)
+                .push_bind(bar.ts as i64)
+                .push_bind(bar.open)
+                .push_bind(bar.high)
+                .push_bind(bar.low)
+                .push_bind(bar.close)
+                .push_bind(bar.volume as i64)
+                .push_bind(bar.trades as i64);
+        });
+        builder.push(
+            " ON CONFLICT (instrument_id, ts) DO UPDATE
+              SET high = MAX(high, excluded.high), low = MIN(low, excluded.low),
+                  close = excluded.close, volume = volume + excluded.volume,
+                  trades = trades + excluded.trades"
+        );
+
+        builder.build()
+            .execute(&mut *tx)
+            .await
+            .context("Failed to upsert bars")?;
+    }
+
+    let deleted = sqlx::query("DELETE FROM mbo_messages WHERE ts_recv < ?1 AND id <= ?2")
+        .bind(compaction.cutoff_ts as i64)
+        .bind(compaction.last_message_id)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to delete messages")?
+        .rows_affected();
+
+    tx.commit()
+        .await
+        .context("Failed to commit transaction")?;
+
+    Ok(Some(deleted as usize))
+}
+
+/// Rebuild the database file without free pages, then truncate the WAL
+///  which would otherwise keep the freed space
+async fn vacuum(pool: &SqlitePool) -> Result<()> {
+    sqlx::query("VACUUM")
+        .execute(pool)
+        .await
+        .context("Failed to vacuum database")?;
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+        .execute(pool)
+        .await
+        .context("Failed to checkpoint WAL")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use databento::dbn::decode::{dbn::Decoder, DecodeRecord};
+    use std::path::Path as StdPath;
+    
+    #[tokio::test]
+    async fn test_sqlite_store_contract() -> Result<()> {
+        let storage = SqliteStore::open(":memory:").await?;
+        crate::storage::contract::exercise_store(&storage).await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_reads_and_writes() -> Result<()> {
+        use databento::dbn::{rtype, Action, FlagSet, RecordHeader, Side};
+        use std::ffi::c_char;
+
+        let temp_db = "test_storage_concurrent.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = SqliteStore::open(temp_db).await?;
+
+        // Many tasks writing at once must all be applied without
+        //  contending for the write lock
+        let writers = (0..8u64)
+            .map(|writer| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    for batch in 0..10u64 {
+                        let messages = (0..100u64)
+                            .map(|i| {
+                                let n = (writer * 10 + batch) * 100 + i;
+                                MboMsg {
+                                    hd: RecordHeader::new::<MboMsg>(rtype::MBO, 1, writer as u32, n),
+                                    order_id: n,
+                                    price: 100,
+                                    size: 1,
+                                    flags: FlagSet::empty(),
+                                    channel_id: 0,
+                                    action: Action::Add as u8 as c_char,
+                                    side: Side::Bid as u8 as c_char,
+                                    ts_recv: n,
+                                    ts_in_delta: 0,
+                                    sequence: n as u32,
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        storage.insert_mbo_batch(&messages).await?;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Readers keep querying while the writes land
+        let readers = (0..8u32)
+            .map(|instrument_id| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    for _ in 0..20 {
+                        storage.query_messages(&MessageQuery {
+                            instrument_id: Some(instrument_id),
+                            limit: Some(100),
+                            ..Default::default()
+                        }).await?;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in writers.into_iter().chain(readers) {
+            task.await??;
+        }
+        assert_eq!(storage.count_messages().await?, 8 * 10 * 100);
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_initialization() -> Result<()> {
+        // Create a temporary database
+        let temp_db = "test_storage_init.db";
+        
+        // Clean up any existing test database
+        let _ = std::fs::remove_file(temp_db);
+        
+        let storage = SqliteStore::open(temp_db).await?;
+        let count = storage.count_messages().await?;
+        
+        assert_eq!(count, 0, "New database should have 0 messages");
+        
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+        
+        Ok(())
+    }
+    
+    #[tokio::test]
+    async fn test_storage_batch_insert() -> Result<()> {
+        use databento::dbn::MboMsg;
+        
+        let temp_db = "test_storage_batch.db";
+        let _ = std::fs::remove_file(temp_db);
+        
+        let storage = SqliteStore::open(temp_db).await?;
+        
+        // Load some real MBO messages
+        let path = StdPath::new("assets/CLX5_mbo.dbn");
+        let mut decoder = Decoder::from_file(path)?;
+        
+        let mut messages = Vec::new();
+        for _ in 0..100 {
+            if let Some(msg) = decoder.decode_record::<MboMsg>()? {
+                messages.push(msg.clone());
+            } else {
+                break;
+            }
+        }
+        
+        assert!(!messages.is_empty(), "Should have loaded some messages");
+        
+        // Insert batch
+        storage.insert_mbo_batch(&messages).await?;
+        
+        // Verify count
+        let count = storage.count_messages().await?;
+        assert_eq!(count, messages.len(), "Should have inserted all messages");
+        
+        println!("Successfully inserted {} messages", count);
+        
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+        
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_repeated_insert_is_noop() -> Result<()> {
+        let temp_db = "test_storage_idempotent.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = SqliteStore::open(temp_db).await?;
+
+        let path = StdPath::new("assets/CLX5_mbo.dbn");
+        let mut decoder = Decoder::from_file(path)?;
+        let mut messages = Vec::new();
+        while let Some(msg) = decoder.decode_record::<MboMsg>()? {
+            messages.push(msg.clone());
+        }
+
+        let inserted = storage.insert_mbo_batch(&messages).await?;
+        assert_eq!(inserted, messages.len(), "Every message should be new the first time");
+        assert_eq!(storage.count_messages().await?, messages.len());
+
+        // Same messages again, whole and in event-aligned halves
+        assert_eq!(storage.insert_mbo_batch(&messages).await?, 0, "Repeated batch should insert nothing");
+        let split = messages.iter()
+            .position(|msg| msg.flags.is_last())
+            .context("No end of event in messages")? + 1;
+        assert_eq!(storage.insert_mbo_batch(&messages[..split]).await?, 0);
+        assert_eq!(storage.insert_mbo_batch(&messages[split..]).await?, 0);
+        assert_eq!(storage.count_messages().await?, messages.len(), "Repeated ingestion should be a no-op");
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_keeps_messages_sharing_natural_key() -> Result<()> {
+        use databento::dbn::{rtype, Action, FlagSet, RecordHeader, Side};
+        use std::ffi::c_char;
+
+        let temp_db = "test_storage_natural_key.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = SqliteStore::open(temp_db).await?;
+
+        // An aggressor trading through two levels, then a fill followed by
+        //  a cancel of the same resting order, all in one event
+        let msg = |action: Action, order_id: u64, price: i64, flags: u8| MboMsg {
+            hd: RecordHeader::new::<MboMsg>(rtype::MBO, 1, 42, 1_000),
+            order_id,
+            price,
+            size: 1,
+            flags: FlagSet::new(flags),
+            channel_id: 0,
+            action: action as u8 as c_char,
+            side: Side::Bid as u8 as c_char,
+            ts_recv: 2_000,
+            ts_in_delta: 0,
+            sequence: 7,
+        };
+        let messages = vec![
+            msg(Action::Trade, 0, 100, 0),
+            msg(Action::Trade, 0, 101, 0),
+            msg(Action::Fill, 5, 100, 0),
+            msg(Action::Cancel, 5, 100, databento::dbn::flags::LAST),
+        ];
+
+        assert_eq!(storage.insert_mbo_batch(&messages).await?, messages.len());
+        assert_eq!(storage.insert_mbo_batch(&messages).await?, 0);
+
+        let stored = storage.query_messages(&MessageQuery::default()).await?
+            .into_iter()
+            .map(|stored| stored.mbo_msg)
+            .collect::<Vec<_>>();
+        assert_eq!(stored, messages, "Messages sharing a natural key should all be kept");
+
+        // Clean up
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_replay_matches_inserted() -> Result<()> {
+        use crate::ingest::{MboSource, storage::StorageSource};
+
+        let temp_db = "test_storage_replay.db";
+        let _ = std::fs::remove_file(temp_db);
+
+        let storage = SqliteStore::open(temp_db).await?;
+
+        let path = StdPath::new("assets/CLX5_mbo.dbn");
+        let mut decoder = Decoder::from_file(path)?;
+        let mut messages = Vec::new();
+        for _ in 0..25_000 {
+            let Some(msg) = decoder.decode_record::<MboMsg>()? else {
+                break;
+            };
+            messages.push(msg.clone());
+        }
+        storage.insert_mbo_batch(&messages).await?;
+
+        // Replay spans several pages and must come back in order
+        let mut source = StorageSource::new(storage.clone(), MessageQuery::default());
+        let mut replayed = Vec::new();
+        while let Some(msg) = source.next_mbo().await? {
+            replayed.push(msg);
+        }
+
+        assert_eq!(replayed.len(), messages.len());
+        for (original, replayed) in messages.iter().zip(replayed.iter()) {
+            assert_eq!(original, replayed, "Replayed message should equal the inserted one");
+        }
+
+        // Clean up
+        drop(source);
+        drop(storage);
+        let _ = std::fs::remove_file(temp_db);
+        let _ = std::fs::remove_file(format!("{}-shm", temp_db));
+        let _ = std::fs::remove_file(format!("{}-wal", temp_db));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use std::ffi::c_char;
+
+use databento::dbn::{rtype, Action, FlagSet, MboMsg, RecordHeader, Side};
+use anyhow::{Context, Result, anyhow};
+use sqlx::{sqlite::SqliteRow, QueryBuilder, Row, Sqlite, SqlitePool};
+
+use crate::storage::{MessageQuery, StoredMbo};
+
+/// Read every message matching a query, see `MarketStore::query_messages`
+pub(super) async fn query_messages(pool: &SqlitePool, query: &MessageQuery) -> Result<Vec<StoredMbo>> {
+    let mut builder = QueryBuilder::<Sqlite>::new(
+        "SELECT id, ts_recv, ts_event, instrument_id, publisher, order_id, action, side,
+                price, size, flags, sequence, ts_in_delta, channel_id
+         FROM mbo_messages WHERE 1 = 1"
+    );
+
+    if let Some(instrument_id) = query.instrument_id {
+        builder.push(" AND instrument_id = ").push_bind(instrument_id);
+    }
+    if let Some(publisher) = query.publisher {
+        builder.push(" AND publisher = ").push_bind(publisher);
+    }
+    if let Some(order_id) = query.order_id {
+        builder.push(" AND order_id = ").push_bind(order_id as i64);
+    }
+    // Stored in the same `{:?}` format the messages were inserted with
+    if let Some(action) = query.action {
+        builder.push(" AND action = ").push_bind(format!("{:?}", action as u8 as c_char));
+    }
+    if let Some(side) = query.side {
+        builder.push(" AND side = ").push_bind(format!("{:?}", side as u8 as c_char));
+    }
+    if let Some(start) = query.start_ts {
+        builder.push(" AND ts_recv >= ").push_bind(start as i64);
+    }
+    if let Some(end) = query.end_ts {
+        builder.push(" AND ts_recv <= ").push_bind(end as i64);
+    }
+    if let Some(start_id) = query.start_id {
+        builder.push(" AND id >= ").push_bind(start_id);
+    }
+    if let Some(end_id) = query.end_id {
+        builder.push(" AND id <= ").push_bind(end_id);
+    }
+    if let Some(after_id) = query.after_id {
+        builder.push(if query.descending { " AND id < " } else { " AND id > " })
+            .push_bind(after_id);
+    }
+
+    builder.push(if query.descending { " ORDER BY id DESC" } else { " ORDER BY id ASC" });
+
+    if let Some(limit) = query.limit {
+        builder.push(" LIMIT ").push_bind(limit as i64);
+    }
+
+    builder.build()
+        .fetch_all(pool)
+        .await
+        .context("Failed to query messages")?
+        .iter()
+        .map(|row| Ok(StoredMbo {
+            id: row.try_get("id")?,
+            mbo_msg: mbo_from_row(row)?,
+        }))
+        .collect::<Result<Vec<_>>>()
+        .context("Failed to decode query results")
+}
+
+/// Rebuild an `MboMsg` from a row of `mbo_messages`
+fn mbo_from_row(row: &SqliteRow) -> Result<MboMsg> {
+    let action: String = row.try_get("action")?;
+    let side: String = row.try_get("side")?;
+
+    Ok(MboMsg {
+        hd: RecordHeader::new::<MboMsg>(
+            rtype::MBO,
+            row.try_get("publisher")?,
+            row.try_get("instrument_id")?,
+            row.try_get::<i64, _>("ts_event")? as u64,
+        ),
+        order_id: row.try_get::<i64, _>("order_id")? as u64,
+        price: row.try_get("price")?,
+        size: row.try_get("size")?,
+        flags: FlagSet::new(row.try_get("flags")?),
+        channel_id: row.try_get("channel_id")?,
+        action: parse_enum::<Action>(&action)
+            .ok_or_else(|| anyhow!("Invalid action `{}`", action))?,
+        side: parse_enum::<Side>(&side)
+            .ok_or_else(|| anyhow!("Invalid side `{}`", side))?,
+        ts_recv: row.try_get::<i64, _>("ts_recv")? as u64,
+        ts_in_delta: row.try_get("ts_in_delta")?,
+        sequence: row.try_get("sequence")?,
+    })
+}
+
+/// Inverse of the `{:?}` formatting used to store actions and sides,
+///  which writes the raw `c_char` as a decimal number
+fn parse_enum<T: TryFrom<u8>>(text: &str) -> Option<c_char> {
+    let raw = text.parse::<u8>().ok()?;
+    T::try_from(raw).ok()?;
+    Some(raw as c_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MarketStore, MessageStream, Storage};
+    use proptest::prelude::*;
+
+    const ACTIONS: [Action; 7] = [
+        Action::Add, Action::Cancel, Action::Modify, Action::Clear,
+        Action::Trade, Action::Fill, Action::None,
+    ];
+    const SIDES: [Side; 3] = [Side::Ask, Side::Bid, Side::None];
+
+    prop_compose! {
+        fn arb_mbo()(
+            publisher_id: u16,
+            instrument_id: u32,
+            ts_event: u64,
+            order_id: u64,
+            price: i64,
+            size: u32,
+            flags: u8,
+            channel_id: u8,
+            action in prop::sample::select(&ACTIONS[..]),
+            side in prop::sample::select(&SIDES[..]),
+            ts_recv: u64,
+            ts_in_delta: i32,
+            sequence: u32,
+        ) -> MboMsg {
+            MboMsg {
+                hd: RecordHeader::new::<MboMsg>(rtype::MBO, publisher_id, instrument_id, ts_event),
+                order_id,
+                price,
+                size,
+                flags: FlagSet::new(flags),
+                channel_id,
+                action: action as u8 as c_char,
+                side: side as u8 as c_char,
+                ts_recv,
+                ts_in_delta,
+                sequence,
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_messages_round_trip(messages in prop::collection::vec(arb_mbo(), 1..50)) {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let first = &messages[0];
+            let filter = MessageQuery {
+                publisher: Some(first.hd.publisher_id),
+                action: Some(first.action()?),
+                side: Some(first.side()?),
+                ..Default::default()
+            };
+            let (stored, streamed, filtered) = runtime.block_on(async {
+                let storage = Storage::open(":memory:").await?;
+                storage.insert_mbo_batch(&messages).await?;
+
+                let stored = storage.query_messages(&MessageQuery::default()).await?;
+                let filtered = storage.query_messages(&filter).await?;
+
+                let mut stream = MessageStream::new(storage, MessageQuery::default());
+                let mut streamed = Vec::new();
+                while let Some(stored) = stream.next_message().await? {
+                    streamed.push(stored);
+                }
+
+                anyhow::Ok((stored, streamed, filtered))
+            }).unwrap();
+
+            let stored = stored.into_iter().map(|stored| stored.mbo_msg).collect::<Vec<_>>();
+            prop_assert_eq!(&stored, &messages);
+            let streamed = streamed.into_iter().map(|stored| stored.mbo_msg).collect::<Vec<_>>();
+            prop_assert_eq!(&streamed, &messages);
+
+            let expected = messages.iter()
+                .filter(|msg| msg.hd.publisher_id == first.hd.publisher_id
+                    && msg.action == first.action
+                    && msg.side == first.side)
+                .cloned()
+                .collect::<Vec<_>>();
+            let filtered = filtered.into_iter().map(|stored| stored.mbo_msg).collect::<Vec<_>>();
+            prop_assert_eq!(&filtered, &expected);
+        }
+    }
+}